@@ -6,6 +6,7 @@ use std::{
     ops::Deref,
     path::{Path, PathBuf},
     sync::atomic::AtomicUsize,
+    time::{Duration, Instant},
 };
 
 use crate::pagecache::{arr_to_u32, u32_to_arr, Heap};
@@ -13,9 +14,39 @@ use crate::*;
 
 const DEFAULT_PATH: &str = "default.sled";
 
+/// The version of the persisted config file format. Files
+/// written before the format marker was introduced are
+/// treated as format 0.
+const CONFIG_FORMAT: usize = 1;
+
+/// Marks a persisted config file that starts with a header
+/// declaring the length of the body that follows it. Files
+/// written before the header was introduced start directly
+/// with the body, which is always plain text.
+const CONFIG_HEADER_MAGIC: [u8; 4] = *b"SLDC";
+
+/// The length of the magic bytes followed by the declared
+/// body length.
+const CONFIG_HEADER_LEN: usize = 8;
+
+/// The number of IO buffers assumed by
+/// `Config::estimated_memory_footprint`. The log does not keep
+/// a fixed pool of them, but one is always being filled while
+/// at least one sealed buffer is usually being written.
+const ESTIMATED_IO_BUFFERS: usize = 2;
+
+/// How long `StagedDb::promote` waits for IO that was still
+/// in flight when the staged `Db` was dropped.
+const STAGED_CLOSE_WAIT: Duration = Duration::from_secs(1);
+
+/// The smallest cache that `Config::cache_capacity_fraction`
+/// will resolve to, regardless of how little memory is detected.
+const MIN_CACHE_CAPACITY_FROM_FRACTION: usize = 4 * 1024 * 1024;
+
 /// The high-level database mode, according to
 /// the trade-offs of the RUM conjecture.
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 pub enum Mode {
     /// In this mode, the database will make
     /// decisions that favor using less space
@@ -30,22 +61,1106 @@ pub enum Mode {
     HighThroughput,
 }
 
+/// Wraps a user-provided callback so that it can be
+/// stored in a `Config` while keeping it `Debug`.
+pub(crate) struct Callback<T: ?Sized>(pub(crate) Arc<T>);
+
+impl<T: ?Sized> Clone for Callback<T> {
+    fn clone(&self) -> Self {
+        Callback(self.0.clone())
+    }
+}
+
+impl<T: ?Sized> Debug for Callback<T> {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> std::result::Result<(), fmt::Error> {
+        f.write_str("Callback")
+    }
+}
+
+impl<T: ?Sized> Deref for Callback<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+type FileOptionsFn = dyn Fn(&mut fs::OpenOptions) + Send + Sync;
+
+type GlobalErrorFn = dyn Fn(&Error) + Send + Sync;
+
+type MergeOperatorFn =
+    dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Option<Vec<u8>> + Send + Sync;
+
+type RecoveryProgressFn = dyn Fn(RecoveryProgress) + Send + Sync;
+
+type VersionPolicyFn =
+    dyn Fn((usize, usize), (usize, usize)) -> bool + Send + Sync;
+
+/// A transformation applied to data as it is written to and
+/// read from disk, which may be used to implement encryption
+/// at rest without building cryptography into sled.
+///
+/// Log segments are read and written in arbitrary sub-ranges,
+/// so implementations must not change the length of the
+/// buffer, and must transform each byte based only on its
+/// LSN, as a stream cipher in counter mode does: decoding
+/// any sub-range of an encoded buffer, given the LSN of its
+/// first byte, must produce the original bytes. The short
+/// header at the start of each segment is not transformed.
+/// Large items stored in the heap are transformed as a
+/// whole, using the LSN of the log message that refers to
+/// them.
+pub trait SegmentTransform: Send + Sync {
+    /// A stable identifier for this transform, which is
+    /// persisted so that reopening the database with a
+    /// different transform, or none at all, is rejected.
+    /// Must not be empty or contain newlines.
+    fn id(&self) -> &str;
+
+    /// Transforms bytes that are about to be written, where
+    /// `lsn` is the LSN of the first byte of `buf`.
+    fn encode(&self, lsn: u64, buf: &mut Vec<u8>);
+
+    /// Reverses `encode` for bytes that have just been read,
+    /// where `lsn` is the LSN of the first byte of `buf`.
+    fn decode(&self, lsn: u64, buf: &mut Vec<u8>) -> Result<()>;
+}
+
+/// Controls how failures to fsync the database directory
+/// are handled. Some filesystems, such as certain network
+/// mounts, do not support fsyncing directories at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum DirFsyncPolicy {
+    /// Any failure to fsync a directory is returned as an
+    /// error. This is the default.
+    Required,
+    /// Failures caused by the filesystem not supporting
+    /// directory fsync (`ENOTSUP` or `EINVAL`) are logged
+    /// and ignored. Other failures are still returned.
+    BestEffort,
+    /// Directories are never fsynced. Newly created or
+    /// renamed files may be lost after a crash.
+    Disabled,
+}
+
+/// Which CRC polynomial checksums the log, the heap and
+/// snapshots. This is chosen when a database is created,
+/// and reopening it with a different variant is rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum CrcVariant {
+    /// The IEEE CRC32 polynomial. This is the default, and
+    /// what databases created before this could be chosen use.
+    Crc32,
+    /// The Castagnoli CRC32C polynomial, which is computed
+    /// with a single instruction per 8 bytes on x86_64 CPUs
+    /// with SSE4.2, making it much faster there.
+    Crc32c,
+}
+
+impl CrcVariant {
+    /// The name that is persisted in the config file.
+    const fn name(self) -> &'static str {
+        match self {
+            CrcVariant::Crc32 => "crc32",
+            CrcVariant::Crc32c => "crc32c",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<CrcVariant> {
+        match name {
+            "crc32" => Some(CrcVariant::Crc32),
+            "crc32c" => Some(CrcVariant::Crc32c),
+            _ => None,
+        }
+    }
+}
+
+/// Which system call is used to make written data durable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum SyncMode {
+    /// Uses `fsync`, which also flushes all file metadata,
+    /// such as modification times. This is the default.
+    SyncAll,
+    /// Uses `fdatasync` where it is available, which only
+    /// flushes the metadata needed to read the data back,
+    /// such as the file size. This is faster when data is
+    /// overwritten in place. Elsewhere, this behaves like
+    /// `SyncAll`.
+    SyncData,
+}
+
+/// How the page cache chooses which pages to evict once it
+/// holds more than `cache_capacity` bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum EvictionPolicy {
+    /// Evicts the least recently used page. This is the
+    /// default.
+    Lru,
+    /// Splits the cache into a probationary segment for pages
+    /// that have been accessed once and a protected segment
+    /// for pages that have been accessed again since, and
+    /// evicts from the probationary segment first. A scan
+    /// over many pages does not push out pages that are read
+    /// repeatedly.
+    SegmentedLru,
+    /// Tracks how often each page has recently been accessed,
+    /// and only admits a new page by evicting the least
+    /// recently used one if the new page has been accessed at
+    /// least as often. This favors pages that are read
+    /// repeatedly over a long time.
+    Lfu,
+}
+
+/// How opening a database that was created by a different
+/// version of sled is handled. Versions are `(major, minor)`
+/// pairs, as returned by `sled::version`, and a database
+/// created by a different major version is always rejected.
+/// When a database is opened despite a different minor
+/// version, the current version is recorded in its `conf`
+/// file.
+#[derive(Clone)]
+pub enum VersionPolicy {
+    /// Rejects any difference in version. This is the
+    /// default.
+    Strict,
+    /// Opens databases created by an older minor version, and
+    /// rejects those created by a newer one.
+    AutoMinor,
+    /// Calls the function with the stored and the current
+    /// version, and opens the database only if it returns
+    /// `true`.
+    Callback(Arc<VersionPolicyFn>),
+}
+
+impl Debug for VersionPolicy {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> std::result::Result<(), fmt::Error> {
+        match self {
+            VersionPolicy::Strict => f.write_str("Strict"),
+            VersionPolicy::AutoMinor => f.write_str("AutoMinor"),
+            VersionPolicy::Callback(_) => f.write_str("Callback"),
+        }
+    }
+}
+
+impl VersionPolicy {
+    /// Whether a database created by version `stored` may be
+    /// opened by version `current`.
+    fn allows(&self, stored: (usize, usize), current: (usize, usize)) -> bool {
+        if stored == current {
+            return true;
+        }
+        if stored.0 != current.0 {
+            return false;
+        }
+        match self {
+            VersionPolicy::Strict => false,
+            VersionPolicy::AutoMinor => stored.1 < current.1,
+            VersionPolicy::Callback(f) => f(stored, current),
+        }
+    }
+}
+
+/// How the data file is expected to be accessed, which is
+/// passed to the kernel with `posix_fadvise` when the
+/// database is opened. This is only a hint, and it has no
+/// effect on platforms other than Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum AccessPattern {
+    /// No particular access pattern. This is the default,
+    /// and no hint is given.
+    Normal,
+    /// Reads are expected at random offsets, so readahead
+    /// is not useful.
+    Random,
+    /// Reads are expected in order. This also prefetches
+    /// each segment while the previous one is being
+    /// recovered.
+    Sequential,
+    /// The whole file is expected to be read soon, so it
+    /// is prefetched into the kernel's page cache.
+    WillNeed,
+    /// The file is not expected to be read soon, so the
+    /// kernel may drop it from its page cache.
+    DontNeed,
+}
+
+impl AccessPattern {
+    /// Gives this hint for `len` bytes of `file` starting at
+    /// `offset`, where a `len` of 0 extends to the end of the
+    /// file.
+    #[cfg(all(target_os = "linux", not(miri)))]
+    pub(crate) fn advise(
+        self,
+        file: &File,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let advice = match self {
+            AccessPattern::Normal => libc::POSIX_FADV_NORMAL,
+            AccessPattern::Random => libc::POSIX_FADV_RANDOM,
+            AccessPattern::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            AccessPattern::WillNeed => libc::POSIX_FADV_WILLNEED,
+            AccessPattern::DontNeed => libc::POSIX_FADV_DONTNEED,
+        };
+
+        // off_t is only 32 bits wide on some 32 bit targets
+        let to_off_t = |n: u64| {
+            libc::off_t::try_from(n).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "access pattern range exceeds off_t",
+                )
+            })
+        };
+        let offset = to_off_t(offset)?;
+        let len = to_off_t(len)?;
+
+        #[allow(unsafe_code)]
+        let ret = unsafe {
+            libc::posix_fadvise(file.as_raw_fd(), offset, len, advice)
+        };
+
+        // unlike most calls, the error is returned directly
+        // rather than through errno.
+        if ret == 0 { Ok(()) } else { Err(io::Error::from_raw_os_error(ret)) }
+    }
+
+    #[cfg(any(not(target_os = "linux"), miri))]
+    #[allow(clippy::unused_self)]
+    pub(crate) fn advise(self, _: &File, _: u64, _: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Whether `Config::open_reporting` created a new
+/// database or opened one that already existed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenOutcome {
+    /// No valid `conf` file was found, so a new
+    /// database was created.
+    Created,
+    /// An existing database was opened.
+    Opened,
+}
+
+/// The state of the lock of a database directory, as
+/// returned by `sled::diagnose_lock`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockDiagnosis {
+    /// Whether a process, possibly this one, currently has
+    /// the database open.
+    pub locked: bool,
+    /// Whether the `DO_NOT_USE_THIS_DIRECTORY_FOR_ANYTHING`
+    /// marker file exists. It is created when the database
+    /// is opened and left behind after it is closed, so on
+    /// its own it does not mean that the database is in use.
+    pub marker_exists: bool,
+}
+
+/// A problem that did not prevent a database from being
+/// opened, but that may deserve attention. These are also
+/// logged as they happen, and can be retrieved afterward
+/// with `Db::open_warnings`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OpenWarning {
+    /// The `conf` file was empty or too short to hold any
+    /// settings, so it was rewritten from the `Config`.
+    EmptyConfig,
+    /// The CRC stored in the `conf` file did not match its
+    /// contents, so its settings could not be verified.
+    ConfigCrcMismatch {
+        /// The CRC stored at the end of the file.
+        expected: u32,
+        /// The CRC computed over the file's contents.
+        actual: u32,
+    },
+    /// A directory could not be fsynced because the
+    /// filesystem does not support it, and the failure was
+    /// ignored because of `DirFsyncPolicy::BestEffort`.
+    DirectoryFsyncUnsupported,
+    /// A message in the log failed its checksum during
+    /// recovery, so it and the rest of its segment were not
+    /// replayed.
+    LogChecksumMismatch {
+        /// The log sequence number of the message.
+        lsn: u64,
+    },
+    /// A segment of the data file failed its CRC check in the
+    /// scan of every segment that `verify_only` runs after
+    /// recovery. This also covers segments that recovery did
+    /// not replay because a snapshot already accounts for them.
+    CorruptSegment {
+        /// The offset of the segment in the data file.
+        offset: u64,
+    },
+    /// A segment of the log came after the point where
+    /// recovery stopped, such as after a write that was torn
+    /// by a crash, so it was not replayed. Unless the
+    /// database was opened with `verify_only`, the segment
+    /// was also zeroed.
+    UnreplayedSegment {
+        /// The log sequence number the segment starts at.
+        lsn: u64,
+    },
+    /// The database is stored on a filesystem where fsync
+    /// does not give sled's durability guarantees, such as a
+    /// network filesystem, or a tmpfs for a database that is
+    /// not `temporary`. Set `Config::allow_risky_filesystem`
+    /// to silence this once the risk is understood.
+    RiskyFilesystem {
+        /// The kind of filesystem, such as `nfs`, `overlayfs`
+        /// or `tmpfs`.
+        fs_type: &'static str,
+    },
+    /// Replaying the log took longer than
+    /// `Config::max_recovery_duration`, so recovery stopped
+    /// early and the segments after it were discarded, along
+    /// with any writes they held. Each discarded segment is
+    /// also recorded as an `UnreplayedSegment`.
+    ///
+    /// Replay stops at the end of the last segment it fully
+    /// replayed, and every later segment is permanently
+    /// zeroed, including writes that were already durable.
+    /// The database opens in the consistent state that the
+    /// replayed segments describe, and the loss is also
+    /// logged as an error. Replay only stops where those
+    /// segments are consistent on their own, so it continues
+    /// past the budget while inside the first segment or a
+    /// batch, and replays the whole log once it finds that a
+    /// segment written since the last snapshot has been
+    /// reused, as happens when pages are rewritten. Frequent
+    /// snapshots make stopping early more likely.
+    PartialRecovery {
+        /// The log sequence number that the database was
+        /// recovered to.
+        recovered_to_lsn: u64,
+        /// The number of segments that were discarded.
+        skipped_segments: u64,
+    },
+    /// A snapshot file was partially written, either one that
+    /// failed its length or CRC check, or a `.generating` file
+    /// older than `Config::stale_snapshot_age` that was left
+    /// behind by a crash, so it was not used. Unless the
+    /// database was opened with `verify_only`, it was also
+    /// removed.
+    DiscardedSnapshot {
+        /// The path of the snapshot file.
+        path: PathBuf,
+    },
+    /// `cache_capacity` is less than twice `segment_size`, so
+    /// the cache can't hold the pages of even a couple of
+    /// segments and is constantly evicting. Set
+    /// `Config::enforce_min_cache` to refuse to open instead.
+    SmallCache {
+        /// The `cache_capacity` the database was opened with,
+        /// after limiting it to the available memory.
+        cache_capacity: usize,
+        /// The smallest recommended `cache_capacity`, which is
+        /// twice `segment_size`.
+        minimum: usize,
+    },
+}
+
+/// A preset for the timing-related configuration
+/// options, trading off throughput against how much
+/// recent work may be lost or redone after a crash.
+///
+/// Setting a profile with `Config::durability_profile`
+/// sets `flush_every_ms`, `idgen_persist_interval` and
+/// `snapshot_after_ops` together. Any of them may be
+/// overridden afterward by calling their individual
+/// setters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DurabilityProfile {
+    /// Flushes every 2000ms, persists the id generator
+    /// every 10,000,000 ids, and snapshots every
+    /// 10,000,000 operations.
+    MaxThroughput,
+    /// The defaults. Flushes every 500ms, persists the id
+    /// generator every 1,000,000 ids, and snapshots every
+    /// 1,000,000 operations.
+    Balanced,
+    /// Flushes every 50ms, persists the id generator every
+    /// 1000 ids, and snapshots every 10,000 operations.
+    MaxDurability,
+}
+
+impl DurabilityProfile {
+    /// Returns the `(flush_every_ms, idgen_persist_interval,
+    /// snapshot_after_ops)` values for this profile.
+    const fn intervals(self) -> (Option<u64>, u64, u64) {
+        match self {
+            DurabilityProfile::MaxThroughput => {
+                (Some(2000), 10_000_000, 10_000_000)
+            }
+            DurabilityProfile::Balanced => (Some(500), 1_000_000, 1_000_000),
+            DurabilityProfile::MaxDurability => (Some(50), 1000, 10_000),
+        }
+    }
+}
+
+/// A zstd compression level, which is always within the
+/// supported range of 1 to 22. Levels of 20 and above are
+/// zstd's "ultra" levels, which use much more memory while
+/// compressing and decompressing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CompressionFactor(u8);
+
+impl CompressionFactor {
+    /// The fastest level, with the lowest compression ratio.
+    pub const FASTEST: CompressionFactor = CompressionFactor(1);
+    /// The level used when none is configured.
+    pub const DEFAULT: CompressionFactor = CompressionFactor(5);
+    /// The lowest of the "ultra" levels.
+    pub const ULTRA: CompressionFactor = CompressionFactor(20);
+    /// The highest level, with the best compression ratio.
+    pub const MAX: CompressionFactor = CompressionFactor(22);
+
+    /// Returns the level, or `None` if it is not between
+    /// 1 and 22.
+    pub const fn new(level: u8) -> Option<CompressionFactor> {
+        if level >= CompressionFactor::FASTEST.0
+            && level <= CompressionFactor::MAX.0
+        {
+            Some(CompressionFactor(level))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the numeric level.
+    pub const fn get(self) -> u8 {
+        self.0
+    }
+
+    /// Whether this is one of zstd's "ultra" levels.
+    pub const fn is_ultra(self) -> bool {
+        self.0 >= CompressionFactor::ULTRA.0
+    }
+}
+
+impl Default for CompressionFactor {
+    fn default() -> CompressionFactor {
+        CompressionFactor::DEFAULT
+    }
+}
+
+/// A commonly used segment size, for selecting one from
+/// runtime configuration with `Config::segment_size_preset`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub enum SegmentSizePreset {
+    /// 256 KiB segments.
+    Segment256K,
+    /// 512 KiB segments, the default.
+    Segment512K,
+    /// 1 MiB segments.
+    Segment1M,
+    /// 2 MiB segments.
+    Segment2M,
+    /// 4 MiB segments.
+    Segment4M,
+}
+
+impl SegmentSizePreset {
+    /// Returns the segment size in bytes.
+    pub const fn bytes(self) -> usize {
+        match self {
+            SegmentSizePreset::Segment256K => 256 * 1024,
+            SegmentSizePreset::Segment512K => 512 * 1024,
+            SegmentSizePreset::Segment1M => 1024 * 1024,
+            SegmentSizePreset::Segment2M => 2 * 1024 * 1024,
+            SegmentSizePreset::Segment4M => 4 * 1024 * 1024,
+        }
+    }
+}
+
+/// A set of optional overrides for the settings of a
+/// `Config`, for layering configuration from several
+/// sources with `Config::merge`. Fields that are `Some`
+/// replace the current value, and fields that are `None`
+/// leave it alone.
+///
+/// With the `serde` feature enabled, this implements
+/// `Deserialize` and `Serialize`, so it can be read directly
+/// from a configuration file in which every field is
+/// optional. Settings that can be turned off, like
+/// `flush_every_ms`, are written as the string `"none"`
+/// when they are off, and durations are written as a whole
+/// number of milliseconds. Hooks like `file_options` and
+/// `segment_transform` cannot be deserialized and are not
+/// included.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct PartialConfig {
+    /// See `Config::path`.
+    pub path: Option<PathBuf>,
+    /// See `Config::cache_capacity`.
+    pub cache_capacity: Option<usize>,
+    /// See `Config::cache_capacity_fraction`.
+    pub cache_capacity_fraction: Option<f64>,
+    /// See `Config::mode`.
+    pub mode: Option<Mode>,
+    /// See `Config::use_compression`.
+    pub use_compression: Option<bool>,
+    /// See `Config::compression_factor`.
+    pub compression_factor: Option<i32>,
+    /// See `Config::temporary`.
+    pub temporary: Option<bool>,
+    /// See `Config::temporary_collision_retries`.
+    pub temporary_collision_retries: Option<usize>,
+    /// See `Config::create_new`.
+    pub create_new: Option<bool>,
+    /// See `Config::segment_size`.
+    pub segment_size: Option<usize>,
+    /// See `Config::flush_every_ms`.
+    #[cfg_attr(feature = "serde", serde(with = "none_or"))]
+    pub flush_every_ms: Option<Option<u64>>,
+    /// See `Config::flush_thread_name`.
+    pub flush_thread_name: Option<String>,
+    /// See `Config::flush_thread_stack_size`.
+    #[cfg_attr(feature = "serde", serde(with = "none_or"))]
+    pub flush_thread_stack_size: Option<Option<usize>>,
+    /// See `Config::idgen_persist_interval`.
+    pub idgen_persist_interval: Option<u64>,
+    /// See `Config::snapshot_after_ops`.
+    pub snapshot_after_ops: Option<u64>,
+    /// See `Config::fast_clean_restart`.
+    pub fast_clean_restart: Option<bool>,
+    /// See `Config::directory_fsync`.
+    pub directory_fsync: Option<DirFsyncPolicy>,
+    /// See `Config::allow_nested`.
+    pub allow_nested: Option<bool>,
+    /// See `Config::follow_heap_symlink`.
+    pub follow_heap_symlink: Option<bool>,
+    /// See `Config::nested_check_depth`.
+    pub nested_check_depth: Option<usize>,
+    /// See `Config::crc_variant`.
+    pub crc_variant: Option<CrcVariant>,
+    /// See `Config::io_alignment`.
+    pub io_alignment: Option<usize>,
+    /// See `Config::zero_on_reuse`.
+    pub zero_on_reuse: Option<bool>,
+    /// See `Config::create_parents`.
+    pub create_parents: Option<bool>,
+    /// See `Config::max_value_size`.
+    #[cfg_attr(feature = "serde", serde(with = "none_or"))]
+    pub max_value_size: Option<Option<usize>>,
+    /// See `Config::max_unflushed_bytes`.
+    #[cfg_attr(feature = "serde", serde(with = "none_or"))]
+    pub max_unflushed_bytes: Option<Option<u64>>,
+    /// See `Config::max_transaction_retries`.
+    #[cfg_attr(feature = "serde", serde(with = "none_or"))]
+    pub max_transaction_retries: Option<Option<usize>>,
+    /// See `Config::compaction_fill_threshold`.
+    pub compaction_fill_threshold: Option<f32>,
+    /// See `Config::enforce_min_cache`.
+    pub enforce_min_cache: Option<bool>,
+    /// See `Config::allow_risky_filesystem`.
+    pub allow_risky_filesystem: Option<bool>,
+    /// See `Config::max_background_threads`.
+    #[cfg_attr(feature = "serde", serde(with = "none_or"))]
+    pub max_background_threads: Option<Option<usize>>,
+    /// See `Config::snapshot_after_bytes`.
+    #[cfg_attr(feature = "serde", serde(with = "none_or"))]
+    pub snapshot_after_bytes: Option<Option<u64>>,
+    /// See `Config::stale_snapshot_age`.
+    #[cfg_attr(feature = "serde", serde(with = "millis"))]
+    pub stale_snapshot_age: Option<Duration>,
+    /// See `Config::max_segment_reuses`.
+    #[cfg_attr(feature = "serde", serde(with = "none_or"))]
+    pub max_segment_reuses: Option<Option<u32>>,
+    /// See `Config::lock_wait`.
+    #[cfg_attr(feature = "serde", serde(with = "none_or_millis"))]
+    pub lock_wait: Option<Option<Duration>>,
+    /// See `Config::open_timeout`.
+    #[cfg_attr(feature = "serde", serde(with = "none_or_millis"))]
+    pub open_timeout: Option<Option<Duration>>,
+    /// See `Config::max_recovery_duration`.
+    #[cfg_attr(feature = "serde", serde(with = "none_or_millis"))]
+    pub max_recovery_duration: Option<Option<Duration>>,
+    /// See `Config::recovery_read_buffer_size`.
+    pub recovery_read_buffer_size: Option<usize>,
+    /// See `Config::keep_temporary_on_error`.
+    pub keep_temporary_on_error: Option<bool>,
+    /// See `Config::temporary_delete_retries`.
+    pub temporary_delete_retries: Option<usize>,
+    /// See `Config::async_temp_cleanup`.
+    pub async_temp_cleanup: Option<bool>,
+    /// See `Config::share_process_local`.
+    pub share_process_local: Option<bool>,
+    /// See `Config::max_unflushed_wait`.
+    #[cfg_attr(feature = "serde", serde(with = "none_or_millis"))]
+    pub max_unflushed_wait: Option<Option<Duration>>,
+    /// See `Config::access_pattern`.
+    pub access_pattern: Option<AccessPattern>,
+    /// See `Config::sync_mode`.
+    pub sync_mode: Option<SyncMode>,
+    /// See `Config::eviction_policy`.
+    pub eviction_policy: Option<EvictionPolicy>,
+    /// See `Config::verify_only`.
+    pub verify_only: Option<bool>,
+    /// See `Config::recover_to_snapshot`.
+    #[cfg_attr(feature = "serde", serde(with = "none_or"))]
+    pub recover_to_snapshot: Option<Option<u64>>,
+    /// See `Config::skip_config_file`.
+    pub skip_config_file: Option<bool>,
+    /// See `Config::heap_path`. This can't turn it back off,
+    /// since `"none"` is also a valid path.
+    pub heap_path: Option<PathBuf>,
+    /// See `Config::version_policy`. This is never read or
+    /// written by serde, since `VersionPolicy::Callback`
+    /// holds a function.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub version_policy: Option<VersionPolicy>,
+}
+
+/// Reads and writes the settings of `PartialConfig` that can
+/// be turned off, writing `Some(None)` as the string `"none"`
+/// because formats like TOML have no null.
+#[cfg(feature = "serde")]
+mod none_or {
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(super) fn serialize<T: Serialize, S: Serializer>(
+        value: &Option<Option<T>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(Some(value)) => value.serialize(serializer),
+            Some(None) => serializer.serialize_str("none"),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(super) fn deserialize<'de, T, D>(
+        deserializer: D,
+    ) -> Result<Option<Option<T>>, D::Error>
+    where
+        T: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum NoneOr<T> {
+            Some(T),
+            None(String),
+        }
+
+        match NoneOr::deserialize(deserializer)? {
+            NoneOr::Some(value) => Ok(Some(Some(value))),
+            NoneOr::None(raw) if raw == "none" => Ok(Some(None)),
+            NoneOr::None(raw) => Err(D::Error::custom(format!(
+                "expected a number or \"none\", found {:?}",
+                raw
+            ))),
+        }
+    }
+}
+
+/// Reads and writes the durations of `PartialConfig` as a
+/// whole number of milliseconds. Longer durations than TOML
+/// integers can hold are written as the longest they can.
+#[cfg(feature = "serde")]
+mod millis {
+    use std::{convert::TryFrom, time::Duration};
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(super) fn to_millis(duration: Duration) -> u64 {
+        u64::try_from(duration.as_millis())
+            .unwrap_or(u64::MAX)
+            .min(i64::MAX as u64)
+    }
+
+    pub(super) fn serialize<S: Serializer>(
+        value: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => serializer.serialize_u64(to_millis(*value)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        let millis = u64::deserialize(deserializer)?;
+        Ok(Some(Duration::from_millis(millis)))
+    }
+}
+
+/// Like `millis`, for the durations of `PartialConfig` that
+/// can be turned off, which are written like `none_or`.
+#[cfg(feature = "serde")]
+mod none_or_millis {
+    use std::time::Duration;
+
+    use serde::{Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(
+        value: &Option<Option<Duration>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let millis = value.map(|value| value.map(super::millis::to_millis));
+        super::none_or::serialize(&millis, serializer)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Option<Duration>>, D::Error> {
+        let millis: Option<Option<u64>> =
+            super::none_or::deserialize(deserializer)?;
+        Ok(millis.map(|millis| millis.map(Duration::from_millis)))
+    }
+}
+
+/// A difference between a storage parameter persisted by
+/// an existing database and the value that the current
+/// `Config` would use, as returned by `Config::config_diff`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParameterDiff {
+    /// The name of the parameter, as it appears in the
+    /// persisted `conf` file.
+    pub field: &'static str,
+    /// The value persisted by the existing database.
+    pub stored: String,
+    /// The value that the current `Config` would use.
+    pub requested: String,
+}
+
+/// A rough estimate of the steady-state memory used by a
+/// database, as returned by `Config::estimated_memory_footprint`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryEstimate {
+    /// The memory used by cached pages, which is
+    /// `cache_capacity`.
+    pub cache_bytes: usize,
+    /// The number of IO buffers assumed to be allocated at
+    /// the same time.
+    pub io_buffer_count: usize,
+    /// The memory used by IO buffers, which is `segment_size`
+    /// for each of them.
+    pub io_buffer_bytes: usize,
+    /// The memory used by each open `Tree` outside of the
+    /// cache, including its empty root nodes.
+    pub per_tree_overhead_bytes: usize,
+    /// The sum of the above, counting the overhead of the
+    /// default `Tree` only.
+    pub total_bytes: usize,
+}
+
+/// The on-disk layout of the log segments of a database, as
+/// returned by `Config::storage_layout`, for tools that read
+/// the data file directly.
+///
+/// Each segment starts with a header holding a CRC of the
+/// rest of the header, followed by the LSN of the segment and
+/// the highest stable LSN when it was written, both xored
+/// with `0x7FFF_FFFF_FFFF_FFFF`. Each message after it starts
+/// with a CRC of the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StorageLayout {
+    /// The size of each segment in bytes, which is
+    /// `Config::segment_size`.
+    pub segment_size: usize,
+    /// The alignment in bytes of the buffers that segments are
+    /// written from, which is `Config::io_alignment`.
+    pub alignment: usize,
+    /// The size of the header at the start of each segment.
+    pub segment_header_bytes: usize,
+    /// The size of the CRC at the start of each segment header
+    /// and each message, which is computed with
+    /// `Config::crc_variant`.
+    pub crc_bytes: usize,
+    /// The bytes of each segment that are left for messages
+    /// after its header.
+    pub usable_bytes_per_segment: usize,
+}
+
+/// How far the replay of the log has come while a database
+/// is being opened, as passed to the callback set with
+/// `Config::on_recovery_progress`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryProgress {
+    /// The number of log segments that have been replayed.
+    pub segments_recovered: u64,
+    /// The number of log segments that need to be replayed.
+    pub segments_total: u64,
+    /// The number of bytes of the log that have been
+    /// replayed.
+    pub bytes_replayed: u64,
+}
+
+impl PartialConfig {
+    /// Reads the settings that are set in the environment as
+    /// `{prefix}_{SETTING}`, where `SETTING` is the upper-case
+    /// name of the setting, such as `CACHE_CAPACITY`. Values
+    /// that can't be parsed are logged and left as `None`.
+    fn from_env(prefix: &str) -> PartialConfig {
+        fn var<T>(
+            prefix: &str,
+            name: &str,
+            parse: impl Fn(&str) -> Option<T>,
+        ) -> Option<T> {
+            let key = format!("{}_{}", prefix, name);
+            let raw = match std::env::var(&key) {
+                Ok(raw) => raw,
+                Err(std::env::VarError::NotPresent) => return None,
+                Err(std::env::VarError::NotUnicode(raw)) => {
+                    warn!("ignoring non-unicode value {:?} for {}", raw, key);
+                    return None;
+                }
+            };
+            let parsed = parse(raw.trim());
+            if parsed.is_none() {
+                warn!("ignoring unparseable value {:?} for {}", raw, key);
+            }
+            parsed
+        }
+
+        fn parsed<T: std::str::FromStr>(raw: &str) -> Option<T> {
+            raw.parse().ok()
+        }
+
+        fn none_or<T: std::str::FromStr>(raw: &str) -> Option<Option<T>> {
+            if raw == "none" { Some(None) } else { parsed(raw).map(Some) }
+        }
+
+        fn millis(raw: &str) -> Option<Duration> {
+            parsed(raw).map(Duration::from_millis)
+        }
+
+        fn none_or_millis(raw: &str) -> Option<Option<Duration>> {
+            if raw == "none" { Some(None) } else { millis(raw).map(Some) }
+        }
+
+        PartialConfig {
+            path: var(prefix, "PATH", |raw| Some(PathBuf::from(raw))),
+            cache_capacity: var(prefix, "CACHE_CAPACITY", parsed),
+            cache_capacity_fraction: var(
+                prefix,
+                "CACHE_CAPACITY_FRACTION",
+                parsed,
+            ),
+            mode: var(prefix, "MODE", |raw| match raw {
+                "low_space" => Some(Mode::LowSpace),
+                "high_throughput" => Some(Mode::HighThroughput),
+                _ => None,
+            }),
+            use_compression: var(prefix, "USE_COMPRESSION", parsed),
+            compression_factor: var(prefix, "COMPRESSION_FACTOR", parsed),
+            temporary: var(prefix, "TEMPORARY", parsed),
+            temporary_collision_retries: var(
+                prefix,
+                "TEMPORARY_COLLISION_RETRIES",
+                parsed,
+            ),
+            create_new: var(prefix, "CREATE_NEW", parsed),
+            segment_size: var(prefix, "SEGMENT_SIZE", parsed),
+            flush_every_ms: var(prefix, "FLUSH_EVERY_MS", none_or),
+            flush_thread_name: var(prefix, "FLUSH_THREAD_NAME", parsed),
+            flush_thread_stack_size: var(
+                prefix,
+                "FLUSH_THREAD_STACK_SIZE",
+                none_or,
+            ),
+            idgen_persist_interval: var(
+                prefix,
+                "IDGEN_PERSIST_INTERVAL",
+                parsed,
+            ),
+            snapshot_after_ops: var(prefix, "SNAPSHOT_AFTER_OPS", parsed),
+            fast_clean_restart: var(prefix, "FAST_CLEAN_RESTART", parsed),
+            directory_fsync: var(prefix, "DIRECTORY_FSYNC", |raw| match raw {
+                "required" => Some(DirFsyncPolicy::Required),
+                "best_effort" => Some(DirFsyncPolicy::BestEffort),
+                "disabled" => Some(DirFsyncPolicy::Disabled),
+                _ => None,
+            }),
+            allow_nested: var(prefix, "ALLOW_NESTED", parsed),
+            follow_heap_symlink: var(prefix, "FOLLOW_HEAP_SYMLINK", parsed),
+            nested_check_depth: var(prefix, "NESTED_CHECK_DEPTH", parsed),
+            crc_variant: var(prefix, "CRC_VARIANT", CrcVariant::from_name),
+            io_alignment: var(prefix, "IO_ALIGNMENT", parsed),
+            zero_on_reuse: var(prefix, "ZERO_ON_REUSE", parsed),
+            create_parents: var(prefix, "CREATE_PARENTS", parsed),
+            max_value_size: var(prefix, "MAX_VALUE_SIZE", none_or),
+            max_unflushed_bytes: var(prefix, "MAX_UNFLUSHED_BYTES", none_or),
+            max_transaction_retries: var(
+                prefix,
+                "MAX_TRANSACTION_RETRIES",
+                none_or,
+            ),
+            compaction_fill_threshold: var(
+                prefix,
+                "COMPACTION_FILL_THRESHOLD",
+                parsed,
+            ),
+            enforce_min_cache: var(prefix, "ENFORCE_MIN_CACHE", parsed),
+            allow_risky_filesystem: var(
+                prefix,
+                "ALLOW_RISKY_FILESYSTEM",
+                parsed,
+            ),
+            max_background_threads: var(
+                prefix,
+                "MAX_BACKGROUND_THREADS",
+                none_or,
+            ),
+            snapshot_after_bytes: var(prefix, "SNAPSHOT_AFTER_BYTES", none_or),
+            stale_snapshot_age: var(prefix, "STALE_SNAPSHOT_AGE", millis),
+            max_segment_reuses: var(prefix, "MAX_SEGMENT_REUSES", none_or),
+            lock_wait: var(prefix, "LOCK_WAIT", none_or_millis),
+            open_timeout: var(prefix, "OPEN_TIMEOUT", none_or_millis),
+            max_recovery_duration: var(
+                prefix,
+                "MAX_RECOVERY_DURATION",
+                none_or_millis,
+            ),
+            recovery_read_buffer_size: var(
+                prefix,
+                "RECOVERY_READ_BUFFER_SIZE",
+                parsed,
+            ),
+            keep_temporary_on_error: var(
+                prefix,
+                "KEEP_TEMPORARY_ON_ERROR",
+                parsed,
+            ),
+            temporary_delete_retries: var(
+                prefix,
+                "TEMPORARY_DELETE_RETRIES",
+                parsed,
+            ),
+            async_temp_cleanup: var(prefix, "ASYNC_TEMP_CLEANUP", parsed),
+            share_process_local: var(prefix, "SHARE_PROCESS_LOCAL", parsed),
+            max_unflushed_wait: var(
+                prefix,
+                "MAX_UNFLUSHED_WAIT",
+                none_or_millis,
+            ),
+            access_pattern: var(prefix, "ACCESS_PATTERN", |raw| match raw {
+                "normal" => Some(AccessPattern::Normal),
+                "random" => Some(AccessPattern::Random),
+                "sequential" => Some(AccessPattern::Sequential),
+                "will_need" => Some(AccessPattern::WillNeed),
+                "dont_need" => Some(AccessPattern::DontNeed),
+                _ => None,
+            }),
+            sync_mode: var(prefix, "SYNC_MODE", |raw| match raw {
+                "sync_all" => Some(SyncMode::SyncAll),
+                "sync_data" => Some(SyncMode::SyncData),
+                _ => None,
+            }),
+            eviction_policy: var(prefix, "EVICTION_POLICY", |raw| match raw {
+                "lru" => Some(EvictionPolicy::Lru),
+                "segmented_lru" => Some(EvictionPolicy::SegmentedLru),
+                "lfu" => Some(EvictionPolicy::Lfu),
+                _ => None,
+            }),
+            verify_only: var(prefix, "VERIFY_ONLY", parsed),
+            recover_to_snapshot: var(prefix, "RECOVER_TO_SNAPSHOT", none_or),
+            skip_config_file: var(prefix, "SKIP_CONFIG_FILE", parsed),
+            heap_path: var(prefix, "HEAP_PATH", |raw| Some(PathBuf::from(raw))),
+            version_policy: None,
+        }
+    }
+}
+
 /// A persisted configuration about high-level
 /// storage file information
-#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 struct StorageParameters {
     pub segment_size: usize,
     pub use_compression: bool,
+    pub segment_transform: Option<String>,
+    pub heap_path: Option<String>,
+    pub crc_variant: CrcVariant,
     pub version: (usize, usize),
 }
 
 impl StorageParameters {
+    /// The parameters that a database opened with `config`
+    /// persists, and expects to find in an existing config file.
+    pub fn from_config(config: &Config) -> StorageParameters {
+        StorageParameters {
+            version: config.version,
+            segment_size: config.segment_size,
+            use_compression: config.use_compression,
+            segment_transform: config
+                .segment_transform
+                .as_ref()
+                .map(|transform| transform.id().to_owned()),
+            heap_path: config
+                .heap_path
+                .as_ref()
+                .map(|path| path.to_string_lossy().into_owned()),
+            crc_variant: config.crc_variant,
+        }
+    }
+
+    /// Returns every field that differs between `self`, the
+    /// stored parameters, and `other`, the requested ones.
+    pub fn diff(&self, other: &StorageParameters) -> Vec<ParameterDiff> {
+        let mut diffs = vec![];
+
+        macro_rules! diff_field {
+            ($field:ident, $fmt:expr) => {
+                if self.$field != other.$field {
+                    diffs.push(ParameterDiff {
+                        field: stringify!($field),
+                        stored: $fmt(&self.$field),
+                        requested: $fmt(&other.$field),
+                    });
+                }
+            };
+        }
+
+        diff_field!(segment_size, ToString::to_string);
+        diff_field!(use_compression, ToString::to_string);
+        diff_field!(segment_transform, |id: &Option<String>| {
+            id.clone().unwrap_or_else(|| "none".to_owned())
+        });
+        diff_field!(heap_path, |path: &Option<String>| {
+            path.clone().unwrap_or_else(|| "heap".to_owned())
+        });
+        diff_field!(crc_variant, |v: &CrcVariant| v.name().to_owned());
+        diff_field!(version, |v: &(usize, usize)| format!("{}.{}", v.0, v.1));
+
+        diffs
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
         let mut out = vec![];
 
+        writeln!(&mut out, "sled_config_format: {}", CONFIG_FORMAT).unwrap();
         writeln!(&mut out, "segment_size: {}", self.segment_size).unwrap();
         writeln!(&mut out, "use_compression: {}", self.use_compression)
             .unwrap();
+        if let Some(ref id) = self.segment_transform {
+            writeln!(&mut out, "segment_transform: {}", id).unwrap();
+        }
+        if let Some(ref heap_path) = self.heap_path {
+            writeln!(&mut out, "heap_path: {}", heap_path).unwrap();
+        }
+        writeln!(&mut out, "crc_variant: {}", self.crc_variant.name()).unwrap();
         writeln!(&mut out, "version: {}.{}", self.version.0, self.version.1)
             .unwrap();
 
@@ -71,7 +1186,7 @@ impl StorageParameters {
                      earlier than 0.29",
                 ));
             };
-            let mut split = line.split(": ").map(String::from);
+            let mut split = line.splitn(2, ": ").map(String::from);
             let k = if let Some(k) = split.next() {
                 k
             } else {
@@ -87,6 +1202,31 @@ impl StorageParameters {
             lines.insert(k, v);
         }
 
+        let format: usize = if let Some(raw) = lines.get("sled_config_format") {
+            if let Ok(parsed) = raw.parse() {
+                parsed
+            } else {
+                error!("failed to parse sled_config_format value: {}", raw);
+                return Err(Error::corruption(None));
+            }
+        } else {
+            // files written before the format marker existed
+            0
+        };
+
+        if format > CONFIG_FORMAT {
+            error!(
+                "persisted config uses format {}, but this version of \
+                 sled only understands formats up to {}",
+                format, CONFIG_FORMAT
+            );
+            return Err(Error::Unsupported(
+                "the persisted config was written in a newer config \
+                 format than this version of sled supports. \
+                 See error log for more details.",
+            ));
+        }
+
         let segment_size: usize = if let Some(raw) = lines.get("segment_size") {
             if let Ok(parsed) = raw.parse() {
                 parsed
@@ -157,7 +1297,30 @@ impl StorageParameters {
             return Err(Error::corruption(None));
         };
 
-        Ok(StorageParameters { segment_size, use_compression, version })
+        // configs written before the variant could be chosen
+        // always used crc32.
+        let crc_variant = if let Some(raw) = lines.get("crc_variant") {
+            if let Some(parsed) = CrcVariant::from_name(raw) {
+                parsed
+            } else {
+                error!("failed to parse crc_variant value: {}", raw);
+                return Err(Error::corruption(None));
+            }
+        } else {
+            CrcVariant::Crc32
+        };
+
+        let segment_transform = lines.get("segment_transform").cloned();
+        let heap_path = lines.get("heap_path").cloned();
+
+        Ok(StorageParameters {
+            segment_size,
+            use_compression,
+            segment_transform,
+            heap_path,
+            crc_variant,
+            version,
+        })
     }
 }
 
@@ -182,16 +1345,41 @@ impl Deref for Config {
     }
 }
 
+/// The generated location of a temporary database that was
+/// not given a path, shared by all clones of a `Config` so that
+/// reopening it finds the same files.
+#[derive(Debug)]
+struct TempPath {
+    path: PathBuf,
+    claimed: bool,
+}
+
 #[doc(hidden)]
 #[derive(Debug, Clone)]
 pub struct Inner {
     #[doc(hidden)]
     pub cache_capacity: usize,
     #[doc(hidden)]
+    pub cache_capacity_fraction: Option<f64>,
+    #[doc(hidden)]
+    pub enforce_min_cache: bool,
+    #[doc(hidden)]
     pub flush_every_ms: Option<u64>,
     #[doc(hidden)]
+    pub flush_thread_name: String,
+    #[doc(hidden)]
+    pub flush_thread_stack_size: Option<usize>,
+    #[doc(hidden)]
     pub segment_size: usize,
     #[doc(hidden)]
+    pub io_alignment: usize,
+    #[doc(hidden)]
+    pub allow_risky_filesystem: bool,
+    #[doc(hidden)]
+    pub zero_on_reuse: bool,
+    #[doc(hidden)]
+    pub max_background_threads: Option<usize>,
+    #[doc(hidden)]
     pub path: PathBuf,
     #[doc(hidden)]
     pub create_new: bool,
@@ -200,6 +1388,8 @@ pub struct Inner {
     #[doc(hidden)]
     pub temporary: bool,
     #[doc(hidden)]
+    pub temporary_collision_retries: usize,
+    #[doc(hidden)]
     pub use_compression: bool,
     #[doc(hidden)]
     pub compression_factor: i32,
@@ -208,8 +1398,75 @@ pub struct Inner {
     #[doc(hidden)]
     pub snapshot_after_ops: u64,
     #[doc(hidden)]
+    pub snapshot_after_bytes: Option<u64>,
+    #[doc(hidden)]
+    pub fast_clean_restart: bool,
+    #[doc(hidden)]
+    pub stale_snapshot_age: Duration,
+    #[doc(hidden)]
+    pub max_segment_reuses: Option<u32>,
+    #[doc(hidden)]
+    pub compaction_fill_threshold: Option<f32>,
+    #[doc(hidden)]
+    pub lock_wait: Option<Duration>,
+    #[doc(hidden)]
+    pub open_timeout: Option<Duration>,
+    #[doc(hidden)]
+    pub max_recovery_duration: Option<Duration>,
+    #[doc(hidden)]
+    pub recovery_read_buffer_size: usize,
+    #[doc(hidden)]
+    pub keep_temporary_on_error: bool,
+    #[doc(hidden)]
+    pub temporary_delete_retries: usize,
+    #[doc(hidden)]
+    pub async_temp_cleanup: bool,
+    #[doc(hidden)]
+    pub share_process_local: bool,
+    #[doc(hidden)]
+    pub max_value_size: Option<usize>,
+    #[doc(hidden)]
+    pub max_unflushed_bytes: Option<u64>,
+    #[doc(hidden)]
+    pub max_unflushed_wait: Option<Duration>,
+    #[doc(hidden)]
+    pub max_transaction_retries: Option<usize>,
+    #[doc(hidden)]
     pub version: (usize, usize),
-    tmp_path: PathBuf,
+    #[doc(hidden)]
+    pub directory_fsync: DirFsyncPolicy,
+    #[doc(hidden)]
+    pub crc_variant: CrcVariant,
+    #[doc(hidden)]
+    pub access_pattern: AccessPattern,
+    #[doc(hidden)]
+    pub sync_mode: SyncMode,
+    #[doc(hidden)]
+    pub eviction_policy: EvictionPolicy,
+    #[doc(hidden)]
+    pub verify_only: bool,
+    #[doc(hidden)]
+    pub recover_to_snapshot: Option<u64>,
+    #[doc(hidden)]
+    pub skip_config_file: bool,
+    #[doc(hidden)]
+    pub version_policy: VersionPolicy,
+    #[doc(hidden)]
+    pub allow_nested: bool,
+    #[doc(hidden)]
+    pub follow_heap_symlink: bool,
+    #[doc(hidden)]
+    pub create_parents: bool,
+    #[doc(hidden)]
+    pub heap_path: Option<PathBuf>,
+    #[doc(hidden)]
+    pub nested_check_depth: usize,
+    pub(crate) file_options: Option<Callback<FileOptionsFn>>,
+    pub(crate) segment_transform: Option<Callback<dyn SegmentTransform>>,
+    pub(crate) on_global_error: Option<Callback<GlobalErrorFn>>,
+    pub(crate) default_merge_operator: Option<Callback<MergeOperatorFn>>,
+    pub(crate) on_recovery_progress: Option<Callback<RecoveryProgressFn>>,
+    tmp_path: Arc<Mutex<TempPath>>,
     pub(crate) global_error: Arc<Atomic<Error>>,
     #[cfg(feature = "event_log")]
     /// an event log for concurrent debugging
@@ -221,19 +1478,67 @@ impl Default for Inner {
         Self {
             // generally useful
             path: PathBuf::from(DEFAULT_PATH),
-            tmp_path: Config::gen_temp_path(),
+            tmp_path: Arc::new(Mutex::new(TempPath {
+                path: Config::gen_temp_path(),
+                claimed: false,
+            })),
             create_new: false,
             cache_capacity: 1024 * 1024 * 1024, // 1gb
+            cache_capacity_fraction: None,
+            enforce_min_cache: false,
             mode: Mode::LowSpace,
             use_compression: false,
-            compression_factor: 5,
+            compression_factor: i32::from(CompressionFactor::DEFAULT.get()),
             temporary: false,
+            temporary_collision_retries: 1,
             version: crate_version(),
+            directory_fsync: DirFsyncPolicy::Required,
+            crc_variant: CrcVariant::Crc32,
+            access_pattern: AccessPattern::Normal,
+            sync_mode: SyncMode::SyncAll,
+            eviction_policy: EvictionPolicy::Lru,
+            verify_only: false,
+            recover_to_snapshot: None,
+            skip_config_file: false,
+            version_policy: VersionPolicy::Strict,
+            allow_nested: false,
+            follow_heap_symlink: false,
+            create_parents: true,
+            heap_path: None,
+            nested_check_depth: 8,
+            file_options: None,
+            segment_transform: None,
+            on_global_error: None,
+            default_merge_operator: None,
+            on_recovery_progress: None,
 
             // useful in testing
             segment_size: 512 * 1024, // 512kb in bytes
+            io_alignment: 8192,
+            allow_risky_filesystem: false,
+            zero_on_reuse: false,
+            max_background_threads: None,
             flush_every_ms: Some(500),
+            flush_thread_name: "sled-flush".to_owned(),
+            flush_thread_stack_size: None,
             idgen_persist_interval: 1_000_000,
+            fast_clean_restart: false,
+            stale_snapshot_age: Duration::from_secs(0),
+            max_segment_reuses: None,
+            compaction_fill_threshold: None,
+            lock_wait: None,
+            open_timeout: None,
+            max_recovery_duration: None,
+            recovery_read_buffer_size: 2 * 1024 * 1024,
+            keep_temporary_on_error: false,
+            temporary_delete_retries: 2,
+            async_temp_cleanup: false,
+            share_process_local: false,
+            max_value_size: None,
+            max_unflushed_bytes: None,
+            max_unflushed_wait: None,
+            max_transaction_retries: None,
+            snapshot_after_bytes: None,
             snapshot_after_ops: if cfg!(feature = "for-internal-testing-only") {
                 10
             } else {
@@ -250,13 +1555,19 @@ impl Inner {
     // Get the path of the database
     #[doc(hidden)]
     pub fn get_path(&self) -> PathBuf {
-        if self.temporary && self.path == PathBuf::from(DEFAULT_PATH) {
-            self.tmp_path.clone()
+        if self.uses_generated_path() {
+            self.tmp_path.lock().path.clone()
         } else {
             self.path.clone()
         }
     }
 
+    /// Whether this is a temporary database stored at a
+    /// generated path rather than one chosen by the user.
+    fn uses_generated_path(&self) -> bool {
+        self.temporary && self.path == PathBuf::from(DEFAULT_PATH)
+    }
+
     fn db_path(&self) -> PathBuf {
         self.get_path().join("db")
     }
@@ -265,6 +1576,35 @@ impl Inner {
         self.get_path().join("conf")
     }
 
+    fn config_temp_path(&self) -> PathBuf {
+        self.get_path().join("conf.tmp")
+    }
+
+    /// Makes the data written to `file` durable according to
+    /// `sync_mode`.
+    pub(crate) fn sync_file(&self, file: &File) -> io::Result<()> {
+        match self.sync_mode {
+            SyncMode::SyncAll => file.sync_all(),
+            SyncMode::SyncData => file.sync_data(),
+        }
+    }
+
+    /// The fraction of live data below which a sealed segment
+    /// is compacted, which follows `mode` unless
+    /// `compaction_fill_threshold` is set.
+    pub(crate) fn effective_compaction_fill_threshold(&self) -> f32 {
+        self.compaction_fill_threshold.unwrap_or(match self.mode {
+            Mode::LowSpace => 1.0,
+            Mode::HighThroughput => 0.5,
+        })
+    }
+
+    /// The directory that large items are stored in, which is
+    /// `heap_path` if it is set.
+    pub(crate) fn heap_dir(&self) -> PathBuf {
+        self.heap_path.clone().unwrap_or_else(|| self.get_path().join("heap"))
+    }
+
     pub(crate) fn normalize<T>(&self, value: T) -> T
     where
         T: Copy
@@ -336,28 +1676,390 @@ impl Config {
         self
     }
 
-    /// Opens a `Db` based on the provided config.
-    pub fn open(&self) -> Result<Db> {
-        // only validate, setup directory, and open file once
-        self.validate()?;
+    /// Sets `segment_size` to one of the sizes in
+    /// `SegmentSizePreset`. Like `segment_size`, this can't
+    /// be changed after the database has been created.
+    pub fn segment_size_preset(self, preset: SegmentSizePreset) -> Config {
+        self.segment_size(preset.bytes())
+    }
+
+    /// Returns a `Config` for the database at `path` with the
+    /// storage parameters that it was created with, so that it
+    /// can be opened without knowing them in advance. This sets
+    /// `segment_size`, `use_compression` and `heap_path`. If
+    /// no database has been initialized at `path` yet, a
+    /// default `Config` with the path set is returned.
+    ///
+    /// A segment transform can't be reconstructed from its id,
+    /// so it must still be set with `segment_transform` if the
+    /// database uses one. The stored version is not adopted,
+    /// because a database created by an incompatible version
+    /// must be migrated with `Db::export` and `Db::import`.
+    pub fn from_existing<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let config = Config::new().path(path);
+
+        let stored = if let Some(stored) = config.read_config()? {
+            stored
+        } else {
+            return Ok(config);
+        };
+
+        if let Some(ref transform) = stored.segment_transform {
+            warn!(
+                "the database at {:?} uses segment transform {:?}, \
+                 which must be set with Config::segment_transform \
+                 before it can be opened",
+                config.get_path(),
+                transform
+            );
+        }
+
+        Ok(config
+            .segment_size(stored.segment_size)
+            .use_compression(stored.use_compression)
+            .heap_path(stored.heap_path.map(PathBuf::from)))
+    }
+
+    /// Returns the `(major, minor)` version of sled that
+    /// created the database at the configured path, or
+    /// `None` if no database has been initialized there yet.
+    /// This does not lock or open the database, so it may be
+    /// used by migration tooling to inspect a database before
+    /// deciding how to open it.
+    pub fn on_disk_version(&self) -> Result<Option<(usize, usize)>> {
+        Ok(self.read_config()?.map(|sp| sp.version))
+    }
+
+    /// Estimates how much memory a database opened with this
+    /// `Config` will use once its cache has filled up, to help
+    /// choose a `cache_capacity`. This is only an estimate:
+    /// the number of IO buffers varies with write load, and
+    /// memory used by the allocator, by transactions, and by
+    /// pages that are being rewritten is not included.
+    pub fn estimated_memory_footprint(&self) -> MemoryEstimate {
+        let cache_bytes = self.cache_capacity;
+        let io_buffer_count = ESTIMATED_IO_BUFFERS;
+        let io_buffer_bytes = self.segment_size * io_buffer_count;
+
+        // a new tree starts with an index root pointing to
+        // an empty leaf.
+        let per_tree_overhead_bytes =
+            std::mem::size_of::<TreeInner>() + 2 * std::mem::size_of::<Node>();
+
+        MemoryEstimate {
+            cache_bytes,
+            io_buffer_count,
+            io_buffer_bytes,
+            per_tree_overhead_bytes,
+            total_bytes: cache_bytes
+                + io_buffer_bytes
+                + per_tree_overhead_bytes,
+        }
+    }
+
+    /// Returns the on-disk layout of the log segments of a
+    /// database opened with this `Config`.
+    pub fn storage_layout(&self) -> StorageLayout {
+        StorageLayout {
+            segment_size: self.segment_size,
+            alignment: self.io_alignment,
+            segment_header_bytes: SEG_HEADER_LEN,
+            crc_bytes: std::mem::size_of::<u32>(),
+            usable_bytes_per_segment: self.segment_size - SEG_HEADER_LEN,
+        }
+    }
+
+    /// Compares the storage parameters persisted by the
+    /// database at the configured path with the ones this
+    /// `Config` would use, returning every difference
+    /// instead of failing on the first one as `open` does.
+    /// Returns an empty list if no database has been
+    /// initialized there yet. Like `on_disk_version`, this
+    /// does not lock or open the database.
+    pub fn config_diff(&self) -> Result<Vec<ParameterDiff>> {
+        Ok(self
+            .read_config()?
+            .map(|stored| stored.diff(&StorageParameters::from_config(self)))
+            .unwrap_or_default())
+    }
+
+    /// Copies the offline database stored at `src` into the
+    /// directory `dst`, producing an independent database that
+    /// opens with the same storage parameters. This is useful
+    /// for creating test fixtures from an existing database.
+    /// If the source uses a `heap_path` outside of `src`, its
+    /// heap is copied into `dst`, and the clone uses its own
+    /// default heap location instead.
+    ///
+    /// Returns an error if `src` is currently opened by
+    /// another `Db`, or if `dst` already contains a database.
+    pub fn clone_offline(src: &Path, dst: &Path) -> Result<()> {
+        let mut src_db = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(src.join("db"))?;
+
+        #[cfg(all(
+            not(miri),
+            any(windows, target_os = "linux", target_os = "macos")
+        ))]
+        {
+            use fs2::FileExt;
+
+            // unlike `try_lock`, never block here, because the
+            // source is expected to be offline.
+            if src_db.try_lock_exclusive().is_err() {
+                return Err(Error::LockContended { path: src.to_path_buf() });
+            }
+        }
+
+        supported!(
+            !dst.join("db").exists() && !dst.join("conf").exists(),
+            "clone destination already contains a database"
+        );
+
+        // a heap stored outside of `src` is copied into the
+        // default location, so that the clone never writes to
+        // the heap of the source.
+        let mut stored = Config::new().path(src).read_config()?;
+        let external_heap = stored.as_mut().and_then(|p| p.heap_path.take());
+
+        fs::create_dir_all(dst)?;
+
+        // the data file is copied through the locked handle,
+        // because other handles may not read it on windows.
+        copy_and_sync(&mut src_db, &dst.join("db"))?;
+
+        for entry_res in fs::read_dir(src)? {
+            let entry = entry_res?;
+            let name = entry.file_name();
+            let name_str = name.to_string_lossy();
+            let is_snapshot = name_str.starts_with("snap.")
+                && !name_str.ends_with(".generating");
+            let is_conf = name_str == "conf" && external_heap.is_none();
+
+            if is_conf || is_snapshot {
+                let mut f = File::open(entry.path())?;
+                copy_and_sync(&mut f, &dst.join(&name))?;
+            }
+        }
+
+        if let (Some(params), Some(_)) = (&stored, &external_heap) {
+            Config::new()
+                .path(dst)
+                .write_storage_parameters(params, &mut vec![])?;
+        }
+
+        let src_heap =
+            external_heap.map_or_else(|| src.join("heap"), PathBuf::from);
+        if src_heap.is_dir() {
+            let dst_heap = dst.join("heap");
+            fs::create_dir_all(&dst_heap)?;
+
+            for entry_res in fs::read_dir(&src_heap)? {
+                let entry = entry_res?;
+                if entry.file_type()?.is_file() {
+                    let mut f = File::open(entry.path())?;
+                    copy_and_sync(&mut f, &dst_heap.join(entry.file_name()))?;
+                }
+            }
+
+            maybe_fsync_directory(dst_heap)?;
+        }
+
+        maybe_fsync_directory(dst)?;
+
+        // hold the source lock until the copy is durable
+        drop(src_db);
+
+        Ok(())
+    }
+
+    /// Opens a new database in a staging directory next to
+    /// `final_path`, which only becomes visible at
+    /// `final_path` once `StagedDb::promote` is called. This
+    /// allows a database to be fully populated before other
+    /// processes can open it. The path set on this `Config`
+    /// is ignored.
+    ///
+    /// If the returned `StagedDb` is dropped without being
+    /// promoted, or promoting it fails, the staging directory
+    /// is removed.
+    pub fn open_staged(&self, final_path: &Path) -> Result<StagedDb> {
+        supported!(!self.temporary, "temporary databases can not be staged");
+
+        let file_name = if let Some(file_name) = final_path.file_name() {
+            file_name.to_string_lossy().into_owned()
+        } else {
+            return Err(Error::Unsupported(
+                "the final path of a staged database must have a file name",
+            ));
+        };
+
+        // staging next to the final path keeps both on the same
+        // filesystem, so that promotion is a single rename.
+        let salt = Config::gen_temp_path();
+        let staging_path = final_path.with_file_name(format!(
+            "{}.staging.{}",
+            file_name,
+            salt.file_name().unwrap().to_string_lossy()
+        ));
+
+        let mut config = self.clone();
+        let m = Arc::make_mut(&mut config.0);
+        m.path = staging_path.clone();
+        m.create_new = true;
+
+        let db = config.open()?;
+
+        Ok(StagedDb {
+            db: Some(db),
+            config,
+            staging_path,
+            final_path: final_path.to_path_buf(),
+            promoted: false,
+        })
+    }
+
+    /// Opens a `Db` based on the provided config.
+    ///
+    /// With `skip_config_file` set, the format of the database,
+    /// such as `segment_size`, `use_compression`, `crc_variant`
+    /// and the sled version, is taken from this `Config`
+    /// instead of the `conf` file. This is meant for prebuilt
+    /// databases that are opened with `verify_only` from a
+    /// read-only filesystem. Opening a database with settings
+    /// that differ from the ones it was created with is not
+    /// detected, and may fail recovery, return corrupted
+    /// values, or discard data.
+    ///
+    /// With `share_process_local` set, only databases that were
+    /// also opened with it set are shared, and the rest of the
+    /// settings of this `Config` are ignored when a shared
+    /// database is returned. A database stops being shared
+    /// once every clone of its `Db` is dropped, even if trees
+    /// opened from it are still alive.
+    ///
+    /// With `open_timeout` set, the database is opened on a
+    /// separate thread. None of the steps of opening, such as
+    /// acquiring the lock, recovery and fsyncs, can be
+    /// cancelled, so on timeout that thread keeps running, and
+    /// the database is closed and its lock released once it
+    /// finishes. Until then, opening the same path again fails
+    /// or waits according to `lock_wait`.
+    pub fn open(&self) -> Result<Db> {
+        self.open_reporting().map(|(db, _outcome)| db)
+    }
+
+    /// Opens a `Db` based on the provided config, and
+    /// reports whether it was newly created or already
+    /// existed, based on whether a valid `conf` file was
+    /// found in its directory.
+    pub fn open_reporting(&self) -> Result<(Db, OpenOutcome)> {
+        if self.share_process_local {
+            Db::open_shared(&self.get_path(), || self.open_reporting_unshared())
+        } else {
+            self.open_reporting_unshared()
+        }
+    }
+
+    fn open_reporting_unshared(&self) -> Result<(Db, OpenOutcome)> {
+        if let Some(open_timeout) = self.open_timeout {
+            self.open_reporting_with_timeout(open_timeout)
+        } else {
+            self.open_reporting_inner()
+        }
+    }
+
+    fn open_reporting_with_timeout(
+        &self,
+        open_timeout: Duration,
+    ) -> Result<(Db, OpenOutcome)> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let config = self.clone();
+
+        let _join_handle = std::thread::Builder::new()
+            .name("sled-open".to_owned())
+            .spawn(move || {
+                // if we timed out, the receiver is gone, and the
+                // result is dropped here, closing the database.
+                let _ = tx.send(config.open_reporting_inner());
+            })?;
+
+        match rx.recv_timeout(open_timeout) {
+            Ok(res) => res,
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                error!(
+                    "opening the database at {:?} took longer than \
+                     open_timeout {:?}. It will be closed in the \
+                     background once opening finishes.",
+                    self.get_path(),
+                    open_timeout
+                );
+                Err(Error::Io(
+                    ErrorKind::TimedOut,
+                    "open exceeded open_timeout",
+                ))
+            }
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                Err(Error::ReportableBug(
+                    "the thread opening the database panicked",
+                ))
+            }
+        }
+    }
+
+    fn open_reporting_inner(&self) -> Result<(Db, OpenOutcome)> {
+        // only validate, setup directory, and open file once
+        self.validate()?;
+        self.check_consistency()?;
+
+        let mut open_warnings = vec![];
 
         let mut config = self.clone();
         config.limit_cache_max_memory();
+        config.limit_cache_to_address_space(usize::BITS)?;
+        config.check_cache_capacity(&mut open_warnings)?;
+        if config.recover_to_snapshot.is_some() && !config.verify_only {
+            debug!("opening with verify_only to recover a past snapshot");
+            Arc::make_mut(&mut config.0).verify_only = true;
+        }
+
+        let (file, outcome) = if config.verify_only {
+            config.open_file_verify_only(&mut open_warnings)?
+        } else {
+            config.open_file(&mut open_warnings)?
+        };
 
-        let file = config.open_file()?;
+        if config.access_pattern != AccessPattern::Normal {
+            if let Err(e) = config.access_pattern.advise(&file, 0, 0) {
+                warn!(
+                    "failed to give access pattern hint {:?}: {}",
+                    config.access_pattern, e
+                );
+            }
+        }
 
-        let heap_path = config.get_path().join("heap");
-        let heap = Heap::start(&heap_path)?;
-        maybe_fsync_directory(heap_path)?;
+        let heap_path = config.heap_dir();
+        let heap = Heap::start(
+            &heap_path,
+            config.segment_transform.clone(),
+            config.crc_variant,
+            config.verify_only,
+        )?;
+        if !config.verify_only {
+            config.fsync_directory_reporting(heap_path, &mut open_warnings)?;
+        }
 
         // seal config in a Config
         let config = RunningConfig {
             inner: config,
             file: Arc::new(file),
             heap: Arc::new(heap),
+            open_warnings: open_warnings.into(),
         };
 
-        Db::start_inner(config)
+        Db::start_inner(config).map(|db| (db, outcome))
     }
 
     #[doc(hidden)]
@@ -388,47 +2090,463 @@ impl Config {
         self
     }
 
+    /// Sets `cache_capacity` to a fraction of the memory
+    /// available to this process, such as `0.25` for a
+    /// quarter of it. The memory limit is detected from the
+    /// cgroup and rlimit settings, falling back to the total
+    /// system memory, and the result will be at least a few
+    /// megabytes. The fraction must be between 0.0 and 1.0.
+    ///
+    /// If no memory limit can be detected, `cache_capacity`
+    /// is left unchanged.
+    pub fn cache_capacity_fraction(mut self, fraction: f64) -> Self {
+        if Arc::strong_count(&self.0) != 1 {
+            error!(
+                "config has already been used to start \
+                 the system and probably should not be \
+                 mutated",
+            );
+        }
+        let m = Arc::make_mut(&mut self.0);
+        m.cache_capacity_fraction = Some(fraction);
+
+        if !(0.0..=1.0).contains(&fraction) {
+            // rejected later by `validate`
+            return self;
+        }
+
+        let memory = sys_limits::get_memory_limit()
+            .or_else(sys_limits::get_total_memory);
+
+        if let Some(memory) = memory {
+            m.cache_capacity = cache_capacity_from_fraction(fraction, memory);
+        } else {
+            warn!(
+                "unable to detect available memory for \
+                 cache_capacity_fraction, leaving cache_capacity \
+                 at {} bytes",
+                m.cache_capacity
+            );
+        }
+
+        self
+    }
+
+    /// Provides a function that is called with the
+    /// `OpenOptions` used to open the main data file, after
+    /// the default flags have been set and before it is
+    /// opened. This may be used to set platform-specific
+    /// flags, such as `O_DIRECT` or `O_DSYNC` with
+    /// `std::os::unix::fs::OpenOptionsExt::custom_flags`.
+    ///
+    /// The function must not disable reading or writing.
+    /// `create_new` is applied after this function runs.
+    pub fn file_options(
+        mut self,
+        customizer: Arc<dyn Fn(&mut fs::OpenOptions) + Send + Sync>,
+    ) -> Self {
+        if Arc::strong_count(&self.0) != 1 {
+            error!(
+                "config has already been used to start \
+                 the system and probably should not be \
+                 mutated",
+            );
+        }
+        let m = Arc::make_mut(&mut self.0);
+        m.file_options = Some(Callback(customizer));
+        self
+    }
+
+    /// Sets a `SegmentTransform` that is applied to all log
+    /// and heap data as the last step before it is written to
+    /// disk, and as the first step after it is read back. The
+    /// transform's `id` is persisted, and opening a database
+    /// with a different transform than it was created with
+    /// returns an error.
+    pub fn segment_transform(
+        mut self,
+        transform: Arc<dyn SegmentTransform>,
+    ) -> Self {
+        if Arc::strong_count(&self.0) != 1 {
+            error!(
+                "config has already been used to start \
+                 the system and probably should not be \
+                 mutated",
+            );
+        }
+        let m = Arc::make_mut(&mut self.0);
+        m.segment_transform = Some(Callback(transform));
+        self
+    }
+
+    /// Sets a function that is called as soon as an error is
+    /// encountered during an asynchronous IO operation, such
+    /// as a background flush. Only the first such error is
+    /// reported, because the database refuses further IO
+    /// after it. The function is called on whichever thread
+    /// hit the error, which may be a background thread, so
+    /// it should return quickly. If it panics, the panic is
+    /// caught and logged.
+    pub fn on_global_error(
+        mut self,
+        callback: Arc<dyn Fn(&Error) + Send + Sync>,
+    ) -> Self {
+        if Arc::strong_count(&self.0) != 1 {
+            error!(
+                "config has already been used to start \
+                 the system and probably should not be \
+                 mutated",
+            );
+        }
+        let m = Arc::make_mut(&mut self.0);
+        m.on_global_error = Some(Callback(callback));
+        self
+    }
+
+    /// Sets a merge operator that is installed on the default
+    /// `Tree` while the database is being opened, so that it is
+    /// in place before any operation can run. This is
+    /// equivalent to calling `Tree::set_merge_operator` on the
+    /// `Db` right after opening it, without the window during
+    /// which merges would fail. It may still be replaced later
+    /// with `Tree::set_merge_operator`.
+    pub fn default_merge_operator(
+        mut self,
+        merge_operator: Arc<MergeOperatorFn>,
+    ) -> Self {
+        if Arc::strong_count(&self.0) != 1 {
+            error!(
+                "config has already been used to start \
+                 the system and probably should not be \
+                 mutated",
+            );
+        }
+        let m = Arc::make_mut(&mut self.0);
+        m.default_merge_operator = Some(Callback(merge_operator));
+        self
+    }
+
+    /// Sets a function that is called periodically while the
+    /// log is replayed during `open`, which may take a while
+    /// for large databases. It is called at most a few times
+    /// per second on the thread that opens the database, and
+    /// once more when the replay is done, so it should return
+    /// quickly.
+    pub fn on_recovery_progress(
+        mut self,
+        callback: Arc<RecoveryProgressFn>,
+    ) -> Self {
+        if Arc::strong_count(&self.0) != 1 {
+            error!(
+                "config has already been used to start \
+                 the system and probably should not be \
+                 mutated",
+            );
+        }
+        let m = Arc::make_mut(&mut self.0);
+        m.on_recovery_progress = Some(Callback(callback));
+        self
+    }
+
+    /// Applies every setting that is `Some` in `other`,
+    /// leaving the rest unchanged. Calling this once per
+    /// source, from the lowest to the highest precedence,
+    /// layers them on top of each other.
+    pub fn merge(mut self, other: PartialConfig) -> Self {
+        macro_rules! merge {
+            ($($name:ident),*) => {
+                $(
+                    if let Some(value) = other.$name {
+                        self = self.$name(value);
+                    }
+                )*
+            };
+        }
+
+        merge!(
+            path,
+            cache_capacity,
+            cache_capacity_fraction,
+            mode,
+            use_compression,
+            compression_factor,
+            temporary,
+            temporary_collision_retries,
+            create_new,
+            segment_size,
+            flush_every_ms,
+            flush_thread_name,
+            flush_thread_stack_size,
+            idgen_persist_interval,
+            snapshot_after_ops,
+            fast_clean_restart,
+            directory_fsync,
+            allow_nested,
+            follow_heap_symlink,
+            nested_check_depth,
+            crc_variant,
+            io_alignment,
+            zero_on_reuse,
+            create_parents,
+            max_value_size,
+            max_unflushed_bytes,
+            max_transaction_retries,
+            compaction_fill_threshold,
+            enforce_min_cache,
+            allow_risky_filesystem,
+            max_background_threads,
+            snapshot_after_bytes,
+            stale_snapshot_age,
+            max_segment_reuses,
+            lock_wait,
+            open_timeout,
+            max_recovery_duration,
+            recovery_read_buffer_size,
+            keep_temporary_on_error,
+            temporary_delete_retries,
+            async_temp_cleanup,
+            share_process_local,
+            max_unflushed_wait,
+            access_pattern,
+            sync_mode,
+            eviction_policy,
+            verify_only,
+            recover_to_snapshot,
+            skip_config_file,
+            version_policy
+        );
+
+        if let Some(heap_path) = other.heap_path {
+            self = self.heap_path(Some(heap_path));
+        }
+
+        self
+    }
+
+    /// Writes every setting as a TOML document, which
+    /// `Config::from_toml` reads back. Durations are written
+    /// as a whole number of milliseconds. Hooks like
+    /// `file_options` and `version_policy` are not written,
+    /// and a path that is not valid UTF-8 is written lossily.
+    #[cfg(feature = "toml")]
+    pub fn to_toml(&self) -> String {
+        let partial = PartialConfig {
+            path: Some(PathBuf::from(self.path.to_string_lossy().into_owned())),
+            cache_capacity: Some(self.cache_capacity),
+            cache_capacity_fraction: self.cache_capacity_fraction,
+            mode: Some(self.mode),
+            use_compression: Some(self.use_compression),
+            compression_factor: Some(self.compression_factor),
+            temporary: Some(self.temporary),
+            temporary_collision_retries: Some(self.temporary_collision_retries),
+            create_new: Some(self.create_new),
+            segment_size: Some(self.segment_size),
+            flush_every_ms: Some(self.flush_every_ms),
+            flush_thread_name: Some(self.flush_thread_name.clone()),
+            flush_thread_stack_size: Some(self.flush_thread_stack_size),
+            idgen_persist_interval: Some(self.idgen_persist_interval),
+            snapshot_after_ops: Some(self.snapshot_after_ops),
+            fast_clean_restart: Some(self.fast_clean_restart),
+            directory_fsync: Some(self.directory_fsync),
+            allow_nested: Some(self.allow_nested),
+            follow_heap_symlink: Some(self.follow_heap_symlink),
+            nested_check_depth: Some(self.nested_check_depth),
+            crc_variant: Some(self.crc_variant),
+            io_alignment: Some(self.io_alignment),
+            zero_on_reuse: Some(self.zero_on_reuse),
+            create_parents: Some(self.create_parents),
+            max_value_size: Some(self.max_value_size),
+            max_unflushed_bytes: Some(self.max_unflushed_bytes),
+            max_transaction_retries: Some(self.max_transaction_retries),
+            compaction_fill_threshold: self.compaction_fill_threshold,
+            enforce_min_cache: Some(self.enforce_min_cache),
+            allow_risky_filesystem: Some(self.allow_risky_filesystem),
+            max_background_threads: Some(self.max_background_threads),
+            snapshot_after_bytes: Some(self.snapshot_after_bytes),
+            stale_snapshot_age: Some(self.stale_snapshot_age),
+            max_segment_reuses: Some(self.max_segment_reuses),
+            lock_wait: Some(self.lock_wait),
+            open_timeout: Some(self.open_timeout),
+            max_recovery_duration: Some(self.max_recovery_duration),
+            recovery_read_buffer_size: Some(self.recovery_read_buffer_size),
+            keep_temporary_on_error: Some(self.keep_temporary_on_error),
+            temporary_delete_retries: Some(self.temporary_delete_retries),
+            async_temp_cleanup: Some(self.async_temp_cleanup),
+            share_process_local: Some(self.share_process_local),
+            max_unflushed_wait: Some(self.max_unflushed_wait),
+            access_pattern: Some(self.access_pattern),
+            sync_mode: Some(self.sync_mode),
+            eviction_policy: Some(self.eviction_policy),
+            verify_only: Some(self.verify_only),
+            recover_to_snapshot: Some(self.recover_to_snapshot),
+            skip_config_file: Some(self.skip_config_file),
+            heap_path: self.heap_path.as_ref().map(|heap_path| {
+                PathBuf::from(heap_path.to_string_lossy().into_owned())
+            }),
+            version_policy: None,
+        };
+
+        toml::to_string(&partial)
+            .expect("every PartialConfig field can be written as TOML")
+    }
+
+    /// Reads a TOML document in the format written by
+    /// `Config::to_toml`, applying its settings on top of the
+    /// defaults with `Config::merge`. Every setting is
+    /// optional. The error for an unknown setting or a value
+    /// of the wrong type names the offending key.
+    #[cfg(feature = "toml")]
+    pub fn from_toml(s: &str) -> std::result::Result<Config, toml::de::Error> {
+        let partial: PartialConfig = toml::from_str(s)?;
+        Ok(Config::new().merge(partial))
+    }
+
+    /// Overrides settings with values from environment
+    /// variables named `{prefix}_{SETTING}`, such as
+    /// `SLED_CACHE_CAPACITY` for a prefix of `SLED`.
+    /// Variables that are not set leave the current value
+    /// alone, and values that can't be parsed are logged
+    /// and ignored.
+    ///
+    /// Every setting that `Config::to_toml` writes is read,
+    /// with `SETTING` being its name in upper case. Settings
+    /// that can be turned off take `none`, durations are a
+    /// whole number of milliseconds, and enum variants are
+    /// written in snake case, such as `high_throughput` for
+    /// `Mode::HighThroughput` or `crc32c` for
+    /// `CrcVariant::Crc32c`.
+    pub fn apply_env(self, prefix: &str) -> Self {
+        self.merge(PartialConfig::from_env(prefix))
+    }
+
+    /// Sets the fraction of a sealed segment's pages that must
+    /// still be live for the segment to be left alone. Once
+    /// replacements bring it below this, the segment becomes
+    /// eligible to be compacted, by rewriting its live pages
+    /// elsewhere so it can be reused. Lower values compact
+    /// less often, using more space in exchange for less
+    /// write amplification. Must be between `0.0` and `1.0`.
+    ///
+    /// When this is not set, `Mode::LowSpace` uses `1.0`,
+    /// compacting a segment as soon as any of its pages are
+    /// replaced, and `Mode::HighThroughput` uses `0.5`.
+    pub fn compaction_fill_threshold(mut self, threshold: f32) -> Self {
+        if Arc::strong_count(&self.0) != 1 {
+            error!(
+                "config has already been used to start \
+                 the system and probably should not be \
+                 mutated",
+            );
+        }
+        let m = Arc::make_mut(&mut self.0);
+        m.compaction_fill_threshold = Some(threshold);
+        self
+    }
+
+    /// Sets `compression_factor` from a `CompressionFactor`,
+    /// which can only hold a valid level.
+    pub fn compression_level(self, level: CompressionFactor) -> Self {
+        self.compression_factor(i32::from(level.get()))
+    }
+
+    /// Sets `flush_every_ms`, `idgen_persist_interval` and
+    /// `snapshot_after_ops` to the values of the provided
+    /// `DurabilityProfile`. Individual setters called after
+    /// this take precedence.
+    pub fn durability_profile(self, profile: DurabilityProfile) -> Self {
+        let (flush_every_ms, idgen_persist_interval, snapshot_after_ops) =
+            profile.intervals();
+
+        self.flush_every_ms(flush_every_ms)
+            .idgen_persist_interval(idgen_persist_interval)
+            .snapshot_after_ops(snapshot_after_ops)
+    }
+
     fn gen_temp_path() -> PathBuf {
         use std::time::SystemTime;
 
+        // the counter alone keeps paths generated by this process
+        // unique, even if the wall clock jumps backward between
+        // calls. it is kept in its own component rather than being
+        // mixed into the timestamp, where a backward jump could
+        // cancel it out.
         static SALT_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
-        let seed = SALT_COUNTER.fetch_add(1, SeqCst) as u128;
+        let seed = SALT_COUNTER.fetch_add(1, SeqCst);
 
         let now = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos()
-            << 48;
+            .map_or(0, |since_epoch| since_epoch.as_nanos());
 
         #[cfg(not(miri))]
-        let pid = u128::from(std::process::id());
+        let pid = std::process::id();
 
         #[cfg(miri)]
         let pid = 0;
 
-        let salt = (pid << 16) + now + seed;
+        let name = format!("pagecache.tmp.{}.{}.{}", now, pid, seed);
 
         if cfg!(target_os = "linux") {
             // use shared memory for temporary linux files
-            format!("/dev/shm/pagecache.tmp.{}", salt).into()
+            Path::new("/dev/shm").join(name)
         } else {
-            std::env::temp_dir().join(format!("pagecache.tmp.{}", salt))
+            std::env::temp_dir().join(name)
         }
     }
 
     fn limit_cache_max_memory(&mut self) {
-        if let Some(limit) = sys_limits::get_memory_limit() {
-            if self.cache_capacity > limit {
-                let m = Arc::make_mut(&mut self.0);
-                m.cache_capacity = limit;
-                error!(
-                    "cache capacity is limited to the cgroup memory \
-                 limit: {} bytes",
-                    self.cache_capacity
-                );
-            }
+        let limited = limit_cache_capacity(self.cache_capacity);
+        if limited != self.cache_capacity {
+            let m = Arc::make_mut(&mut self.0);
+            m.cache_capacity = limited;
+        }
+    }
+
+    // clamps, or with enforce_min_cache rejects, a cache that
+    // could never be allocated. takes the pointer width so
+    // tests can simulate a 32 bit target.
+    fn limit_cache_to_address_space(
+        &mut self,
+        pointer_width: u32,
+    ) -> Result<()> {
+        let cache_capacity = limit_cache_to_address_space(
+            self.cache_capacity,
+            pointer_width,
+            self.enforce_min_cache,
+        )?;
+        if cache_capacity != self.cache_capacity {
+            Arc::make_mut(&mut self.0).cache_capacity = cache_capacity;
         }
+        Ok(())
+    }
+
+    // warns about, or with enforce_min_cache rejects, a cache
+    // that can't hold a couple of segments worth of pages.
+    fn check_cache_capacity(
+        &self,
+        warnings: &mut Vec<OpenWarning>,
+    ) -> Result<()> {
+        let minimum = self.segment_size.saturating_mul(2);
+        if self.cache_capacity >= minimum {
+            return Ok(());
+        }
+        if self.enforce_min_cache {
+            return Err(Error::Unsupported(
+                "cache_capacity must be at least twice segment_size \
+                 when enforce_min_cache is set",
+            ));
+        }
+        warn!(
+            "cache_capacity of {} bytes is less than twice segment_size, \
+             so the cache will constantly evict pages. Set it to at \
+             least {} bytes.",
+            self.cache_capacity, minimum
+        );
+        warnings.push(OpenWarning::SmallCache {
+            cache_capacity: self.cache_capacity,
+            minimum,
+        });
+        Ok(())
     }
 
     builder!(
@@ -437,6 +2555,11 @@ impl Config {
             usize,
             "maximum size in bytes for the system page cache"
         ),
+        (
+            enforce_min_cache,
+            bool,
+            "refuse to open with Error::Unsupported when cache_capacity is less than twice segment_size, instead of logging a warning and recording OpenWarning::SmallCache, or when it is more than a quarter of the address space of a 32 bit target, instead of logging an error and limiting it to that. Defaults to false"
+        ),
         (
             mode,
             Mode,
@@ -446,13 +2569,38 @@ impl Config {
         (
             compression_factor,
             i32,
-            "the compression factor to use with zstd compression. Ranges from 1 up to 22. Levels >= 20 are 'ultra'."
+            "the compression factor to use with zstd compression. Ranges from 1 up to 22. Levels >= 20 are 'ultra'. See also compression_level, which takes a CompressionFactor that is checked when it is constructed."
         ),
         (
             temporary,
             bool,
             "deletes the database after drop. if no path is set, uses /dev/shm on linux"
         ),
+        (
+            temporary_collision_retries,
+            usize,
+            "how many times to retry with a freshly generated path when a temporary database without an explicit path collides with files left behind by a previous run. Defaults to 1"
+        ),
+        (
+            keep_temporary_on_error,
+            bool,
+            "when a temporary database encountered an error during an asynchronous IO operation, keep its files after drop and log their location instead of deleting them, so that the failure can be investigated"
+        ),
+        (
+            temporary_delete_retries,
+            usize,
+            "how many more times to try deleting the files of a temporary database on drop, sleeping briefly in between, when the first attempt fails. This papers over virus scanners and indexers that briefly hold handles to new files on windows. Defaults to 2"
+        ),
+        (
+            async_temp_cleanup,
+            bool,
+            "delete the files of a temporary database on a background thread, so that dropping it does not wait for a large directory to be removed from slow storage. The directory is first renamed to a unique name ending in .deleting next to it, so its path is free as soon as drop returns. Removal is best-effort, and is done in place on drop when the rename fails"
+        ),
+        (
+            share_process_local,
+            bool,
+            "make open return a clone of the Db that is already open at the same path in this process, instead of failing because the database is locked, as described on Config::open. Defaults to false"
+        ),
         (
             create_new,
             bool,
@@ -462,15 +2610,222 @@ impl Config {
             snapshot_after_ops,
             u64,
             "take a fuzzy snapshot of pagecache metadata after this many ops"
-        )
-    );
-
-    // panics if config options are outside of advised range
-    fn validate(&self) -> Result<()> {
-        supported!(
-            self.segment_size.count_ones() == 1,
+        ),
+        (
+            snapshot_after_bytes,
+            Option<u64>,
+            "also take a fuzzy snapshot of pagecache metadata after this many bytes have been written to the log and the heap, whichever of the two limits is reached first. This counts every write, including items stored in the heap, page consolidations and the rewrites of segment cleaning. None only counts ops"
+        ),
+        (
+            fast_clean_restart,
+            bool,
+            "take a snapshot when the database is cleanly shut down and record a marker for it, so that the next startup does not need to replay the log. The marker is removed when the database is opened, so a crash causes a normal recovery"
+        ),
+        (
+            stale_snapshot_age,
+            Duration,
+            "how long ago a snapshot file that was left partially written by a crash, whose name ends in .generating, must have last been modified before open removes it. Only the process holding the database lock writes snapshots, so the default of zero removes all of them. Snapshots that fail their length or CRC check are always removed, and recovery falls back to the newest valid one, or to replaying the whole log. Each removal is recorded as OpenWarning::DiscardedSnapshot"
+        ),
+        (
+            max_segment_reuses,
+            Option<u32>,
+            "the number of times pages in a sealed segment may be replaced before the segment is rewritten to compact its remaining live data, regardless of the usual cleanup heuristics. This bounds how many stale fragments a read may need to skip over. None keeps the usual heuristics"
+        ),
+        (
+            lock_wait,
+            Option<Duration>,
+            "how long to keep retrying when the database file is locked by another process, such as one that is still shutting down, before returning an error. None fails immediately"
+        ),
+        (
+            open_timeout,
+            Option<Duration>,
+            "how long open may take before it returns Error::Io(TimedOut, ..), while opening continues in the background as described on Config::open. None waits for as long as opening takes"
+        ),
+        (
+            max_recovery_duration,
+            Option<Duration>,
+            "how long replaying the log during recovery may take before it stops early and discards the rest of the log, as described by OpenWarning::PartialRecovery. None, the default, always replays the whole log"
+        ),
+        (
+            recovery_read_buffer_size,
+            usize,
+            "how many bytes of the log to read at once while replaying it during recovery, so that segments that follow each other in the file are read together with fewer, larger reads. It is rounded down to a multiple of segment_size, and a value below segment_size reads each segment and its header separately. Reads after the database is open are not affected. Defaults to 2 MiB, which is four segments of the default size"
+        ),
+        (
+            max_value_size,
+            Option<usize>,
+            "the largest value in bytes, before compression, that may be written. Writes of larger values fail with Error::Unsupported before the value is compressed or logged. None allows values up to the usual platform limit"
+        ),
+        (
+            max_unflushed_bytes,
+            Option<u64>,
+            "the most bytes that may be written to the log without being flushed to disk yet. Once more are pending, writes block until the log has been flushed to below half of this, which bounds the memory used by bursts of writes. The pending bytes are handed to the IO threads right away rather than waiting for flush_every_ms. Writes that are part of a batch or transaction that is already being logged never block, so the limit may be exceeded by them. None, the default, never blocks"
+        ),
+        (
+            max_unflushed_wait,
+            Option<Duration>,
+            "how long a write may block because of max_unflushed_bytes before it fails with Error::Io(TimedOut, ..) instead. None waits for as long as the flush takes"
+        ),
+        (
+            max_transaction_retries,
+            Option<usize>,
+            "how many times a transaction closure may be rerun because of a conflict before the transaction fails with TransactionError::TooManyRetries, which bounds how long a transaction can spend under heavy contention. None, the default, retries for as long as conflicts occur"
+        ),
+        (
+            directory_fsync,
+            DirFsyncPolicy,
+            "how to handle failures to fsync the database directory after creating or renaming files in it"
+        ),
+        (
+            crc_variant,
+            CrcVariant,
+            "which CRC polynomial checksums the log, the heap and snapshots of a new database. CrcVariant::Crc32c is much faster on x86_64 CPUs with SSE4.2. Existing databases must be opened with the variant they were created with. Defaults to CrcVariant::Crc32"
+        ),
+        (
+            access_pattern,
+            AccessPattern,
+            "how the data file is expected to be accessed, which is passed to the kernel as a hint when the database is opened"
+        ),
+        (
+            sync_mode,
+            SyncMode,
+            "whether flushes of the log and of the settings file use fsync or fdatasync"
+        ),
+        (
+            eviction_policy,
+            EvictionPolicy,
+            "how the page cache chooses which pages to evict once it holds more than cache_capacity bytes"
+        ),
+        (
+            verify_only,
+            bool,
+            "open an existing database only to check it. Recovery runs as usual, replaying the log and checking the CRC of every message, after which every segment of the data file is checked too, including those that a snapshot made unnecessary to replay. Any problems found are reported by Db::open_warnings. Nothing on disk is created or changed, the database is locked with a shared lock instead of an exclusive one, and every write to the returned Db fails with Error::Unsupported. The heap is only checked by Db::verify_integrity"
+        ),
+        (
+            recover_to_snapshot,
+            Option<u64>,
+            "open the database read-only as it was at this log sequence number, such as one returned by Db::checkpoint, ignoring every later write. None, the default, recovers everything"
+        ),
+        (
+            skip_config_file,
+            bool,
+            "neither read nor write the conf file that records the format of the database, and take it from this Config without checking it, as described on Config::open. Defaults to false"
+        ),
+        (
+            version_policy,
+            VersionPolicy,
+            "how opening a database that was created by a different minor version of sled is handled. A different major version is always rejected"
+        ),
+        (
+            allow_nested,
+            bool,
+            "allows the database to be opened inside the directory of another sled database, which is normally rejected because the two may corrupt each other"
+        ),
+        (
+            create_parents,
+            bool,
+            "create missing parent directories of the database path and heap_path when opening. When false, open only creates the final directory, and fails with Error::Io(NotFound, ..) if its parent does not exist, so that a mistyped mount point does not silently create a database in the wrong place. Defaults to true"
+        ),
+        (
+            follow_heap_symlink,
+            bool,
+            "when the heap directory is a symlink whose target is missing, such as after restoring a partial backup, recreate the target directory instead of refusing to open"
+        ),
+        (
+            heap_path,
+            Option<PathBuf>,
+            "the directory to store large items in, such as one on a cheaper, larger disk than the rest of the database. None stores them in the heap subdirectory of the database path. This is persisted, and can't be changed after the database has been created. Temporary databases delete it when they are dropped"
+        ),
+        (
+            nested_check_depth,
+            usize,
+            "how many parent directories to check for another sled database when opening, unless allow_nested is set"
+        ),
+        (
+            flush_thread_name,
+            String,
+            "the name given to the background flush thread, which makes it identifiable in tools like top and perf when several databases are open. Linux truncates thread names to 15 bytes. Defaults to \"sled-flush\""
+        ),
+        (
+            flush_thread_stack_size,
+            Option<usize>,
+            "the stack size in bytes of the background flush thread, or None to use the platform default"
+        ),
+        (
+            allow_risky_filesystem,
+            bool,
+            "whether to open on NFS, overlayfs, or a tmpfs for a non-temporary database without logging a warning and recording OpenWarning::RiskyFilesystem. fsync on these does not give sled's durability guarantees"
+        ),
+        (
+            max_background_threads,
+            Option<usize>,
+            "the most threads that may run the periodic flushes of databases in this process. When set, this database's flushes run on a thread pool shared with every other database that sets it, instead of on its own flush thread, and flush_thread_name and flush_thread_stack_size are ignored. The pool grows to the largest value that any database sets. None, the default, gives each database its own flush thread"
+        ),
+        (
+            zero_on_reuse,
+            bool,
+            "whether to overwrite a log segment with zeros, and fsync it, before reusing it, so that data from its previous use can't be recovered from the file with forensic tools. This adds a segment's worth of writes for each reuse. Defaults to false"
+        ),
+        (
+            io_alignment,
+            usize,
+            "the alignment in bytes of the memory of the IO buffers that are written to the log. Only the buffers are aligned, while the offsets and lengths of the writes are not, so this does not make it possible to open the file with O_DIRECT. Must be a power of 2. Defaults to 8192"
+        )
+    );
+
+    /// Checks for combinations of options that are each valid
+    /// on their own but conflict with each other, returning an
+    /// `Error::Unsupported` naming both options. This is called
+    /// by `open`. A `compression_factor` that is set while
+    /// `use_compression` is false is ignored, and only logged.
+    pub fn check_consistency(&self) -> Result<()> {
+        if !self.use_compression
+            && self.compression_factor
+                != i32::from(CompressionFactor::DEFAULT.get())
+        {
+            warn!(
+                "compression_factor is set to {}, but use_compression \
+                 is false, so it is ignored",
+                self.compression_factor
+            );
+        }
+        if self.temporary && self.create_new && !self.uses_generated_path() {
+            supported!(
+                !self.db_path().exists(),
+                "temporary and create_new are both set, but the path \
+                 already contains a database that create_new would \
+                 refuse to open and temporary would delete"
+            );
+        }
+        Ok(())
+    }
+
+    // panics if config options are outside of advised range
+    fn validate(&self) -> Result<()> {
+        if let Some(ref transform) = self.segment_transform {
+            supported!(
+                !transform.id().is_empty() && !transform.id().contains('\n'),
+                "SegmentTransform::id must not be empty or contain newlines"
+            );
+        }
+        if let Some(ref heap_path) = self.heap_path {
+            supported!(
+                !heap_path.to_string_lossy().contains('\n'),
+                "heap_path must not contain newlines"
+            );
+        }
+        supported!(
+            !self.flush_thread_name.contains('\0'),
+            "flush_thread_name must not contain null bytes"
+        );
+        supported!(
+            self.segment_size.count_ones() == 1,
             "segment_size should be a power of 2"
         );
+        supported!(
+            self.io_alignment.count_ones() == 1,
+            "io_alignment should be a power of 2"
+        );
         supported!(
             self.segment_size >= 256,
             "segment_size should be hundreds of kb at minimum, and we won't start if below 256"
@@ -493,21 +2848,102 @@ impl Config {
             self.compression_factor <= 22,
             "compression_factor must be <= 22"
         );
+        supported!(
+            self.max_segment_reuses != Some(0),
+            "max_segment_reuses must be above 0"
+        );
+        supported!(
+            self.max_background_threads != Some(0),
+            "max_background_threads must be above 0"
+        );
+        supported!(
+            self.snapshot_after_bytes != Some(0),
+            "snapshot_after_bytes must be above 0"
+        );
         supported!(
             self.idgen_persist_interval > 0,
             "idgen_persist_interval must be above 0"
         );
+        if let Some(fraction) = self.cache_capacity_fraction {
+            supported!(
+                (0.0..=1.0).contains(&fraction),
+                "cache_capacity_fraction must be between 0.0 and 1.0"
+            );
+        }
+        if let Some(threshold) = self.compaction_fill_threshold {
+            supported!(
+                (0.0..=1.0).contains(&threshold),
+                "compaction_fill_threshold must be between 0.0 and 1.0"
+            );
+        }
+        if self.verify_only {
+            supported!(
+                !self.temporary && !self.create_new,
+                "verify_only can only open an existing database"
+            );
+        }
+
+        #[cfg(feature = "event_log")]
+        self.event_log.config_validated();
+
         Ok(())
     }
 
-    fn open_file(&self) -> Result<File> {
-        let heap_dir: PathBuf = self.get_path().join("heap");
+    fn open_file(
+        &self,
+        warnings: &mut Vec<OpenWarning>,
+    ) -> Result<(File, OpenOutcome)> {
+        if !self.allow_nested {
+            self.check_not_nested()?;
+        }
+
+        let claimed_temporary_path = if self.uses_generated_path() {
+            self.claim_temporary_path()?
+        } else {
+            false
+        };
+
+        let heap_dir: PathBuf = self.heap_dir();
+
+        if (self.heap_path.is_some() || !self.create_parents)
+            && !self.get_path().exists()
+        {
+            self.create_dir(&self.get_path())?;
+        }
+
+        let heap_is_symlink = fs::symlink_metadata(&heap_dir)
+            .map(|m| m.file_type().is_symlink())
+            .unwrap_or(false);
 
-        if !heap_dir.exists() {
-            fs::create_dir_all(heap_dir)?;
+        if heap_is_symlink && !heap_dir.exists() {
+            if !self.follow_heap_symlink {
+                error!(
+                    "heap directory {:?} is a symlink to {:?}, which \
+                     does not exist. Restore it, or set \
+                     Config::follow_heap_symlink to recreate it.",
+                    heap_dir,
+                    fs::read_link(&heap_dir)?
+                );
+                return Err(Error::Unsupported(
+                    "heap directory is a broken symlink",
+                ));
+            }
+            let target = heap_dir
+                .parent()
+                .map_or_else(PathBuf::new, Path::to_path_buf)
+                .join(fs::read_link(&heap_dir)?);
+            warn!("recreating missing heap symlink target {:?}", target);
+            self.create_dir(&target)?;
+        } else if !heap_dir.exists() {
+            self.create_dir(&heap_dir)?;
+        }
+
+        if !self.allow_risky_filesystem {
+            self.check_filesystem(warnings);
         }
 
-        self.verify_config()?;
+        let (outcome, needs_config_write) = self.verify_config(warnings)?;
+        self.check_db_not_lost()?;
 
         // open the data file
         let mut options = fs::OpenOptions::new();
@@ -516,17 +2952,278 @@ impl Config {
         let _ = options.read(true);
         let _ = options.write(true);
 
-        if self.create_new {
+        if let Some(ref customizer) = self.file_options {
+            customizer(&mut options);
+        }
+
+        if self.create_new || claimed_temporary_path {
             options.create_new(true);
         }
 
-        let _ = std::fs::File::create(
-            self.get_path().join("DO_NOT_USE_THIS_DIRECTORY_FOR_ANYTHING"),
+        let _ = std::fs::File::create(self.get_path().join(LOCK_MARKER));
+
+        let file =
+            self.try_lock(retry_on_eintr(|| options.open(&self.db_path()))?)?;
+
+        // a crash while writing the settings file may have left
+        // its temporary file behind.
+        let temp_config = self.config_temp_path();
+        if temp_config.exists() {
+            debug!("removing leftover settings file {:?}", temp_config);
+            fs::remove_file(temp_config)?;
+        }
+
+        // only written once we hold the lock, so that a process
+        // that has the database open never sees it change.
+        if needs_config_write {
+            self.write_config(warnings)?;
+        }
+
+        self.fsync_directory_reporting(self.get_path(), warnings)?;
+        Ok((file, outcome))
+    }
+
+    /// Creates `dir`, along with any missing parents unless
+    /// `create_parents` is disabled, in which case the parent
+    /// has to exist already.
+    fn create_dir(&self, dir: &Path) -> Result<()> {
+        if self.create_parents {
+            fs::create_dir_all(dir)?;
+            return Ok(());
+        }
+
+        let parent_exists = match dir.parent() {
+            Some(parent) => parent.as_os_str().is_empty() || parent.is_dir(),
+            None => true,
+        };
+        if !parent_exists {
+            error!(
+                "not creating {:?} because its parent directory does \
+                 not exist and Config::create_parents is disabled",
+                dir
+            );
+            return Err(Error::Io(
+                ErrorKind::NotFound,
+                "parent directory does not exist",
+            ));
+        }
+
+        match fs::create_dir(dir) {
+            Err(e) if e.kind() != ErrorKind::AlreadyExists => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Opens the data file of an existing database for
+    /// `verify_only`, without creating or changing anything.
+    fn open_file_verify_only(
+        &self,
+        warnings: &mut Vec<OpenWarning>,
+    ) -> Result<(File, OpenOutcome)> {
+        let (outcome, _) = self.verify_config(warnings)?;
+        self.check_db_not_lost()?;
+
+        let file = retry_on_eintr(|| {
+            fs::OpenOptions::new().read(true).open(self.db_path())
+        })?;
+
+        Ok((self.try_lock(file)?, outcome))
+    }
+
+    /// Returns an error if the database was opened with
+    /// `verify_only`, which rejects all writes.
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        if self.verify_only {
+            Err(Error::Unsupported(
+                "the database was opened with verify_only, which rejects writes",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Snapshots only record where each page lives in the `db`
+    /// file, so they cannot be used to recover anything once it
+    /// is gone. Rather than starting over with an empty log that
+    /// the snapshot points into, we refuse to open.
+    fn check_db_not_lost(&self) -> Result<()> {
+        let db_len = fs::metadata(self.db_path()).map(|m| m.len()).unwrap_or(0);
+        if db_len > 0 {
+            return Ok(());
+        }
+
+        let snapshots: Vec<PathBuf> = fs::read_dir(self.get_path())?
+            .filter_map(|entry_res| {
+                let path = entry_res.ok()?.path();
+                let name = path.file_name()?.to_string_lossy().into_owned();
+                if name.starts_with("snap.") && !name.ends_with(".generating") {
+                    Some(path)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        if snapshots.is_empty() {
+            return Ok(());
+        }
+
+        error!(
+            "the db file at {:?} is missing or empty, but snapshots \
+             {:?} exist. Snapshots only contain the locations of \
+             pages within the db file, so the database cannot be \
+             rebuilt from them. Restore the db file, or remove the \
+             snapshots to start over with an empty database.",
+            self.db_path(),
+            snapshots
         );
+        Err(Error::Unsupported(
+            "the db file is missing, and snapshots cannot be restored \
+             without it. See error log for more details.",
+        ))
+    }
 
-        let file = self.try_lock(options.open(&self.db_path())?)?;
-        maybe_fsync_directory(self.get_path())?;
-        Ok(file)
+    /// Creates the generated directory of a temporary database
+    /// the first time it is opened, returning `true` if it was
+    /// created by this call. A generated path is never expected
+    /// to exist already, so rather than silently reusing stale
+    /// files left behind by a crashed process that happened to
+    /// use the same salt, we generate a new path up to
+    /// `temporary_collision_retries` times. Later opens of the
+    /// same `Config` reuse the claimed directory.
+    fn claim_temporary_path(&self) -> Result<bool> {
+        let mut tmp_path = self.tmp_path.lock();
+        if tmp_path.claimed {
+            return Ok(false);
+        }
+
+        let mut retries = self.temporary_collision_retries;
+        loop {
+            if let Some(parent) = tmp_path.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            match fs::create_dir(&tmp_path.path) {
+                Ok(()) => break,
+                Err(e)
+                    if e.kind() == ErrorKind::AlreadyExists && retries > 0 =>
+                {
+                    warn!(
+                        "generated temporary path {:?} already exists, \
+                         retrying with a new one",
+                        tmp_path.path
+                    );
+                    retries -= 1;
+                    tmp_path.path = Config::gen_temp_path();
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        tmp_path.claimed = true;
+        Ok(true)
+    }
+
+    /// Fsyncs a directory according to the configured
+    /// `DirFsyncPolicy`.
+    pub(crate) fn fsync_directory<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> io::Result<()> {
+        self.fsync_directory_reporting(path, &mut vec![])
+    }
+
+    /// Like `fsync_directory`, but records a failure that was
+    /// ignored under `DirFsyncPolicy::BestEffort` in `warnings`.
+    fn fsync_directory_reporting<P: AsRef<Path>>(
+        &self,
+        path: P,
+        warnings: &mut Vec<OpenWarning>,
+    ) -> io::Result<()> {
+        if self.directory_fsync == DirFsyncPolicy::Disabled {
+            return Ok(());
+        }
+
+        #[cfg(feature = "failpoints")]
+        let res = if fail::is_active("fsync directory") {
+            Err(io::Error::from_raw_os_error(libc::EINVAL))
+        } else {
+            maybe_fsync_directory(&path)
+        };
+
+        #[cfg(not(feature = "failpoints"))]
+        let res = maybe_fsync_directory(&path);
+
+        match res {
+            Err(ref e)
+                if self.directory_fsync == DirFsyncPolicy::BestEffort
+                    && is_unsupported_fsync(e) =>
+            {
+                warn!(
+                    "ignoring failure to fsync directory {:?}: {}",
+                    path.as_ref(),
+                    e
+                );
+                warnings.push(OpenWarning::DirectoryFsyncUnsupported);
+                Ok(())
+            }
+            other => other,
+        }
+    }
+
+    /// Warns about storing the database or its heap on a
+    /// filesystem whose fsync can't be trusted. A tmpfs is
+    /// expected for `temporary` databases.
+    fn check_filesystem(&self, warnings: &mut Vec<OpenWarning>) {
+        let mut dirs = vec![self.get_path()];
+        if self.heap_path.is_some() {
+            dirs.push(self.heap_dir());
+        }
+
+        for dir in dirs {
+            let fs_type = match sys_limits::get_risky_filesystem(&dir) {
+                Some("tmpfs") if self.temporary => continue,
+                Some(fs_type) => fs_type,
+                None => continue,
+            };
+            warn!(
+                "{:?} is on a {} filesystem, where sled can't guarantee \
+                 durability. Set Config::allow_risky_filesystem to \
+                 silence this warning.",
+                dir, fs_type
+            );
+            let warning = OpenWarning::RiskyFilesystem { fs_type };
+            if !warnings.contains(&warning) {
+                warnings.push(warning);
+            }
+        }
+    }
+
+    // walks up the parents of our path looking for a
+    // directory that contains another database.
+    fn check_not_nested(&self) -> Result<()> {
+        let path = self.get_path();
+        let absolute_path = if path.is_absolute() {
+            path
+        } else {
+            std::env::current_dir()?.join(path)
+        };
+
+        for ancestor in
+            absolute_path.ancestors().skip(1).take(self.nested_check_depth)
+        {
+            if ancestor.join("conf").is_file() && ancestor.join("db").is_file()
+            {
+                error!(
+                    "the configured path {:?} is nested inside of \
+                     the sled database at {:?}",
+                    absolute_path, ancestor
+                );
+                return Err(Error::Unsupported(
+                    "configured path is nested inside another sled database",
+                ));
+            }
+        }
+
+        Ok(())
     }
 
     fn try_lock(&self, file: File) -> Result<File> {
@@ -537,44 +3234,94 @@ impl Config {
         {
             use fs2::FileExt;
 
-            let try_lock =
-                if cfg!(any(feature = "for-internal-testing-only", feature = "light_testing")) {
-                    // we block here because during testing
-                    // there are many filesystem race condition
-                    // that happen, causing locks to be held
-                    // for long periods of time, so we should
-                    // block to wait on reopening files.
-                    file.lock_exclusive()
-                } else {
-                    file.try_lock_exclusive()
-                };
+            type LockFn = fn(&File) -> io::Result<()>;
+
+            // a shared lock keeps writers out while still
+            // allowing several verifications at once.
+            let (try_lock_fn, lock_fn): (LockFn, LockFn) = if self.verify_only {
+                (FileExt::try_lock_shared, FileExt::lock_shared)
+            } else {
+                (FileExt::try_lock_exclusive, FileExt::lock_exclusive)
+            };
+
+            let try_lock = if let Some(lock_wait) = self.lock_wait {
+                // a blocking `lock_exclusive` can't be cancelled
+                // once the wait expires, so we poll instead.
+                let deadline = Instant::now() + lock_wait;
+                loop {
+                    let res = try_lock_fn(&file);
+                    if res.is_ok() || Instant::now() >= deadline {
+                        break res;
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            } else if cfg!(any(
+                feature = "for-internal-testing-only",
+                feature = "light_testing"
+            )) {
+                // we block here because during testing
+                // there are many filesystem race condition
+                // that happen, causing locks to be held
+                // for long periods of time, so we should
+                // block to wait on reopening files.
+                lock_fn(&file)
+            } else {
+                try_lock_fn(&file)
+            };
 
             if try_lock.is_err() {
-                return Err(Error::Io(
-                    ErrorKind::Other,
-                    "could not acquire database file lock",
-                ));
+                #[cfg(feature = "event_log")]
+                self.event_log.lock_contended();
+
+                return Err(Error::LockContended { path: self.get_path() });
             }
         }
 
+        #[cfg(feature = "event_log")]
+        self.event_log.lock_acquired();
+
         Ok(file)
     }
 
-    fn verify_config(&self) -> Result<()> {
-        match self.read_config() {
+    /// Checks the settings file against this `Config`, also
+    /// returning whether the settings file has to be written
+    /// once the database is locked.
+    fn verify_config(
+        &self,
+        warnings: &mut Vec<OpenWarning>,
+    ) -> Result<(OpenOutcome, bool)> {
+        if self.skip_config_file {
+            let exists = self.db_path().exists();
+            if self.verify_only && !exists {
+                return Err(Error::Unsupported(
+                    "verify_only can only open an existing database",
+                ));
+            }
+            debug!("skipping the settings file, trusting the Config");
+            let outcome =
+                if exists { OpenOutcome::Opened } else { OpenOutcome::Created };
+            return Ok((outcome, false));
+        }
+
+        match self.read_config_reporting(warnings) {
             Ok(Some(old)) => {
-                if self.use_compression {
-                    supported!(
-                        old.use_compression,
-                        "cannot change compression configuration across restarts. \
-                        this database was created without compression enabled."
-                    );
-                } else {
-                    supported!(
-                        !old.use_compression,
-                        "cannot change compression configuration across restarts. \
-                        this database was created with compression enabled."
-                    );
+                #[cfg(feature = "event_log")]
+                {
+                    if self.use_compression != old.use_compression {
+                        self.event_log.config_read_mismatch("use_compression");
+                    }
+                    if self.segment_size != old.segment_size {
+                        self.event_log.config_read_mismatch("segment_size");
+                    }
+                    if self.version != old.version {
+                        self.event_log.config_read_mismatch("version");
+                    }
+                }
+
+                if self.use_compression != old.use_compression {
+                    return Err(Error::CompressionMismatch {
+                        db_uses_compression: old.use_compression,
+                    });
                 }
 
                 supported!(
@@ -582,7 +3329,47 @@ impl Config {
                     "cannot change the io buffer size across restarts."
                 );
 
-                if self.version != old.version {
+                let transform_id =
+                    self.segment_transform.as_ref().map(|t| t.id());
+                if transform_id != old.segment_transform.as_deref() {
+                    error!(
+                        "This database was created with segment transform \
+                         {:?}, but it was opened with segment transform {:?}.",
+                        old.segment_transform, transform_id,
+                    );
+                    return Err(Error::Unsupported(
+                        "cannot change the segment transform across restarts. \
+                         See error log for more details.",
+                    ));
+                }
+
+                if self.crc_variant != old.crc_variant {
+                    error!(
+                        "This database was created with crc variant {:?}, \
+                         but it was opened with crc variant {:?}. Set \
+                         Config::crc_variant to match.",
+                        old.crc_variant, self.crc_variant,
+                    );
+                    return Err(Error::Unsupported(
+                        "cannot change the crc variant across restarts. \
+                         See error log for more details.",
+                    ));
+                }
+
+                let heap_path = StorageParameters::from_config(self).heap_path;
+                if heap_path != old.heap_path {
+                    error!(
+                        "This database was created with heap path {:?}, \
+                         but it was opened with heap path {:?}.",
+                        old.heap_path, heap_path,
+                    );
+                    return Err(Error::Unsupported(
+                        "cannot change the heap path across restarts. \
+                         See error log for more details.",
+                    ));
+                }
+
+                if !self.version_policy.allows(old.version, self.version) {
                     error!(
                         "This database was created using \
                          pagecache version {}.{}, but our pagecache \
@@ -594,58 +3381,124 @@ impl Config {
                         self.version.0,
                         self.version.1,
                     );
-                    supported!(
-                        self.version == old.version,
+                    return Err(Error::Unsupported(
                         "The stored database must use a compatible sled version.
-                        See error log for more details."
-                    );
+                        See error log for more details.",
+                    ));
                 }
-                Ok(())
+
+                if self.version != old.version {
+                    warn!(
+                        "opening database created using version {}.{} \
+                         with version {}.{}",
+                        old.version.0,
+                        old.version.1,
+                        self.version.0,
+                        self.version.1,
+                    );
+                }
+                Ok((
+                    OpenOutcome::Opened,
+                    self.version != old.version && !self.verify_only,
+                ))
             }
-            Ok(None) => self.write_config(),
+            Ok(None) if self.verify_only => Err(Error::Unsupported(
+                "verify_only can only open an existing database",
+            )),
+            Ok(None) => Ok((OpenOutcome::Created, true)),
             Err(e) => Err(e),
         }
     }
 
-    fn serialize(&self) -> Vec<u8> {
-        let persisted_config = StorageParameters {
-            version: self.version,
-            segment_size: self.segment_size,
-            use_compression: self.use_compression,
-        };
+    fn write_config(&self, warnings: &mut Vec<OpenWarning>) -> Result<()> {
+        self.write_storage_parameters(
+            &StorageParameters::from_config(self),
+            warnings,
+        )
+    }
+
+    fn write_storage_parameters(
+        &self,
+        params: &StorageParameters,
+        warnings: &mut Vec<OpenWarning>,
+    ) -> Result<()> {
+        let temp_path = self.config_temp_path();
+
+        let res = self.write_config_inner(params, &temp_path, warnings);
+
+        if res.is_err() {
+            // a partially written file would otherwise be left
+            // behind, for example when the disk is full.
+            if let Err(e) = fs::remove_file(&temp_path) {
+                if e.kind() != ErrorKind::NotFound {
+                    warn!(
+                        "failed to remove temporary settings file {:?}: {}",
+                        temp_path, e
+                    );
+                }
+            }
+        }
 
-        persisted_config.serialize()
+        res
     }
 
-    fn write_config(&self) -> Result<()> {
-        let bytes = self.serialize();
+    fn write_config_inner(
+        &self,
+        params: &StorageParameters,
+        temp_path: &Path,
+        warnings: &mut Vec<OpenWarning>,
+    ) -> Result<()> {
+        let body = params.serialize();
+        let mut bytes = Vec::with_capacity(CONFIG_HEADER_LEN + body.len());
+        bytes.extend_from_slice(&CONFIG_HEADER_MAGIC);
+        let body_len = u32::try_from(body.len()).unwrap();
+        bytes.extend_from_slice(&u32_to_arr(body_len));
+        bytes.extend_from_slice(&body);
+
+        // the crc covers the header too, so that a corrupted
+        // length is detected.
         let crc: u32 = crc32(&*bytes);
         let crc_arr = u32_to_arr(crc);
 
-        let temp_path = self.get_path().join("conf.tmp");
         let final_path = self.config_path();
 
-        let mut f =
-            fs::OpenOptions::new().write(true).create(true).open(&temp_path)?;
+        let mut f = retry_on_eintr(|| {
+            fs::OpenOptions::new().write(true).create(true).open(temp_path)
+        })?;
 
+        // `write_all` resumes partial writes that were interrupted
+        // internally, so any `Interrupted` error that reaches us
+        // means that nothing was written.
         io_fail!(self, "write_config bytes");
-        f.write_all(&*bytes)?;
+        retry_on_eintr(|| f.write_all(&*bytes))?;
         io_fail!(self, "write_config crc");
-        f.write_all(&crc_arr)?;
+        retry_on_eintr(|| f.write_all(&crc_arr))?;
         io_fail!(self, "write_config fsync");
-        f.sync_all()?;
+        retry_on_eintr(|| self.sync_file(&f))?;
         io_fail!(self, "write_config rename");
-        fs::rename(temp_path, final_path)?;
+        retry_on_eintr(|| fs::rename(temp_path, &final_path))?;
         io_fail!(self, "write_config dir fsync");
-        maybe_fsync_directory(self.get_path())?;
+        self.fsync_directory_reporting(self.get_path(), warnings)?;
         io_fail!(self, "write_config post");
+
+        #[cfg(feature = "event_log")]
+        self.event_log.config_written();
+
         Ok(())
     }
 
     fn read_config(&self) -> Result<Option<StorageParameters>> {
+        self.read_config_reporting(&mut vec![])
+    }
+
+    fn read_config_reporting(
+        &self,
+        warnings: &mut Vec<OpenWarning>,
+    ) -> Result<Option<StorageParameters>> {
         let path = self.config_path();
 
-        let f_res = fs::OpenOptions::new().read(true).open(&path);
+        let f_res =
+            retry_on_eintr(|| fs::OpenOptions::new().read(true).open(&path));
 
         let mut f = match f_res {
             Err(ref e) if e.kind() == ErrorKind::NotFound => {
@@ -657,32 +3510,68 @@ impl Config {
             Ok(f) => f,
         };
 
-        if f.metadata()?.len() <= 8 {
+        if retry_on_eintr(|| f.metadata())?.len() <= 8 {
             warn!("empty/corrupt configuration file found");
+            warnings.push(OpenWarning::EmptyConfig);
             return Ok(None);
         }
 
         let mut buf = vec![];
-        let _ = f.read_to_end(&mut buf)?;
+        let _ = retry_on_eintr(|| {
+            buf.clear();
+            let _ = f.seek(io::SeekFrom::Start(0))?;
+            f.read_to_end(&mut buf)
+        })?;
         let len = buf.len();
         let _ = buf.split_off(len - 4);
 
         let mut crc_arr = [0_u8; 4];
-        let _ = f.seek(io::SeekFrom::End(-4))?;
-        f.read_exact(&mut crc_arr)?;
+        retry_on_eintr(|| {
+            let _ = f.seek(io::SeekFrom::End(-4))?;
+            f.read_exact(&mut crc_arr)
+        })?;
         let crc_expected = arr_to_u32(&crc_arr);
 
         let crc_actual = crc32(&*buf);
 
+        // a torn write can leave a file whose last 4 bytes are not
+        // the crc, so we check that the body is as long as the
+        // header says it is before trusting the crc.
+        let body_start = if buf.starts_with(&CONFIG_HEADER_MAGIC) {
+            if buf.len() < CONFIG_HEADER_LEN {
+                error!("settings file {:?} has a truncated header", path);
+                return Err(Error::corruption(None));
+            }
+            let declared_len =
+                arr_to_u32(&buf[CONFIG_HEADER_MAGIC.len()..CONFIG_HEADER_LEN])
+                    as usize;
+            let actual_len = buf.len() - CONFIG_HEADER_LEN;
+            if declared_len != actual_len {
+                error!(
+                    "settings file {:?} declares a body of {} bytes, \
+                     but {} bytes were found",
+                    path, declared_len, actual_len
+                );
+                return Err(Error::corruption(None));
+            }
+            CONFIG_HEADER_LEN
+        } else {
+            0
+        };
+
         if crc_expected != crc_actual {
             warn!(
                 "crc for settings file {:?} failed! \
                  can't verify that config is safe",
                 path
             );
+            warnings.push(OpenWarning::ConfigCrcMismatch {
+                expected: crc_expected,
+                actual: crc_actual,
+            });
         }
 
-        StorageParameters::deserialize(&buf).map(Some)
+        StorageParameters::deserialize(&buf[body_start..]).map(Some)
     }
 
     /// Return the global error if one was encountered during
@@ -696,7 +3585,7 @@ impl Config {
         } else {
             #[allow(unsafe_code)]
             unsafe {
-                Err(*ge.deref())
+                Err(ge.deref().clone())
             }
         }
     }
@@ -714,16 +3603,27 @@ impl Config {
 
     pub(crate) fn set_global_error(&self, error_value: Error) {
         let guard = pin();
-        let error = Owned::new(error_value);
+        let error = Owned::new(error_value.clone());
 
         let expected_old = Shared::null();
 
-        let _ = self.global_error.compare_and_set(
+        let res = self.global_error.compare_and_set(
             expected_old,
             error,
             SeqCst,
             &guard,
         );
+
+        if res.is_ok() {
+            if let Some(callback) = &self.on_global_error {
+                let called = std::panic::catch_unwind(
+                    std::panic::AssertUnwindSafe(|| callback(&error_value)),
+                );
+                if called.is_err() {
+                    error!("on_global_error callback panicked");
+                }
+            }
+        }
     }
 
     #[cfg(feature = "failpoints")]
@@ -746,6 +3646,7 @@ pub struct RunningConfig {
     inner: Config,
     pub(crate) file: Arc<File>,
     pub(crate) heap: Arc<Heap>,
+    pub(crate) open_warnings: Arc<[OpenWarning]>,
 }
 
 impl Deref for RunningConfig {
@@ -769,14 +3670,231 @@ impl Drop for RunningConfig {
 impl Drop for Inner {
     fn drop(&mut self) {
         if self.temporary {
+            let guard = pin();
+            let failed = !self.global_error.load(Acquire, &guard).is_null();
+            if failed && self.keep_temporary_on_error {
+                error!(
+                    "keeping temporary storage file {:?} because \
+                     the database encountered an IO error",
+                    self.get_path()
+                );
+                return;
+            }
+
             // Our files are temporary, so nuke them.
             debug!("removing temporary storage file {:?}", self.get_path());
-            let _res = fs::remove_dir_all(&self.get_path());
+            let mut paths = vec![self.get_path()];
+            paths.extend(self.heap_path.clone());
+            for path in paths {
+                let removed = if self.async_temp_cleanup {
+                    remove_dir_in_background(
+                        &path,
+                        self.temporary_delete_retries,
+                    )
+                } else {
+                    remove_dir_with_retries(
+                        &path,
+                        self.temporary_delete_retries,
+                    )
+                };
+                if let Err(e) = removed {
+                    warn!(
+                        "failed to remove temporary storage file {:?}: {:?}",
+                        path, e
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Held by tests to keep the threads started by
+/// `remove_dir_in_background` from removing anything until
+/// they release it.
+#[cfg(test)]
+static PAUSE_TEMP_CLEANUP: Mutex<()> = parking_lot::const_mutex(());
+
+/// Renames `path` to a unique sibling ending in `.deleting`, and
+/// removes that on a detached thread with `remove_dir_with_retries`.
+/// Falls back to removing `path` in place if it can't be renamed.
+fn remove_dir_in_background(path: &Path, retries: usize) -> io::Result<()> {
+    static DELETIONS: AtomicUsize = AtomicUsize::new(0);
+
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(
+        ".{}.{}.deleting",
+        std::process::id(),
+        DELETIONS.fetch_add(1, SeqCst)
+    ));
+    let deleting = path.with_file_name(name);
+
+    match fs::rename(path, &deleting) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            debug!(
+                "failed to rename {:?} for removal in the background, \
+                 removing it in place: {:?}",
+                path, e
+            );
+            return remove_dir_with_retries(path, retries);
+        }
+    }
+
+    let cleanup = {
+        let deleting = deleting.clone();
+        move || {
+            #[cfg(test)]
+            drop(PAUSE_TEMP_CLEANUP.lock());
+
+            if let Err(e) = remove_dir_with_retries(&deleting, retries) {
+                warn!(
+                    "failed to remove temporary storage file {:?}: {:?}",
+                    deleting, e
+                );
+            }
+        }
+    };
+    let spawned = std::thread::Builder::new()
+        .name("sled-temp-cleanup".into())
+        .spawn(cleanup);
+
+    match spawned {
+        Ok(_detached) => Ok(()),
+        Err(_) => remove_dir_with_retries(&deleting, retries),
+    }
+}
+
+/// Removes `path` recursively, retrying up to `retries` more times
+/// with a short, growing sleep in between if removal fails. A path
+/// that does not exist counts as removed.
+fn remove_dir_with_retries(path: &Path, retries: usize) -> io::Result<()> {
+    let mut attempt = 0;
+    loop {
+        match fs::remove_dir_all(path) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) if attempt >= retries => return Err(e),
+            Err(e) => {
+                debug!(
+                    "failed to remove {:?} on attempt {}, retrying: {:?}",
+                    path,
+                    attempt + 1,
+                    e
+                );
+                attempt += 1;
+                std::thread::sleep(Duration::from_millis(10 * attempt as u64));
+            }
         }
     }
 }
 
 impl RunningConfig {
+    /// Returns the absolute directory that this database is
+    /// stored in, including the generated path used by
+    /// temporary databases that were not given a path.
+    pub fn resolved_path(&self) -> PathBuf {
+        let path = self.get_path();
+
+        if let Ok(canonical) = fs::canonicalize(&path) {
+            return canonical;
+        }
+
+        if path.is_absolute() {
+            path
+        } else {
+            match std::env::current_dir() {
+                Ok(cwd) => cwd.join(path),
+                Err(_) => path,
+            }
+        }
+    }
+
+    /// Calls `f` with the open data file, for advisory
+    /// operations like `fstat` or `posix_fadvise`. The file is
+    /// only lent for the duration of the call, so it can't be
+    /// kept open after the database is closed.
+    ///
+    /// `f` must not write to, truncate, lock or unlock the
+    /// file. sled only uses positioned reads and writes, so
+    /// moving the file's cursor is harmless.
+    pub fn with_data_file<T>(&self, f: impl FnOnce(&File) -> T) -> T {
+        f(&self.file)
+    }
+
+    fn clean_shutdown_path(&self) -> PathBuf {
+        self.get_path().join("clean_shutdown")
+    }
+
+    /// Records that the database was shut down cleanly after
+    /// writing a snapshot that covers the log up to `lsn`.
+    pub(crate) fn write_clean_shutdown(&self, lsn: Lsn) -> Result<()> {
+        let bytes = format!("clean_shutdown: {}\n", lsn).into_bytes();
+        let crc_arr = u32_to_arr(crc32(&bytes));
+
+        let temp_path = self.get_path().join("clean_shutdown.tmp");
+
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)?;
+
+        io_fail!(self, "write_clean_shutdown bytes");
+        f.write_all(&bytes)?;
+        f.write_all(&crc_arr)?;
+        io_fail!(self, "write_clean_shutdown fsync");
+        f.sync_all()?;
+        fs::rename(temp_path, self.clean_shutdown_path())?;
+        self.fsync_directory(self.get_path())?;
+
+        Ok(())
+    }
+
+    /// Reads and removes the clean shutdown marker, so that
+    /// any crash after startup invalidates it. With
+    /// `verify_only`, the marker is left in place.
+    pub(crate) fn take_clean_shutdown(&self) -> Result<Option<Lsn>> {
+        let path = self.clean_shutdown_path();
+
+        let buf = match fs::read(&path) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                return Ok(None);
+            }
+            Err(other) => {
+                return Err(other.into());
+            }
+            Ok(buf) => buf,
+        };
+
+        if !self.verify_only {
+            fs::remove_file(&path)?;
+            self.fsync_directory(self.get_path())?;
+        }
+
+        if buf.len() <= 4 {
+            warn!("empty/corrupt clean shutdown marker found");
+            return Ok(None);
+        }
+
+        let (bytes, crc_arr) = buf.split_at(buf.len() - 4);
+        if crc32(bytes) != arr_to_u32(crc_arr) {
+            warn!("crc for clean shutdown marker {:?} failed", path);
+            return Ok(None);
+        }
+
+        let lsn = std::str::from_utf8(bytes)
+            .ok()
+            .and_then(|s| s.strip_prefix("clean_shutdown: "))
+            .and_then(|s| s.trim_end().parse().ok());
+
+        if lsn.is_none() {
+            warn!("failed to parse clean shutdown marker {:?}", path);
+        }
+
+        Ok(lsn)
+    }
+
     // returns the snapshot file paths for this system
     #[doc(hidden)]
     pub fn get_snapshot_files(&self) -> io::Result<Vec<PathBuf>> {
@@ -815,10 +3933,1831 @@ impl RunningConfig {
     }
 }
 
-fn crate_version() -> (usize, usize) {
+/// Limits a cache capacity to a quarter of the address space
+/// on targets with pointers of at most 32 bits, where a larger
+/// cache could never be allocated. With `enforce_min_cache`,
+/// such a capacity is rejected instead.
+pub(crate) fn limit_cache_to_address_space(
+    cache_capacity: usize,
+    pointer_width: u32,
+    enforce_min_cache: bool,
+) -> Result<usize> {
+    if pointer_width > 32 {
+        return Ok(cache_capacity);
+    }
+    let maximum = 1_usize << (pointer_width - 2);
+    if cache_capacity <= maximum {
+        return Ok(cache_capacity);
+    }
+    if enforce_min_cache {
+        return Err(Error::Unsupported(
+            "cache_capacity must be at most a quarter of the \
+             address space when enforce_min_cache is set",
+        ));
+    }
+    error!(
+        "cache capacity of {} bytes does not fit in the address space, \
+         so it is limited to {} bytes",
+        cache_capacity, maximum
+    );
+    Ok(maximum)
+}
+
+/// Limits a cache capacity to the cgroup memory limit, if
+/// there is one.
+pub(crate) fn limit_cache_capacity(cache_capacity: usize) -> usize {
+    match sys_limits::get_memory_limit() {
+        Some(limit) if cache_capacity > limit => {
+            error!(
+                "cache capacity is limited to the cgroup memory \
+                 limit: {} bytes",
+                limit
+            );
+            limit
+        }
+        _ => cache_capacity,
+    }
+}
+
+#[allow(
+    clippy::float_arithmetic,
+    clippy::cast_precision_loss,
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss
+)]
+fn cache_capacity_from_fraction(fraction: f64, memory: usize) -> usize {
+    let capacity = (memory as f64 * fraction) as usize;
+    capacity.max(MIN_CACHE_CAPACITY_FROM_FRACTION)
+}
+
+/// Retries an IO operation for as long as it fails with
+/// `ErrorKind::Interrupted`, which happens when a signal
+/// arrives during a blocking syscall.
+fn retry_on_eintr<T, F>(mut f: F) -> io::Result<T>
+where
+    F: FnMut() -> io::Result<T>,
+{
+    loop {
+        match f() {
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            other => return other,
+        }
+    }
+}
+
+/// A database being built in a staging directory by
+/// `Config::open_staged`. It may be used like a `Db`, and
+/// becomes visible at its final path once `promote` is
+/// called.
+#[derive(Debug)]
+pub struct StagedDb {
+    db: Option<Db>,
+    config: Config,
+    staging_path: PathBuf,
+    final_path: PathBuf,
+    promoted: bool,
+}
+
+impl Deref for StagedDb {
+    type Target = Db;
+
+    fn deref(&self) -> &Db {
+        self.db.as_ref().unwrap()
+    }
+}
+
+impl StagedDb {
+    /// The directory that the database is being built in.
+    pub fn staging_path(&self) -> &Path {
+        &self.staging_path
+    }
+
+    /// Flushes and closes the staged database, renames its
+    /// directory to the final path, and opens it there.
+    /// Fails if anything already exists at the final path.
+    /// If the staged database can't be moved into place, the
+    /// staging directory is removed.
+    ///
+    /// Every clone of the staged `Db` and its `Tree`s must
+    /// be dropped before calling this, or
+    /// `Error::LockContended` will be returned.
+    pub fn promote(self) -> Result<Db> {
+        self.promote_inner(false)
+    }
+
+    /// Like `promote`, but replaces any database that
+    /// already exists at the final path. The existing
+    /// database is moved aside before the staged one is
+    /// renamed into place, so there is a short window during
+    /// which nothing exists at the final path. If the staged
+    /// database can't be renamed into place, the existing one
+    /// is moved back.
+    pub fn promote_replacing(self) -> Result<Db> {
+        self.promote_inner(true)
+    }
+
+    fn promote_inner(mut self, replace: bool) -> Result<Db> {
+        let db = self.db.take().unwrap();
+        db.flush()?;
+        drop(db);
+
+        self.ensure_closed()?;
+
+        self.config.fsync_directory(&self.staging_path)?;
+
+        let final_exists = fs::symlink_metadata(&self.final_path).is_ok();
+        let replaced_path = if final_exists {
+            if !replace {
+                error!(
+                    "refusing to promote staged database {:?} over \
+                     existing path {:?}",
+                    self.staging_path, self.final_path
+                );
+                return Err(Error::Unsupported(
+                    "the final path of a staged database already exists. \
+                     See error log for more details.",
+                ));
+            }
+            let mut replaced = self.staging_path.clone().into_os_string();
+            replaced.push(".replaced");
+            let replaced = PathBuf::from(replaced);
+            fs::rename(&self.final_path, &replaced)?;
+            Some(replaced)
+        } else {
+            None
+        };
+
+        if let Err(e) = fs::rename(&self.staging_path, &self.final_path) {
+            if let Some(replaced) = replaced_path {
+                if let Err(restore_error) =
+                    fs::rename(&replaced, &self.final_path)
+                {
+                    error!(
+                        "failed to move the replaced database at {:?} \
+                         back to {:?} after failing to promote a staged \
+                         database: {}",
+                        replaced, self.final_path, restore_error
+                    );
+                }
+            }
+            return Err(e.into());
+        }
+        self.promoted = true;
+
+        if let Some(parent) = self.final_path.parent() {
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+            self.config.fsync_directory(parent)?;
+        }
+
+        if let Some(replaced) = replaced_path {
+            fs::remove_dir_all(replaced)?;
+        }
+
+        let mut config = self.config.clone();
+        let m = Arc::make_mut(&mut config.0);
+        m.path = self.final_path.clone();
+        m.create_new = false;
+
+        config.open()
+    }
+
+    // the data file is locked for as long as any clone of the
+    // staged database is still open. IO that was in flight when
+    // the last one was dropped may hold it briefly, so we give
+    // that a moment to finish.
+    fn ensure_closed(&self) -> Result<()> {
+        #[cfg(all(
+            not(miri),
+            any(windows, target_os = "linux", target_os = "macos")
+        ))]
+        {
+            use fs2::FileExt;
+
+            let db_file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(self.staging_path.join("db"))?;
+
+            let deadline = Instant::now() + STAGED_CLOSE_WAIT;
+            while db_file.try_lock_exclusive().is_err() {
+                if Instant::now() >= deadline {
+                    error!(
+                        "the staged database at {:?} is still open, so \
+                         it can not be promoted",
+                        self.staging_path
+                    );
+                    return Err(Error::LockContended {
+                        path: self.staging_path.clone(),
+                    });
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for StagedDb {
+    fn drop(&mut self) {
+        drop(self.db.take());
+        if !self.promoted {
+            debug!(
+                "removing unpromoted staging directory {:?}",
+                self.staging_path
+            );
+            if let Err(e) = fs::remove_dir_all(&self.staging_path) {
+                warn!(
+                    "failed to remove staging directory {:?}: {}",
+                    self.staging_path, e
+                );
+            }
+        }
+    }
+}
+
+fn is_unsupported_fsync(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::ENOTSUP) | Some(libc::EINVAL))
+}
+
+fn copy_and_sync(from: &mut File, to: &Path) -> io::Result<()> {
+    let mut f = fs::OpenOptions::new().write(true).create_new(true).open(to)?;
+    let _ = io::copy(from, &mut f)?;
+    f.sync_all()
+}
+
+/// The name of the file that is created in a database directory
+/// to warn people against storing their own files in it.
+const LOCK_MARKER: &str = "DO_NOT_USE_THIS_DIRECTORY_FOR_ANYTHING";
+
+/// Reports whether the database at `path` is currently open,
+/// by briefly taking and releasing a shared lock on its data
+/// file, and whether its marker file exists. This helps tell
+/// a database that is really in use apart from one whose
+/// marker was left behind by a process that exited or crashed.
+/// A process that tries to open the database while the lock is
+/// being probed may fail with `Error::LockContended`.
+///
+/// On platforms where sled does not lock its files, `locked`
+/// is always `false`.
+pub fn diagnose_lock(path: &Path) -> LockDiagnosis {
+    LockDiagnosis {
+        locked: is_locked(&path.join("db")),
+        marker_exists: path.join(LOCK_MARKER).exists(),
+    }
+}
+
+/// Removes the `DO_NOT_USE_THIS_DIRECTORY_FOR_ANYTHING` marker
+/// file from the database directory at `path`, without touching
+/// any of its data. Fails with `ErrorKind::WouldBlock` if the
+/// database is currently open, as reported by
+/// `sled::diagnose_lock`. A marker that does not exist counts as
+/// removed.
+pub fn clear_stale_marker(path: &Path) -> io::Result<()> {
+    if is_locked(&path.join("db")) {
+        return Err(io::Error::new(
+            ErrorKind::WouldBlock,
+            "the database is open in another process or Db",
+        ));
+    }
+    match fs::remove_file(path.join(LOCK_MARKER)) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        other => other,
+    }
+}
+
+fn is_locked(db_path: &Path) -> bool {
+    #[cfg(all(
+        not(miri),
+        any(windows, target_os = "linux", target_os = "macos")
+    ))]
+    {
+        use fs2::FileExt;
+
+        // a database that was never created can't be locked
+        if let Ok(file) = File::open(db_path) {
+            if FileExt::try_lock_shared(&file).is_err() {
+                return true;
+            }
+            let _ = FileExt::unlock(&file);
+        }
+    }
+
+    #[cfg(not(all(
+        not(miri),
+        any(windows, target_os = "linux", target_os = "macos")
+    )))]
+    let _ = db_path;
+
+    false
+}
+
+/// Returns the `(major, minor)` version of this crate, which
+/// is the version recorded by databases that it creates.
+///
+/// This is re-exported as `sled::version`.
+pub fn crate_version() -> (usize, usize) {
     let vsn = env!("CARGO_PKG_VERSION");
     let mut parts = vsn.split('.');
     let major = parts.next().unwrap().parse().unwrap();
     let minor = parts.next().unwrap().parse().unwrap();
     (major, minor)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deserialize_legacy_config_without_format_marker() {
+        let legacy = b"segment_size: 524288\n\
+                       use_compression: false\n\
+                       version: 0.34\n";
+
+        let params = StorageParameters::deserialize(legacy).unwrap();
+
+        assert_eq!(
+            params,
+            StorageParameters {
+                segment_size: 524288,
+                use_compression: false,
+                segment_transform: None,
+                heap_path: None,
+                crc_variant: CrcVariant::Crc32,
+                version: (0, 34),
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_format_1_config() {
+        let params = StorageParameters {
+            segment_size: 1024,
+            use_compression: true,
+            segment_transform: Some("xor: v1".to_owned()),
+            heap_path: Some("/mnt/spinning/heap".to_owned()),
+            crc_variant: CrcVariant::Crc32c,
+            version: (0, 34),
+        };
+
+        let bytes = params.serialize();
+        assert!(bytes.starts_with(b"sled_config_format: 1\n"));
+
+        assert_eq!(StorageParameters::deserialize(&bytes).unwrap(), params);
+    }
+
+    impl quickcheck::Arbitrary for StorageParameters {
+        fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> StorageParameters {
+            use rand::Rng;
+
+            // values may not contain newlines, since each
+            // parameter is serialized on its own line.
+            fn line<G: quickcheck::Gen>(g: &mut G) -> String {
+                let s: String = quickcheck::Arbitrary::arbitrary(g);
+                s.replace(&['\n', '\r'][..], "")
+            }
+
+            StorageParameters {
+                segment_size: 1 << g.gen_range(8, 25),
+                use_compression: g.gen(),
+                segment_transform: if g.gen() { Some(line(g)) } else { None },
+                heap_path: if g.gen() { Some(line(g)) } else { None },
+                crc_variant: if g.gen() {
+                    CrcVariant::Crc32c
+                } else {
+                    CrcVariant::Crc32
+                },
+                version: (g.gen(), g.gen()),
+            }
+        }
+    }
+
+    quickcheck::quickcheck! {
+        #[cfg_attr(miri, ignore)]
+        fn storage_parameters_roundtrip(params: StorageParameters) -> bool {
+            StorageParameters::deserialize(&params.serialize()).unwrap()
+                == params
+        }
+    }
+
+    #[test]
+    fn storage_parameters_roundtrip_edge_cases() {
+        let cases = vec![
+            StorageParameters {
+                segment_size: 256,
+                use_compression: false,
+                segment_transform: None,
+                heap_path: None,
+                crc_variant: CrcVariant::Crc32,
+                version: (0, 0),
+            },
+            StorageParameters {
+                segment_size: 1 << 24,
+                use_compression: true,
+                segment_transform: Some(String::new()),
+                heap_path: Some("heap: with colon".to_owned()),
+                crc_variant: CrcVariant::Crc32c,
+                version: (10, 123),
+            },
+            StorageParameters {
+                segment_size: 4096,
+                use_compression: false,
+                segment_transform: Some("xor: v1".to_owned()),
+                heap_path: None,
+                crc_variant: CrcVariant::Crc32,
+                version: (usize::MAX, usize::MAX),
+            },
+        ];
+
+        for params in cases {
+            assert_eq!(
+                StorageParameters::deserialize(&params.serialize()).unwrap(),
+                params
+            );
+        }
+    }
+
+    #[test]
+    fn storage_parameters_from_config() {
+        let config = Config::new()
+            .temporary(true)
+            .segment_size(256)
+            .use_compression(false);
+
+        let params = StorageParameters::from_config(&config);
+        assert_eq!(params.segment_size, 256);
+        assert_eq!(params.version, config.version);
+        assert_eq!(params.heap_path, None);
+        assert_eq!(
+            StorageParameters::deserialize(&params.serialize()).unwrap(),
+            params
+        );
+    }
+
+    #[test]
+    fn cache_capacity_from_memory_fraction() {
+        let limit = 512 * 1024 * 1024;
+
+        assert_eq!(cache_capacity_from_fraction(0.25, limit), limit / 4);
+        assert_eq!(cache_capacity_from_fraction(1.0, limit), limit);
+        assert_eq!(
+            cache_capacity_from_fraction(0.0, limit),
+            MIN_CACHE_CAPACITY_FROM_FRACTION
+        );
+    }
+
+    #[test]
+    fn cache_capacity_fraction_out_of_range() {
+        let config = Config::new().temporary(true).cache_capacity_fraction(1.5);
+
+        assert!(matches!(config.open(), Err(Error::Unsupported(_))));
+    }
+
+    #[test]
+    fn durability_profiles() {
+        let max_throughput =
+            Config::new().durability_profile(DurabilityProfile::MaxThroughput);
+        assert_eq!(max_throughput.flush_every_ms, Some(2000));
+        assert_eq!(max_throughput.idgen_persist_interval, 10_000_000);
+        assert_eq!(max_throughput.snapshot_after_ops, 10_000_000);
+
+        let balanced =
+            Config::new().durability_profile(DurabilityProfile::Balanced);
+        assert_eq!(balanced.flush_every_ms, Some(500));
+        assert_eq!(balanced.idgen_persist_interval, 1_000_000);
+        assert_eq!(balanced.snapshot_after_ops, 1_000_000);
+
+        let max_durability =
+            Config::new().durability_profile(DurabilityProfile::MaxDurability);
+        assert_eq!(max_durability.flush_every_ms, Some(50));
+        assert_eq!(max_durability.idgen_persist_interval, 1000);
+        assert_eq!(max_durability.snapshot_after_ops, 10_000);
+
+        let overridden = Config::new()
+            .durability_profile(DurabilityProfile::MaxDurability)
+            .flush_every_ms(Some(250));
+        assert_eq!(overridden.flush_every_ms, Some(250));
+        assert_eq!(overridden.idgen_persist_interval, 1000);
+    }
+
+    #[test]
+    fn compression_mismatch() {
+        if cfg!(feature = "no_zstd") {
+            return;
+        }
+
+        for db_uses_compression in [true, false] {
+            let path = std::env::temp_dir().join(format!(
+                "sled_compression_mismatch_{}",
+                db_uses_compression
+            ));
+            let _ = fs::remove_dir_all(&path);
+
+            let config =
+                Config::new().path(&path).use_compression(db_uses_compression);
+            drop(config.open().unwrap());
+
+            let res = Config::new()
+                .path(&path)
+                .use_compression(!db_uses_compression)
+                .open();
+            assert_eq!(
+                res.unwrap_err(),
+                Error::CompressionMismatch { db_uses_compression }
+            );
+
+            // auto-correcting the builder allows it to open
+            drop(config.open().unwrap());
+
+            fs::remove_dir_all(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn temporary_path_collision() {
+        let collide = |config: &Config| {
+            let stale = config.get_path();
+            fs::create_dir_all(stale.join("heap")).unwrap();
+            fs::write(stale.join("db"), b"stale").unwrap();
+            stale
+        };
+
+        let config = Config::new().temporary(true);
+        let stale = collide(&config);
+
+        let db = config.open().unwrap();
+        assert_ne!(config.get_path(), stale);
+        db.insert(b"k", b"v").unwrap();
+        drop(db);
+
+        // reopening the same config reuses the claimed path
+        let db = config.open().unwrap();
+        assert_eq!(db.get(b"k").unwrap().unwrap(), b"v");
+        drop(db);
+
+        assert_eq!(fs::read(stale.join("db")).unwrap(), b"stale");
+        fs::remove_dir_all(&stale).unwrap();
+
+        let config =
+            Config::new().temporary(true).temporary_collision_retries(0);
+        let stale = collide(&config);
+        assert!(config.open().is_err());
+        assert_eq!(fs::read(stale.join("db")).unwrap(), b"stale");
+    }
+
+    #[test]
+    fn compression_factor_bounds() {
+        assert_eq!(CompressionFactor::new(0), None);
+        assert_eq!(CompressionFactor::new(1), Some(CompressionFactor::FASTEST));
+        assert_eq!(CompressionFactor::new(22), Some(CompressionFactor::MAX));
+        assert_eq!(CompressionFactor::new(23), None);
+
+        assert!(!CompressionFactor::new(19).unwrap().is_ultra());
+        assert!(CompressionFactor::ULTRA.is_ultra());
+
+        let config = Config::new().compression_level(CompressionFactor::ULTRA);
+        assert_eq!(config.compression_factor, 20);
+        assert_eq!(
+            Config::new().compression_factor,
+            i32::from(CompressionFactor::default().get())
+        );
+    }
+
+    #[test]
+    fn gen_temp_path_is_unique() {
+        let paths: std::collections::HashSet<PathBuf> =
+            (0..10_000).map(|_| Config::gen_temp_path()).collect();
+        assert_eq!(paths.len(), 10_000);
+    }
+
+    #[test]
+    fn segment_size_preset() {
+        let config = Config::new()
+            .temporary(true)
+            .segment_size_preset(SegmentSizePreset::Segment1M);
+        let db = config.open().unwrap();
+        assert_eq!(
+            config.read_config().unwrap().unwrap().segment_size,
+            1048576
+        );
+        drop(db);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dangling_heap_symlink() {
+        let path = std::env::temp_dir().join("test_dangling_heap_symlink");
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        std::os::unix::fs::symlink("missing_heap", path.join("heap")).unwrap();
+
+        let config = || Config::new().path(&path);
+
+        assert_eq!(
+            config().open().unwrap_err(),
+            Error::Unsupported("heap directory is a broken symlink")
+        );
+
+        let db = config().follow_heap_symlink(true).open().unwrap();
+        db.insert(b"k", vec![1; 64 * 1024]).unwrap();
+        db.flush().unwrap();
+        drop(db);
+        assert!(path.join("missing_heap").is_dir());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn conflicting_options() {
+        // a compression_factor without use_compression is ignored
+        assert_eq!(
+            Config::new()
+                .temporary(true)
+                .compression_factor(9)
+                .check_consistency(),
+            Ok(())
+        );
+
+        let path = std::env::temp_dir().join("test_conflicting_options");
+        let _ = fs::remove_dir_all(&path);
+        drop(Config::new().path(&path).open().unwrap());
+
+        let config = Config::new().path(&path).create_new(true);
+        assert!(config.check_consistency().is_ok());
+        assert_eq!(
+            config.clone().temporary(true).check_consistency(),
+            Err(Error::Unsupported(
+                "temporary and create_new are both set, but the path \
+                 already contains a database that create_new would \
+                 refuse to open and temporary would delete"
+            ))
+        );
+
+        // dropping the temporary config removed the directory
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn merge_partial_config() {
+        let defaults = Config::new();
+        let merged = Config::new().merge(PartialConfig {
+            cache_capacity: Some(1234),
+            ..PartialConfig::default()
+        });
+
+        assert_eq!(merged.cache_capacity, 1234);
+        assert_eq!(merged.segment_size, defaults.segment_size);
+        assert_eq!(merged.flush_every_ms, defaults.flush_every_ms);
+        assert_eq!(merged.path, defaults.path);
+        assert_eq!(merged.use_compression, defaults.use_compression);
+        assert_eq!(merged.directory_fsync, defaults.directory_fsync);
+
+        // later layers override earlier ones
+        let merged = merged.merge(PartialConfig {
+            cache_capacity: Some(5678),
+            flush_every_ms: Some(None),
+            ..PartialConfig::default()
+        });
+        assert_eq!(merged.cache_capacity, 5678);
+        assert_eq!(merged.flush_every_ms, None);
+
+        // settings that can't be written with serde merge too
+        let merged = merged.merge(PartialConfig {
+            heap_path: Some(PathBuf::from("some/heap")),
+            version_policy: Some(VersionPolicy::AutoMinor),
+            ..PartialConfig::default()
+        });
+        assert_eq!(merged.heap_path, Some(PathBuf::from("some/heap")));
+        assert!(matches!(merged.version_policy, VersionPolicy::AutoMinor));
+    }
+
+    #[test]
+    fn partial_config_covers_every_setting() {
+        // the names of the top-level fields in a pretty Debug
+        fn field_names(debug: &str) -> Vec<&str> {
+            debug
+                .lines()
+                .filter_map(|line| line.strip_prefix("    ")?.split_once(':'))
+                .map(|(name, _)| name)
+                .filter(|name| {
+                    name.chars().all(|c| c == '_' || c.is_ascii_alphanumeric())
+                })
+                .collect()
+        }
+
+        // hooks and state that are not settings
+        let internal = [
+            "version",
+            "tmp_path",
+            "global_error",
+            "event_log",
+            "file_options",
+            "segment_transform",
+            "on_global_error",
+            "default_merge_operator",
+            "on_recovery_progress",
+        ];
+
+        let inner = format!("{:#?}", Inner::default());
+        let partial = format!("{:#?}", PartialConfig::default());
+        let covered = field_names(&partial);
+        assert!(covered.contains(&"path"), "{}", partial);
+
+        let missing: Vec<&str> = field_names(&inner)
+            .into_iter()
+            .filter(|name| !internal.contains(name) && !covered.contains(name))
+            .collect();
+        assert!(missing.is_empty(), "PartialConfig lacks {:?}", missing);
+    }
+
+    #[test]
+    #[cfg(feature = "toml")]
+    fn toml_round_trip() {
+        let config = Config::new()
+            .path("some/db")
+            .cache_capacity(1234)
+            .mode(Mode::HighThroughput)
+            .compression_factor(7)
+            .segment_size(1 << 20)
+            .flush_every_ms(None)
+            .flush_thread_name("flusher".to_string())
+            .flush_thread_stack_size(Some(1 << 21))
+            .snapshot_after_ops(42)
+            .directory_fsync(DirFsyncPolicy::BestEffort)
+            .nested_check_depth(3)
+            .crc_variant(CrcVariant::Crc32c)
+            .io_alignment(4096)
+            .zero_on_reuse(true)
+            .create_parents(false)
+            .max_value_size(Some(1 << 16))
+            .max_unflushed_bytes(Some(1 << 22))
+            .max_transaction_retries(Some(9))
+            .compaction_fill_threshold(0.25)
+            .use_compression(true)
+            .temporary_collision_retries(4)
+            .create_new(true)
+            .idgen_persist_interval(77)
+            .fast_clean_restart(true)
+            .allow_nested(true)
+            .follow_heap_symlink(true)
+            .enforce_min_cache(true)
+            .allow_risky_filesystem(true)
+            .max_background_threads(Some(3))
+            .snapshot_after_bytes(Some(1 << 20))
+            .stale_snapshot_age(Duration::from_millis(1500))
+            .max_segment_reuses(Some(4))
+            .lock_wait(Some(Duration::from_millis(250)))
+            .open_timeout(Some(Duration::from_secs(30)))
+            .max_recovery_duration(Some(Duration::from_millis(7)))
+            .recovery_read_buffer_size(1 << 16)
+            .keep_temporary_on_error(true)
+            .temporary_delete_retries(5)
+            .async_temp_cleanup(true)
+            .share_process_local(true)
+            .max_unflushed_wait(Some(Duration::from_millis(90)))
+            .sync_mode(SyncMode::SyncData)
+            .eviction_policy(EvictionPolicy::Lfu)
+            .access_pattern(AccessPattern::Sequential)
+            .verify_only(true)
+            .heap_path(Some(PathBuf::from("some/heap")))
+            .recover_to_snapshot(Some(1234))
+            .skip_config_file(true);
+
+        let text = config.to_toml();
+        assert!(text.contains("flush_every_ms = \"none\""), "{}", text);
+        assert!(text.contains("stale_snapshot_age = 1500"), "{}", text);
+        assert!(text.contains("open_timeout = 30000"), "{}", text);
+
+        let parsed = Config::from_toml(&text).unwrap();
+        assert_eq!(parsed.path, PathBuf::from("some/db"));
+        assert_eq!(parsed.cache_capacity, 1234);
+        assert!(matches!(parsed.mode, Mode::HighThroughput));
+        assert_eq!(parsed.compression_factor, 7);
+        assert_eq!(parsed.segment_size, 1 << 20);
+        assert_eq!(parsed.flush_every_ms, None);
+        assert_eq!(parsed.flush_thread_name, "flusher");
+        assert_eq!(parsed.flush_thread_stack_size, Some(1 << 21));
+        assert_eq!(parsed.snapshot_after_ops, 42);
+        assert_eq!(parsed.directory_fsync, DirFsyncPolicy::BestEffort);
+        assert_eq!(parsed.nested_check_depth, 3);
+        assert_eq!(parsed.crc_variant, CrcVariant::Crc32c);
+        assert_eq!(parsed.io_alignment, 4096);
+        assert!(parsed.zero_on_reuse);
+        assert!(!parsed.create_parents);
+        assert_eq!(parsed.max_value_size, Some(1 << 16));
+        assert_eq!(parsed.max_unflushed_bytes, Some(1 << 22));
+        assert_eq!(parsed.max_transaction_retries, Some(9));
+        assert_eq!(parsed.compaction_fill_threshold, Some(0.25));
+        assert!(parsed.use_compression);
+        assert_eq!(parsed.temporary_collision_retries, 4);
+        assert!(parsed.create_new);
+        assert_eq!(parsed.idgen_persist_interval, 77);
+        assert!(parsed.fast_clean_restart);
+        assert!(parsed.allow_nested);
+        assert!(parsed.follow_heap_symlink);
+        assert!(parsed.enforce_min_cache);
+        assert!(parsed.allow_risky_filesystem);
+        assert_eq!(parsed.max_background_threads, Some(3));
+        assert_eq!(parsed.snapshot_after_bytes, Some(1 << 20));
+        assert_eq!(parsed.stale_snapshot_age, Duration::from_millis(1500));
+        assert_eq!(parsed.max_segment_reuses, Some(4));
+        assert_eq!(parsed.lock_wait, Some(Duration::from_millis(250)));
+        assert_eq!(parsed.open_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(
+            parsed.max_recovery_duration,
+            Some(Duration::from_millis(7))
+        );
+        assert_eq!(parsed.recovery_read_buffer_size, 1 << 16);
+        assert!(parsed.keep_temporary_on_error);
+        assert_eq!(parsed.temporary_delete_retries, 5);
+        assert!(parsed.async_temp_cleanup);
+        assert!(parsed.share_process_local);
+        assert_eq!(parsed.max_unflushed_wait, Some(Duration::from_millis(90)));
+        assert_eq!(parsed.sync_mode, SyncMode::SyncData);
+        assert_eq!(parsed.eviction_policy, EvictionPolicy::Lfu);
+        assert_eq!(parsed.access_pattern, AccessPattern::Sequential);
+        assert!(parsed.verify_only);
+        assert_eq!(parsed.heap_path, Some(PathBuf::from("some/heap")));
+        assert_eq!(parsed.recover_to_snapshot, Some(1234));
+        assert!(parsed.skip_config_file);
+        assert_eq!(parsed.to_toml(), text);
+
+        // limits that are off are written as "none"
+        let text = Config::new().to_toml();
+        assert!(text.contains("max_value_size = \"none\""), "{}", text);
+        let parsed = Config::from_toml(&text).unwrap();
+        assert_eq!(parsed.max_value_size, None);
+        assert_eq!(parsed.max_transaction_retries, None);
+        assert_eq!(parsed.lock_wait, None);
+        assert_eq!(parsed.heap_path, None);
+
+        // errors name the offending key
+        let err = Config::from_toml("cache_capacity = \"big\"").unwrap_err();
+        assert!(err.to_string().contains("cache_capacity"), "{}", err);
+        let err = Config::from_toml("cache_capacty = 1").unwrap_err();
+        assert!(err.to_string().contains("cache_capacty"), "{}", err);
+        let err = Config::from_toml("flush_every_ms = \"never\"").unwrap_err();
+        assert!(err.to_string().contains("flush_every_ms"), "{}", err);
+    }
+
+    #[test]
+    fn apply_env() {
+        std::env::set_var("SLED_TEST_APPLY_ENV_CACHE_CAPACITY", "4096");
+        std::env::set_var("SLED_TEST_APPLY_ENV_MODE", "high_throughput");
+        std::env::set_var("SLED_TEST_APPLY_ENV_FLUSH_EVERY_MS", "none");
+        std::env::set_var("SLED_TEST_APPLY_ENV_SEGMENT_SIZE", "big");
+        std::env::set_var("SLED_TEST_APPLY_ENV_LOCK_WAIT", "250");
+        std::env::set_var("SLED_TEST_APPLY_ENV_MAX_VALUE_SIZE", "4096");
+        std::env::set_var(
+            "SLED_TEST_APPLY_ENV_EVICTION_POLICY",
+            "segmented_lru",
+        );
+        std::env::set_var("SLED_TEST_APPLY_ENV_CRC_VARIANT", "crc32c");
+        std::env::set_var("SLED_TEST_APPLY_ENV_HEAP_PATH", "some/heap");
+
+        let config = Config::new()
+            .cache_capacity(1024)
+            .segment_size(256)
+            .apply_env("SLED_TEST_APPLY_ENV");
+
+        assert_eq!(config.cache_capacity, 4096);
+        assert!(matches!(config.mode, Mode::HighThroughput));
+        assert_eq!(config.flush_every_ms, None);
+        assert_eq!(config.lock_wait, Some(Duration::from_millis(250)));
+        assert_eq!(config.max_value_size, Some(4096));
+        assert_eq!(config.eviction_policy, EvictionPolicy::SegmentedLru);
+        assert_eq!(config.crc_variant, CrcVariant::Crc32c);
+        assert_eq!(config.heap_path, Some(PathBuf::from("some/heap")));
+        // unparseable values leave the current value alone
+        assert_eq!(config.segment_size, 256);
+        assert_eq!(config.use_compression, Config::new().use_compression);
+    }
+
+    #[test]
+    fn retry_on_eintr_completes() {
+        struct Flaky {
+            interruptions: usize,
+            data: Vec<u8>,
+        }
+
+        impl Flaky {
+            fn interrupt(&mut self) -> io::Result<()> {
+                if self.interruptions > 0 {
+                    self.interruptions -= 1;
+                    Err(io::Error::from(ErrorKind::Interrupted))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+
+        impl Write for Flaky {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.interrupt()?;
+                self.data.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                self.interrupt()
+            }
+        }
+
+        impl Read for Flaky {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.interrupt()?;
+                let n = std::cmp::min(buf.len(), self.data.len());
+                buf[..n].copy_from_slice(&self.data[..n]);
+                let _ = self.data.drain(..n);
+                Ok(n)
+            }
+        }
+
+        let mut flaky = Flaky { interruptions: 3, data: vec![] };
+        assert_eq!(retry_on_eintr(|| flaky.write(b"sled")).unwrap(), 4);
+        assert_eq!(flaky.interruptions, 0);
+
+        flaky.interruptions = 2;
+        retry_on_eintr(|| flaky.flush()).unwrap();
+
+        flaky.interruptions = 3;
+        let mut buf = [0; 4];
+        assert_eq!(retry_on_eintr(|| flaky.read(&mut buf)).unwrap(), 4);
+        assert_eq!(&buf, b"sled");
+
+        let mut failing = Flaky { interruptions: 0, data: vec![] };
+        let res = retry_on_eintr(|| -> io::Result<()> {
+            failing.interrupt()?;
+            Err(io::Error::from(ErrorKind::Other))
+        });
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::Other);
+    }
+
+    #[test]
+    fn open_reporting() {
+        let path = std::env::temp_dir().join("test_open_reporting");
+        let _ = fs::remove_dir_all(&path);
+
+        let config = Config::new().path(&path);
+
+        let (db, outcome) = config.open_reporting().unwrap();
+        assert_eq!(outcome, OpenOutcome::Created);
+        drop(db);
+
+        let (db, outcome) = config.open_reporting().unwrap();
+        assert_eq!(outcome, OpenOutcome::Opened);
+        drop(db);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn config_diff() {
+        let path = std::env::temp_dir().join("test_config_diff");
+        let _ = fs::remove_dir_all(&path);
+
+        let config = Config::new().path(&path).segment_size(256);
+        assert!(config.config_diff().unwrap().is_empty());
+
+        drop(config.open().unwrap());
+        assert!(config.config_diff().unwrap().is_empty());
+
+        let diffs = Config::new()
+            .path(&path)
+            .segment_size(512)
+            .use_compression(true)
+            .config_diff()
+            .unwrap();
+
+        assert_eq!(
+            diffs,
+            vec![
+                ParameterDiff {
+                    field: "segment_size",
+                    stored: "256".to_owned(),
+                    requested: "512".to_owned(),
+                },
+                ParameterDiff {
+                    field: "use_compression",
+                    stored: "false".to_owned(),
+                    requested: "true".to_owned(),
+                },
+            ]
+        );
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(all(
+        not(miri),
+        any(windows, target_os = "linux", target_os = "macos")
+    ))]
+    fn lock_wait() {
+        use fs2::FileExt;
+
+        let path = std::env::temp_dir().join("test_lock_wait");
+        let _ = fs::remove_dir_all(&path);
+
+        let config = Config::new().path(&path);
+        drop(config.open().unwrap());
+
+        let held = File::open(config.db_path()).unwrap();
+        held.lock_exclusive().unwrap();
+
+        let releaser = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            held.unlock().unwrap();
+        });
+
+        let db = config.lock_wait(Some(Duration::from_secs(1))).open().unwrap();
+        releaser.join().unwrap();
+        drop(db);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(not(feature = "no_zstd"))]
+    fn from_existing() {
+        let path = std::env::temp_dir().join("test_from_existing");
+        let _ = fs::remove_dir_all(&path);
+
+        // no database yet
+        let config = Config::from_existing(&path).unwrap();
+        assert_eq!(config.get_path(), path);
+        assert!(!config.use_compression);
+
+        let db = Config::new()
+            .path(&path)
+            .use_compression(true)
+            .segment_size(4096)
+            .open()
+            .unwrap();
+        db.insert(b"k", b"v").unwrap();
+        drop(db);
+
+        let config = Config::from_existing(&path).unwrap();
+        assert!(config.use_compression);
+        assert_eq!(config.segment_size, 4096);
+
+        let db = config.open().unwrap();
+        assert_eq!(db.get(b"k").unwrap().unwrap(), b"v");
+        drop(db);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn crc_variant() {
+        let path = std::env::temp_dir().join("test_crc_variant");
+        let _ = fs::remove_dir_all(&path);
+
+        let config = Config::new()
+            .path(&path)
+            .segment_size(4096)
+            .crc_variant(CrcVariant::Crc32c);
+        let db = config.open().unwrap();
+        for i in 0..64_u32 {
+            db.insert(i.to_be_bytes(), vec![0; 64]).unwrap();
+        }
+        db.insert(b"large", vec![1; 64 * 1024]).unwrap();
+        db.checkpoint().unwrap();
+        drop(db);
+        assert_eq!(
+            config.read_config().unwrap().unwrap().crc_variant,
+            CrcVariant::Crc32c
+        );
+
+        // segment headers only validate with the chosen variant
+        let file = fs::File::open(path.join("db")).unwrap();
+        let header = |crc_variant| {
+            crate::pagecache::read_segment_header(&file, 0, crc_variant)
+                .unwrap()
+        };
+        assert!(header(CrcVariant::Crc32c).ok);
+        assert!(!header(CrcVariant::Crc32).ok);
+        drop(file);
+
+        let db = config.open().unwrap();
+        assert!(db.was_recovered());
+        for i in 0..64_u32 {
+            assert_eq!(db.get(i.to_be_bytes()).unwrap().unwrap(), vec![0; 64]);
+        }
+        assert_eq!(db.get(b"large").unwrap().unwrap(), vec![1; 64 * 1024]);
+        assert!(db.verify_integrity().unwrap().crc_failures.is_empty());
+        drop(db);
+
+        let mismatched = config.clone().crc_variant(CrcVariant::Crc32).open();
+        assert!(matches!(mismatched, Err(Error::Unsupported(_))));
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn lock_contended() {
+        let path = std::env::temp_dir().join("test_lock_contended");
+        let _ = fs::remove_dir_all(&path);
+
+        let db = Config::new().path(&path).open().unwrap();
+
+        let contender = Config::new()
+            .path(&path)
+            .lock_wait(Some(Duration::from_millis(10)));
+        let res = std::thread::spawn(move || contender.open().map(drop))
+            .join()
+            .unwrap();
+        assert_eq!(res, Err(Error::LockContended { path: path.clone() }));
+
+        drop(db);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn torn_config_is_corrupt() {
+        let path = std::env::temp_dir().join("test_torn_config_is_corrupt");
+        let _ = fs::remove_dir_all(&path);
+
+        let config = Config::new().path(&path);
+        drop(config.open().unwrap());
+
+        let conf = fs::OpenOptions::new()
+            .write(true)
+            .open(config.config_path())
+            .unwrap();
+        let len = conf.metadata().unwrap().len();
+        conf.set_len(len - 2).unwrap();
+        drop(conf);
+
+        assert_eq!(config.read_config(), Err(Error::corruption(None)));
+
+        // files written before the header existed are still read
+        let legacy = StorageParameters::from_config(&config).serialize();
+        let mut bytes = legacy.clone();
+        bytes.extend_from_slice(&u32_to_arr(crc32(&legacy)));
+        fs::write(config.config_path(), bytes).unwrap();
+
+        assert_eq!(
+            config.read_config().unwrap(),
+            Some(StorageParameters::from_config(&config))
+        );
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn open_warnings() {
+        let path = std::env::temp_dir().join("test_open_warnings");
+        let _ = fs::remove_dir_all(&path);
+
+        // the temporary directory may be a tmpfs
+        let config = Config::new().path(&path).allow_risky_filesystem(true);
+        let db = config.open().unwrap();
+        assert!(db.open_warnings().is_empty());
+        drop(db);
+
+        let mut bytes = fs::read(config.config_path()).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        let expected = arr_to_u32(&bytes[last - 3..]);
+        let actual = crc32(&bytes[..last - 3]);
+        fs::write(config.config_path(), bytes).unwrap();
+
+        let db = config.open().unwrap();
+        assert_eq!(
+            db.open_warnings(),
+            &[OpenWarning::ConfigCrcMismatch { expected, actual }]
+        );
+        drop(db);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn small_cache() {
+        let config = |cache_capacity, enforce_min_cache| {
+            Config::new()
+                .temporary(true)
+                .cache_capacity(cache_capacity)
+                .segment_size(512 * 1024)
+                .enforce_min_cache(enforce_min_cache)
+        };
+
+        let db = config(1000, false).open().unwrap();
+        assert!(db.open_warnings().contains(&OpenWarning::SmallCache {
+            cache_capacity: 1000,
+            minimum: 1024 * 1024,
+        }));
+        drop(db);
+
+        assert!(matches!(
+            config(1000, true).open(),
+            Err(Error::Unsupported(_))
+        ));
+
+        let db = config(1024 * 1024, true).open().unwrap();
+        let warned = db
+            .open_warnings()
+            .iter()
+            .any(|w| matches!(w, OpenWarning::SmallCache { .. }));
+        assert!(!warned);
+    }
+
+    #[test]
+    fn cache_capacity_address_space() {
+        let mut config = Config::new().cache_capacity(usize::MAX);
+        config.limit_cache_to_address_space(64).unwrap();
+        assert_eq!(config.cache_capacity, usize::MAX);
+
+        config.limit_cache_to_address_space(32).unwrap();
+        assert_eq!(config.cache_capacity, 1 << 30);
+
+        let mut config = config.cache_capacity(1 << 30);
+        config.limit_cache_to_address_space(32).unwrap();
+        assert_eq!(config.cache_capacity, 1 << 30);
+
+        let mut config =
+            config.cache_capacity(usize::MAX).enforce_min_cache(true);
+        assert!(matches!(
+            config.limit_cache_to_address_space(32),
+            Err(Error::Unsupported(_))
+        ));
+
+        // the same limit applies to Db::set_cache_capacity
+        assert_eq!(
+            limit_cache_to_address_space(usize::MAX, 32, false),
+            Ok(1 << 30)
+        );
+        assert_eq!(
+            limit_cache_to_address_space(1 << 20, 32, true),
+            Ok(1 << 20)
+        );
+        assert!(matches!(
+            limit_cache_to_address_space(usize::MAX, 32, true),
+            Err(Error::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    #[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+    #[cfg_attr(miri, ignore)]
+    fn diagnose_lock() {
+        let path = std::env::temp_dir().join("test_diagnose_lock");
+        let _ = fs::remove_dir_all(&path);
+
+        assert_eq!(
+            super::diagnose_lock(&path),
+            LockDiagnosis { locked: false, marker_exists: false }
+        );
+
+        let db = Config::new().path(&path).open().unwrap();
+        db.insert(b"k", b"v").unwrap();
+        assert_eq!(
+            super::diagnose_lock(&path),
+            LockDiagnosis { locked: true, marker_exists: true }
+        );
+        assert_eq!(
+            clear_stale_marker(&path).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+        assert!(path.join(LOCK_MARKER).exists());
+        drop(db);
+
+        // the marker outlives the lock
+        assert_eq!(
+            super::diagnose_lock(&path),
+            LockDiagnosis { locked: false, marker_exists: true }
+        );
+        clear_stale_marker(&path).unwrap();
+        assert_eq!(
+            super::diagnose_lock(&path),
+            LockDiagnosis { locked: false, marker_exists: false }
+        );
+        clear_stale_marker(&path).unwrap();
+
+        let db = Config::new().path(&path).open().unwrap();
+        assert_eq!(db.get(b"k").unwrap().unwrap(), b"v");
+        drop(db);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[cfg(all(not(miri), target_os = "linux"))]
+    #[test]
+    fn risky_filesystem() {
+        use crate::sys_limits::MOCK_FS_TYPE;
+
+        const NFS_SUPER_MAGIC: u64 = 0x6969;
+        const TMPFS_MAGIC: u64 = 0x0102_1994;
+
+        let path = std::env::temp_dir().join("test_risky_filesystem");
+        let _ = fs::remove_dir_all(&path);
+        let config = Config::new().path(&path);
+
+        MOCK_FS_TYPE.with(|mock| mock.set(Some(NFS_SUPER_MAGIC)));
+        let db = config.open().unwrap();
+        assert_eq!(
+            db.open_warnings(),
+            &[OpenWarning::RiskyFilesystem { fs_type: "nfs" }]
+        );
+        drop(db);
+
+        let db = config.clone().allow_risky_filesystem(true).open().unwrap();
+        assert!(db.open_warnings().is_empty());
+        drop(db);
+
+        // a tmpfs is only a problem for data that should outlive it
+        MOCK_FS_TYPE.with(|mock| mock.set(Some(TMPFS_MAGIC)));
+        let db = config.open().unwrap();
+        assert_eq!(
+            db.open_warnings(),
+            &[OpenWarning::RiskyFilesystem { fs_type: "tmpfs" }]
+        );
+        drop(db);
+
+        let db = Config::new().temporary(true).open().unwrap();
+        assert!(db.open_warnings().is_empty());
+        drop(db);
+
+        MOCK_FS_TYPE.with(|mock| mock.set(None));
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn verify_only() {
+        let path = std::env::temp_dir().join("test_verify_only");
+        let _ = fs::remove_dir_all(&path);
+
+        // the temporary directory may be a tmpfs
+        let config = Config::new()
+            .path(&path)
+            .segment_size(4096)
+            .allow_risky_filesystem(true);
+        let db = config.open().unwrap();
+        for i in 0..128_u8 {
+            db.insert(b"k", vec![i; 64]).unwrap();
+        }
+        db.flush().unwrap();
+        drop(db);
+
+        let dir_contents = || {
+            let mut contents = vec![];
+            for entry in fs::read_dir(&path).unwrap() {
+                let entry = entry.unwrap();
+                if entry.file_type().unwrap().is_file() {
+                    contents
+                        .push((entry.path(), fs::read(entry.path()).unwrap()));
+                }
+            }
+            contents.sort();
+            contents
+        };
+
+        // the writer's background threads may hold the database
+        // for a moment after it is dropped, and opening waits
+        // for them to finish
+        drop(config.clone().verify_only(true).open().unwrap());
+
+        let before = dir_contents();
+        let db = config.clone().verify_only(true).open().unwrap();
+        assert!(db.open_warnings().is_empty());
+        assert_eq!(db.get(b"k").unwrap().unwrap(), vec![127; 64]);
+        assert!(matches!(db.insert(b"k", b"v"), Err(Error::Unsupported(_))));
+        drop(db);
+        assert_eq!(dir_contents(), before);
+
+        // corrupt a value in a segment that the last snapshot
+        // already covers, in a tree that is not read on open, so
+        // that only the scan of every segment can find it
+        let db = config.open().unwrap();
+        db.open_tree(b"marked")
+            .unwrap()
+            .insert(b"k", vec![0xA5_u8; 64])
+            .unwrap();
+        for i in 0..64_u8 {
+            db.insert(b"k", vec![i; 64]).unwrap();
+        }
+        db.checkpoint().unwrap();
+        drop(db);
+        drop(config.clone().verify_only(true).open().unwrap());
+
+        let mut bytes = fs::read(path.join("db")).unwrap();
+        let position = bytes
+            .windows(64)
+            .position(|window| window.iter().all(|b| *b == 0xA5))
+            .unwrap();
+        bytes[position + 32] ^= 0xFF;
+        fs::write(path.join("db"), bytes).unwrap();
+
+        let before = dir_contents();
+        let db = config.clone().verify_only(true).open().unwrap();
+        assert_eq!(
+            db.open_warnings(),
+            &[OpenWarning::CorruptSegment {
+                offset: position as u64 / 4096 * 4096
+            }]
+        );
+        drop(db);
+        assert_eq!(dir_contents(), before);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn skip_config_file() {
+        let path = std::env::temp_dir().join("test_skip_config_file");
+        let _ = fs::remove_dir_all(&path);
+
+        let config = Config::new()
+            .path(&path)
+            .segment_size(4096)
+            .allow_risky_filesystem(true);
+        let db = config.open().unwrap();
+        db.insert(b"k", b"v").unwrap();
+        db.flush().unwrap();
+        drop(db);
+
+        fs::remove_file(config.config_path()).unwrap();
+
+        let db = config
+            .clone()
+            .verify_only(true)
+            .skip_config_file(true)
+            .open()
+            .unwrap();
+        assert_eq!(db.get(b"k").unwrap().unwrap(), b"v");
+        drop(db);
+        assert!(!config.config_path().exists());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn storage_layout() {
+        let config = Config::new();
+        let layout = config.storage_layout();
+        assert_eq!(layout.segment_size, config.segment_size);
+        assert_eq!(layout.alignment, config.io_alignment);
+        assert_eq!(layout.segment_header_bytes, SEG_HEADER_LEN);
+        assert_eq!(layout.crc_bytes, 4);
+        assert_eq!(
+            layout.usable_bytes_per_segment,
+            layout.segment_size - layout.segment_header_bytes
+        );
+    }
+
+    #[test]
+    fn estimated_memory_footprint() {
+        let config = Config::new()
+            .cache_capacity(64 * 1024 * 1024)
+            .segment_size(1 << 20);
+        let estimate = config.estimated_memory_footprint();
+        assert_eq!(estimate.cache_bytes, 64 * 1024 * 1024);
+        assert_eq!(
+            estimate.io_buffer_bytes,
+            config.segment_size * estimate.io_buffer_count
+        );
+        assert_eq!(
+            estimate.total_bytes,
+            estimate.cache_bytes
+                + estimate.io_buffer_bytes
+                + estimate.per_tree_overhead_bytes
+        );
+    }
+
+    #[test]
+    fn open_staged() {
+        let path = std::env::temp_dir().join("test_open_staged");
+        let _ = fs::remove_dir_all(&path);
+
+        let staged = Config::new().open_staged(&path).unwrap();
+        staged.insert(b"k", b"v").unwrap();
+        let staging_path = staged.staging_path().to_path_buf();
+        assert!(!path.exists());
+
+        let db = staged.promote().unwrap();
+        assert!(!staging_path.exists());
+        assert_eq!(db.get(b"k").unwrap().unwrap(), b"v");
+        drop(db);
+
+        let db = Config::new().path(&path).open().unwrap();
+        assert_eq!(db.get(b"k").unwrap().unwrap(), b"v");
+        drop(db);
+
+        // promoting over an existing database must be explicit
+        let staged = Config::new().open_staged(&path).unwrap();
+        staged.insert(b"k", b"v2").unwrap();
+        let staging_path = staged.staging_path().to_path_buf();
+        assert!(staged.promote().is_err());
+        assert!(!staging_path.exists());
+        assert!(path.exists());
+
+        let staged = Config::new().open_staged(&path).unwrap();
+        staged.insert(b"k", b"v2").unwrap();
+        let db = staged.promote_replacing().unwrap();
+        assert_eq!(db.get(b"k").unwrap().unwrap(), b"v2");
+        drop(db);
+
+        // unpromoted staging directories are cleaned up
+        let staged = Config::new().open_staged(&path).unwrap();
+        let staging_path = staged.staging_path().to_path_buf();
+        drop(staged);
+        assert!(!staging_path.exists());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn keep_temporary_on_error() {
+        let path = std::env::temp_dir().join("test_keep_temporary_on_error");
+        let _ = fs::remove_dir_all(&path);
+
+        let config = || {
+            Config::new()
+                .path(&path)
+                .temporary(true)
+                .keep_temporary_on_error(true)
+        };
+
+        drop(config().open().unwrap());
+        assert!(!path.exists());
+
+        let db = config().open().unwrap();
+        db.context
+            .pagecache
+            .config
+            .set_global_error(Error::Unsupported("injected by the test"));
+        drop(db);
+        assert!(path.exists());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn create_parents() {
+        let root = std::env::temp_dir().join("test_create_parents");
+        let _ = fs::remove_dir_all(&root);
+        let path = root.join("missing").join("db");
+
+        let config = |create_parents| {
+            Config::new()
+                .path(&path)
+                .create_parents(create_parents)
+                .allow_risky_filesystem(true)
+        };
+
+        assert!(matches!(
+            config(false).open(),
+            Err(Error::Io(
+                ErrorKind::NotFound,
+                "parent directory does not exist"
+            ))
+        ));
+        assert!(!root.exists());
+
+        drop(config(true).open().unwrap());
+        assert!(path.join("heap").is_dir());
+
+        // the final directory is still created when its parent exists
+        fs::remove_dir_all(&path).unwrap();
+        drop(config(false).open().unwrap());
+        assert!(path.join("heap").is_dir());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn temporary_delete_retries() {
+        let path = std::env::temp_dir().join("test_temporary_delete_retries");
+        let _ = fs::remove_dir_all(&path);
+        let _ = fs::remove_file(&path);
+
+        // a file where the directory is expected makes removal fail
+        // until another thread swaps it for a directory.
+        fs::write(&path, b"").unwrap();
+        assert!(remove_dir_with_retries(&path, 0).is_err());
+
+        let swapper = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(20));
+                fs::remove_file(&path).unwrap();
+                fs::create_dir(&path).unwrap();
+            })
+        };
+        remove_dir_with_retries(&path, 10).unwrap();
+        swapper.join().unwrap();
+        assert!(!path.exists());
+
+        // removing a path that is already gone succeeds
+        remove_dir_with_retries(&path, 0).unwrap();
+    }
+
+    #[test]
+    fn async_temp_cleanup() {
+        // the database gets a parent of its own, so that only its
+        // renamed directory can show up next to it
+        let parent = std::env::temp_dir().join("test_async_temp_cleanup");
+        let _ = fs::remove_dir_all(&parent);
+        fs::create_dir_all(&parent).unwrap();
+        let path = parent.join("db");
+
+        let db = Config::new()
+            .path(&path)
+            .temporary(true)
+            .async_temp_cleanup(true)
+            .open()
+            .unwrap();
+        db.insert(b"k", b"v").unwrap();
+        db.flush().unwrap();
+
+        // drop returns while the removal is paused, leaving
+        // only the renamed directory behind
+        let pause = PAUSE_TEMP_CLEANUP.lock();
+        drop(db);
+        assert!(!path.exists());
+        let renamed: Vec<PathBuf> = fs::read_dir(&parent)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(renamed.len(), 1, "{:?}", renamed);
+        assert!(
+            renamed[0].to_string_lossy().ends_with(".deleting"),
+            "{:?}",
+            renamed
+        );
+        assert!(renamed[0].join("conf").exists());
+
+        // and the directory is removed once it continues
+        drop(pause);
+        let start = Instant::now();
+        while renamed[0].exists() {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "the renamed directory was never removed"
+            );
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        fs::remove_dir(&parent).unwrap();
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn temporary_delete_retries_transient_handle() {
+        use std::os::windows::fs::OpenOptionsExt;
+
+        let path = std::env::temp_dir()
+            .join("test_temporary_delete_retries_transient_handle");
+        let _ = fs::remove_dir_all(&path);
+
+        let db = Config::new()
+            .path(&path)
+            .temporary(true)
+            .temporary_delete_retries(10)
+            .open()
+            .unwrap();
+
+        // an exclusive handle, like the ones virus scanners take,
+        // blocks deletion until it is closed.
+        let handle = fs::OpenOptions::new()
+            .read(true)
+            .share_mode(0)
+            .open(path.join("conf"))
+            .unwrap();
+        let closer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            drop(handle);
+        });
+
+        drop(db);
+        closer.join().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn on_disk_version() {
+        let config = Config::new().temporary(true);
+        assert_eq!(config.on_disk_version().unwrap(), None);
+
+        let db = config.open().unwrap();
+        assert_eq!(config.on_disk_version().unwrap(), Some(crate_version()));
+        drop(db);
+    }
+
+    #[test]
+    fn version_policy() {
+        let path = std::env::temp_dir().join("test_version_policy");
+
+        // creates a database recording the `stored` version, opens
+        // it with `policy`, and returns the version on disk after
+        let open_with = |stored: (usize, usize), policy: VersionPolicy| {
+            let _ = fs::remove_dir_all(&path);
+
+            let mut config = Config::new().path(&path);
+            Arc::make_mut(&mut config.0).version = stored;
+            drop(config.open().unwrap());
+
+            let config = Config::new().path(&path).version_policy(policy);
+            let res = config.open().map(drop);
+            (res, config.on_disk_version().unwrap().unwrap())
+        };
+
+        let current = crate_version();
+        let older = (current.0, current.1 - 1);
+        let newer = (current.0, current.1 + 1);
+        let other_major = (current.0 + 1, current.1);
+        let unsupported =
+            |res: Result<()>| matches!(res, Err(Error::Unsupported(_)));
+
+        let (res, on_disk) = open_with(older, VersionPolicy::Strict);
+        assert!(unsupported(res));
+        assert_eq!(on_disk, older);
+
+        let (res, on_disk) = open_with(older, VersionPolicy::AutoMinor);
+        assert_eq!(res, Ok(()));
+        assert_eq!(on_disk, current);
+
+        let (res, on_disk) = open_with(newer, VersionPolicy::AutoMinor);
+        assert!(unsupported(res));
+        assert_eq!(on_disk, newer);
+
+        let (res, _) = open_with(other_major, VersionPolicy::AutoMinor);
+        assert!(unsupported(res));
+
+        let calls = Arc::new(std::sync::Mutex::new(vec![]));
+        let gate = |proceed: bool| {
+            let calls = calls.clone();
+            VersionPolicy::Callback(Arc::new(move |stored, current| {
+                calls.lock().unwrap().push((stored, current));
+                proceed
+            }))
+        };
+
+        let (res, on_disk) = open_with(newer, gate(true));
+        assert_eq!(res, Ok(()));
+        assert_eq!(on_disk, current);
+
+        let (res, on_disk) = open_with(older, gate(false));
+        assert!(unsupported(res));
+        assert_eq!(on_disk, older);
+
+        // a different major version is rejected without asking
+        let (res, _) = open_with(other_major, gate(true));
+        assert!(unsupported(res));
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![(newer, current), (older, current)]
+        );
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn file_options_customizer() {
+        use std::sync::atomic::AtomicBool;
+
+        let called = Arc::new(AtomicBool::new(false));
+        let called2 = called.clone();
+
+        let db = Config::new()
+            .temporary(true)
+            .file_options(Arc::new(move |options| {
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::OpenOptionsExt;
+                    let _ = options.custom_flags(libc::O_DSYNC);
+                }
+                #[cfg(not(unix))]
+                let _ = options;
+
+                called2.store(true, SeqCst);
+            }))
+            .open()
+            .unwrap();
+
+        assert!(called.load(SeqCst));
+
+        db.insert(b"k", b"v").unwrap();
+        db.flush().unwrap();
+        assert_eq!(db.get(b"k").unwrap().unwrap(), b"v");
+    }
+
+    #[test]
+    fn deserialize_future_format_config() {
+        let future = b"sled_config_format: 99\n\
+                       segment_size: 524288\n\
+                       use_compression: false\n\
+                       version: 0.34\n";
+
+        assert!(matches!(
+            StorageParameters::deserialize(future),
+            Err(Error::Unsupported(_))
+        ));
+    }
+}
@@ -1,5 +1,44 @@
 use super::*;
 
+static OPEN_DATABASES: AtomicUsize = AtomicUsize::new(0);
+static MAX_OPEN_DATABASES: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Sets the maximum number of databases that may be open
+/// at once in this process. Opening another database past
+/// this limit fails with `Error::Unsupported("open database
+/// limit reached")`, which guards against code that opens
+/// databases in a loop until it runs out of file descriptors.
+/// A database counts as open until every `Db` and `Tree`
+/// referring to it is dropped. Lowering the limit does not
+/// close databases that are already open. Defaults to
+/// `usize::MAX`, which is unlimited.
+pub fn set_max_open_databases(max: usize) {
+    MAX_OPEN_DATABASES.store(max, SeqCst);
+}
+
+/// One of the process-wide open database slots, released
+/// when the last `Context` for a database is dropped.
+#[derive(Debug)]
+struct OpenDatabaseSlot;
+
+impl OpenDatabaseSlot {
+    fn acquire() -> Result<OpenDatabaseSlot> {
+        let max = MAX_OPEN_DATABASES.load(SeqCst);
+        OPEN_DATABASES
+            .fetch_update(SeqCst, SeqCst, |open| {
+                if open < max { Some(open + 1) } else { None }
+            })
+            .map(|_| OpenDatabaseSlot)
+            .map_err(|_| Error::Unsupported("open database limit reached"))
+    }
+}
+
+impl Drop for OpenDatabaseSlot {
+    fn drop(&mut self) {
+        OPEN_DATABASES.fetch_sub(1, SeqCst);
+    }
+}
+
 #[derive(Debug, Clone)]
 #[doc(hidden)]
 pub struct Context {
@@ -16,6 +55,7 @@ pub struct Context {
     pub(crate) flusher: Arc<Mutex<Option<flusher::Flusher>>>,
     #[doc(hidden)]
     pub pagecache: PageCache,
+    _open_slot: Arc<OpenDatabaseSlot>,
 }
 
 impl std::ops::Deref for Context {
@@ -27,14 +67,33 @@ impl std::ops::Deref for Context {
 }
 
 impl Context {
+    /// Returns how many `Context`s refer to this database.
+    /// Each `Db` clone and each tree it has opened holds one.
+    pub(crate) fn handle_count(&self) -> usize {
+        Arc::strong_count(&self._open_slot)
+    }
+
     pub(crate) fn start(config: RunningConfig) -> Result<Self> {
         trace!("starting context");
 
+        let open_slot = OpenDatabaseSlot::acquire()?;
+
         let pagecache = PageCache::start(config.clone())?;
 
+        let mut config = config;
+        if !pagecache.recovery_warnings().is_empty() {
+            config.open_warnings = config
+                .open_warnings
+                .iter()
+                .chain(pagecache.recovery_warnings())
+                .cloned()
+                .collect();
+        }
+
         Ok(Self {
             config,
             pagecache,
+            _open_slot: Arc::new(open_slot),
             #[cfg(not(miri))]
             flusher: Arc::new(parking_lot::Mutex::new(None)),
         })
@@ -0,0 +1,132 @@
+//! Checksums for the log, the heap and snapshots, computed
+//! with the polynomial chosen by `Config::crc_variant`.
+//! CRC32C is computed with the SSE4.2 `crc32` instruction
+//! on x86_64 CPUs that support it, and with a lookup table
+//! otherwise.
+
+use std::convert::TryInto;
+
+use crate::CrcVariant;
+
+/// The reflected CRC32C (Castagnoli) polynomial.
+const CASTAGNOLI: u32 = 0x82F6_3B78;
+
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ CASTAGNOLI } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// An incremental checksum, like `crc32fast::Hasher`.
+pub(crate) enum Hasher {
+    Crc32(crc32fast::Hasher),
+    // the running state, before the final inversion
+    Crc32c(u32),
+}
+
+impl Hasher {
+    pub(crate) fn new(variant: CrcVariant) -> Hasher {
+        match variant {
+            CrcVariant::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+            CrcVariant::Crc32c => Hasher::Crc32c(!0),
+        }
+    }
+
+    pub(crate) fn update(&mut self, buf: &[u8]) {
+        match self {
+            Hasher::Crc32(hasher) => hasher.update(buf),
+            Hasher::Crc32c(state) => *state = crc32c_update(*state, buf),
+        }
+    }
+
+    pub(crate) fn finalize(self) -> u32 {
+        match self {
+            Hasher::Crc32(hasher) => hasher.finalize(),
+            Hasher::Crc32c(state) => !state,
+        }
+    }
+}
+
+/// Returns the checksum of `buf` using `variant`.
+pub(crate) fn checksum(variant: CrcVariant, buf: &[u8]) -> u32 {
+    let mut hasher = Hasher::new(variant);
+    hasher.update(buf);
+    hasher.finalize()
+}
+
+fn crc32c_update(state: u32, buf: &[u8]) -> u32 {
+    #[cfg(all(not(miri), target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("sse4.2") {
+            #[allow(unsafe_code)]
+            return unsafe { crc32c_sse42(state, buf) };
+        }
+    }
+
+    crc32c_table_update(state, buf)
+}
+
+fn crc32c_table_update(mut state: u32, buf: &[u8]) -> u32 {
+    for byte in buf {
+        let idx = (state ^ u32::from(*byte)) & 0xFF;
+        state = (state >> 8) ^ CRC32C_TABLE[idx as usize];
+    }
+    state
+}
+
+#[cfg(all(not(miri), target_arch = "x86_64"))]
+#[target_feature(enable = "sse4.2")]
+#[allow(unsafe_code)]
+unsafe fn crc32c_sse42(state: u32, buf: &[u8]) -> u32 {
+    use std::arch::x86_64::{_mm_crc32_u8, _mm_crc32_u64};
+
+    let mut chunks = buf.chunks_exact(8);
+    let mut state = u64::from(state);
+    for chunk in &mut chunks {
+        let word = u64::from_le_bytes(chunk.try_into().unwrap());
+        state = _mm_crc32_u64(state, word);
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    let mut state = state as u32;
+    for byte in chunks.remainder() {
+        state = _mm_crc32_u8(state, *byte);
+    }
+    state
+}
+
+#[cfg(test)]
+mod qc {
+    use super::*;
+
+    #[test]
+    fn check_values() {
+        // the standard check value of each polynomial
+        assert_eq!(checksum(CrcVariant::Crc32, b"123456789"), 0xCBF4_3926);
+        assert_eq!(checksum(CrcVariant::Crc32c, b"123456789"), 0xE306_9283);
+        assert_eq!(checksum(CrcVariant::Crc32c, b""), 0);
+    }
+
+    quickcheck::quickcheck! {
+        #[cfg_attr(miri, ignore)]
+        fn accelerated_matches_table(buf: Vec<u8>, split: usize) -> bool {
+            let split = if buf.is_empty() { 0 } else { split % buf.len() };
+            let mut hasher = Hasher::new(CrcVariant::Crc32c);
+            hasher.update(&buf[..split]);
+            hasher.update(&buf[split..]);
+            hasher.finalize() == !crc32c_table_update(!0, &buf)
+        }
+    }
+}
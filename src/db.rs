@@ -1,8 +1,25 @@
-use std::ops::Deref;
+use std::{
+    collections::HashMap,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, Weak},
+    time::Duration,
+};
 
-use crate::*;
+use crate::{
+    tree::{UserBytes, View},
+    *,
+};
 
 const DEFAULT_TREE_ID: &[u8] = b"__sled__default";
+const NAMESPACE_PREFIX: &str = "__sled__namespace:";
+
+type SharedDbs = Mutex<HashMap<PathBuf, WeakDb>>;
+
+/// The databases opened with `Config::share_process_local`,
+/// keyed by their canonicalized path.
+static SHARED_DBS: Lazy<SharedDbs, fn() -> SharedDbs> =
+    Lazy::new(Default::default);
 
 /// The `sled` embedded database! Implements
 /// `Deref<Target = sled::Tree>` to refer to
@@ -18,6 +35,75 @@ pub struct Db {
     pub context: Context,
     pub(crate) default: Tree,
     tenants: Arc<RwLock<FastMap8<IVec, Tree>>>,
+    // the size of the user data when the database was opened,
+    // or `None` if it must be counted again
+    user_bytes_base: Arc<Mutex<Option<i64>>>,
+    // held while get_or_init runs, so that only one thread
+    // initializes the database
+    init_lock: Arc<Mutex<()>>,
+    // removes this database from SHARED_DBS once the last
+    // clone is dropped. this is the last field, so that the
+    // database is closed by the time it is removed.
+    shared: Option<Arc<SharedRegistration>>,
+}
+
+/// The entry of a `Db` in `SHARED_DBS`, which does not keep
+/// the database open.
+struct WeakDb {
+    default: Weak<TreeInner>,
+    tenants: Weak<RwLock<FastMap8<IVec, Tree>>>,
+    user_bytes_base: Weak<Mutex<Option<i64>>>,
+    init_lock: Weak<Mutex<()>>,
+    shared: Weak<SharedRegistration>,
+}
+
+impl WeakDb {
+    fn upgrade(&self) -> Option<Db> {
+        let default = Tree(self.default.upgrade()?);
+        Some(Db {
+            context: default.context.clone(),
+            default,
+            tenants: self.tenants.upgrade()?,
+            user_bytes_base: self.user_bytes_base.upgrade()?,
+            init_lock: self.init_lock.upgrade()?,
+            shared: Some(self.shared.upgrade()?),
+        })
+    }
+}
+
+struct SharedRegistration {
+    path: PathBuf,
+}
+
+impl Drop for SharedRegistration {
+    fn drop(&mut self) {
+        let mut shared_dbs = SHARED_DBS.lock();
+        // the path may have been opened again while this
+        // registration was waiting for the lock
+        let registered = matches!(
+            shared_dbs.get(&self.path),
+            Some(entry) if std::ptr::eq(entry.shared.as_ptr(), self)
+        );
+        if registered {
+            shared_dbs.remove(&self.path);
+        }
+    }
+}
+
+/// How much space a database takes up on disk compared to
+/// the user data stored in it, as returned by
+/// `Db::space_amplification`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpaceAmplification {
+    /// The total size of the keys and values stored in all
+    /// trees.
+    pub user_bytes: u64,
+    /// The size of the storage files, as returned by
+    /// `Db::size_on_disk`.
+    pub physical_bytes: u64,
+    /// `physical_bytes` divided by `user_bytes`, or infinity
+    /// if no user data is stored.
+    pub amplification: f64,
 }
 
 impl Deref for Db {
@@ -46,7 +132,96 @@ impl Debug for Db {
     }
 }
 
+/// Sums the sizes of all nodes in a tree, walking each level
+/// from its leftmost node across its siblings.
+fn tree_size(tree: &Tree) -> Result<u64> {
+    let mut size = 0;
+    for_each_page(tree, |_pid, view| size += view.rss())?;
+    Ok(size)
+}
+
+/// Calls `f` with every page of the tree, level by level
+/// from the root, following each level from left to right.
+fn for_each_page(
+    tree: &Tree,
+    mut f: impl FnMut(PageId, &View<'_>),
+) -> Result<()> {
+    let mut guard = pin();
+
+    let mut ops = 0;
+    let mut leftmost = Some(tree.root.load(Acquire));
+    while let Some(mut pid) = leftmost.take() {
+        loop {
+            ops += 1;
+            if ops % 64 == 0 {
+                // re-pin to avoid memory blow-ups on large trees
+                guard = pin();
+            }
+
+            let view = if let Some(view) = tree.view_for_pid(pid, &guard)? {
+                view
+            } else {
+                break;
+            };
+
+            f(pid, &view);
+
+            if leftmost.is_none() && view.is_index {
+                leftmost = view.iter_index_pids().next();
+            }
+
+            if let Some(next_pid) = view.next {
+                pid = next_pid.get();
+            } else {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
 impl Db {
+    /// Returns the database at `path` if it was already opened
+    /// with `Config::share_process_local` and is still open in
+    /// this process, and otherwise opens it with `open` and
+    /// registers it for later calls.
+    pub(crate) fn open_shared<F>(
+        path: &Path,
+        open: F,
+    ) -> Result<(Self, OpenOutcome)>
+    where
+        F: FnOnce() -> Result<(Self, OpenOutcome)>,
+    {
+        // held while opening, so that concurrent opens of the
+        // same path share a single database
+        let mut shared_dbs = SHARED_DBS.lock();
+
+        if let Ok(canonical) = std::fs::canonicalize(path) {
+            if let Some(db) =
+                shared_dbs.get(&canonical).and_then(WeakDb::upgrade)
+            {
+                debug!("sharing the database already open at {:?}", canonical);
+                return Ok((db, OpenOutcome::Opened));
+            }
+        }
+
+        let (mut db, outcome) = open()?;
+        let canonical = std::fs::canonicalize(path)?;
+        let shared = Arc::new(SharedRegistration { path: canonical.clone() });
+        let entry = WeakDb {
+            default: Arc::downgrade(&db.default.0),
+            tenants: Arc::downgrade(&db.tenants),
+            user_bytes_base: Arc::downgrade(&db.user_bytes_base),
+            init_lock: Arc::downgrade(&db.init_lock),
+            shared: Arc::downgrade(&shared),
+        };
+        db.shared = Some(shared);
+        shared_dbs.insert(canonical, entry);
+
+        Ok((db, outcome))
+    }
+
     pub(crate) fn start_inner(config: RunningConfig) -> Result<Self> {
         #[cfg(feature = "metrics")]
         let _measure = Measure::new(&M.tree_start);
@@ -56,9 +231,13 @@ impl Db {
         #[cfg(not(miri))]
         {
             let flusher_pagecache = context.pagecache.clone();
-            let flusher = context.flush_every_ms.map(move |fem| {
+            // nothing is ever written with verify_only
+            let flush_every_ms =
+                context.flush_every_ms.filter(|_| !context.verify_only);
+            let flusher = flush_every_ms.map(move |fem| {
                 flusher::Flusher::new(
-                    "log flusher".to_owned(),
+                    flusher_pagecache.config.flush_thread_name.clone(),
+                    flusher_pagecache.config.flush_thread_stack_size,
                     flusher_pagecache,
                     fem,
                 )
@@ -71,10 +250,27 @@ impl Db {
         let default =
             meta::open_tree(&context, DEFAULT_TREE_ID.to_vec(), &guard)?;
 
+        if let Some(ref merge_operator) = context.default_merge_operator {
+            let merge_operator = merge_operator.0.clone();
+            default.set_merge_operator(
+                move |key: &[u8], old: Option<&[u8]>, merged: &[u8]| {
+                    merge_operator(key, old, merged)
+                },
+            );
+        }
+
+        // a new database holds no user data, so it never needs
+        // to be counted
+        let user_bytes_base =
+            if context.was_recovered() { None } else { Some(0) };
+
         let ret = Self {
             context: context.clone(),
             default,
             tenants: Arc::new(RwLock::new(FastMap8::default())),
+            user_bytes_base: Arc::new(Mutex::new(user_bytes_base)),
+            init_lock: Arc::new(Mutex::new(())),
+            shared: None,
         };
 
         let mut tenants = ret.tenants.write();
@@ -86,6 +282,13 @@ impl Db {
                 context: context.clone(),
                 root: AtomicU64::new(*root),
                 merge_operator: RwLock::new(None),
+                pinned: AtomicBool::new(false),
+                user_bytes: if id == DEFAULT_TREE_ID {
+                    // count writes through either handle
+                    ret.default.user_bytes.clone()
+                } else {
+                    Arc::new(UserBytes::new(user_bytes_base))
+                },
             }));
             assert!(tenants.insert(id.clone(), tree).is_none());
         }
@@ -142,47 +345,53 @@ impl Db {
         trace!("dropping tree {:?}", name_ref,);
 
         let mut tenants = self.tenants.write();
-
-        let tree = if let Some(tree) = tenants.remove(name_ref) {
-            tree
-        } else {
+        if !tenants.contains_key(name_ref) {
             return Ok(false);
-        };
-
-        // signal to all threads that this tree is no longer valid
-        tree.root.store(u64::max_value(), SeqCst);
+        }
 
         let guard = pin();
 
-        let mut root_id =
-            Some(self.context.pagecache.meta_pid_for_name(name_ref, &guard)?);
+        let leftmost_chain =
+            self.detach_trees(&[name_ref.into()], &mut tenants, &guard)?;
 
-        let mut leftmost_chain: Vec<PageId> = vec![root_id.unwrap()];
-        let mut cursor = root_id.unwrap();
-        while let Some(view) = self.view_for_pid(cursor, &guard)? {
-            if view.is_index {
-                let leftmost_child = view.iter_index_pids().next().unwrap();
-                leftmost_chain.push(leftmost_child);
-                cursor = leftmost_child;
-            } else {
-                break;
-            }
-        }
+        // drop writer lock and asynchronously
+        drop(tenants);
 
-        loop {
-            let res = self
-                .context
-                .pagecache
-                .cas_root_in_meta(name_ref, root_id, None, &guard)?;
+        guard.flush();
 
-            if let Err(actual_root) = res {
-                root_id = actual_root;
-            } else {
-                break;
-            }
-        }
+        drop(guard);
+
+        self.gc_pages(leftmost_chain)?;
+
+        Ok(true)
+    }
+
+    /// Removes every tree whose name starts with `prefix`, and
+    /// returns how many were removed. The default tree is never
+    /// removed, even if `prefix` is empty.
+    ///
+    /// The matching trees are removed from the persisted
+    /// metadata in a single atomic update, so other threads,
+    /// and a crash, observe either all of them or none of them
+    /// removed. If that fails, none of them are removed. Like
+    /// `drop_tree`, this is blocking and fairly slow.
+    pub fn drop_trees_with_prefix(&self, prefix: &[u8]) -> Result<usize> {
+        trace!("dropping trees with prefix {:?}", prefix);
+
+        let mut tenants = self.tenants.write();
+
+        let names: Vec<IVec> = tenants
+            .keys()
+            .filter(|name| {
+                name.starts_with(prefix) && &***name != DEFAULT_TREE_ID
+            })
+            .cloned()
+            .collect();
+
+        let guard = pin();
+
+        let leftmost_chain = self.detach_trees(&names, &mut tenants, &guard)?;
 
-        // drop writer lock and asynchronously
         drop(tenants);
 
         guard.flush();
@@ -191,7 +400,137 @@ impl Db {
 
         self.gc_pages(leftmost_chain)?;
 
-        Ok(true)
+        Ok(names.len())
+    }
+
+    /// Renames the tree `old` to `new` without copying its
+    /// data, by atomically changing the name that its root is
+    /// stored under in the persisted metadata. Returns
+    /// `Error::Unsupported` if there is no tree named `old`, if
+    /// there already is one named `new`, or if either is the
+    /// default tree.
+    ///
+    /// `Tree` handles opened under the old name stop working,
+    /// like those of a dropped tree, and its subscribers are
+    /// not notified of later writes. The tree must be opened
+    /// again with `open_tree(new)`. A merge operator and
+    /// `pin_tree` carry over to the new name.
+    pub fn rename_tree(&self, old: &[u8], new: &[u8]) -> Result<()> {
+        if old == DEFAULT_TREE_ID || new == DEFAULT_TREE_ID {
+            return Err(Error::Unsupported("cannot rename the default tree"));
+        }
+        trace!("renaming tree {:?} to {:?}", old, new);
+
+        let mut tenants = self.tenants.write();
+
+        if tenants.contains_key(new) {
+            return Err(Error::Unsupported(
+                "cannot rename a tree to the name of an existing tree",
+            ));
+        }
+        let old_tree = if let Some(tree) = tenants.get(old) {
+            tree.clone()
+        } else {
+            return Err(Error::Unsupported(
+                "cannot rename a tree that does not exist",
+            ));
+        };
+
+        let guard = pin();
+
+        let root = if let Some(root) =
+            self.context.pagecache.rename_in_meta(old, new, &guard)?
+        {
+            root
+        } else {
+            return Err(Error::ReportableBug(
+                "the persisted tree names diverged from the open trees",
+            ));
+        };
+
+        // signal to all threads that the old handles are no
+        // longer valid
+        old_tree.root.store(u64::MAX, SeqCst);
+        let _ = tenants.remove(old);
+
+        let tree = Tree(Arc::new(TreeInner {
+            tree_id: new.into(),
+            subscribers: Subscribers::default(),
+            context: self.context.clone(),
+            root: AtomicU64::new(root),
+            merge_operator: RwLock::new(old_tree.merge_operator.write().take()),
+            pinned: AtomicBool::new(old_tree.pinned.load(Acquire)),
+            user_bytes: old_tree.user_bytes.clone(),
+        }));
+        assert!(tenants.insert(new.into(), tree).is_none());
+
+        Ok(())
+    }
+
+    // Atomically removes trees from `tenants` and from the
+    // persisted metadata, returning the leftmost page of each
+    // level of each tree for `gc_pages`. If this fails, none of
+    // the trees are removed. Every name must be in `tenants`.
+    fn detach_trees(
+        &self,
+        names: &[IVec],
+        tenants: &mut FastMap8<IVec, Tree>,
+        guard: &Guard,
+    ) -> Result<Vec<PageId>> {
+        // signal to all threads that these trees are no longer
+        // valid, until they are restored below on failure
+        let roots: Vec<u64> = names
+            .iter()
+            .map(|name| tenants[name].root.swap(u64::max_value(), SeqCst))
+            .collect();
+
+        let detached = self.detach_from_meta(names, guard);
+
+        if detached.is_ok() {
+            for name in names {
+                let _ = tenants.remove(name);
+            }
+
+            // the user data of the trees is no longer stored
+            *self.user_bytes_base.lock() = None;
+        } else {
+            for (name, root) in names.iter().zip(roots) {
+                tenants[name].root.store(root, SeqCst);
+            }
+        }
+
+        detached
+    }
+
+    // Finds the leftmost page of each level of each tree, then
+    // removes all of the trees from the persisted metadata at
+    // once.
+    fn detach_from_meta(
+        &self,
+        names: &[IVec],
+        guard: &Guard,
+    ) -> Result<Vec<PageId>> {
+        let mut leftmost_chain = vec![];
+        for name in names {
+            let root_id =
+                self.context.pagecache.meta_pid_for_name(name, guard)?;
+            leftmost_chain.push(root_id);
+
+            let mut cursor = root_id;
+            while let Some(view) = self.view_for_pid(cursor, guard)? {
+                if view.is_index {
+                    let leftmost_child = view.iter_index_pids().next().unwrap();
+                    leftmost_chain.push(leftmost_child);
+                    cursor = leftmost_child;
+                } else {
+                    break;
+                }
+            }
+        }
+
+        self.context.pagecache.remove_from_meta(names, guard)?;
+
+        Ok(leftmost_chain)
     }
 
     // Remove all pages for this tree from the underlying
@@ -247,6 +586,219 @@ impl Db {
         tenants.iter().map(|(name, _)| name.clone()).collect()
     }
 
+    /// Flushes all pending writes and synchronously writes a
+    /// complete snapshot of the pagecache metadata, returning
+    /// the LSN that the snapshot covers. Unlike the fuzzy
+    /// snapshots taken every `snapshot_after_ops` operations,
+    /// this is complete when it returns, which makes it
+    /// suitable to call before taking a filesystem-level
+    /// backup. If a background snapshot is in progress, this
+    /// waits for it to finish first.
+    ///
+    /// The returned LSN can be passed to
+    /// `Config::recover_to_snapshot` to open the database as it
+    /// was at this point. Recovery then starts from the newest
+    /// snapshot at or below it and stops replaying the log
+    /// there. The database is opened with `verify_only`, so
+    /// nothing on disk is changed and later writes are still
+    /// there when it is opened normally. sled removes older
+    /// snapshots when it writes a new one, so when none is left
+    /// at or below the LSN, the whole log is replayed up to it,
+    /// which fails with `Error::Corruption` once the segments
+    /// that held the older writes have been reused. Large
+    /// values that were freed after the LSN may have been
+    /// overwritten, and fail their CRC check when read.
+    pub fn checkpoint(&self) -> Result<u64> {
+        let lsn = self.context.pagecache.checkpoint()?;
+        Ok(u64::try_from(lsn).unwrap())
+    }
+
+    /// Flushes and fsyncs all writes, then runs `f` while the
+    /// files of the database are left untouched, which allows
+    /// a consistent copy or filesystem snapshot to be taken
+    /// from within `f`. Writes from other threads, as well as
+    /// background GC and snapshots, block until `f` returns.
+    /// So may reads, because reading a page may compact it.
+    /// Heap slots that are no longer referenced may still be
+    /// released while `f` runs.
+    ///
+    /// `f` may call the methods of this database that only
+    /// report on its state without touching its data, such as
+    /// `last_written_lsn`, `pending_flush_bytes` and
+    /// `storage_path`. It must not read or write data, and
+    /// this must not be called from within a transaction,
+    /// because both may wait for themselves forever.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # let db = sled::Config::new().temporary(true).open()?;
+    /// db.insert(b"k", b"v")?;
+    /// let path = db.storage_path();
+    /// let files =
+    ///     db.with_quiesced(|| std::fs::read_dir(&path).map(Iterator::count))??;
+    /// assert!(files > 0);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn with_quiesced<T>(&self, f: impl FnOnce() -> T) -> Result<T> {
+        self.context.pagecache.quiesce(f)
+    }
+
+    /// Returns the total size of the keys and values stored in
+    /// each tree, keyed by tree name.
+    ///
+    /// The sizes are kept up to date by every write. For trees
+    /// that were recovered, the first call reads each tree to
+    /// count it, and writes that run concurrently with that
+    /// count may make it inexact.
+    pub fn tree_sizes(
+        &self,
+    ) -> Result<std::collections::HashMap<Vec<u8>, u64>> {
+        let tenants = self.tenants.read();
+
+        let mut ret = std::collections::HashMap::with_capacity(tenants.len());
+        for (name, tree) in tenants.iter() {
+            ret.insert(name.to_vec(), tree.user_bytes()?);
+        }
+
+        Ok(ret)
+    }
+
+    /// Changes `Config::cache_capacity` while the database is
+    /// open, such as to free memory for the rest of the process
+    /// while it is under memory pressure. Like at open, the
+    /// capacity is limited to the cgroup memory limit and, on
+    /// 32 bit targets, to a quarter of the address space. When
+    /// it shrinks, pages are evicted right away until the cache
+    /// fits, other than pinned pages and the most recently used
+    /// page of each part of the cache.
+    ///
+    /// Returns `Error::Unsupported` if `cache_capacity` is below
+    /// 256 bytes, the smallest capacity that can be opened with,
+    /// or if it does not fit in the address space and
+    /// `Config::enforce_min_cache` is set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    ///
+    /// db.set_cache_capacity(16 * 1024 * 1024)?;
+    /// # Ok(()) }
+    /// ```
+    pub fn set_cache_capacity(&self, cache_capacity: usize) -> Result<()> {
+        if cache_capacity < 256 {
+            return Err(Error::Unsupported(
+                "cache_capacity must be at least 256 bytes",
+            ));
+        }
+        let cache_capacity =
+            crate::config::limit_cache_capacity(cache_capacity);
+        let cache_capacity = crate::config::limit_cache_to_address_space(
+            cache_capacity,
+            usize::BITS,
+            self.context.pagecache.config.enforce_min_cache,
+        )?;
+        self.context.pagecache.set_cache_capacity(cache_capacity)
+    }
+
+    /// Keeps every page of the tree named `name` in the cache,
+    /// for trees that are small and read often enough that they
+    /// should never have to be read back from disk. Pinned
+    /// pages are never evicted, but still count toward
+    /// `Config::cache_capacity`, so other pages are evicted in
+    /// their place. A warning is logged if pinned trees take up
+    /// more than the whole capacity. Pages that the tree gains
+    /// later are pinned as well.
+    ///
+    /// Pinning is not persisted, and lasts until `unpin_tree`
+    /// is called or the tree is dropped. Returns
+    /// `Error::CollectionNotFound` if there is no such tree.
+    pub fn pin_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<()> {
+        let tenants = self.tenants.read();
+        let tree =
+            tenants.get(name.as_ref()).ok_or(Error::CollectionNotFound)?;
+
+        tree.pinned.store(true, SeqCst);
+        for_each_page(tree, |pid, _view| self.context.pagecache.pin(pid))?;
+
+        let mut pinned_bytes = 0;
+        for tree in tenants.values() {
+            if tree.pinned.load(Acquire) {
+                pinned_bytes += tree_size(tree)?;
+            }
+        }
+        let cache_capacity =
+            u64::try_from(self.context.pagecache.cache_capacity()).unwrap();
+        if pinned_bytes > cache_capacity {
+            warn!(
+                "pinned trees take up {} bytes, which is more than \
+                 the cache capacity of {} bytes",
+                pinned_bytes, cache_capacity
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Lets the pages of a tree pinned by `pin_tree` be evicted
+    /// again. Returns `Error::CollectionNotFound` if there is no
+    /// such tree.
+    pub fn unpin_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<()> {
+        let tenants = self.tenants.read();
+        let tree =
+            tenants.get(name.as_ref()).ok_or(Error::CollectionNotFound)?;
+
+        tree.pinned.store(false, SeqCst);
+        for_each_page(tree, |pid, _view| self.context.pagecache.unpin(pid))
+    }
+
+    /// Checks the structure of every tree, then reads every
+    /// item stored in the heap to confirm that the slot it
+    /// is stored in is allocated and that its CRC matches.
+    /// Structural problems are returned as an error, while
+    /// heap problems are listed in the returned report.
+    ///
+    /// This reads all large values from disk, so it is very
+    /// expensive. It is meant for use after recovering from
+    /// a crash, and should be run while nothing else is
+    /// writing to the database, because pages that are
+    /// rewritten concurrently may be reported spuriously.
+    pub fn verify_integrity(&self) -> Result<IntegrityReport> {
+        for tree in self.tenants.read().values() {
+            tree.verify_integrity()?;
+        }
+
+        self.context.pagecache.verify_heap_pointers()
+    }
+
+    /// Reads the whole data file and checks the CRCs of
+    /// every segment header and of every message that has
+    /// been written to disk, reporting the offsets of the
+    /// segments that fail. Segments are read straight from
+    /// the file one at a time, so this does not pollute the
+    /// cache or block other operations, and may be run on a
+    /// live database. Items stored in the heap are checked
+    /// by `verify_integrity` instead.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let config = sled::Config::new().temporary(true);
+    /// # let db = config.open()?;
+    /// db.insert(b"a", b"1")?;
+    /// db.flush()?;
+    ///
+    /// let report = db.scrub()?;
+    /// assert!(report.crc_failures.is_empty());
+    /// # Ok(()) }
+    /// ```
+    pub fn scrub(&self) -> Result<ScrubReport> {
+        self.context.pagecache.scrub()
+    }
+
     /// Returns `true` if the database was
     /// recovered from a previous process.
     /// Note that database state is only
@@ -261,6 +813,36 @@ impl Db {
         self.context.was_recovered()
     }
 
+    /// Changes how often the background thread flushes
+    /// dirty data to disk, taking effect immediately:
+    /// unless `Config::max_background_threads` is set, this
+    /// returns once the thread has flushed with the new
+    /// interval. Passing `None` pauses periodic flushing
+    /// without stopping the background thread, until a new
+    /// interval is set. This overrides the
+    /// `flush_every_ms` configuration option for the
+    /// rest of the lifetime of this `Db`.
+    pub fn set_flush_interval(&self, every_ms: Option<u64>) {
+        #[cfg(not(miri))]
+        {
+            let mut flusher = self.context.flusher.lock();
+            if let (None, Some(every_ms)) = (&*flusher, every_ms) {
+                *flusher = Some(flusher::Flusher::new(
+                    self.context.flush_thread_name.clone(),
+                    self.context.flush_thread_stack_size,
+                    self.context.pagecache.clone(),
+                    every_ms,
+                ));
+            }
+            if let Some(ref flusher) = *flusher {
+                flusher.set_flush_every_ms(every_ms);
+            }
+        }
+
+        #[cfg(miri)]
+        let _ = every_ms;
+    }
+
     /// Generate a monotonic ID. Not guaranteed to be
     /// contiguous. Written to disk every `idgen_persist_interval`
     /// operations, followed by a blocking flush. During recovery, we
@@ -273,6 +855,46 @@ impl Db {
         self.context.generate_id()
     }
 
+    /// Runs `init` to populate a new database unless the key
+    /// `sentinel` is already set in the default tree, then sets
+    /// it and flushes, returning whether `init` ran. Only one
+    /// call runs `init` at a time, and the file lock taken by
+    /// `open` keeps other processes out, so the database is
+    /// initialized once even if several threads call this.
+    ///
+    /// The sentinel is written after everything `init` wrote,
+    /// so it is never recovered without them. If `init` fails,
+    /// or the process crashes before the flush, the sentinel is
+    /// not set and `init` runs again next time, on top of any
+    /// of its writes that were recovered.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # let db = sled::Config::new().temporary(true).open()?;
+    /// let ran = db.get_or_init(b"initialized", |db| {
+    ///     db.insert(b"schema_version", b"1")?;
+    ///     Ok(())
+    /// })?;
+    /// assert!(ran);
+    /// assert!(!db.get_or_init(b"initialized", |_| unreachable!())?);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn get_or_init<F>(&self, sentinel: &[u8], init: F) -> Result<bool>
+    where
+        F: FnOnce(&Db) -> Result<()>,
+    {
+        let _init_lock = self.init_lock.lock();
+        if self.contains_key(sentinel)? {
+            return Ok(false);
+        }
+
+        init(self)?;
+        let _ = self.insert(sentinel, &[])?;
+        let _ = self.flush()?;
+        Ok(true)
+    }
+
     /// A database export method for all collections in the `Db`,
     /// for use in sled version upgrades. Can be used in combination
     /// with the `import` method below on a database running a later
@@ -451,25 +1073,426 @@ impl Db {
     }
 
     /// Returns the on-disk size of the storage files
-    /// for this database.
+    /// for this database: the `db` file, the contents
+    /// of the `heap` directory, the `conf` files, and
+    /// any snapshots. This is literal filesystem usage,
+    /// including space that is no longer referenced by
+    /// live data but has not yet been reclaimed.
     pub fn size_on_disk(&self) -> Result<u64> {
         self.context.pagecache.size_on_disk()
     }
 
-    /// Traverses all files and calculates their total physical
-    /// size, then traverses all pages and calculates their
-    /// total logical size, then divides the physical size
+    /// Returns the number of bytes that have been written
+    /// to the log but are not yet durable on disk. This is
+    /// a cheap atomic read, suitable for applying backpressure
+    /// to writers when it grows past a threshold. Calling
+    /// `flush` brings it back to zero.
+    pub fn pending_flush_bytes(&self) -> u64 {
+        self.context.pagecache.log.pending_flush_bytes()
+    }
+
+    /// Shuts the database down, returning any error that `Drop`
+    /// would only be able to log. If this is the last handle
+    /// to the database, this stops the background flusher,
+    /// flushes and fsyncs the log, records a clean shutdown if
+    /// `Config::fast_clean_restart` is set, and releases the
+    /// file lock before returning. If clones of this `Db`, or
+    /// trees opened from it, are still alive, this only
+    /// flushes, and the database stays open until they are
+    /// dropped too.
+    pub fn close(self) -> Result<()> {
+        if !self.is_only_handle() {
+            self.flush()?;
+            return Ok(());
+        }
+
+        // stopping the flusher lets it write out whatever is
+        // left, so that it can't race with the final flush
+        #[cfg(not(miri))]
+        drop(self.context.flusher.lock().take());
+
+        self.context.pagecache.close()?;
+
+        // IO threads and deferred destructors may briefly hold
+        // on to clones of the config after nothing is written
+        // anymore, so release the lock explicitly rather than
+        // when the last of them is dropped.
+        #[cfg(all(
+            not(miri),
+            any(windows, target_os = "linux", target_os = "macos")
+        ))]
+        {
+            let file = self.context.pagecache.config.file.clone();
+            drop(self);
+            let _ = fs2::FileExt::unlock(&*file);
+        }
+
+        Ok(())
+    }
+
+    /// Whether nothing but this `Db` can reach the database,
+    /// including through trees opened from it.
+    fn is_only_handle(&self) -> bool {
+        let tenants = self.tenants.read();
+        Arc::strong_count(&self.tenants) == 1
+            && Arc::strong_count(&self.default.0) == 1
+            && tenants.values().all(|tree| Arc::strong_count(&tree.0) == 1)
+            && self.context.handle_count() == 2 + tenants.len()
+    }
+
+    /// Returns a human-readable report of the state of this
+    /// database, for attaching to bug reports: the version,
+    /// any global error, the cache occupancy, the log
+    /// positions and io buffer, the heap slots, and the
+    /// effective configuration. This is safe to call while
+    /// the database is in use, and only takes locks briefly.
+    pub fn diagnostics(&self) -> String {
+        use std::fmt::Write;
+
+        let config = &self.context.pagecache.config;
+        let mut ret = String::new();
+        writeln!(ret, "sled diagnostics").unwrap();
+        writeln!(ret, "version: {}.{}", config.version.0, config.version.1)
+            .unwrap();
+        writeln!(ret, "path: {}", self.storage_path().display()).unwrap();
+        match config.global_error() {
+            Ok(()) => writeln!(ret, "global error: none").unwrap(),
+            Err(e) => writeln!(ret, "global error: {}", e).unwrap(),
+        }
+        writeln!(ret, "trees: {}", self.tenants.read().len()).unwrap();
+        self.context.pagecache.diagnostics(&mut ret);
+        writeln!(ret, "config: {:#?}", **config).unwrap();
+        ret
+    }
+
+    /// Renders sled's counters in the Prometheus text
+    /// exposition format, for serving from a `/metrics`
+    /// endpoint. Every metric is prefixed with `sled_` and
+    /// labeled with `instance`, the path returned by
+    /// `storage_path`. The cache, flush, and byte counters
+    /// are shared by every database in the process, while
+    /// the other values describe this one.
+    ///
+    /// The space amplification gauge uses the size of the user
+    /// data that every write keeps up to date. For a database
+    /// that was recovered, or after a tree was dropped, it is
+    /// left out until `space_amplification` has counted the
+    /// data again.
+    ///
+    /// Requires the `metrics` feature to be enabled.
+    #[cfg(feature = "metrics")]
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::float_arithmetic)]
+    pub fn metrics_prometheus(&self) -> String {
+        use std::fmt::Write;
+
+        let instance = self
+            .storage_path()
+            .display()
+            .to_string()
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+
+        let pulls = M.pull.count();
+        let mut metrics = vec![
+            (
+                "cache_hits_total",
+                "counter",
+                "Page reads that were served from the cache.",
+                M.get_page.count().saturating_sub(pulls) as f64,
+            ),
+            (
+                "cache_misses_total",
+                "counter",
+                "Page reads that had to go to disk.",
+                pulls as f64,
+            ),
+            (
+                "log_flushes_total",
+                "counter",
+                "IO buffers written to the log.",
+                M.write_to_log.count() as f64,
+            ),
+            (
+                "written_bytes_total",
+                "counter",
+                "Bytes written to the log.",
+                M.written_bytes.sum() as f64,
+            ),
+            (
+                "pending_flush_bytes",
+                "gauge",
+                "Bytes written to the log that are not yet durable.",
+                self.pending_flush_bytes() as f64,
+            ),
+            (
+                "heap_fragmentation_ratio",
+                "gauge",
+                "Fraction of heap slab space held by freed slots.",
+                self.context.pagecache.config.heap.fragmentation(),
+            ),
+        ];
+        // never count the user data here, so that scrapes stay cheap
+        let user_bytes_base = *self.user_bytes_base.lock();
+        if let Some(base) = user_bytes_base {
+            let user_bytes = base + self.context.pagecache.user_bytes_delta();
+            if user_bytes > 0 {
+                if let Ok(physical_bytes) = self.size_on_disk() {
+                    metrics.push((
+                        "space_amplification_ratio",
+                        "gauge",
+                        "Size of the storage files divided by the size \
+                         of the stored keys and values.",
+                        physical_bytes as f64 / user_bytes as f64,
+                    ));
+                }
+            }
+        }
+
+        let mut ret = String::new();
+        for (name, kind, help, value) in metrics {
+            writeln!(ret, "# HELP sled_{} {}", name, help).unwrap();
+            writeln!(ret, "# TYPE sled_{} {}", name, kind).unwrap();
+            writeln!(
+                ret,
+                "sled_{}{{instance=\"{}\"}} {}",
+                name, instance, value
+            )
+            .unwrap();
+        }
+        ret
+    }
+
+    /// Returns the wall-clock time of the most recent flush
+    /// that made more of the log durable, whether it was done
+    /// by the background flusher or by a call to `flush`, or
+    /// `None` if nothing has been flushed since the database
+    /// was opened. Unlike the modification time of the data
+    /// file, this is not affected by metadata operations.
+    pub fn last_flush_time(&self) -> Option<std::time::SystemTime> {
+        self.context.pagecache.log.last_stable_time()
+    }
+
+    /// Returns the log sequence number of the last byte that
+    /// writes have reserved in the log so far. Once
+    /// `wait_for_durable` returns `true` for it, every write
+    /// that completed before this call is durable on disk.
+    pub fn last_written_lsn(&self) -> u64 {
+        u64::try_from(self.context.pagecache.log.max_reserved_lsn())
+            .unwrap_or(0)
+    }
+
+    /// Blocks until the log is durable on disk up to and
+    /// including `lsn`, as returned by `last_written_lsn`,
+    /// returning `Ok(true)`. This does not flush anything
+    /// itself, but waits for the periodic flusher or a call
+    /// to `flush` on another thread to make it durable. It
+    /// returns `Ok(false)` if `timeout` passes first, and an
+    /// error if an IO error keeps the log from ever becoming
+    /// durable. `None` waits for as long as it takes.
+    pub fn wait_for_durable(
+        &self,
+        lsn: u64,
+        timeout: Option<Duration>,
+    ) -> Result<bool> {
+        let lsn = Lsn::try_from(lsn).unwrap_or(Lsn::MAX);
+        self.context.pagecache.log.wait_for_stable(lsn, timeout)
+    }
+
+    /// Returns the absolute directory that this database
+    /// is stored in. For temporary databases that were
+    /// not given a path, this is the generated location
+    /// under `/dev/shm` on Linux, or the system temporary
+    /// directory elsewhere.
+    pub fn storage_path(&self) -> std::path::PathBuf {
+        self.context.resolved_path()
+    }
+
+    /// Returns the problems that were tolerated while this
+    /// database was being opened, such as a `conf` file whose
+    /// CRC did not match. These are also logged, but may be
+    /// retrieved here for reporting where logs are not
+    /// captured.
+    pub fn open_warnings(&self) -> &[OpenWarning] {
+        &self.context.open_warnings
+    }
+
+    /// Calls `f` with the open data file, for advisory
+    /// operations like `fstat` or `posix_fadvise`. See
+    /// `RunningConfig::with_data_file` for what `f` must
+    /// not do.
+    pub fn with_data_file<T>(&self, f: impl FnOnce(&std::fs::File) -> T) -> T {
+        self.context.with_data_file(f)
+    }
+
+    /// Calculates the physical size of the log, then
+    /// traverses all pages and calculates their total
+    /// logical size, then divides the physical size
     /// by the logical size.
     #[doc(hidden)]
-    pub fn space_amplification(&self) -> Result<f64> {
+    pub fn log_space_amplification(&self) -> Result<f64> {
         self.context.pagecache.space_amplification()
     }
 
+    /// Returns the total size of the keys and values stored in
+    /// all trees, along with the size of the storage files and
+    /// the ratio between the two, which includes the framing,
+    /// checksums, padding and stale versions that sled stores
+    /// alongside the data.
+    ///
+    /// The size of the user data is kept up to date by every
+    /// write. For a database that was recovered, or after a
+    /// tree was dropped, the first call reads every tree to
+    /// count it, and writes that run concurrently with that
+    /// count may make it inexact.
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::float_arithmetic)]
+    pub fn space_amplification(&self) -> Result<SpaceAmplification> {
+        let pagecache = &self.context.pagecache;
+
+        let base = {
+            let mut base = self.user_bytes_base.lock();
+            if let Some(base) = *base {
+                base
+            } else {
+                let delta = pagecache.user_bytes_delta();
+                let mut counted = 0;
+                for tree in self.tenants.read().values() {
+                    for kv in tree.iter() {
+                        let (k, v) = kv?;
+                        counted += (k.len() + v.len()) as i64;
+                    }
+                }
+                *base.insert(counted - delta)
+            }
+        };
+
+        let user_bytes =
+            u64::try_from(base + pagecache.user_bytes_delta()).unwrap_or(0);
+        let physical_bytes = self.size_on_disk()?;
+        let amplification = if user_bytes == 0 {
+            f64::INFINITY
+        } else {
+            physical_bytes as f64 / user_bytes as f64
+        };
+
+        Ok(SpaceAmplification { user_bytes, physical_bytes, amplification })
+    }
+
     /// Returns a true value if one of the tree names linked
     /// to the database is found, if not a false will be returned.
     pub fn contains_tree<V: AsRef<[u8]>>(&self, name: V) -> bool {
         self.tenants.read().contains_key(name.as_ref())
     }
+
+    /// Returns a `Namespace` whose trees are stored in this
+    /// `Db` under names scoped to `name`, so that separate
+    /// namespaces may use the same tree names without seeing
+    /// each other's data. Namespaces share this `Db`'s files,
+    /// cache and lock, and dropping one leaves the `Db` open.
+    ///
+    /// Returns `Error::Unsupported` if `name` is empty.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    ///
+    /// let users = db.open_namespace("users")?;
+    /// let orders = db.open_namespace("orders")?;
+    /// users.open_tree("index")?.insert(b"k", b"user")?;
+    /// orders.open_tree("index")?.insert(b"k", b"order")?;
+    ///
+    /// assert_eq!(users.open_tree("index")?.get(b"k")?.unwrap(), b"user");
+    /// assert_eq!(users.tree_names(), vec![sled::IVec::from("index")]);
+    /// # Ok(()) }
+    /// ```
+    pub fn open_namespace(&self, name: &str) -> Result<Namespace> {
+        if name.is_empty() {
+            return Err(Error::Unsupported(
+                "namespace names must not be empty",
+            ));
+        }
+
+        // the length keeps namespaces like "a" and "a:b" from
+        // overlapping through tree names that contain ':'
+        let prefix = format!("{}{}:{}:", NAMESPACE_PREFIX, name.len(), name)
+            .into_bytes();
+
+        Ok(Namespace { db: self.clone(), name: name.into(), prefix })
+    }
+}
+
+/// A set of trees in a `Db` that is isolated from the trees
+/// of other namespaces, created by `Db::open_namespace`.
+#[derive(Clone)]
+pub struct Namespace {
+    db: Db,
+    name: String,
+    prefix: Vec<u8>,
+}
+
+impl Debug for Namespace {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> std::result::Result<(), fmt::Error> {
+        f.debug_struct("Namespace").field("name", &self.name).finish()
+    }
+}
+
+impl Namespace {
+    /// Returns the name that this namespace was opened with.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the `Db` that this namespace stores its trees in.
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    fn scoped(&self, name: &[u8]) -> Vec<u8> {
+        let mut scoped = self.prefix.clone();
+        scoped.extend_from_slice(name);
+        scoped
+    }
+
+    /// Open or create a tree in this namespace. Like
+    /// `Db::open_tree`, but only visible through this namespace.
+    pub fn open_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<Tree> {
+        self.db.open_tree(self.scoped(name.as_ref()))
+    }
+
+    /// Remove a tree from this namespace. This is blocking
+    /// and fairly slow.
+    pub fn drop_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<bool> {
+        self.db.drop_tree(self.scoped(name.as_ref()))
+    }
+
+    /// Removes every tree in this namespace, returning how
+    /// many were removed. See `Db::drop_trees_with_prefix`.
+    pub fn clear(&self) -> Result<usize> {
+        self.db.drop_trees_with_prefix(&self.prefix)
+    }
+
+    /// Returns the names of the trees in this namespace,
+    /// without the namespace's prefix.
+    pub fn tree_names(&self) -> Vec<IVec> {
+        self.db
+            .tree_names()
+            .into_iter()
+            .filter_map(|name| name.strip_prefix(&*self.prefix).map(IVec::from))
+            .collect()
+    }
+
+    /// Returns `true` if this namespace contains a tree
+    /// with the given name.
+    pub fn contains_tree<V: AsRef<[u8]>>(&self, name: V) -> bool {
+        self.db.contains_tree(self.scoped(name.as_ref()))
+    }
 }
 
 /// These types provide the information that allows an entire
@@ -480,3 +1503,623 @@ impl Db {
 /// they impact the migration path.
 type CollectionType = Vec<u8>;
 type CollectionName = Vec<u8>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn share_process_local() {
+        let path = std::env::temp_dir().join("test_share_process_local");
+        let _ = std::fs::remove_dir_all(&path);
+
+        let config = || Config::new().path(&path).share_process_local(true);
+
+        let (first, outcome) = config().open_reporting().unwrap();
+        assert_eq!(outcome, OpenOutcome::Created);
+        let (second, outcome) = config().open_reporting().unwrap();
+        assert_eq!(outcome, OpenOutcome::Opened);
+
+        first.insert(b"k", b"v").unwrap();
+        assert_eq!(second.get(b"k").unwrap().unwrap(), b"v");
+        let tree = second.open_tree(b"tree").unwrap();
+        tree.insert(b"k2", b"v2").unwrap();
+        assert_eq!(
+            first.open_tree(b"tree").unwrap().get(b"k2").unwrap().unwrap(),
+            b"v2"
+        );
+        drop(tree);
+
+        let canonical = std::fs::canonicalize(&path).unwrap();
+        drop(first);
+        assert!(SHARED_DBS.lock().contains_key(&canonical));
+        drop(second);
+        assert!(!SHARED_DBS.lock().contains_key(&canonical));
+
+        // the database was closed, so it can be opened again
+        let db = config().open().unwrap();
+        assert_eq!(db.get(b"k").unwrap().unwrap(), b"v");
+        drop(db);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn pin_tree() {
+        let db = Config::new()
+            .temporary(true)
+            .cache_capacity(64 * 1024)
+            .open()
+            .unwrap();
+
+        let pinned = db.open_tree(b"pinned").unwrap();
+        let unpinned = db.open_tree(b"unpinned").unwrap();
+        for i in 0..256_u32 {
+            pinned.insert(i.to_be_bytes(), vec![0; 64]).unwrap();
+            unpinned.insert(i.to_be_bytes(), vec![0; 64]).unwrap();
+        }
+        db.pin_tree(b"pinned").unwrap();
+        assert_eq!(db.pin_tree(b"missing"), Err(Error::CollectionNotFound));
+
+        // pages gained after pinning are pinned too
+        for i in 256..512_u32 {
+            pinned.insert(i.to_be_bytes(), vec![0; 64]).unwrap();
+            unpinned.insert(i.to_be_bytes(), vec![0; 64]).unwrap();
+        }
+
+        // walking a tree reads its pages back in, so the pages
+        // are listed before the cache is thrashed
+        let pids = |tree: &Tree| {
+            let mut pids = vec![];
+            for_each_page(tree, |pid, _view| pids.push(pid)).unwrap();
+            pids
+        };
+        let pinned_pids = pids(&pinned);
+        let unpinned_pids = pids(&unpinned);
+
+        let large = db.open_tree(b"large").unwrap();
+        for i in 0..4096_u32 {
+            large.insert(i.to_be_bytes(), vec![0; 1024]).unwrap();
+        }
+        for item in large.iter() {
+            item.unwrap();
+        }
+
+        let guard = pin();
+        let evicted = |pids: &[PageId]| {
+            pids.iter()
+                .filter(|pid| !db.context.pagecache.is_resident(**pid, &guard))
+                .count()
+        };
+        assert_eq!(evicted(&pinned_pids), 0);
+        assert!(evicted(&unpinned_pids) > 0);
+        drop(guard);
+
+        db.unpin_tree(b"pinned").unwrap();
+        assert!(!pinned.pinned.load(Acquire));
+    }
+
+    #[test]
+    fn set_cache_capacity() {
+        let db = Config::new()
+            .temporary(true)
+            .cache_capacity(1024 * 1024)
+            .open()
+            .unwrap();
+
+        for i in 0..4096_u32 {
+            db.insert(i.to_be_bytes(), vec![0; 128]).unwrap();
+        }
+        for item in db.iter() {
+            item.unwrap();
+        }
+        let pagecache = &db.context.pagecache;
+        assert!(pagecache.cache_size() > 64 * 1024);
+
+        db.set_cache_capacity(64 * 1024).unwrap();
+        assert_eq!(pagecache.cache_capacity(), 64 * 1024);
+        assert!(
+            pagecache.cache_size() <= 64 * 1024,
+            "{} bytes are still cached",
+            pagecache.cache_size()
+        );
+
+        // the evicted pages are read back in
+        assert_eq!(db.iter().count(), 4096);
+
+        db.set_cache_capacity(1024 * 1024).unwrap();
+        assert_eq!(pagecache.cache_capacity(), 1024 * 1024);
+        assert!(matches!(db.set_cache_capacity(1), Err(Error::Unsupported(_))));
+    }
+
+    #[test]
+    fn get_or_init() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let mut runs = 0;
+
+        for i in 0..2 {
+            let ran = db
+                .get_or_init(b"sentinel", |db| {
+                    runs += 1;
+                    db.insert(b"seed", b"value")?;
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(ran, i == 0);
+        }
+        assert_eq!(runs, 1);
+        assert_eq!(db.get(b"seed").unwrap().unwrap(), b"value");
+
+        // a failed init leaves the sentinel unset
+        let db = Config::new().temporary(true).open().unwrap();
+        let err = db.get_or_init(b"sentinel", |_| {
+            Err(Error::Unsupported("seeding failed"))
+        });
+        assert_eq!(err, Err(Error::Unsupported("seeding failed")));
+        assert!(!db.contains_key(b"sentinel").unwrap());
+    }
+
+    #[test]
+    fn rename_tree() {
+        let path = std::env::temp_dir().join("sled_rename_tree");
+        let _ = std::fs::remove_dir_all(&path);
+
+        let db = Config::new().path(&path).open().unwrap();
+        let tree = db.open_tree(b"old").unwrap();
+        for i in 0..1024_u32 {
+            tree.insert(i.to_be_bytes(), vec![0; 64]).unwrap();
+        }
+        let other = db.open_tree(b"other").unwrap();
+
+        assert!(matches!(
+            db.rename_tree(b"old", b"other"),
+            Err(Error::Unsupported(_))
+        ));
+        assert!(matches!(
+            db.rename_tree(b"missing", b"new"),
+            Err(Error::Unsupported(_))
+        ));
+        assert!(matches!(
+            db.rename_tree(DEFAULT_TREE_ID, b"new"),
+            Err(Error::Unsupported(_))
+        ));
+
+        db.rename_tree(b"old", b"new").unwrap();
+        assert!(!db.contains_tree(b"old"));
+        assert!(db.contains_tree(b"new"));
+        assert_eq!(
+            tree.get(0_u32.to_be_bytes()),
+            Err(Error::CollectionNotFound)
+        );
+
+        let renamed = db.open_tree(b"new").unwrap();
+        assert_eq!(renamed.len(), 1024);
+        renamed.insert(b"more", b"data").unwrap();
+
+        // the rename is persisted
+        drop((db, tree, other, renamed));
+        let db = Config::new().path(&path).open().unwrap();
+        assert!(!db.contains_tree(b"old"));
+        assert_eq!(db.open_tree(b"new").unwrap().len(), 1025);
+        drop(db);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn drop_trees_with_prefix() {
+        let db = Config::new().temporary(true).open().unwrap();
+        db.insert(b"k", b"v").unwrap();
+        for name in &[&b"tenant:a"[..], b"tenant:b", b"other"] {
+            db.open_tree(name).unwrap().insert(b"k", b"v").unwrap();
+        }
+
+        assert_eq!(db.drop_trees_with_prefix(b"tenant:"), Ok(2));
+        assert_eq!(db.drop_trees_with_prefix(b"tenant:"), Ok(0));
+
+        let guard = pin();
+        let meta = db.context.pagecache.get_meta(&guard);
+        assert!(meta.inner.keys().all(|name| !name.starts_with(b"tenant:")));
+        drop(guard);
+
+        let mut names = db.tree_names();
+        names.sort();
+        assert_eq!(names, vec![IVec::from(DEFAULT_TREE_ID), b"other".into()]);
+        assert!(db.open_tree(b"tenant:a").unwrap().is_empty());
+
+        // the default tree survives an empty prefix
+        assert_eq!(db.drop_trees_with_prefix(b""), Ok(2));
+        assert_eq!(db.tree_names(), vec![IVec::from(DEFAULT_TREE_ID)]);
+        assert_eq!(db.get(b"k").unwrap().unwrap(), b"v");
+    }
+
+    #[test]
+    fn open_namespace() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let a = db.open_namespace("a").unwrap();
+        let b = db.open_namespace("b").unwrap();
+        // would share tree names with "a" without the length
+        let a_colon = db.open_namespace("a:1:b").unwrap();
+
+        a.open_tree(b"t").unwrap().insert(b"k", b"a").unwrap();
+        b.open_tree(b"t").unwrap().insert(b"k", b"b").unwrap();
+        a_colon.open_tree(b"t").unwrap().insert(b"k", b"a:1:b").unwrap();
+        db.open_tree(b"t").unwrap().insert(b"k", b"db").unwrap();
+
+        let get =
+            |tree: Result<Tree>| tree.unwrap().get(b"k").unwrap().unwrap();
+        assert_eq!(get(a.open_tree(b"t")), b"a");
+        assert_eq!(get(b.open_tree(b"t")), b"b");
+        assert_eq!(get(db.open_tree(b"t")), b"db");
+        assert_eq!(a.tree_names(), vec![IVec::from(b"t")]);
+        assert!(!a.contains_tree(b"u"));
+        assert!(matches!(db.open_namespace(""), Err(Error::Unsupported(_))));
+
+        // dropping a namespace leaves the db and others open
+        drop(b);
+        assert!(a.drop_tree(b"t").unwrap());
+        assert!(!a.contains_tree(b"t"));
+        assert_eq!(a_colon.clear(), Ok(1));
+        assert!(a_colon.tree_names().is_empty());
+
+        let b = db.open_namespace("b").unwrap();
+        assert_eq!(get(b.open_tree(b"t")), b"b");
+        assert!(db.contains_tree(b"t"));
+    }
+
+    #[test]
+    fn space_amplification() {
+        let config = Config::new().temporary(true);
+        let db = config.open().unwrap();
+        let tree = db.open_tree(b"tree").unwrap();
+
+        let mut written = 0;
+        for i in 0..1000_u32 {
+            let value = vec![0; i as usize % 100];
+            written += 4 + value.len() as u64;
+            if i % 2 == 0 {
+                db.insert(i.to_be_bytes(), value).unwrap();
+            } else {
+                tree.insert(i.to_be_bytes(), value).unwrap();
+            }
+        }
+
+        db.flush().unwrap();
+        let stats = db.space_amplification().unwrap();
+        assert_eq!(stats.user_bytes, written);
+        assert_eq!(stats.physical_bytes, db.size_on_disk().unwrap());
+        assert!(stats.amplification >= 1.0, "{:?}", stats);
+
+        // overwrites, removals, compare and swaps and merges
+        // are accounted for too
+        db.insert(0_u32.to_be_bytes(), vec![0; 10]).unwrap();
+        db.remove(2_u32.to_be_bytes()).unwrap();
+        tree.compare_and_swap(
+            1_u32.to_be_bytes(),
+            Some(vec![0; 1]),
+            None::<&[u8]>,
+        )
+        .unwrap()
+        .unwrap();
+        tree.set_merge_operator(
+            |_k: &[u8], _old: Option<&[u8]>, new: &[u8]| Some(new.to_vec()),
+        );
+        tree.merge(3_u32.to_be_bytes(), vec![0; 50]).unwrap();
+        written = written + 10 - (4 + 2) - (4 + 1) + (50 - 3);
+        assert_eq!(db.space_amplification().unwrap().user_bytes, written);
+
+        // dropped trees are no longer counted
+        let tree_bytes: u64 = tree
+            .iter()
+            .map(|kv| {
+                let (k, v) = kv.unwrap();
+                (k.len() + v.len()) as u64
+            })
+            .sum();
+        drop(tree);
+        assert!(db.drop_tree(b"tree").unwrap());
+        written -= tree_bytes;
+        assert_eq!(db.space_amplification().unwrap().user_bytes, written);
+    }
+
+    #[test]
+    fn last_flush_time() {
+        let db =
+            Config::new().temporary(true).flush_every_ms(None).open().unwrap();
+
+        let before = std::time::SystemTime::now();
+        db.insert(b"k", b"v").unwrap();
+        db.flush().unwrap();
+        let flushed = db.last_flush_time().unwrap();
+        assert!(flushed >= before);
+        assert!(flushed <= std::time::SystemTime::now());
+
+        // nothing new to flush leaves the time unchanged
+        db.flush().unwrap();
+        assert_eq!(db.last_flush_time(), Some(flushed));
+    }
+
+    #[test]
+    fn with_quiesced() {
+        fn copy_dir(from: &std::path::Path, to: &std::path::Path) {
+            std::fs::create_dir_all(to).unwrap();
+            for entry in std::fs::read_dir(from).unwrap() {
+                let entry = entry.unwrap();
+                let to = to.join(entry.file_name());
+                if entry.file_type().unwrap().is_dir() {
+                    copy_dir(&entry.path(), &to);
+                } else {
+                    std::fs::copy(entry.path(), to).unwrap();
+                }
+            }
+        }
+
+        let path = std::env::temp_dir().join("test_with_quiesced");
+        let copy = std::env::temp_dir().join("test_with_quiesced_copy");
+        let _ = std::fs::remove_dir_all(&path);
+        let _ = std::fs::remove_dir_all(&copy);
+
+        let db = Config::new()
+            .path(&path)
+            .allow_risky_filesystem(true)
+            .open()
+            .unwrap();
+        db.insert(b"large", vec![1; 64 * 1024]).unwrap();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let inserted = Arc::new(AtomicU64::new(0));
+        let writer = {
+            let db = db.clone();
+            let stop = stop.clone();
+            let inserted = inserted.clone();
+            std::thread::spawn(move || {
+                let mut i = 0_u64;
+                while !stop.load(Acquire) {
+                    db.insert(i.to_be_bytes(), vec![0; 64]).unwrap();
+                    i += 1;
+                    inserted.store(i, Release);
+                }
+            })
+        };
+        while inserted.load(Acquire) < 100 {
+            std::thread::yield_now();
+        }
+
+        let (before, after) = db
+            .with_quiesced(|| {
+                // only methods that report on the state of the
+                // database may be called in here
+                let lsn = db.last_written_lsn();
+                let before = inserted.load(Acquire);
+                assert_eq!(db.pending_flush_bytes(), 0);
+                copy_dir(&path, &copy);
+
+                // the writer is blocked until we return, after
+                // finishing an insert that had already reserved
+                // its space in the log, if any
+                std::thread::sleep(Duration::from_millis(50));
+                assert_eq!(db.last_written_lsn(), lsn);
+                (before, inserted.load(Acquire))
+            })
+            .unwrap();
+        assert!(after <= before + 1);
+
+        stop.store(true, Release);
+        writer.join().unwrap();
+        assert!(inserted.load(Acquire) > after);
+        drop(db);
+
+        let restored = Config::new()
+            .path(&copy)
+            .allow_risky_filesystem(true)
+            .open()
+            .unwrap();
+        assert!(restored.was_recovered());
+        let restored_inserts = u64::try_from(restored.len() - 1).unwrap();
+        assert!(before <= restored_inserts && restored_inserts <= after);
+        assert_eq!(
+            restored.get(b"large").unwrap().unwrap(),
+            vec![1; 64 * 1024]
+        );
+        restored.verify_integrity().unwrap();
+        drop(restored);
+
+        std::fs::remove_dir_all(&path).unwrap();
+        std::fs::remove_dir_all(&copy).unwrap();
+    }
+
+    #[test]
+    fn close() {
+        let path = std::env::temp_dir().join("test_db_close");
+        let _ = std::fs::remove_dir_all(&path);
+        let config = Config::new()
+            .path(&path)
+            .fast_clean_restart(true)
+            .snapshot_after_ops(8)
+            .allow_risky_filesystem(true);
+
+        let db = config.open().unwrap();
+        let tree = db.open_tree(b"tree").unwrap();
+        db.insert(b"a", b"1").unwrap();
+        tree.insert(b"b", b"2").unwrap();
+        // keep background snapshots running up to the close
+        for i in 0_u32..256 {
+            db.insert(i.to_be_bytes(), b"v").unwrap();
+        }
+        assert!(!db.is_only_handle());
+        drop(tree);
+        assert!(db.is_only_handle());
+        assert!(!db.clone().is_only_handle());
+        assert_eq!(db.close(), Ok(()));
+
+        // the lock is released, and the clean shutdown is
+        // recorded, before close returns
+        assert!(path.join("clean_shutdown").exists());
+        #[cfg(all(
+            not(miri),
+            any(windows, target_os = "linux", target_os = "macos")
+        ))]
+        {
+            use fs2::FileExt;
+            let file = std::fs::File::open(path.join("db")).unwrap();
+            file.try_lock_exclusive().unwrap();
+            file.unlock().unwrap();
+        }
+        let db = config.open().unwrap();
+        assert_eq!(db.get(b"a").unwrap().unwrap(), b"1");
+        let tree = db.open_tree(b"tree").unwrap();
+        assert_eq!(tree.get(b"b").unwrap().unwrap(), b"2");
+
+        // closing one of several handles leaves the others usable
+        let clone = db.clone();
+        assert_eq!(db.close(), Ok(()));
+        tree.insert(b"c", b"3").unwrap();
+        drop(tree);
+        assert_eq!(clone.close(), Ok(()));
+
+        let db = config.open().unwrap();
+        let tree = db.open_tree(b"tree").unwrap();
+        assert_eq!(tree.get(b"c").unwrap().unwrap(), b"3");
+        drop(tree);
+        db.close().unwrap();
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn diagnostics() {
+        let db =
+            Config::new().temporary(true).segment_size(4096).open().unwrap();
+        db.insert(b"small", vec![0; 64]).unwrap();
+        db.insert(b"large", vec![0; 64 * 1024]).unwrap();
+        db.flush().unwrap();
+
+        let report = db.diagnostics();
+        let (major, minor) = crate::config::crate_version();
+        assert!(report.contains(&format!("version: {}.{}", major, minor)));
+        assert!(report.contains("segment_size: 4096"));
+        assert!(report.contains("global error: none"));
+        assert!(report.contains("byte slots: 1 total, 0 free"));
+        assert!(report.contains("compacted segments: "));
+    }
+
+    #[test]
+    fn compaction_fill_threshold() {
+        let compacted = |threshold: f32| {
+            let db = Config::new()
+                .temporary(true)
+                .segment_size(4096)
+                .compaction_fill_threshold(threshold)
+                .open()
+                .unwrap();
+            for i in 0..2048_u32 {
+                db.insert(i.to_be_bytes(), vec![0; 64]).unwrap();
+            }
+            db.flush().unwrap();
+
+            // replace a quarter of the data
+            for i in (0..2048_u32).step_by(4) {
+                db.insert(i.to_be_bytes(), vec![1; 64]).unwrap();
+            }
+            db.flush().unwrap();
+
+            db.context.pagecache.compacted_segments()
+        };
+
+        let eager = compacted(1.0);
+        let lazy = compacted(0.1);
+        assert!(eager > 0);
+        assert!(lazy < eager, "{} segments compacted at 0.1", lazy);
+
+        assert!(matches!(
+            Config::new().temporary(true).compaction_fill_threshold(1.5).open(),
+            Err(Error::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn max_segment_reuses() {
+        let compacted = |max_reuses: Option<u32>| {
+            // a low threshold, so that replacing a quarter of the
+            // data does not compact anything on its own
+            let db = Config::new()
+                .temporary(true)
+                .segment_size(4096)
+                .compaction_fill_threshold(0.1)
+                .max_segment_reuses(max_reuses)
+                .open()
+                .unwrap();
+            for i in 0..2048_u32 {
+                db.insert(i.to_be_bytes(), vec![0; 64]).unwrap();
+            }
+            db.flush().unwrap();
+
+            // replace a quarter of the data
+            for i in (0..2048_u32).step_by(4) {
+                db.insert(i.to_be_bytes(), vec![1; 64]).unwrap();
+            }
+            db.flush().unwrap();
+
+            db.context.pagecache.compacted_segments()
+        };
+
+        let limited = compacted(Some(1));
+        let unlimited = compacted(None);
+        assert!(
+            limited > unlimited,
+            "{} segments compacted with a limit of 1, {} without",
+            limited,
+            unlimited
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics_prometheus() {
+        let db = Config::new().temporary(true).open().unwrap();
+        for i in 0..64_u32 {
+            db.insert(i.to_be_bytes(), vec![0; 64]).unwrap();
+            db.get(i.to_be_bytes()).unwrap();
+        }
+        db.flush().unwrap();
+
+        let is_name = |name: &str| {
+            !name.is_empty()
+                && !name.starts_with(|c: char| c.is_ascii_digit())
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        };
+
+        let text = db.metrics_prometheus();
+        assert!(text.contains("sled_cache_hits"));
+        assert!(text.contains("sled_space_amplification_ratio"));
+        assert!(text.ends_with('\n'));
+        for line in text.lines() {
+            if let Some(comment) = line.strip_prefix("# ") {
+                let mut parts = comment.splitn(3, ' ');
+                let keyword = parts.next().unwrap();
+                assert!(keyword == "HELP" || keyword == "TYPE", "{}", line);
+                assert!(is_name(parts.next().unwrap()), "{}", line);
+                if keyword == "TYPE" {
+                    let kind = parts.next().unwrap();
+                    assert!(kind == "counter" || kind == "gauge", "{}", line);
+                }
+                continue;
+            }
+
+            let (series, value) = line.rsplit_once(' ').unwrap();
+            assert!(value.parse::<f64>().is_ok(), "{}", line);
+            let (name, labels) = series.split_once('{').unwrap();
+            assert!(name.starts_with("sled_") && is_name(name), "{}", line);
+            let label = labels.strip_suffix('}').unwrap();
+            let (key, quoted) = label.split_once('=').unwrap();
+            assert_eq!(key, "instance");
+            assert!(
+                quoted.len() >= 2
+                    && quoted.starts_with('"')
+                    && quoted.ends_with('"'),
+                "{}",
+                line
+            );
+        }
+    }
+}
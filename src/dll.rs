@@ -173,6 +173,32 @@ impl DoublyLinkedList {
         }
     }
 
+    /// Unlinks a node from anywhere in the list, returning
+    /// ownership of it.
+    pub(crate) fn remove(&mut self, ptr: *mut Node) -> Box<Node> {
+        self.len -= 1;
+
+        unsafe {
+            if self.tail == ptr {
+                self.tail = (*ptr).next;
+            }
+
+            if self.head == ptr {
+                self.head = (*ptr).prev;
+            }
+
+            let mut node = Box::from_raw(ptr);
+
+            node.unwire();
+
+            node
+        }
+    }
+
+    pub(crate) fn peek_tail(&self) -> Option<&CacheAccess> {
+        unsafe { self.tail.as_ref().map(|tail| &**tail) }
+    }
+
     #[cfg(test)]
     pub(crate) fn pop_head(&mut self) -> Option<CacheAccess> {
         if self.head.is_null() {
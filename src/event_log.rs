@@ -36,6 +36,12 @@ enum Event {
     MetaOnRecovery { meta: Meta },
     RecoveredLsn(Lsn),
     Stabilized(Lsn),
+    ConfigValidated,
+    ConfigWritten,
+    ConfigReadMismatch { field: &'static str },
+    LockAcquired,
+    LockContended,
+    ReplayedSegments(usize),
 }
 
 /// A lock-free queue of Events.
@@ -139,6 +145,12 @@ impl EventLog {
                         assert_eq!(meta, rec_meta);
                     }
                 }
+                Event::ConfigValidated
+                | Event::ConfigWritten
+                | Event::ConfigReadMismatch { .. }
+                | Event::LockAcquired
+                | Event::LockContended
+                | Event::ReplayedSegments(_) => {}
             }
         }
 
@@ -155,6 +167,11 @@ impl EventLog {
         self.inner.push(Event::RecoveredLsn(lsn), &guard);
     }
 
+    pub(crate) fn replayed_segments(&self, segments: usize) {
+        let guard = pin();
+        self.inner.push(Event::ReplayedSegments(segments), &guard);
+    }
+
     pub(crate) fn pages_before_restart(
         &self,
         pages: Map<PageId, Vec<DiskPtr>>,
@@ -177,4 +194,122 @@ impl EventLog {
         let guard = pin();
         self.inner.push(Event::MetaOnRecovery { meta }, &guard);
     }
+
+    pub(crate) fn config_validated(&self) {
+        let guard = pin();
+        self.inner.push(Event::ConfigValidated, &guard);
+    }
+
+    pub(crate) fn config_written(&self) {
+        let guard = pin();
+        self.inner.push(Event::ConfigWritten, &guard);
+    }
+
+    pub(crate) fn config_read_mismatch(&self, field: &'static str) {
+        let guard = pin();
+        self.inner.push(Event::ConfigReadMismatch { field }, &guard);
+    }
+
+    pub(crate) fn lock_acquired(&self) {
+        let guard = pin();
+        self.inner.push(Event::LockAcquired, &guard);
+    }
+
+    pub(crate) fn lock_contended(&self) {
+        let guard = pin();
+        self.inner.push(Event::LockContended, &guard);
+    }
+
+    #[cfg(test)]
+    fn config_events(&self) -> Vec<Event> {
+        let guard = pin();
+        let mut ret: Vec<Event> = self
+            .iter(&guard)
+            .filter(|e| {
+                matches!(
+                    e,
+                    Event::ConfigValidated
+                        | Event::ConfigWritten
+                        | Event::ConfigReadMismatch { .. }
+                        | Event::LockAcquired
+                        | Event::LockContended
+                )
+            })
+            .cloned()
+            .collect();
+        ret.reverse();
+        ret
+    }
+
+    #[cfg(test)]
+    fn last_replayed_segments(&self) -> Option<usize> {
+        let guard = pin();
+        self.iter(&guard).find_map(|e| {
+            if let Event::ReplayedSegments(segments) = e {
+                Some(*segments)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_written_after_lock_acquired() {
+        let config = Config::new().temporary(true);
+        let db = config.open().unwrap();
+
+        let events = db.context.event_log.config_events();
+
+        let written = events
+            .iter()
+            .position(|e| matches!(e, Event::ConfigWritten))
+            .expect("ConfigWritten should be logged on first open");
+        let locked = events
+            .iter()
+            .position(|e| matches!(e, Event::LockAcquired))
+            .expect("LockAcquired should be logged on open");
+
+        assert!(matches!(events[0], Event::ConfigValidated));
+        // the config is only written once the lock is held
+        assert!(locked < written, "unexpected event order: {:?}", events);
+    }
+
+    #[test]
+    fn fast_clean_restart_replays_nothing() {
+        for fast_clean_restart in [true, false] {
+            let path = std::env::temp_dir().join(format!(
+                "sled_fast_clean_restart_{}",
+                fast_clean_restart
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+
+            let config = Config::new()
+                .path(&path)
+                .snapshot_after_ops(1_000_000)
+                .fast_clean_restart(fast_clean_restart);
+
+            let db = config.open().unwrap();
+            for i in 0_u32..100 {
+                db.insert(i.to_be_bytes(), vec![0; 64]).unwrap();
+            }
+            drop(db);
+
+            let db = config.open().unwrap();
+            let replayed = config.event_log.last_replayed_segments().unwrap();
+            if fast_clean_restart {
+                assert_eq!(replayed, 0);
+            } else {
+                assert!(replayed > 0);
+            }
+            assert_eq!(db.len(), 100);
+            drop(db);
+
+            std::fs::remove_dir_all(&path).unwrap();
+        }
+    }
 }
@@ -1,5 +1,5 @@
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use parking_lot::{Condvar, Mutex};
 
@@ -22,34 +22,121 @@ impl ShutdownState {
     }
 }
 
+/// Stored in the flush interval to signal that periodic
+/// flushing is paused until a new interval is set.
+const PAUSED: u64 = u64::MAX;
+
 #[derive(Debug)]
 pub(crate) struct Flusher {
     shutdown: Arc<Mutex<ShutdownState>>,
     sc: Arc<Condvar>,
+    flush_every_ms: Arc<AtomicU64>,
+    // how many times the interval has been changed, and how
+    // many of those changes the flusher thread has flushed
+    // with, so that a change can wait to take effect.
+    interval_changes: Arc<AtomicU64>,
+    applied_changes: Arc<AtomicU64>,
     join_handle: Mutex<Option<std::thread::JoinHandle<()>>>,
+    // set instead of `join_handle` when the flushing is done
+    // by the `GlobalBackgroundPool`
+    job: Option<Arc<FlushJob>>,
 }
 
 impl Flusher {
-    /// Spawns a thread that periodically calls `callback` until dropped.
+    /// Spawns a thread that periodically calls `callback` until dropped,
+    /// or hands the work to the `GlobalBackgroundPool` if
+    /// `Config::max_background_threads` is set.
     pub(crate) fn new(
         name: String,
+        stack_size: Option<usize>,
         pagecache: PageCache,
         flush_every_ms: u64,
     ) -> Self {
         #[allow(clippy::mutex_atomic)] // mutex used in CondVar below
         let shutdown = Arc::new(Mutex::new(ShutdownState::Running));
         let sc = Arc::new(Condvar::new());
+        let flush_every_ms = Arc::new(AtomicU64::new(flush_every_ms));
+        let interval_changes = Arc::new(AtomicU64::new(0));
+        let applied_changes = Arc::new(AtomicU64::new(0));
+
+        if let Some(max_threads) = pagecache.config.max_background_threads {
+            let job = Arc::new(FlushJob {
+                pagecache,
+                flush_every_ms: flush_every_ms.clone(),
+            });
+            POOL.register(job.clone(), max_threads);
+            return Self {
+                shutdown,
+                sc,
+                flush_every_ms,
+                interval_changes,
+                applied_changes,
+                join_handle: Mutex::new(None),
+                job: Some(job),
+            };
+        }
 
-        let join_handle = thread::Builder::new()
-            .name(name)
+        let mut builder = thread::Builder::new().name(name);
+        if let Some(stack_size) = stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+
+        let join_handle = builder
             .spawn({
                 let shutdown2 = shutdown.clone();
                 let sc2 = sc.clone();
-                move || run(&shutdown2, &sc2, &pagecache, flush_every_ms)
+                let flush_every_ms2 = flush_every_ms.clone();
+                let interval_changes2 = interval_changes.clone();
+                let applied_changes2 = applied_changes.clone();
+                move || {
+                    run(
+                        &shutdown2,
+                        &sc2,
+                        &pagecache,
+                        &flush_every_ms2,
+                        &interval_changes2,
+                        &applied_changes2,
+                    )
+                }
             })
             .unwrap();
 
-        Self { shutdown, sc, join_handle: Mutex::new(Some(join_handle)) }
+        Self {
+            shutdown,
+            sc,
+            flush_every_ms,
+            interval_changes,
+            applied_changes,
+            join_handle: Mutex::new(Some(join_handle)),
+            job: None,
+        }
+    }
+
+    /// Changes the interval that the background thread
+    /// flushes at, taking effect immediately. `None`
+    /// pauses periodic flushing until a new interval
+    /// is set. Unless the flushing is done by the
+    /// `GlobalBackgroundPool`, this returns once the
+    /// thread has flushed with the new interval.
+    pub(crate) fn set_flush_every_ms(&self, flush_every_ms: Option<u64>) {
+        // holding the mutex makes this linearized with
+        // the flusher thread going to sleep.
+        let mut shutdown = self.shutdown.lock();
+        self.flush_every_ms.store(flush_every_ms.unwrap_or(PAUSED), SeqCst);
+        let change = self.interval_changes.fetch_add(1, SeqCst) + 1;
+
+        let _notified = self.sc.notify_all();
+        if self.job.is_some() {
+            drop(shutdown);
+            POOL.notify();
+            return;
+        }
+
+        while shutdown.is_running()
+            && self.applied_changes.load(SeqCst) < change
+        {
+            self.sc.wait(&mut shutdown);
+        }
     }
 }
 
@@ -57,13 +144,18 @@ fn run(
     shutdown_mu: &Arc<Mutex<ShutdownState>>,
     sc: &Arc<Condvar>,
     pagecache: &PageCache,
-    flush_every_ms: u64,
+    flush_every_ms: &AtomicU64,
+    interval_changes: &AtomicU64,
+    applied_changes: &AtomicU64,
 ) {
-    let flush_every = Duration::from_millis(flush_every_ms);
     let mut shutdown = shutdown_mu.lock();
     let mut wrote_data = false;
     while shutdown.is_running() || wrote_data {
         let before = std::time::Instant::now();
+        let changes = interval_changes.load(SeqCst);
+        let flush_every_ms_value = flush_every_ms.load(SeqCst);
+        let paused = flush_every_ms_value == PAUSED;
+        let flush_every = Duration::from_millis(flush_every_ms_value);
         let cc = concurrency_control::read();
         match pagecache.log.roll_iobuf() {
             Ok(0) => {
@@ -132,7 +224,7 @@ fn run(
                 && before.elapsed() < flush_every / 2
         } {}
 
-        if let Err(e) = pagecache.config.file.sync_all() {
+        if let Err(e) = pagecache.config.sync_file(&pagecache.config.file) {
             error!("failed to fsync from periodic flush thread: {}", e);
         }
 
@@ -140,7 +232,20 @@ fn run(
             .checked_sub(before.elapsed())
             .unwrap_or_else(|| Duration::from_millis(1));
 
-        if shutdown.is_running() {
+        // wakes up any `set_flush_every_ms` that is waiting
+        // for this flush with its new interval.
+        if applied_changes.swap(changes, SeqCst) != changes {
+            let _notified = sc.notify_all();
+        }
+
+        if shutdown.is_running() && paused {
+            // sleep until we are either shut down or
+            // given a new flush interval.
+            while shutdown.is_running() && flush_every_ms.load(SeqCst) == PAUSED
+            {
+                sc.wait(&mut shutdown);
+            }
+        } else if shutdown.is_running() {
             // only sleep before the next flush if we are
             // running normally. if we're shutting down,
             // flush faster.
@@ -159,6 +264,12 @@ fn run(
 
 impl Drop for Flusher {
     fn drop(&mut self) {
+        if let Some(ref job) = self.job {
+            POOL.unregister(job);
+            job.flush_remaining();
+            return;
+        }
+
         let mut shutdown = self.shutdown.lock();
         if shutdown.is_running() {
             *shutdown = ShutdownState::ShuttingDown;
@@ -182,3 +293,256 @@ impl Drop for Flusher {
         }
     }
 }
+
+static POOL: Lazy<GlobalBackgroundPool, fn() -> GlobalBackgroundPool> =
+    Lazy::new(Default::default);
+
+/// The periodic flushing of one database, run by the
+/// `GlobalBackgroundPool`.
+#[derive(Debug)]
+struct FlushJob {
+    pagecache: PageCache,
+    flush_every_ms: Arc<AtomicU64>,
+}
+
+impl FlushJob {
+    /// Writes out the current IO buffer, spends up to half of
+    /// the flush interval rewriting pages out of fragmented
+    /// segments, and fsyncs the file.
+    fn run_once(&self) -> Result<()> {
+        let before = Instant::now();
+        let flush_every =
+            Duration::from_millis(self.flush_every_ms.load(SeqCst));
+
+        let cc = concurrency_control::read();
+        self.pagecache.log.roll_iobuf()?;
+        drop(cc);
+
+        while self.pagecache.attempt_gc()? {
+            if before.elapsed() >= flush_every / 2 {
+                break;
+            }
+        }
+
+        if let Err(e) =
+            self.pagecache.config.sync_file(&self.pagecache.config.file)
+        {
+            error!("failed to fsync from background pool: {}", e);
+        }
+
+        Ok(())
+    }
+
+    /// Writes out everything that is left once the job has
+    /// been removed from the pool.
+    fn flush_remaining(&self) {
+        loop {
+            let cc = concurrency_control::read();
+            match self.pagecache.log.roll_iobuf() {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    error!("failed to flush on shutdown: {}", e);
+                    self.pagecache.log.iobufs.set_global_error(e);
+                    return;
+                }
+            }
+            drop(cc);
+        }
+
+        if let Err(e) =
+            self.pagecache.config.sync_file(&self.pagecache.config.file)
+        {
+            error!("failed to fsync on shutdown: {}", e);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PoolEntry {
+    job: Arc<FlushJob>,
+    last_run: Instant,
+    running: bool,
+}
+
+impl PoolEntry {
+    fn next_run(&self) -> Option<Instant> {
+        let flush_every_ms = self.job.flush_every_ms.load(SeqCst);
+        if self.running || flush_every_ms == PAUSED {
+            None
+        } else {
+            Some(self.last_run + Duration::from_millis(flush_every_ms))
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    entries: Vec<PoolEntry>,
+    threads: usize,
+}
+
+/// A process-wide set of threads that takes turns running
+/// the periodic flushes of every database opened with
+/// `Config::max_background_threads`, in place of a flush
+/// thread per database. It spawns threads as databases
+/// register, up to the largest cap any of them asked for.
+#[derive(Debug, Default)]
+pub(crate) struct GlobalBackgroundPool {
+    mu: Mutex<PoolState>,
+    cv: Condvar,
+}
+
+impl GlobalBackgroundPool {
+    fn register(&'static self, job: Arc<FlushJob>, max_threads: usize) {
+        let mut state = self.mu.lock();
+        state.entries.push(PoolEntry {
+            job,
+            last_run: Instant::now(),
+            running: false,
+        });
+
+        while state.threads < max_threads {
+            let name = format!("sled-background-{}", state.threads);
+            thread::Builder::new()
+                .name(name)
+                .spawn(move || self.perform_work())
+                .expect("failed to spawn background pool thread");
+            state.threads += 1;
+        }
+
+        drop(state);
+        self.notify();
+    }
+
+    /// Removes a job, waiting for a run of it that is in
+    /// progress to finish first.
+    fn unregister(&self, job: &Arc<FlushJob>) {
+        let mut state = self.mu.lock();
+        loop {
+            let position = state
+                .entries
+                .iter()
+                .position(|entry| Arc::ptr_eq(&entry.job, job));
+            match position {
+                Some(idx) if state.entries[idx].running => {
+                    self.cv.wait(&mut state);
+                }
+                Some(idx) => {
+                    state.entries.swap_remove(idx);
+                    return;
+                }
+                // dropped by a worker after it failed
+                None => return,
+            }
+        }
+    }
+
+    fn notify(&self) {
+        // holding the mutex makes this linearized with
+        // workers going to sleep.
+        let state = self.mu.lock();
+        drop(state);
+        let _notified = self.cv.notify_all();
+    }
+
+    fn perform_work(&self) {
+        let mut state = self.mu.lock();
+        loop {
+            let next = state
+                .entries
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, entry)| Some((entry.next_run()?, idx)))
+                .min();
+
+            let idx = match next {
+                None => {
+                    self.cv.wait(&mut state);
+                    continue;
+                }
+                Some((at, _)) if at > Instant::now() => {
+                    let _ = self.cv.wait_until(&mut state, at);
+                    continue;
+                }
+                Some((_, idx)) => idx,
+            };
+
+            state.entries[idx].running = true;
+            let job = state.entries[idx].job.clone();
+            drop(state);
+
+            let res = job.run_once();
+
+            state = self.mu.lock();
+            let idx = state
+                .entries
+                .iter()
+                .position(|entry| Arc::ptr_eq(&entry.job, &job))
+                .expect("jobs are only removed once they stop running");
+            if let Err(e) = res {
+                error!("failed to flush from background pool: {}", e);
+                job.pagecache.log.iobufs.set_global_error(e);
+                state.entries.swap_remove(idx);
+            } else {
+                state.entries[idx].running = false;
+                state.entries[idx].last_run = Instant::now();
+            }
+            let _notified = self.cv.notify_all();
+        }
+    }
+
+    #[cfg(test)]
+    fn thread_count(&self) -> usize {
+        self.mu.lock().threads
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_background_threads() {
+        let dbs: Vec<Db> = (0..8)
+            .map(|_| {
+                Config::new()
+                    .temporary(true)
+                    .flush_every_ms(Some(10))
+                    .flush_thread_name("capped-flush".to_owned())
+                    .max_background_threads(Some(2))
+                    .open()
+                    .unwrap()
+            })
+            .collect();
+
+        assert!(POOL.thread_count() <= 2);
+        #[cfg(target_os = "linux")]
+        {
+            let dedicated = std::fs::read_dir("/proc/self/task")
+                .unwrap()
+                .filter(|task| {
+                    let comm = task.as_ref().unwrap().path().join("comm");
+                    matches!(
+                        std::fs::read_to_string(comm),
+                        Ok(name) if name.trim() == "capped-flush"
+                    )
+                })
+                .count();
+            assert_eq!(dedicated, 0);
+        }
+
+        // the pool still flushes every database
+        for db in &dbs {
+            db.insert(b"k", b"v").unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while dbs.iter().any(|db| db.pending_flush_bytes() > 0) {
+            assert!(Instant::now() < deadline, "pool did not flush");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        drop(dbs);
+        assert!(POOL.mu.lock().entries.is_empty());
+    }
+}
@@ -189,6 +189,7 @@ mod cache_padded;
 mod concurrency_control;
 mod config;
 mod context;
+mod crc;
 mod db;
 mod dll;
 mod ebr;
@@ -275,10 +276,19 @@ pub use self::{
 
 pub use self::{
     batch::Batch,
-    config::{Config, Mode},
-    db::Db,
+    config::{
+        clear_stale_marker, crate_version as version, diagnose_lock,
+        AccessPattern, CompressionFactor, Config, CrcVariant, DirFsyncPolicy,
+        DurabilityProfile, EvictionPolicy, LockDiagnosis, MemoryEstimate, Mode,
+        OpenOutcome, OpenWarning, ParameterDiff, PartialConfig,
+        RecoveryProgress, SegmentSizePreset, SegmentTransform, StagedDb,
+        StorageLayout, SyncMode, VersionPolicy,
+    },
+    context::set_max_open_databases,
+    db::{Db, Namespace, SpaceAmplification},
     iter::Iter,
     ivec::IVec,
+    pagecache::{IntegrityReport, ScrubReport},
     result::{Error, Result},
     subscriber::{Event, Subscriber},
     transaction::Transactional,
@@ -358,13 +368,17 @@ fn crc32(buf: &[u8]) -> u32 {
     hasher.finalize()
 }
 
-fn calculate_message_crc32(header: &[u8], body: &[u8]) -> u32 {
+fn calculate_message_crc32(
+    crc_variant: CrcVariant,
+    header: &[u8],
+    body: &[u8],
+) -> u32 {
     trace!(
         "calculating crc32 for header len {} body len {}",
         header.len(),
         body.len()
     );
-    let mut hasher = crc32fast::Hasher::new();
+    let mut hasher = crc::Hasher::new(crc_variant);
     hasher.update(body);
     hasher.update(&header[4..]);
     let crc32 = hasher.finalize();
@@ -511,6 +525,24 @@ mod compile_time_assertions {
         _assert_send_sync::<Error>();
         _assert_send_sync::<Event>();
         _assert_send_sync::<Mode>();
+        _assert_send_sync::<DurabilityProfile>();
+        _assert_send_sync::<DirFsyncPolicy>();
+        _assert_send_sync::<AccessPattern>();
+        _assert_send_sync::<SyncMode>();
+        _assert_send_sync::<EvictionPolicy>();
+        _assert_send_sync::<VersionPolicy>();
+        _assert_send_sync::<CompressionFactor>();
+        _assert_send_sync::<OpenOutcome>();
+        _assert_send_sync::<OpenWarning>();
+        _assert_send_sync::<StagedDb>();
+        _assert_send_sync::<ParameterDiff>();
+        _assert_send_sync::<MemoryEstimate>();
+        _assert_send_sync::<StorageLayout>();
+        _assert_send_sync::<LockDiagnosis>();
+        _assert_send_sync::<RecoveryProgress>();
+        _assert_send_sync::<SegmentSizePreset>();
+        _assert_send_sync::<IntegrityReport>();
+        _assert_send_sync::<ScrubReport>();
     }
 
     const fn _assert_send<S: Send>() {}
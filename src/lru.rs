@@ -12,8 +12,8 @@ use crate::{
     atomic_shim::AtomicU64,
     debug_delay,
     dll::{DoublyLinkedList, Node},
-    fastlock::FastLock,
-    FastSet8, Guard, PageId,
+    fastlock::{FastLock, FastLockGuard},
+    EvictionPolicy, FastMap8, FastSet8, Guard, PageId,
 };
 
 #[cfg(any(test, feature = "lock_free_delays"))]
@@ -28,6 +28,19 @@ const N_SHARDS: usize = 2;
 #[cfg(not(any(test, feature = "lock_free_delays")))]
 const N_SHARDS: usize = 256;
 
+/// The share of a shard's capacity, in fifths, that the
+/// protected segment may use under `EvictionPolicy::SegmentedLru`.
+const PROTECTED_FIFTHS: usize = 4;
+
+/// The access count at which `EvictionPolicy::Lfu` stops
+/// counting further accesses to a page.
+const MAX_FREQUENCY: u8 = 15;
+
+/// Under `EvictionPolicy::Lfu`, all access counts are halved
+/// after this many accesses per cached page, so that pages
+/// which were popular a long time ago are eventually evicted.
+const FREQUENCY_AGING_FACTOR: usize = 10;
+
 struct AccessBlock {
     len: AtomicUsize,
     block: [AtomicU64; MAX_QUEUE_ITEMS],
@@ -257,7 +270,7 @@ pub struct Lru {
 
 impl Lru {
     /// Instantiates a new `Lru` cache.
-    pub(crate) fn new(cache_capacity: usize) -> Self {
+    pub(crate) fn new(cache_capacity: usize, policy: EvictionPolicy) -> Self {
         assert!(
             cache_capacity >= N_SHARDS,
             "Please configure the cache \
@@ -267,7 +280,10 @@ impl Lru {
 
         let mut shards = Vec::with_capacity(N_SHARDS);
         shards.resize_with(N_SHARDS, || {
-            (AccessQueue::default(), FastLock::new(Shard::new(shard_capacity)))
+            (
+                AccessQueue::default(),
+                FastLock::new(Shard::new(shard_capacity, policy)),
+            )
         });
 
         Self { shards }
@@ -316,6 +332,83 @@ impl Lru {
         }
         ret
     }
+
+    /// Changes the capacity of the cache, returning the pages
+    /// that have to be evicted to fit within a smaller one.
+    pub(crate) fn set_capacity(&self, cache_capacity: usize) -> Vec<PageId> {
+        const SHARD_BITS: usize = N_SHARDS.trailing_zeros() as usize;
+
+        assert!(
+            cache_capacity >= N_SHARDS,
+            "Please configure the cache \
+             capacity to be at least 256 bytes"
+        );
+        let shard_capacity = cache_capacity / N_SHARDS;
+
+        let mut ret = vec![];
+        for (shard_idx, (_access_queue, shard_mu)) in
+            self.shards.iter().enumerate()
+        {
+            let mut to_evict = vec![];
+            let mut shard = spin_lock(shard_mu);
+            shard.capacity = shard_capacity;
+            shard.evict_over_capacity(&mut to_evict);
+            drop(shard);
+
+            for pos in to_evict {
+                let address = (PageId::from(pos) << SHARD_BITS)
+                    + PageId::try_from(shard_idx).unwrap();
+                ret.push(address);
+            }
+        }
+        ret
+    }
+
+    /// Prevents a page from being evicted until `unpin` is
+    /// called. Its bytes still count toward the capacity.
+    pub(crate) fn pin(&self, id: PageId) {
+        self.with_shard(id, Shard::pin);
+    }
+
+    /// Makes a page pinned by `pin` evictable again.
+    pub(crate) fn unpin(&self, id: PageId) {
+        self.with_shard(id, Shard::unpin);
+    }
+
+    fn with_shard(&self, id: PageId, f: impl FnOnce(&mut Shard, u32)) {
+        const SHARD_BITS: usize = N_SHARDS.trailing_zeros() as usize;
+
+        let shards = N_SHARDS as u64;
+        let (shard_idx, shifted_pid) = (id % shards, id >> SHARD_BITS);
+        let shard_mu = &self.shards[safe_usize(shard_idx)].1;
+        let pid = u32::try_from(shifted_pid)
+            .expect("expected caller to shift pid down");
+
+        f(&mut spin_lock(shard_mu), pid);
+    }
+
+    /// Returns the bytes that are counted toward the cache
+    /// capacity, and the capacity itself. Accesses that are
+    /// still queued for a shard are not reflected yet.
+    pub(crate) fn occupancy(&self) -> (usize, usize) {
+        let mut size = 0;
+        let mut capacity = 0;
+        for (_access_queue, shard_mu) in &self.shards {
+            let shard = spin_lock(shard_mu);
+            size += shard.size;
+            capacity += shard.capacity;
+        }
+        (size, capacity)
+    }
+}
+
+fn spin_lock(shard_mu: &FastLock<Shard>) -> FastLockGuard<'_, Shard> {
+    loop {
+        if let Some(shard) = shard_mu.try_lock() {
+            return shard;
+        }
+        std::thread::yield_now();
+    }
 }
 
 #[derive(Eq)]
@@ -369,20 +462,39 @@ impl Hash for Entry {
     }
 }
 
+/// Under `EvictionPolicy::SegmentedLru`, `dll` and `entries`
+/// hold the probationary segment. The other policies leave the
+/// protected segment empty.
 struct Shard {
+    policy: EvictionPolicy,
     dll: DoublyLinkedList,
     entries: FastSet8<Entry>,
+    protected_dll: DoublyLinkedList,
+    protected_entries: FastSet8<Entry>,
+    protected_size: usize,
+    frequencies: FastMap8<u32, u8>,
+    frequency_accesses: usize,
+    // pinned pages and their sizes, which are counted in
+    // `size` but never evicted
+    pinned: FastMap8<u32, usize>,
     capacity: usize,
     size: usize,
 }
 
 impl Shard {
-    fn new(capacity: usize) -> Self {
+    fn new(capacity: usize, policy: EvictionPolicy) -> Self {
         assert!(capacity > 0, "shard capacity must be non-zero");
 
         Self {
+            policy,
             dll: DoublyLinkedList::default(),
             entries: FastSet8::default(),
+            protected_dll: DoublyLinkedList::default(),
+            protected_entries: FastSet8::default(),
+            protected_size: 0,
+            frequencies: FastMap8::default(),
+            frequency_accesses: 0,
+            pinned: FastMap8::default(),
             capacity,
             size: 0,
         }
@@ -390,30 +502,88 @@ impl Shard {
 
     /// `PageId`s in the shard list are indexes of the entries.
     fn accessed(&mut self, cache_access: CacheAccess) -> Vec<u32> {
-        if let Some(entry) = self.entries.get(&cache_access.pid) {
+        let mut to_evict = vec![];
+
+        if let Some(pinned_size) = self.pinned.get_mut(&cache_access.pid) {
+            self.size -= *pinned_size;
+            *pinned_size = cache_access.size();
+            self.size += cache_access.size();
+
+            self.evict_over_capacity(&mut to_evict);
+            return to_evict;
+        }
+
+        if self.policy == EvictionPolicy::Lfu {
+            self.record_frequency(cache_access.pid);
+        }
+
+        let mut admitted = None;
+
+        if let Some(entry) = self.protected_entries.get(&cache_access.pid) {
+            let old_sz_po2 = unsafe { (*entry.0).swap_sz(cache_access.sz) };
+            let old_size = 1 << usize::from(old_sz_po2);
+
+            self.size -= old_size;
+            self.protected_size -= old_size;
+            self.protected_size += cache_access.size();
+            self.protected_dll.promote(entry.0);
+        } else if let Some(entry) = self.entries.get(&cache_access.pid) {
             let old_sz_po2 = unsafe { (*entry.0).swap_sz(cache_access.sz) };
             let old_size = 1 << usize::from(old_sz_po2);
 
             self.size -= old_size;
-            self.dll.promote(entry.0);
+
+            if self.policy == EvictionPolicy::SegmentedLru {
+                // a second access moves the page out of probation
+                let ptr = entry.0;
+                assert!(self.entries.remove(&cache_access.pid));
+                drop(self.dll.remove(ptr));
+                self.protect(cache_access);
+            } else {
+                self.dll.promote(entry.0);
+            }
         } else {
             let ptr = self.dll.push_head(cache_access);
             self.entries.insert(Entry(ptr));
+            admitted = Some(ptr);
         };
 
         self.size += cache_access.size();
 
-        let mut to_evict = vec![];
+        if let Some(ptr) = admitted {
+            if self.size > self.capacity && self.rejects(cache_access.pid) {
+                assert!(self.entries.remove(&cache_access.pid));
+                drop(self.dll.remove(ptr));
 
+                to_evict.push(cache_access.pid);
+
+                self.size -= cache_access.size();
+            }
+        }
+
+        self.evict_over_capacity(&mut to_evict);
+
+        to_evict
+    }
+
+    fn evict_over_capacity(&mut self, to_evict: &mut Vec<u32>) {
         while self.size > self.capacity {
-            if self.dll.len() == 1 {
-                // don't evict what we just added
+            if self.dll.len() + self.protected_dll.len() <= 1 {
+                // don't evict what we just added, and leave
+                // pinned pages alone
                 break;
             }
 
-            let node = self.dll.pop_tail().unwrap();
-
-            assert!(self.entries.remove(&node.pid));
+            let node = if self.dll.len() > 0 {
+                let node = self.dll.pop_tail().unwrap();
+                assert!(self.entries.remove(&node.pid));
+                node
+            } else {
+                let node = self.protected_dll.pop_tail().unwrap();
+                assert!(self.protected_entries.remove(&node.pid));
+                self.protected_size -= node.size();
+                node
+            };
 
             to_evict.push(node.pid);
 
@@ -427,8 +597,103 @@ impl Shard {
             // the DLL and our entries map.
             drop(node);
         }
+    }
 
-        to_evict
+    fn pin(&mut self, pid: u32) {
+        if self.pinned.contains_key(&pid) {
+            return;
+        }
+
+        let size = if let Some(entry) = self.protected_entries.get(&pid) {
+            let ptr = entry.0;
+            assert!(self.protected_entries.remove(&pid));
+            let node = self.protected_dll.remove(ptr);
+            self.protected_size -= node.size();
+            node.size()
+        } else if let Some(entry) = self.entries.get(&pid) {
+            let ptr = entry.0;
+            assert!(self.entries.remove(&pid));
+            self.dll.remove(ptr).size()
+        } else {
+            // not cached right now, so its size is
+            // recorded once it is next accessed
+            0
+        };
+
+        self.pinned.insert(pid, size);
+    }
+
+    fn unpin(&mut self, pid: u32) {
+        if let Some(size) = self.pinned.remove(&pid) {
+            if size > 0 {
+                // its bytes are already counted in `size`
+                let ptr =
+                    self.dll.push_head(CacheAccess::new(pid.into(), size));
+                self.entries.insert(Entry(ptr));
+            }
+        }
+    }
+
+    /// Moves a page to the head of the protected segment,
+    /// demoting the least recently used protected pages back
+    /// to probation if the segment grows past its share of
+    /// the capacity.
+    fn protect(&mut self, cache_access: CacheAccess) {
+        let ptr = self.protected_dll.push_head(cache_access);
+        self.protected_entries.insert(Entry(ptr));
+        self.protected_size += cache_access.size();
+
+        let protected_capacity = self.capacity / 5 * PROTECTED_FIFTHS;
+
+        while self.protected_size > protected_capacity
+            && self.protected_dll.len() > 1
+        {
+            let node = self.protected_dll.pop_tail().unwrap();
+            assert!(self.protected_entries.remove(&node.pid));
+            self.protected_size -= node.size();
+
+            let demoted: CacheAccess = **node;
+            drop(node);
+
+            let ptr = self.dll.push_head(demoted);
+            self.entries.insert(Entry(ptr));
+        }
+    }
+
+    /// Under `EvictionPolicy::Lfu`, a newly cached page is only
+    /// kept at the expense of the least recently used page if
+    /// it has been accessed at least as often.
+    fn rejects(&self, pid: u32) -> bool {
+        if self.policy != EvictionPolicy::Lfu {
+            return false;
+        }
+
+        let victim = if let Some(victim) = self.dll.peek_tail() {
+            victim.pid
+        } else {
+            return false;
+        };
+
+        let frequency = |pid| self.frequencies.get(&pid).copied().unwrap_or(0);
+
+        victim != pid && frequency(pid) < frequency(victim)
+    }
+
+    fn record_frequency(&mut self, pid: u32) {
+        let frequency = self.frequencies.entry(pid).or_insert(0);
+        *frequency = frequency.saturating_add(1).min(MAX_FREQUENCY);
+
+        self.frequency_accesses += 1;
+
+        if self.frequency_accesses
+            >= FREQUENCY_AGING_FACTOR * (self.entries.len() + 1)
+        {
+            self.frequencies.retain(|_, frequency| {
+                *frequency /= 2;
+                *frequency > 0
+            });
+            self.frequency_accesses = 0;
+        }
     }
 }
 
@@ -441,7 +706,7 @@ fn safe_usize(value: PageId) -> usize {
 fn lru_smoke_test() {
     use crate::pin;
 
-    let lru = Lru::new(2);
+    let lru = Lru::new(2, EvictionPolicy::Lru);
     for i in 0..1000 {
         let guard = pin();
         lru.accessed(i, 16, &guard);
@@ -455,7 +720,7 @@ fn lru_access_test() {
     let ci = CacheAccess::new(6, 20667);
     assert_eq!(ci.size(), 32 * 1024);
 
-    let lru = Lru::new(4096);
+    let lru = Lru::new(4096, EvictionPolicy::Lru);
 
     let guard = pin();
 
@@ -473,3 +738,64 @@ fn lru_access_test() {
     assert_eq!(lru.accessed(22, 20667, &guard), vec![]);
     assert_eq!(lru.accessed(24, 20667, &guard), vec![14, 16, 18, 20]);
 }
+
+#[test]
+fn eviction_policy_scan_resistance() {
+    use crate::pin;
+
+    // counts how many pages of a repeatedly read hot set are
+    // evicted by a single scan over many other pages
+    fn hot_set_evictions(policy: EvictionPolicy) -> usize {
+        let lru = Lru::new(4096, policy);
+        let guard = pin();
+        let mut evicted = vec![];
+
+        for _ in 0..8 {
+            for pid in 0..16 {
+                evicted.extend(lru.accessed(pid, 64, &guard));
+            }
+        }
+
+        for pid in 1000..2000 {
+            evicted.extend(lru.accessed(pid, 64, &guard));
+        }
+
+        evicted.iter().filter(|pid| **pid < 16).count()
+    }
+
+    let lru = hot_set_evictions(EvictionPolicy::Lru);
+    assert_eq!(lru, 16);
+    assert!(hot_set_evictions(EvictionPolicy::SegmentedLru) < lru);
+    assert!(hot_set_evictions(EvictionPolicy::Lfu) < lru);
+}
+
+#[test]
+fn pinned_pages_are_not_evicted() {
+    use crate::pin;
+
+    let lru = Lru::new(4096, EvictionPolicy::Lru);
+    let guard = pin();
+    let mut evicted = vec![];
+
+    for pid in 0..8 {
+        lru.pin(pid);
+        evicted.extend(lru.accessed(pid, 64, &guard));
+    }
+
+    for pid in 1000..2000 {
+        evicted.extend(lru.accessed(pid, 64, &guard));
+    }
+
+    assert!(!evicted.is_empty());
+    assert!(evicted.iter().all(|pid| *pid >= 1000));
+
+    for pid in 0..8 {
+        lru.unpin(pid);
+    }
+
+    for pid in 2000..3000 {
+        evicted.extend(lru.accessed(pid, 64, &guard));
+    }
+
+    assert_eq!(evicted.iter().filter(|pid| **pid < 8).count(), 8);
+}
@@ -1,4 +1,6 @@
-use crate::*;
+use std::sync::atomic::AtomicBool;
+
+use crate::{tree::UserBytes, *};
 
 /// A simple map that can be used to store metadata
 /// for the pagecache tenant.
@@ -52,6 +54,8 @@ where
                     subscribers: Subscribers::default(),
                     root: AtomicU64::new(root_id),
                     merge_operator: RwLock::new(None),
+                    pinned: AtomicBool::new(false),
+                    user_bytes: Arc::new(UserBytes::new(None)),
                 })));
             }
             Err(Error::CollectionNotFound) => {}
@@ -103,6 +107,9 @@ where
             context: context.clone(),
             root: AtomicU64::new(root_id),
             merge_operator: RwLock::new(None),
+            pinned: AtomicBool::new(false),
+            // a new tree starts out empty
+            user_bytes: Arc::new(UserBytes::new(Some(0))),
         })));
     }
 }
@@ -12,10 +12,15 @@ use std::{
 };
 
 use crate::{
+    config::Callback,
+    crc,
     ebr::pin,
-    pagecache::{pread_exact, pwrite_all, MessageKind},
-    stack::Stack,
-    Error, Lsn, Result,
+    pagecache::{
+        decode_from_disk, encode_for_disk, pread_exact, pwrite_all,
+        MessageKind,
+    },
+    stack::{Iter as StackIter, Stack},
+    CrcVariant, Error, Lsn, Result, SegmentTransform,
 };
 
 #[cfg(not(feature = "for-internal-testing-only"))]
@@ -26,6 +31,10 @@ pub(crate) const MIN_SZ: u64 = 128;
 
 const MIN_TRAILING_ZEROS: u64 = MIN_SZ.trailing_zeros() as u64;
 
+/// The bytes before the message in each slot: its kind, a CRC
+/// and the LSN of the log message that refers to it.
+pub(crate) const SLOT_HEADER_LEN: u64 = 13;
+
 pub type SlabId = u8;
 pub type SlabIdx = u32;
 
@@ -105,6 +114,7 @@ pub(crate) struct Reservation {
     file: File,
     pub heap_id: HeapId,
     from_tip: bool,
+    transform: Option<Callback<dyn SegmentTransform>>,
 }
 
 impl Drop for Reservation {
@@ -127,7 +137,13 @@ impl Reservation {
         assert_eq!(data.len() as u64, self.heap_id.slab_size());
 
         // write data
-        pwrite_all(&self.file, data, self.heap_id.offset())?;
+        if let Some(ref transform) = self.transform {
+            let encoded =
+                encode_for_disk(&**transform, self.heap_id.original_lsn, data)?;
+            pwrite_all(&self.file, &encoded, self.heap_id.offset())?;
+        } else {
+            pwrite_all(&self.file, data, self.heap_id.offset())?;
+        }
 
         // sync data
         if self.from_tip {
@@ -181,20 +197,29 @@ pub(crate) struct Heap {
     // smallest slab to 2^48 in
     // the last.
     slabs: [Slab; 32],
+    transform: Option<Callback<dyn SegmentTransform>>,
+    crc_variant: CrcVariant,
 }
 
 impl Heap {
-    pub fn start<P: AsRef<Path>>(p: P) -> Result<Heap> {
+    /// Opens the heap files in directory `p`, creating them
+    /// unless `read_only` is set.
+    pub fn start<P: AsRef<Path>>(
+        p: P,
+        transform: Option<Callback<dyn SegmentTransform>>,
+        crc_variant: CrcVariant,
+        read_only: bool,
+    ) -> Result<Heap> {
         let mut slabs_vec = vec![];
 
         for slab_id in 0..32 {
-            let slab = Slab::start(&p, slab_id)?;
+            let slab = Slab::start(&p, slab_id, read_only)?;
             slabs_vec.push(slab);
         }
 
         let slabs: [Slab; 32] = slabs_vec.try_into().unwrap();
 
-        Ok(Heap { slabs })
+        Ok(Heap { slabs, transform, crc_variant })
     }
 
     pub fn gc_unknown_items(&self, snapshot: &crate::pagecache::Snapshot) {
@@ -237,7 +262,62 @@ impl Heap {
     pub fn read(&self, heap_id: HeapId) -> Result<(MessageKind, Vec<u8>)> {
         log::trace!("Heap::read({:?})", heap_id);
         let (slab_id, slab_idx, original_lsn) = heap_id.decompose();
-        self.slabs[slab_id as usize].read(slab_idx, original_lsn)
+        self.slabs[slab_id as usize].read(
+            slab_idx,
+            original_lsn,
+            self.transform.as_deref(),
+            self.crc_variant,
+        )
+    }
+
+    /// Whether the slot that `heap_id` refers to has been
+    /// handed out and not freed since.
+    pub fn is_allocated(&self, heap_id: HeapId) -> bool {
+        let (slab_id, slab_idx, _) = heap_id.decompose();
+        let slab = &self.slabs[slab_id as usize];
+        if slab_idx >= slab.tip.load(Acquire) {
+            return false;
+        }
+
+        let guard = pin();
+        let mut free = StackIter::from_ptr(slab.free.head(&guard), &guard);
+        !free.any(|idx| *idx == slab_idx)
+    }
+
+    /// Returns the slot size, the number of slots handed
+    /// out, and how many of those are free, for each slab
+    /// that has handed out any slots.
+    pub fn slab_stats(&self) -> Vec<(u64, u32, usize)> {
+        let guard = pin();
+        self.slabs
+            .iter()
+            .filter_map(|slab| {
+                let tip = slab.tip.load(Acquire);
+                if tip == 0 {
+                    return None;
+                }
+                let free =
+                    StackIter::from_ptr(slab.free.head(&guard), &guard).count();
+                Some((slab_id_to_size(slab.slab_id), tip, free))
+            })
+            .collect()
+    }
+
+    /// Returns the fraction of the space handed out by the
+    /// slabs that is held by freed slots waiting to be
+    /// reused, from 0.0 for a fully packed heap to 1.0 for
+    /// one where every slot has been freed.
+    #[cfg(feature = "metrics")]
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::float_arithmetic)]
+    pub fn fragmentation(&self) -> f64 {
+        let mut total = 0.;
+        let mut free = 0.;
+        for (size, slots, free_slots) in self.slab_stats() {
+            total += f64::from(slots) * size as f64;
+            free += free_slots as f64 * size as f64;
+        }
+        if total == 0. { 0. } else { free / total }
     }
 
     pub fn free(&self, heap_id: HeapId) {
@@ -249,7 +329,8 @@ impl Heap {
     pub fn reserve(&self, size: u64, original_lsn: Lsn) -> Reservation {
         assert!(size < 1 << 48);
         let slab_id = size_to_slab_id(size);
-        let ret = self.slabs[slab_id as usize].reserve(original_lsn);
+        let mut ret = self.slabs[slab_id as usize].reserve(original_lsn);
+        ret.transform = self.transform.clone();
         log::trace!("Heap::reserve({}) -> {:?}", size, ret.heap_id);
         ret
     }
@@ -264,14 +345,18 @@ struct Slab {
 }
 
 impl Slab {
-    pub fn start<P: AsRef<Path>>(directory: P, slab_id: u8) -> Result<Slab> {
+    pub fn start<P: AsRef<Path>>(
+        directory: P,
+        slab_id: u8,
+        read_only: bool,
+    ) -> Result<Slab> {
         let bs = slab_id_to_size(slab_id);
         let free = Arc::new(Stack::default());
 
         let mut options = std::fs::OpenOptions::new();
-        options.create(true);
+        options.create(!read_only);
         options.read(true);
-        options.write(true);
+        options.write(!read_only);
 
         let file =
             options.open(directory.as_ref().join(format!("{:02}", slab_id)))?;
@@ -292,6 +377,8 @@ impl Slab {
         &self,
         slab_idx: SlabIdx,
         original_lsn: Lsn,
+        transform: Option<&dyn SegmentTransform>,
+        crc_variant: CrcVariant,
     ) -> Result<(MessageKind, Vec<u8>)> {
         let bs = slab_id_to_size(self.slab_id);
         let offset = u64::from(slab_idx) * bs;
@@ -302,10 +389,14 @@ impl Slab {
 
         pread_exact(&self.file, &mut heap_buf, offset)?;
 
+        if let Some(transform) = transform {
+            decode_from_disk(transform, original_lsn, &mut heap_buf)?;
+        }
+
         let stored_crc =
             u32::from_le_bytes(heap_buf[1..5].as_ref().try_into().unwrap());
 
-        let mut hasher = crc32fast::Hasher::new();
+        let mut hasher = crc::Hasher::new(crc_variant);
         hasher.update(&heap_buf[0..1]);
         hasher.update(&heap_buf[5..]);
         let actual_crc = hasher.finalize();
@@ -364,6 +455,7 @@ impl Slab {
             file: self.file.try_clone().unwrap(),
             from_tip,
             heap_id,
+            transform: None,
         }
     }
 
@@ -1,7 +1,11 @@
 use std::{
     alloc::{alloc, dealloc, Layout},
     cell::UnsafeCell,
+    future::Future,
+    pin::Pin,
     sync::atomic::AtomicPtr,
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant, SystemTime},
 };
 
 use crate::{pagecache::*, *};
@@ -19,24 +23,23 @@ macro_rules! io_fail {
     };
 }
 
-struct AlignedBuf(*mut u8, usize);
+struct AlignedBuf(*mut u8, Layout);
 
 impl AlignedBuf {
-    fn new(len: usize) -> AlignedBuf {
-        let layout = Layout::from_size_align(len, 8192).unwrap();
+    fn new(len: usize, align: usize) -> AlignedBuf {
+        let layout = Layout::from_size_align(len, align).unwrap();
         let ptr = unsafe { alloc(layout) };
 
         assert!(!ptr.is_null(), "failed to allocate critical IO buffer");
 
-        AlignedBuf(ptr, len)
+        AlignedBuf(ptr, layout)
     }
 }
 
 impl Drop for AlignedBuf {
     fn drop(&mut self) {
-        let layout = Layout::from_size_align(self.1, 8192).unwrap();
         unsafe {
-            dealloc(self.0, layout);
+            dealloc(self.0, self.1);
         }
     }
 }
@@ -89,7 +92,7 @@ impl IoBuf {
         let buf_ptr = self.buf.get();
 
         unsafe {
-            assert!((*buf_ptr).1 >= at + len);
+            assert!((*buf_ptr).1.size() >= at + len);
             std::slice::from_raw_parts_mut(
                 (*buf_ptr).0.add(self.base + at),
                 len,
@@ -106,6 +109,7 @@ impl IoBuf {
         last: Header,
         lsn: Lsn,
         max_stable_lsn: Lsn,
+        crc_variant: CrcVariant,
     ) {
         debug!("storing lsn {} in beginning of buffer", lsn);
         assert!(self.capacity >= SEG_HEADER_LEN);
@@ -115,7 +119,7 @@ impl IoBuf {
         self.lsn = lsn;
 
         let header = SegmentHeader { lsn, max_stable_lsn, ok: true };
-        let header_bytes: [u8; SEG_HEADER_LEN] = header.into();
+        let header_bytes = header.encode(crc_variant);
 
         #[allow(unsafe_code)]
         unsafe {
@@ -160,6 +164,9 @@ pub(crate) struct StabilityIntervals {
     fsynced_ranges: Vec<(Lsn, Lsn)>,
     batches: BTreeMap<Lsn, Lsn>,
     stable_lsn: Lsn,
+    // tasks waiting in `Stabilized` for the paired lsn
+    // to become stable.
+    wakers: Vec<(Lsn, Waker)>,
 }
 
 impl StabilityIntervals {
@@ -168,9 +175,23 @@ impl StabilityIntervals {
             stable_lsn: lsn,
             fsynced_ranges: vec![],
             batches: BTreeMap::default(),
+            wakers: vec![],
         }
     }
 
+    fn take_wakers_up_to(&mut self, lsn: Lsn) -> Vec<Waker> {
+        let mut ready = vec![];
+        self.wakers.retain(|(waiting_for, waker)| {
+            if *waiting_for <= lsn {
+                ready.push(waker.clone());
+                false
+            } else {
+                true
+            }
+        });
+        ready
+    }
+
     pub(crate) fn mark_batch(&mut self, interval: (Lsn, Lsn)) {
         assert!(interval.0 > self.stable_lsn);
         self.batches.insert(interval.0, interval.1);
@@ -298,7 +319,13 @@ pub(crate) struct IoBufs {
     // file, and there may be buffers that have been written out-of-order
     // to stable storage due to interesting thread interleavings.
     pub stable_lsn: AtomicLsn,
+    // The wall-clock time at which `stable_lsn` last advanced, or
+    // `None` if nothing has become stable since the log started.
+    pub last_stable_time: Mutex<Option<SystemTime>>,
     pub max_reserved_lsn: AtomicLsn,
+    // Counts reservations, and blocks new ones while
+    // `PageCache::quiesce` keeps the log from changing.
+    pub quiesce: Quiesce,
     pub max_header_stable_lsn: Arc<AtomicLsn>,
     pub segment_accountant: Mutex<SegmentAccountant>,
     pub segment_cleaner: SegmentCleaner,
@@ -378,7 +405,10 @@ impl IoBufs {
         let base = assert_usize(next_lid % segment_size as LogOffset);
 
         let mut iobuf = IoBuf {
-            buf: Arc::new(UnsafeCell::new(AlignedBuf::new(segment_size))),
+            buf: Arc::new(UnsafeCell::new(AlignedBuf::new(
+                segment_size,
+                config.io_alignment,
+            ))),
             header: CachePadded::new(AtomicU64::new(0)),
             base,
             offset: next_lid,
@@ -389,7 +419,7 @@ impl IoBufs {
         };
 
         if snapshot.active_segment.is_none() {
-            iobuf.store_segment_header(0, next_lsn, stable);
+            iobuf.store_segment_header(0, next_lsn, stable, config.crc_variant);
         }
 
         Ok(IoBufs {
@@ -401,7 +431,9 @@ impl IoBufs {
             interval_updated: Condvar::new(),
 
             stable_lsn: AtomicLsn::new(stable),
+            last_stable_time: Mutex::new(None),
             max_reserved_lsn: AtomicLsn::new(stable),
+            quiesce: Quiesce::default(),
             max_header_stable_lsn: Arc::new(AtomicLsn::new(next_lsn)),
             segment_accountant: Mutex::new(segment_accountant),
             segment_cleaner,
@@ -537,6 +569,13 @@ impl IoBufs {
             segment_base: None,
             segments,
             last_stage: false,
+            checksum_failures: vec![],
+            deadline: None,
+            paused_at: None,
+            paused: false,
+            batch_end: None,
+            contiguous_to: None,
+            read_ahead: None,
         }
     }
 
@@ -587,7 +626,7 @@ impl IoBufs {
             #[cfg(feature = "metrics")]
             drop(serialization_timer);
 
-            let mut hasher = crc32fast::Hasher::new();
+            let mut hasher = crc::Hasher::new(self.config.crc_variant);
             hasher.update(&heap_buf[0..1]);
             hasher.update(&heap_buf[5..]);
             let crc = hasher.finalize().to_le_bytes();
@@ -646,6 +685,14 @@ impl IoBufs {
 
         let bytes_to_write = header::offset(header);
 
+        #[cfg(feature = "failpoints")]
+        {
+            if crate::fail::is_active("slow flush") {
+                // simulates writing to a saturated device
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        }
+
         trace!(
             "write_to_log log_offset {} lsn {} len {}",
             log_offset,
@@ -703,6 +750,7 @@ impl IoBufs {
 
             // this as to stay aligned with the hashing
             let crc32_arr = u32_to_arr(calculate_message_crc32(
+                self.config.crc_variant,
                 &header_bytes,
                 &padding_bytes[..pad_len],
             ));
@@ -738,10 +786,27 @@ impl IoBufs {
 
         io_fail!(self, "buffer write");
         let f = &self.config.file;
-        pwrite_all(f, data, log_offset)?;
+        if let Some(ref transform) = self.config.segment_transform {
+            // segment headers are written untransformed so
+            // that recovery can learn the LSN of each segment
+            let plain_len = if self.config.normalize(log_offset) == log_offset {
+                std::cmp::min(SEG_HEADER_LEN, data.len())
+            } else {
+                0
+            };
+            let encoded = encode_for_disk(
+                &**transform,
+                base_lsn + plain_len as Lsn,
+                &data[plain_len..],
+            )?;
+            pwrite_all(f, &data[..plain_len], log_offset)?;
+            pwrite_all(f, &encoded, log_offset + plain_len as LogOffset)?;
+        } else {
+            pwrite_all(f, data, log_offset)?;
+        }
         if !self.config.temporary {
             if iobuf.from_tip {
-                f.sync_all()?;
+                self.config.sync_file(f)?;
             } else if cfg!(not(target_os = "linux")) {
                 f.sync_data()?;
             } else {
@@ -844,9 +909,12 @@ impl IoBufs {
 
         let updated = intervals.mark_fsync(interval);
 
+        let mut ready = vec![];
         if let Some(new_stable_lsn) = updated {
             trace!("mark_interval new highest lsn {}", new_stable_lsn);
             self.stable_lsn.store(new_stable_lsn, SeqCst);
+            *self.last_stable_time.lock() = Some(SystemTime::now());
+            ready = intervals.take_wakers_up_to(new_stable_lsn);
 
             #[cfg(feature = "event_log")]
             {
@@ -863,6 +931,10 @@ impl IoBufs {
             drop(intervals);
         }
         let _notified = self.interval_updated.notify_all();
+
+        for waker in ready {
+            waker.wake();
+        }
     }
 
     pub(in crate::pagecache) fn current_iobuf(&self) -> Arc<IoBuf> {
@@ -881,13 +953,18 @@ impl IoBufs {
 
         // wake up any waiting threads
         // so they don't stall forever
-        let intervals = self.intervals.lock();
+        let mut intervals = self.intervals.lock();
+        let ready = intervals.take_wakers_up_to(Lsn::MAX);
 
         // having held the mutex makes this linearized
         // with the notify below.
         drop(intervals);
 
         let _notified = self.interval_updated.notify_all();
+
+        for waker in ready {
+            waker.wake();
+        }
     }
 }
 
@@ -1052,6 +1129,89 @@ pub(in crate::pagecache) fn make_stable_inner(
     Ok(assert_usize(stable - first_stable))
 }
 
+/// Blocks until the specified log sequence number has
+/// been made stable on disk by some other thread, such as
+/// the periodic flusher, without writing anything itself.
+/// Returns `false` if `timeout` passes first.
+pub(in crate::pagecache) fn wait_for_stable(
+    iobufs: &Arc<IoBufs>,
+    lsn: Lsn,
+    timeout: Option<Duration>,
+) -> Result<bool> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+    let mut intervals = iobufs.intervals.lock();
+
+    loop {
+        // stable_lsn is advanced and waiters are notified
+        // while holding the intervals mutex, so checking
+        // it here while holding the mutex can't miss a
+        // notification.
+        iobufs.config.global_error()?;
+
+        if iobufs.stable() >= lsn {
+            return Ok(true);
+        }
+
+        if let Some(deadline) = deadline {
+            let res =
+                iobufs.interval_updated.wait_until(&mut intervals, deadline);
+            if res.timed_out() {
+                iobufs.config.global_error()?;
+                return Ok(iobufs.stable() >= lsn);
+            }
+        } else {
+            iobufs.interval_updated.wait(&mut intervals);
+        }
+    }
+}
+
+/// Blocks while more than `Config::max_unflushed_bytes` are
+/// reserved but not stable yet, until stable is within half
+/// of the limit of the highest reserved lsn. The current
+/// buffer is sealed so that the IO threads write it right
+/// away. Fails with `ErrorKind::TimedOut` once
+/// `Config::max_unflushed_wait` passes.
+pub(in crate::pagecache) fn throttle_unflushed(
+    iobufs: &Arc<IoBufs>,
+) -> Result<()> {
+    let limit = match iobufs.config.max_unflushed_bytes {
+        Some(limit) => Lsn::try_from(limit).unwrap_or(Lsn::MAX),
+        None => return Ok(()),
+    };
+
+    let max_reserved_lsn = iobufs.max_reserved_lsn.load(Acquire);
+    if max_reserved_lsn.saturating_sub(iobufs.stable()) <= limit {
+        return Ok(());
+    }
+
+    let lsn = max_reserved_lsn - limit / 2;
+    trace!(
+        "throttling write until lsn {} is stable, current stable {}",
+        lsn,
+        iobufs.stable()
+    );
+
+    let iobuf = iobufs.current_iobuf();
+    let header = iobuf.get_header();
+    if header::offset(header) != 0
+        && !header::is_sealed(header)
+        && iobuf.lsn <= lsn
+    {
+        maybe_seal_and_write_iobuf(iobufs, &iobuf, header, false)?;
+    }
+
+    if wait_for_stable(iobufs, lsn, iobufs.config.max_unflushed_wait)? {
+        Ok(())
+    } else {
+        Err(Error::Io(
+            std::io::ErrorKind::TimedOut,
+            "write blocked by max_unflushed_bytes for longer than \
+             max_unflushed_wait",
+        ))
+    }
+}
+
 /// Called by users who wish to force the current buffer
 /// to flush some pending writes. Returns the number
 /// of bytes written during this call.
@@ -1061,6 +1221,70 @@ pub(in crate::pagecache) fn flush(iobufs: &Arc<IoBufs>) -> Result<usize> {
     make_stable(iobufs, max_reserved_lsn)
 }
 
+/// Returns a future that completes once everything
+/// reserved so far has been made stable on disk, like
+/// `flush` but without blocking the calling thread.
+pub(in crate::pagecache) fn flush_async(iobufs: &Arc<IoBufs>) -> Stabilized {
+    let lsn = iobufs.max_reserved_lsn.load(Acquire);
+    Stabilized { iobufs: iobufs.clone(), lsn, first_stable: iobufs.stable() }
+}
+
+/// The future returned by `flush_async`. Each poll seals
+/// the current IO buffer if it holds data that we are
+/// waiting for, which hands it off to the IO threadpool
+/// to be written, and the task is woken by `mark_interval`
+/// once the write has been fsynced.
+#[derive(Debug)]
+pub(crate) struct Stabilized {
+    iobufs: Arc<IoBufs>,
+    lsn: Lsn,
+    first_stable: Lsn,
+}
+
+impl Future for Stabilized {
+    type Output = Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let iobufs = &self.iobufs;
+
+        if iobufs.stable() < self.lsn && iobufs.config.global_error().is_ok() {
+            let _cc = concurrency_control::read();
+            let iobuf = iobufs.current_iobuf();
+            let header = iobuf.get_header();
+            if header::offset(header) != 0
+                && !header::is_sealed(header)
+                && iobuf.lsn <= self.lsn
+            {
+                if let Err(e) =
+                    maybe_seal_and_write_iobuf(iobufs, &iobuf, header, false)
+                {
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+
+        // registering the waker while holding the mutex makes
+        // this linearized with `mark_interval` and
+        // `set_global_error`, so no wakeup can be missed.
+        let mut intervals = iobufs.intervals.lock();
+
+        if let Err(e) = iobufs.config.global_error() {
+            return Poll::Ready(Err(e));
+        }
+
+        let stable = iobufs.stable();
+        if stable >= self.lsn {
+            return Poll::Ready(Ok(assert_usize(stable - self.first_stable)));
+        }
+
+        let waker = cx.waker();
+        intervals.wakers.retain(|(_, registered)| !registered.will_wake(waker));
+        intervals.wakers.push((self.lsn, waker.clone()));
+
+        Poll::Pending
+    }
+}
+
 /// Attempt to seal the current IO buffer, possibly
 /// writing it to disk if there are no other writers
 /// operating on it.
@@ -1140,7 +1364,7 @@ pub(in crate::pagecache) fn maybe_seal_and_write_iobuf(
         match iobufs.with_sa(|sa| sa.next(next_lsn)) {
             Ok(ret) => ret,
             Err(e) => {
-                iobufs.set_global_error(e);
+                iobufs.set_global_error(e.clone());
                 return Err(e);
             }
         }
@@ -1161,7 +1385,10 @@ pub(in crate::pagecache) fn maybe_seal_and_write_iobuf(
     // its entire life cycle as soon as we do that.
     let next_iobuf = if maxed {
         let mut next_iobuf = IoBuf {
-            buf: Arc::new(UnsafeCell::new(AlignedBuf::new(segment_size))),
+            buf: Arc::new(UnsafeCell::new(AlignedBuf::new(
+                segment_size,
+                iobufs.config.io_alignment,
+            ))),
             header: CachePadded::new(AtomicU64::new(0)),
             base: 0,
             offset: next_offset,
@@ -1171,7 +1398,12 @@ pub(in crate::pagecache) fn maybe_seal_and_write_iobuf(
             stored_max_stable_lsn: -1,
         };
 
-        next_iobuf.store_segment_header(sealed, next_lsn, iobufs.stable());
+        next_iobuf.store_segment_header(
+            sealed,
+            next_lsn,
+            iobufs.stable(),
+            iobufs.config.crc_variant,
+        );
 
         next_iobuf
     } else {
@@ -1266,3 +1498,41 @@ impl Debug for IoBuf {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_alignment() {
+        assert!(
+            Config::new().temporary(true).io_alignment(3000).open().is_err()
+        );
+
+        for &align in &[4096, 64 * 1024] {
+            let db = Config::new()
+                .temporary(true)
+                .segment_size(4096)
+                .io_alignment(align)
+                .open()
+                .unwrap();
+
+            let iobufs = &db.context.pagecache.log.iobufs;
+            let mut addresses = vec![];
+            for i in 0..64_u32 {
+                // small enough to stay in the log with the tiny
+                // heap threshold of the testing features
+                db.insert(i.to_be_bytes(), vec![0; 64]).unwrap();
+                let iobuf = iobufs.current_iobuf();
+                addresses.push(unsafe { (*iobuf.buf.get()).0 } as usize);
+            }
+            addresses.dedup();
+
+            // the inserts rolled over into freshly allocated buffers
+            assert!(addresses.len() > 1);
+            for address in addresses {
+                assert_eq!(address % align, 0);
+            }
+        }
+    }
+}
@@ -1,9 +1,9 @@
-use std::{collections::BTreeMap, io};
+use std::{collections::BTreeMap, fs::File, io, time::Instant};
 
 use super::{
-    pread_exact_or_eof, read_message, read_segment_header, BasedBuf, DiskPtr,
-    LogKind, LogOffset, LogRead, Lsn, SegmentHeader, SegmentNumber,
-    MAX_MSG_HEADER_LEN, SEG_HEADER_LEN,
+    decode_from_disk, pread_exact_or_eof, read_message, read_segment_header,
+    BasedBuf, DiskPtr, LogKind, LogOffset, LogRead, Lsn, SegmentHeader,
+    SegmentNumber, MAX_MSG_HEADER_LEN, SEG_HEADER_LEN,
 };
 use crate::*;
 
@@ -15,6 +15,89 @@ pub struct LogIter {
     pub max_lsn: Option<Lsn>,
     pub cur_lsn: Option<Lsn>,
     pub last_stage: bool,
+    pub checksum_failures: Vec<Lsn>,
+    // once this passes, iteration pauses before each
+    // segment that is read, so that the caller may decide
+    // whether to stop there
+    pub deadline: Option<Instant>,
+    // the segment that iteration last paused before
+    pub paused_at: Option<Lsn>,
+    // set when iteration ended because it paused
+    pub paused: bool,
+    // the last lsn of the most recently read batch
+    pub batch_end: Option<Lsn>,
+    // the lsn of the next segment, while no segment has been
+    // skipped since the first one that was expected
+    pub contiguous_to: Option<Lsn>,
+    // set during recovery, when nothing else reads or writes
+    // the log, to read several segments at once
+    pub read_ahead: Option<ReadAhead>,
+}
+
+#[cfg(test)]
+thread_local! {
+    /// The number of reads that `LogIter` has issued to load
+    /// segments on this thread.
+    pub static SEGMENT_READS: std::cell::Cell<usize> =
+        const { std::cell::Cell::new(0) };
+}
+
+fn count_segment_read() {
+    #[cfg(test)]
+    SEGMENT_READS.with(|reads| reads.set(reads.get() + 1));
+}
+
+/// Bytes of the log that were read ahead of the segment
+/// that is currently being iterated over.
+#[derive(Debug)]
+pub struct ReadAhead {
+    size: usize,
+    window: Option<BasedBuf>,
+}
+
+impl ReadAhead {
+    /// Reads `size` bytes, rounded down to a multiple of the
+    /// segment size, at once. Returns `None` when that is less
+    /// than a single segment.
+    pub fn new(config: &RunningConfig) -> Option<Self> {
+        let size = config.recovery_read_buffer_size / config.segment_size
+            * config.segment_size;
+        if size == 0 { None } else { Some(Self { size, window: None }) }
+    }
+
+    /// Returns the segment at `offset`, which may be cut
+    /// short by the end of the file, reading it and the
+    /// segments after it unless they were already read.
+    fn segment(
+        &mut self,
+        file: &File,
+        offset: LogOffset,
+        segment_size: usize,
+    ) -> Result<Vec<u8>> {
+        let covered = if let Some(ref window) = self.window {
+            let end = window.offset + window.buf.len() as LogOffset;
+            // a short window ends at the end of the file
+            let hit_eof = window.buf.len() < self.size;
+            offset >= window.offset
+                && (offset + segment_size as LogOffset <= end
+                    || (hit_eof && offset <= end))
+        } else {
+            false
+        };
+
+        if !covered {
+            let mut buf = vec![0; self.size];
+            count_segment_read();
+            let size = pread_exact_or_eof(file, &mut buf, offset)?;
+            buf.truncate(size);
+            self.window = Some(BasedBuf { buf, offset });
+        }
+
+        let window = self.window.as_ref().unwrap();
+        let start = usize::try_from(offset - window.offset).unwrap();
+        let end = std::cmp::min(start + segment_size, window.buf.len());
+        Ok(window.buf[start..end].to_vec())
+    }
 }
 
 impl Iterator for LogIter {
@@ -118,6 +201,7 @@ impl Iterator for LogIter {
                             return None;
                         }
                     }
+                    self.batch_end = Some(last_lsn_in_batch);
                     self.cur_lsn = Some(lsn + Lsn::from(inline_len));
                     continue;
                 }
@@ -125,13 +209,20 @@ impl Iterator for LogIter {
                     trace!("read zeroed in LogIter::next");
                     self.cur_lsn = Some(lsn + Lsn::from(inline_len));
                 }
-                Ok(LogRead::Corrupted) => {
+                Ok(read @ (LogRead::Corrupted | LogRead::ChecksumMismatch)) => {
                     trace!(
                         "read corrupted msg in LogIter::next as lid {} lsn {}",
                         lid,
                         lsn
                     );
                     if self.last_stage {
+                        // torn writes are cut off by max_lsn before
+                        // the last stage, so a bad checksum here is
+                        // not expected.
+                        if let LogRead::ChecksumMismatch = read {
+                            self.checksum_failures.push(lsn);
+                        }
+
                         // this happens when the second half of a freed segment
                         // is overwritten before its segment header. it's fine
                         // to just treat it like a cap
@@ -201,6 +292,36 @@ impl LogIter {
             }
         }
 
+        if let Some(deadline) = self.deadline {
+            // the first segment may hold pages that are already in
+            // the snapshot, a batch must be replayed entirely, and
+            // a skipped segment may have held pages that were only
+            // moved to later segments
+            let in_batch = matches!(self.batch_end, Some(end) if end >= lsn);
+            if self.cur_lsn.is_some()
+                && !in_batch
+                && self.contiguous_to.is_some()
+                && self.paused_at != Some(lsn)
+                && Instant::now() >= deadline
+            {
+                self.paused_at = Some(lsn);
+                self.paused = true;
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "paused after exceeding max_recovery_duration",
+                )
+                .into());
+            }
+        }
+
+        #[cfg(feature = "failpoints")]
+        {
+            if crate::fail::is_active("slow replay") {
+                // simulates reading segments from degraded storage
+                std::thread::sleep(std::time::Duration::from_millis(20));
+            }
+        }
+
         assert!(
             lsn + (self.config.segment_size as Lsn)
                 >= self.cur_lsn.unwrap_or(0),
@@ -219,7 +340,26 @@ impl LogIter {
             lsn + self.config.segment_size as Lsn >= self.cur_lsn.unwrap_or(0)
         );
         let f = &self.config.file;
-        let segment_header = read_segment_header(f, offset)?;
+        let read_ahead = if let Some(ref mut read_ahead) = self.read_ahead {
+            Some(read_ahead.segment(f, offset, self.config.segment_size)?)
+        } else {
+            None
+        };
+        let segment_header = if let Some(ref buf) = read_ahead {
+            let header = buf.get(..SEG_HEADER_LEN).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "failed to fill whole buffer",
+                )
+            })?;
+            SegmentHeader::decode(
+                <[u8; SEG_HEADER_LEN]>::try_from(header).unwrap(),
+                self.config.crc_variant,
+            )
+        } else {
+            count_segment_read();
+            read_segment_header(f, offset, self.config.crc_variant)?
+        };
         if offset % self.config.segment_size as LogOffset != 0 {
             debug!("segment offset not divisible by segment length");
             return Err(Error::corruption(None));
@@ -248,22 +388,57 @@ impl LogIter {
 
         trace!("read segment header {:?}", segment_header);
 
-        let mut buf = vec![0; self.config.segment_size];
-        let size = pread_exact_or_eof(f, &mut buf, offset)?;
+        let mut buf = if let Some(buf) = read_ahead {
+            buf
+        } else {
+            let mut buf = vec![0; self.config.segment_size];
+            count_segment_read();
+            let size = pread_exact_or_eof(f, &mut buf, offset)?;
+            buf.truncate(size);
+            buf
+        };
+        let size = buf.len();
 
         trace!("setting stored segment buffer length to {} after read", size);
-        buf.truncate(size);
+
+        if let Some(ref transform) = self.config.segment_transform {
+            if size > SEG_HEADER_LEN {
+                decode_from_disk(
+                    &**transform,
+                    segment_header.lsn + SEG_HEADER_LEN as Lsn,
+                    &mut buf[SEG_HEADER_LEN..],
+                )?;
+            }
+        }
 
         self.cur_lsn = Some(segment_header.lsn + SEG_HEADER_LEN as Lsn);
 
         self.segment_base = Some(BasedBuf { buf, offset });
 
+        let segment_size = self.config.segment_size as Lsn;
+        self.contiguous_to = self
+            .contiguous_to
+            .filter(|expected| *expected == lsn)
+            .map(|_| lsn + segment_size);
+
         // NB this should only happen after we've successfully read
         // the header, because we want to zero the segment if we
         // fail to read that, and we use the remaining segment
         // list to perform zeroing off of.
         self.segments.remove(&lsn);
 
+        if self.config.access_pattern == AccessPattern::Sequential {
+            // segments are not stored in lsn order, so the
+            // kernel's readahead can't anticipate the next one.
+            if let Some((_, &next_offset)) = self.segments.iter().next() {
+                let _ = AccessPattern::WillNeed.advise(
+                    f,
+                    next_offset,
+                    self.config.segment_size as u64,
+                );
+            }
+        }
+
         Ok(())
     }
 }
@@ -292,7 +467,9 @@ fn scan_segment_headers_and_tail(
     ) -> Option<(LogOffset, SegmentHeader)> {
         let segment_len = u64::try_from(config.segment_size).unwrap();
         let base_lid = idx * segment_len;
-        let segment = read_segment_header(&config.file, base_lid).ok()?;
+        let segment =
+            read_segment_header(&config.file, base_lid, config.crc_variant)
+                .ok()?;
         trace!(
             "SA scanned header at lid {} during startup: {:?}",
             base_lid,
@@ -444,6 +621,13 @@ fn check_contiguity_in_unstable_tail(
         max_lsn: missing_item_in_tail,
         cur_lsn: None,
         last_stage: false,
+        checksum_failures: vec![],
+        deadline: None,
+        paused_at: None,
+        paused: false,
+        batch_end: None,
+        contiguous_to: None,
+        read_ahead: ReadAhead::new(config),
     };
 
     // run the iterator to completion
@@ -509,5 +693,59 @@ pub fn raw_segment_iter_from(
         segment_base: None,
         segments,
         last_stage: true,
+        checksum_failures: vec![],
+        deadline: None,
+        paused_at: None,
+        paused: false,
+        batch_end: None,
+        contiguous_to: Some(normalized_lsn),
+        read_ahead: ReadAhead::new(config),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovery_read_buffer_size() {
+        let path = std::env::temp_dir().join("test_recovery_read_buffer_size");
+        let _ = std::fs::remove_dir_all(&path);
+
+        let config = |read_buffer_size| {
+            Config::new()
+                .path(&path)
+                .segment_size(4096)
+                .snapshot_after_ops(1_000_000)
+                .recovery_read_buffer_size(read_buffer_size)
+        };
+
+        let db = config(0).open().unwrap();
+        for i in 0..2048_u32 {
+            db.insert(i.to_be_bytes(), vec![0; 64]).unwrap();
+        }
+        drop(db);
+
+        let recover = |read_buffer_size| {
+            SEGMENT_READS.with(|reads| reads.set(0));
+            let db = config(read_buffer_size).open().unwrap();
+            let reads = SEGMENT_READS.with(std::cell::Cell::get);
+            assert_eq!(db.len(), 2048);
+            for i in 0..2048_u32 {
+                assert_eq!(db.get(i.to_be_bytes()).unwrap().unwrap().len(), 64);
+            }
+            reads
+        };
+
+        let unbuffered = recover(0);
+        let buffered = recover(64 * 4096);
+        assert!(
+            buffered < unbuffered,
+            "{} reads with read-ahead, {} without",
+            buffered,
+            unbuffered
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
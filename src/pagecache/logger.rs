@@ -1,11 +1,12 @@
-use std::fs::File;
+use std::{fs::File, time::Duration};
 
 use super::{
-    arr_to_lsn, arr_to_u32, assert_usize, header, iobuf, lsn_to_arr,
-    pread_exact, pread_exact_or_eof, roll_iobuf, u32_to_arr, Arc, BasedBuf,
-    DiskPtr, HeapId, IoBuf, IoBufs, LogKind, LogOffset, Lsn, MessageKind,
-    Reservation, Serialize, Snapshot, BATCH_MANIFEST_PID, COUNTER_PID,
-    MAX_MSG_HEADER_LEN, META_PID, SEG_HEADER_LEN,
+    arr_to_lsn, arr_to_u32, assert_usize, decode_from_disk, header, iobuf,
+    lsn_to_arr, pread_exact, pread_exact_or_eof, reservation_held, roll_iobuf,
+    u32_to_arr, Arc, BasedBuf, DiskPtr, HeapId, IoBuf, IoBufs, LogKind,
+    LogOffset, Lsn, MessageKind, QuiesceGuard, Reservation, Serialize,
+    Snapshot, BATCH_MANIFEST_PID, COUNTER_PID, MAX_MSG_HEADER_LEN, META_PID,
+    SEG_HEADER_LEN,
 };
 
 use crate::*;
@@ -36,6 +37,14 @@ impl Log {
         iobuf::flush(&self.iobufs)
     }
 
+    /// Returns a future that completes once all pending IO
+    /// buffers have been written to disk, without blocking
+    /// the calling thread. Resolves to the number of bytes
+    /// written while it was pending.
+    pub(crate) fn flush_async(&self) -> iobuf::Stabilized {
+        iobuf::flush_async(&self.iobufs)
+    }
+
     /// Return an iterator over the log, starting with
     /// a specified offset.
     pub fn iter_from(&self, lsn: Lsn) -> super::LogIter {
@@ -88,6 +97,37 @@ impl Log {
         self.iobufs.stable()
     }
 
+    /// returns the number of bytes that have been reserved
+    /// in the log but are not yet stable on disk
+    pub(crate) fn pending_flush_bytes(&self) -> u64 {
+        let max_reserved_lsn = self.iobufs.max_reserved_lsn.load(Acquire);
+        let stable_lsn = self.iobufs.stable();
+        u64::try_from(max_reserved_lsn.saturating_sub(stable_lsn)).unwrap_or(0)
+    }
+
+    /// returns the wall-clock time at which the log last
+    /// became stable further on disk, if it has since starting
+    pub(crate) fn last_stable_time(&self) -> Option<std::time::SystemTime> {
+        *self.iobufs.last_stable_time.lock()
+    }
+
+    /// returns the last log sequence number that has been
+    /// reserved in the log so far
+    pub(crate) fn max_reserved_lsn(&self) -> Lsn {
+        self.iobufs.max_reserved_lsn.load(Acquire)
+    }
+
+    /// blocks until the specified log sequence number has
+    /// been made stable on disk by another thread, or until
+    /// the timeout passes. Returns whether it became stable.
+    pub(crate) fn wait_for_stable(
+        &self,
+        lsn: Lsn,
+        timeout: Option<Duration>,
+    ) -> Result<bool> {
+        iobuf::wait_for_stable(&self.iobufs, lsn, timeout)
+    }
+
     /// blocks until the specified log sequence number has
     /// been made stable on disk. Returns the number of
     /// bytes written during this call. this is appropriate
@@ -107,6 +147,8 @@ impl Log {
         heap_pointer: HeapId,
         guard: &Guard,
     ) -> Result<Reservation<'_>> {
+        self.config.check_writable()?;
+
         let ret = self.reserve_inner(
             LogKind::Replace,
             pid,
@@ -116,7 +158,7 @@ impl Log {
         );
 
         if let Err(e) = &ret {
-            self.iobufs.set_global_error(*e);
+            self.iobufs.set_global_error(e.clone());
         }
 
         ret
@@ -134,10 +176,20 @@ impl Log {
         item: &T,
         guard: &Guard,
     ) -> Result<Reservation<'_>> {
+        // checked before reserving, because a failed reservation
+        // sets the global error.
+        self.config.check_writable()?;
+
+        // a thread that holds a reservation would keep the log
+        // from being flushed past it, and wait for itself
+        if !reservation_held() {
+            iobuf::throttle_unflushed(&self.iobufs)?;
+        }
+
         let ret = self.reserve_inner(log_kind, pid, item, None, guard);
 
         if let Err(e) = &ret {
-            self.iobufs.set_global_error(*e);
+            self.iobufs.set_global_error(e.clone());
         }
 
         ret
@@ -154,6 +206,8 @@ impl Log {
         #[cfg(feature = "metrics")]
         let _measure = Measure::new(&M.reserve_lat);
 
+        let quiesce = QuiesceGuard::new(&self.iobufs.quiesce);
+
         let serialized_len = item.serialized_size();
         let max_buf_len =
             u64::try_from(MAX_MSG_HEADER_LEN).unwrap() + serialized_len;
@@ -389,11 +443,15 @@ impl Log {
                 .max_reserved_lsn
                 .fetch_max(reservation_lsn + inline_buf_len as Lsn - 1, SeqCst);
 
+            let heap_len = if over_heap_threshold {
+                serialized_len + super::heap::SLOT_HEADER_LEN
+            } else {
+                0
+            };
+
             let (heap_reservation, heap_id_opt) = if over_heap_threshold {
-                let heap_reservation = self
-                    .config
-                    .heap
-                    .reserve(serialized_len + 13, reservation_lsn);
+                let heap_reservation =
+                    self.config.heap.reserve(heap_len, reservation_lsn);
                 let heap_id = heap_reservation.heap_id;
                 (Some(heap_reservation), Some(heap_id))
             } else {
@@ -428,6 +486,8 @@ impl Log {
                 is_heap_item_rewrite: heap_rewrite.is_some(),
                 header_len: usize::try_from(message_header.serialized_size())
                     .unwrap(),
+                heap_len,
+                _quiesce: quiesce,
             });
         }
     }
@@ -484,8 +544,9 @@ impl Log {
 
 impl Drop for Log {
     fn drop(&mut self) {
-        // don't do any more IO if we're crashing
-        if self.config.global_error().is_err() {
+        // don't do any more IO if we're crashing, or if
+        // nothing may be written
+        if self.config.global_error().is_err() || self.config.verify_only {
             return;
         }
 
@@ -546,6 +607,8 @@ pub enum LogRead {
     Cap(SegmentNumber),
     /// This log message was not readable due to corruption
     Corrupted,
+    /// This log message did not match its checksum
+    ChecksumMismatch,
     /// This heap slot has been replaced
     DanglingHeap(MessageHeader, HeapId, u32),
     /// This data may only be read if at least this future location is stable
@@ -569,8 +632,11 @@ impl LogRead {
     }
 }
 
-impl From<[u8; SEG_HEADER_LEN]> for SegmentHeader {
-    fn from(buf: [u8; SEG_HEADER_LEN]) -> Self {
+impl SegmentHeader {
+    pub(crate) fn decode(
+        buf: [u8; SEG_HEADER_LEN],
+        crc_variant: CrcVariant,
+    ) -> Self {
         #[allow(unsafe_code)]
         unsafe {
             let crc32_header =
@@ -582,7 +648,7 @@ impl From<[u8; SEG_HEADER_LEN]> for SegmentHeader {
             let xor_max_stable_lsn = arr_to_lsn(buf.get_unchecked(12..20));
             let max_stable_lsn = xor_max_stable_lsn ^ 0x7FFF_FFFF_FFFF_FFFF;
 
-            let crc32_tested = crc32(&buf[4..20]);
+            let crc32_tested = crc::checksum(crc_variant, &buf[4..20]);
 
             let ok = crc32_tested == crc32_header;
 
@@ -597,10 +663,12 @@ impl From<[u8; SEG_HEADER_LEN]> for SegmentHeader {
             Self { lsn, max_stable_lsn, ok }
         }
     }
-}
 
-impl From<SegmentHeader> for [u8; SEG_HEADER_LEN] {
-    fn from(header: SegmentHeader) -> [u8; SEG_HEADER_LEN] {
+    pub(crate) fn encode(
+        self,
+        crc_variant: CrcVariant,
+    ) -> [u8; SEG_HEADER_LEN] {
+        let header = self;
         let mut buf = [0; SEG_HEADER_LEN];
 
         let xor_lsn = header.lsn ^ 0x7FFF_FFFF_FFFF_FFFF;
@@ -623,7 +691,8 @@ impl From<SegmentHeader> for [u8; SEG_HEADER_LEN] {
             );
         }
 
-        let crc32 = u32_to_arr(crc32(&buf[4..20]) ^ 0xFFFF_FFFF);
+        let crc32 =
+            u32_to_arr(crc::checksum(crc_variant, &buf[4..20]) ^ 0xFFFF_FFFF);
 
         #[allow(unsafe_code)]
         unsafe {
@@ -641,12 +710,13 @@ impl From<SegmentHeader> for [u8; SEG_HEADER_LEN] {
 pub(crate) fn read_segment_header(
     file: &File,
     lid: LogOffset,
+    crc_variant: CrcVariant,
 ) -> Result<SegmentHeader> {
     trace!("reading segment header at {}", lid);
 
     let mut seg_header_buf = [0; SEG_HEADER_LEN];
     pread_exact(file, &mut seg_header_buf, lid)?;
-    let segment_header = SegmentHeader::from(seg_header_buf);
+    let segment_header = SegmentHeader::decode(seg_header_buf, crc_variant);
 
     if segment_header.lsn < Lsn::try_from(lid).unwrap() {
         debug!(
@@ -660,12 +730,18 @@ pub(crate) fn read_segment_header(
 }
 
 pub(crate) trait ReadAt {
+    /// Whether reads return bytes as they are stored on disk,
+    /// before any `SegmentTransform` has been reversed.
+    const ENCODED: bool;
+
     fn pread_exact(&self, dst: &mut [u8], at: u64) -> Result<()>;
 
     fn pread_exact_or_eof(&self, dst: &mut [u8], at: u64) -> Result<usize>;
 }
 
 impl ReadAt for File {
+    const ENCODED: bool = true;
+
     fn pread_exact(&self, dst: &mut [u8], at: u64) -> Result<()> {
         pread_exact(self, dst, at)
     }
@@ -676,6 +752,9 @@ impl ReadAt for File {
 }
 
 impl ReadAt for BasedBuf {
+    // segments are decoded as they are read into a `BasedBuf`
+    const ENCODED: bool = false;
+
     fn pread_exact(&self, dst: &mut [u8], mut at: u64) -> Result<()> {
         if at < self.offset
             || u64::try_from(dst.len()).unwrap() + at
@@ -736,8 +815,21 @@ pub(crate) fn read_message<R: ReadAt>(
         "tried to read a message from the red zone"
     );
 
+    let transform =
+        if R::ENCODED { config.segment_transform.as_deref() } else { None };
+    let lid_lsn = Lsn::try_from(expected_segment_number.0).unwrap()
+        * segment_len as Lsn
+        + Lsn::try_from(lid - seg_start).unwrap();
+
     let msg_header_buf = &mut [0; 128];
-    let _read_bytes = file.pread_exact_or_eof(msg_header_buf, lid)?;
+    let read_bytes = file.pread_exact_or_eof(msg_header_buf, lid)?;
+    if let Some(transform) = transform {
+        decode_from_disk(
+            transform,
+            lid_lsn,
+            &mut msg_header_buf[..read_bytes],
+        )?;
+    }
     let header_cursor = &mut msg_header_buf.as_ref();
     let len_before = header_cursor.len();
     let header = MessageHeader::deserialize(header_cursor)?;
@@ -781,12 +873,20 @@ pub(crate) fn read_message<R: ReadAt>(
     if header_len > len_after {
         // we have to read more data from disk
         file.pread_exact(&mut buf, lid + message_offset as LogOffset)?;
+        if let Some(transform) = transform {
+            decode_from_disk(
+                transform,
+                lid_lsn + message_offset as Lsn,
+                &mut buf,
+            )?;
+        }
     } else {
         // we already read this data in the initial read
         buf.copy_from_slice(header_cursor[..header_len].as_ref());
     }
 
     let crc32 = calculate_message_crc32(
+        config.crc_variant,
         msg_header_buf[..message_offset].as_ref(),
         &buf,
     );
@@ -799,7 +899,7 @@ pub(crate) fn read_message<R: ReadAt>(
             header.crc32,
             crc32
         );
-        return Ok(LogRead::Corrupted);
+        return Ok(LogRead::ChecksumMismatch);
     }
 
     let inline_len = u32::try_from(message_offset).unwrap()
@@ -43,6 +43,7 @@ use self::{
     iobuf::{roll_iobuf, IoBuf, IoBufs},
     iterator::{raw_segment_iter_from, LogIter},
     pagetable::PageTable,
+    reservation::{reservation_held, Quiesce, QuiesceGuard},
     segment::{SegmentAccountant, SegmentCleaner, SegmentOp},
 };
 
@@ -230,6 +231,41 @@ pub(crate) const fn u32_to_arr(number: u32) -> [u8; 4] {
     number.to_le_bytes()
 }
 
+/// Applies a `SegmentTransform` to bytes that are about to be
+/// written to disk, where `lsn` is the LSN of the first byte.
+pub(in crate::pagecache) fn encode_for_disk(
+    transform: &dyn SegmentTransform,
+    lsn: Lsn,
+    buf: &[u8],
+) -> Result<Vec<u8>> {
+    let mut encoded = buf.to_vec();
+    transform.encode(u64::try_from(lsn).unwrap(), &mut encoded);
+    if encoded.len() != buf.len() {
+        return Err(Error::Unsupported(
+            "SegmentTransform::encode must not change the buffer length",
+        ));
+    }
+    Ok(encoded)
+}
+
+/// Reverses a `SegmentTransform` on bytes that were just read
+/// from disk, where `lsn` is the LSN of the first byte.
+pub(in crate::pagecache) fn decode_from_disk(
+    transform: &dyn SegmentTransform,
+    lsn: Lsn,
+    buf: &mut [u8],
+) -> Result<()> {
+    let mut decoded = buf.to_vec();
+    transform.decode(u64::try_from(lsn).unwrap(), &mut decoded)?;
+    if decoded.len() != buf.len() {
+        return Err(Error::Unsupported(
+            "SegmentTransform::decode must not change the buffer length",
+        ));
+    }
+    buf.copy_from_slice(&decoded);
+    Ok(())
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct NodeView<'g>(pub(crate) PageView<'g>);
 
@@ -358,6 +394,31 @@ impl<'a> RecoveryGuard<'a> {
     }
 }
 
+/// The result of `Db::verify_integrity`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// The number of pages whose pointers were checked.
+    pub pages_checked: u64,
+    /// The ids of pages that point to heap slots that were
+    /// never written or have since been freed.
+    pub dangling_pointers: Vec<u64>,
+    /// The ids of pages that point to heap slots whose
+    /// contents do not match their stored CRC.
+    pub crc_failures: Vec<u64>,
+}
+
+/// The result of `Db::scrub`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    /// The number of segments that were read.
+    pub segments_scanned: u64,
+    /// The file offsets of segments whose header, or one of
+    /// whose messages, does not match its stored CRC.
+    pub crc_failures: Vec<u64>,
+    /// The number of bytes read from the data file.
+    pub bytes_read: u64,
+}
+
 /// A page consists of a sequence of state transformations
 /// with associated storage parameters like disk pos, lsn, time.
 #[derive(Debug, Clone)]
@@ -456,7 +517,25 @@ pub struct PageCacheInner {
     // fuzzy snapshot-related items
     snapshot_min_lsn: AtomicLsn,
     links: AtomicU64,
+    // bytes written to the log and heap since the last
+    // snapshot that `snapshot_after_bytes` triggered
+    unsnapshotted_bytes: AtomicU64,
     snapshot_lock: Mutex<()>,
+    // background snapshots that may still be running, each
+    // holding a clone of the pagecache, which `close` waits for
+    snapshot_promises: Mutex<Vec<OneShot<()>>>,
+
+    // the change in the size of user keys and values since
+    // the pagecache was started, maintained by tree writes
+    user_bytes: crate::atomic_shim::AtomicI64,
+
+    // only set once startup has completed, so that a
+    // partially-recovered pagecache is never snapshotted
+    // on drop.
+    record_clean_shutdown: std::sync::atomic::AtomicBool,
+
+    // problems found in the log during recovery
+    recovery_warnings: Vec<OpenWarning>,
 }
 
 impl Debug for PageCache {
@@ -472,14 +551,24 @@ impl Debug for PageCache {
     }
 }
 
-#[cfg(feature = "event_log")]
 impl Drop for PageCacheInner {
     fn drop(&mut self) {
         trace!("dropping pagecache");
 
+        if self.record_clean_shutdown.load(Acquire)
+            && self.log.iobufs.config.global_error().is_ok()
+        {
+            if let Err(e) = self.record_clean_shutdown() {
+                error!("failed to record clean shutdown: {:?}", e);
+            }
+        }
+
         // we can't as easily assert recovery
         // invariants across failpoints for now
-        if self.log.iobufs.config.global_error().is_ok() {
+        #[cfg(feature = "event_log")]
+        if self.log.iobufs.config.global_error().is_ok()
+            && self.config.recover_to_snapshot.is_none()
+        {
             let mut pages_before_restart = Map::default();
 
             let guard = pin();
@@ -509,12 +598,31 @@ impl PageCache {
 
         config.reset_global_error();
 
+        #[cfg(feature = "failpoints")]
+        {
+            if crate::fail::is_active("slow recovery") {
+                // simulates recovering from degraded storage
+                std::thread::sleep(std::time::Duration::from_millis(500));
+            }
+        }
+
+        // a past snapshot is an older view of the same pages,
+        // so it can't be compared against the last shutdown.
+        #[cfg(feature = "event_log")]
+        if config.recover_to_snapshot.is_some() {
+            config.event_log.reset();
+        }
+
         // try to pull any existing snapshot off disk, and
         // apply any new data to it to "catch-up" the
         // snapshot before loading it.
-        let snapshot = read_snapshot_or_default(&config)?;
+        let mut recovery_warnings = vec![];
+        let snapshot =
+            read_snapshot_or_default(&config, &mut recovery_warnings)?;
 
-        config.heap.gc_unknown_items(&snapshot);
+        if !config.verify_only {
+            config.heap.gc_unknown_items(&snapshot);
+        }
 
         #[cfg(feature = "testing")]
         {
@@ -527,7 +635,7 @@ impl PageCache {
 
             let paused_faults = crate::fail::pause_faults();
 
-            let snapshot2 = read_snapshot_or_default(&config)
+            let snapshot2 = read_snapshot_or_default(&config, &mut vec![])
                 .expect("second read snapshot");
 
             crate::fail::restore_faults(paused_faults);
@@ -579,7 +687,7 @@ impl PageCache {
         let _measure = Measure::new(&M.start_pagecache);
 
         let cache_capacity = config.cache_capacity;
-        let lru = Lru::new(cache_capacity);
+        let lru = Lru::new(cache_capacity, config.eviction_policy);
 
         let mut pc = PageCacheInner {
             was_recovered: false,
@@ -594,7 +702,12 @@ impl PageCache {
             next_pid_to_allocate: Mutex::new(0),
             snapshot_min_lsn: AtomicLsn::new(snapshot.stable_lsn.unwrap_or(0)),
             links: AtomicU64::new(0),
+            unsnapshotted_bytes: AtomicU64::new(0),
             snapshot_lock: Mutex::new(()),
+            snapshot_promises: Mutex::new(vec![]),
+            user_bytes: 0.into(),
+            record_clean_shutdown: false.into(),
+            recovery_warnings,
         };
 
         // now we read it back in
@@ -663,7 +776,10 @@ impl PageCache {
         pc.idgen.store(idgen_recovery, Release);
         pc.idgen_persists.store(idgen_persists, Release);
 
-        if was_recovered {
+        if was_recovered && pc.config.verify_only {
+            // nothing may be written, and ids can't be
+            // generated past the next persist interval anyway.
+        } else if was_recovered {
             // advance pc.idgen_persists and the counter page by one
             // interval, so that when generate_id() is next called, it
             // will advance them further by another interval, and wait for
@@ -695,6 +811,23 @@ impl PageCache {
                 .meta_after_restart(pc.get_meta(&guard2).deref().clone());
         }
 
+        if pc.config.verify_only {
+            // recovery only reads the log after the last snapshot,
+            // so every segment is checked separately
+            let report = pc.scrub()?;
+            pc.recovery_warnings.extend(
+                report
+                    .crc_failures
+                    .into_iter()
+                    .map(|offset| OpenWarning::CorruptSegment { offset }),
+            );
+        }
+
+        pc.record_clean_shutdown = (pc.config.fast_clean_restart
+            && !pc.config.temporary
+            && !pc.config.verify_only)
+            .into();
+
         trace!("pagecache started");
 
         Ok(PageCache(Arc::new(pc)))
@@ -823,6 +956,8 @@ impl PageCache {
 
                     self.log.iobufs.sa_mark_link(pid, cache_info, guard);
 
+                    self.count_written_bytes(&log_reservation);
+
                     // NB complete must happen AFTER calls to SA, because
                     // when the iobuf's n_writers hits 0, we may transition
                     // the segment to inactive, resulting in a race otherwise.
@@ -838,12 +973,32 @@ impl PageCache {
                     old.read = new_shared;
 
                     let link_count = self.links.fetch_add(1, Relaxed);
+                    let ops_due = link_count > 0
+                        && link_count % self.config.snapshot_after_ops == 0;
 
-                    if link_count > 0
-                        && link_count % self.config.snapshot_after_ops == 0
+                    // only the thread that resets the count
+                    // takes the snapshot
+                    let bytes_due = if let Some(threshold) =
+                        self.config.snapshot_after_bytes
                     {
+                        self.unsnapshotted_bytes.load(Relaxed) >= threshold
+                            && self.unsnapshotted_bytes.swap(0, Relaxed)
+                                >= threshold
+                    } else {
+                        false
+                    };
+
+                    if ops_due || bytes_due {
                         let s2: PageCache = self.clone();
-                        threadpool::take_fuzzy_snapshot(s2);
+                        let promise = threadpool::take_fuzzy_snapshot(s2);
+
+                        let mut promises = self.snapshot_promises.lock();
+                        promises.retain_mut(|promise| {
+                            promise
+                                .wait_timeout(std::time::Duration::from_secs(0))
+                                .is_err()
+                        });
+                        promises.push(promise);
                     }
 
                     return Ok(Ok(old));
@@ -870,7 +1025,9 @@ impl PageCache {
             }
         }
     }
+}
 
+impl PageCacheInner {
     pub(crate) fn take_fuzzy_snapshot(&self) -> Result<()> {
         #[cfg(feature = "metrics")]
         let _measure = Measure::new(&M.fuzzy_snapshot);
@@ -881,6 +1038,65 @@ impl PageCache {
             );
             return Ok(());
         }
+
+        self.write_snapshot_while_locked()?;
+
+        // explicitly drop this to make it clear that it needs to
+        // be held for the duration of the snapshot operation.
+        drop(lock);
+
+        Ok(())
+    }
+
+    /// Flushes all pending writes and then writes a snapshot
+    /// that covers them, returning the snapshot's stable LSN.
+    /// Waits for any in-progress background snapshot to
+    /// complete first, rather than running concurrently.
+    pub(crate) fn checkpoint(&self) -> Result<Lsn> {
+        let lock = self.snapshot_lock.lock();
+
+        let _ = self.flush()?;
+
+        let stable_lsn = self.write_snapshot_while_locked()?;
+
+        // explicitly drop this to make it clear that it needs to
+        // be held for the duration of the snapshot operation.
+        drop(lock);
+
+        Ok(stable_lsn)
+    }
+
+    /// Flushes and fsyncs everything written so far, then runs
+    /// `f` while nothing can change the log, snapshot or config
+    /// files: new reservations block, and snapshots and file
+    /// truncations wait, until `f` returns.
+    pub(crate) fn quiesce<T>(&self, f: impl FnOnce() -> T) -> Result<T> {
+        let snapshot_lock = self.snapshot_lock.lock();
+
+        // waits for every outstanding reservation to complete,
+        // so that the flush below writes all of them.
+        let quiesced = self.log.iobufs.quiesce.start();
+
+        let _ = self.flush()?;
+
+        // segment accountant ops are deferred while it is locked,
+        // so no segment can be freed and truncated until we're done.
+        let mut sa = self.log.iobufs.segment_accountant.lock();
+        sa.complete_truncations()?;
+        self.config.file.sync_all()?;
+
+        let ret = f();
+
+        drop(sa);
+        drop(quiesced);
+        drop(snapshot_lock);
+
+        Ok(ret)
+    }
+
+    /// Writes a snapshot of all pages, returning its stable LSN.
+    /// The caller must hold `snapshot_lock`.
+    fn write_snapshot_while_locked(&self) -> Result<Lsn> {
         let stable_lsn_before: Lsn = self.log.stable_offset();
 
         // This is how we determine the number of the pages we will snapshot.
@@ -956,21 +1172,70 @@ impl PageCache {
         // NB: this must only happen after writing the snapshot to disk
         self.snapshot_min_lsn.fetch_max(stable_lsn_before, SeqCst);
 
-        // explicitly drop this to make it clear that it needs to
-        // be held for the duration of the snapshot operation.
-        drop(lock);
-
-        Ok(())
+        Ok(stable_lsn_before)
     }
-}
 
-impl PageCacheInner {
     /// Flushes any pending IO buffers to disk to ensure durability.
     /// Returns the number of bytes written during this call.
     pub(crate) fn flush(&self) -> Result<usize> {
         self.log.flush()
     }
 
+    /// Like `flush`, but returns a future instead of blocking.
+    pub(crate) fn flush_async(&self) -> iobuf::Stabilized {
+        self.log.flush_async()
+    }
+
+    /// Waits for background snapshots and file truncations,
+    /// flushes the log and, with `fast_clean_restart`, records
+    /// a clean shutdown, returning any error instead of logging
+    /// it on drop. Only called by `Db::close` once nothing else
+    /// can write.
+    pub(crate) fn close(&self) -> Result<()> {
+        let promises = std::mem::take(&mut *self.snapshot_promises.lock());
+        for promise in promises {
+            promise.wait();
+        }
+
+        self.config.global_error()?;
+        if self.record_clean_shutdown.swap(false, SeqCst) {
+            self.record_clean_shutdown()?;
+        } else {
+            while self.flush()? > 0 {}
+        }
+
+        self.log.iobufs.segment_accountant.lock().complete_truncations()
+    }
+
+    /// Writes a snapshot that covers the entire log, followed
+    /// by a marker recording its LSN, so that the next startup
+    /// has nothing to replay. Only called once the last handle
+    /// to the pagecache has been dropped.
+    fn record_clean_shutdown(&self) -> Result<()> {
+        // taking a snapshot may rewrite pages that are spread
+        // across several log locations, so retry a few times
+        // until nothing was written after the snapshot.
+        for _ in 0..3 {
+            while self.flush()? > 0 {}
+
+            self.take_fuzzy_snapshot()?;
+
+            while self.flush()? > 0 {}
+
+            let snapshot_lsn = self.snapshot_min_lsn.load(Acquire);
+            if self.log.stable_offset() == snapshot_lsn {
+                return self.config.write_clean_shutdown(snapshot_lsn);
+            }
+        }
+
+        debug!(
+            "not recording a clean shutdown because the log \
+             kept advancing past the shutdown snapshot"
+        );
+
+        Ok(())
+    }
+
     /// Create a new page, trying to reuse old freed pages if possible
     /// to maximize underlying `PageTable` pointer density. Returns
     /// the page ID and its pointer for use in future atomic `replace`
@@ -1130,6 +1395,9 @@ impl PageCacheInner {
             self.cas_page(pid, old, Update::Free, false, guard)?;
 
         if new_pointer.is_ok() {
+            // a reused pid must not stay pinned
+            self.lru.unpin(pid);
+
             let free_mu = self.free.clone();
             guard.defer(move || {
                 let mut free = free_mu.lock();
@@ -1324,6 +1592,7 @@ impl PageCacheInner {
                     // when the iobuf's n_writers hits 0, we may transition
                     // the segment to inactive, resulting in a race otherwise.
                     if let Some(log_reservation) = log_reservation_opt {
+                        self.count_written_bytes(&log_reservation);
                         log_reservation.complete()?;
                     }
 
@@ -1407,15 +1676,17 @@ impl PageCacheInner {
         Ok(())
     }
 
-    /// Traverses all files and calculates their total physical
-    /// size, then traverses all pages and calculates their
-    /// total logical size, then divides the physical size
-    /// by the logical size.
+    /// Calculates the physical size of the log, then
+    /// traverses all pages and calculates their total
+    /// logical size, then divides the physical size
+    /// by the logical size. Heap slabs are left out,
+    /// because their slots are sized in powers of two
+    /// and are reused rather than compacted.
     #[allow(clippy::cast_precision_loss)]
     #[allow(clippy::float_arithmetic)]
     #[doc(hidden)]
     pub(crate) fn space_amplification(&self) -> Result<f64> {
-        let on_disk_bytes = self.size_on_disk()? as f64;
+        let on_disk_bytes = self.config.file.metadata()?.len() as f64;
         let logical_size = (self.logical_size_of_all_tree_pages()?
             + self.config.segment_size as u64)
             as f64;
@@ -1423,41 +1694,278 @@ impl PageCacheInner {
         Ok(on_disk_bytes / logical_size)
     }
 
+    /// Records that a tree write changed the total size of
+    /// user keys and values by `delta` bytes.
+    pub(crate) fn add_user_bytes(&self, delta: i64) {
+        self.user_bytes.fetch_add(delta, SeqCst);
+    }
+
+    /// Returns how much the total size of user keys and values
+    /// has changed since the pagecache was started.
+    pub(crate) fn user_bytes_delta(&self) -> i64 {
+        self.user_bytes.load(SeqCst)
+    }
+
+    /// The number of segments that dropped below
+    /// `compaction_fill_threshold` and were queued to be
+    /// compacted since startup.
+    pub(crate) fn compacted_segments(&self) -> u64 {
+        self.log.iobufs.with_sa(|sa| sa.compacted_segments())
+    }
+
+    /// Writes the occupancy of the cache, the positions of
+    /// the log and the state of its current io buffer and
+    /// segments, and the slots of the heap for
+    /// `Db::diagnostics`.
+    pub(crate) fn diagnostics(&self, out: &mut String) {
+        use std::fmt::Write;
+
+        let (cache_size, cache_capacity) = self.lru.occupancy();
+        writeln!(out, "cache:").unwrap();
+        writeln!(out, "  occupied bytes: {}", cache_size).unwrap();
+        writeln!(out, "  capacity bytes: {}", cache_capacity).unwrap();
+
+        let iobuf = self.log.iobufs.current_iobuf();
+        let header = iobuf.get_header();
+        writeln!(out, "log:").unwrap();
+        writeln!(out, "  last flushed lsn: {}", self.log.stable_offset())
+            .unwrap();
+        writeln!(out, "  last reserved lsn: {}", self.log.max_reserved_lsn())
+            .unwrap();
+        writeln!(
+            out,
+            "  pending flush bytes: {}",
+            self.log.pending_flush_bytes()
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "  io buffer: lid {}, lsn {}, capacity {}, reserved {}, \
+             writers {}, sealed {}",
+            iobuf.offset,
+            iobuf.lsn,
+            iobuf.capacity,
+            header::offset(header),
+            header::n_writers(header),
+            header::is_sealed(header),
+        )
+        .unwrap();
+        writeln!(out, "  compacted segments: {}", self.compacted_segments())
+            .unwrap();
+
+        let slab_stats = self.config.heap.slab_stats();
+        writeln!(out, "heap:").unwrap();
+        if slab_stats.is_empty() {
+            writeln!(out, "  empty").unwrap();
+        }
+        for (size, slots, free) in slab_stats {
+            writeln!(
+                out,
+                "  {} byte slots: {} total, {} free",
+                size, slots, free
+            )
+            .unwrap();
+        }
+    }
+
+    /// Sums the sizes of the `db` file, the contents of the
+    /// heap directory, the `conf` and `conf.tmp` files, and
+    /// any snapshot files. Files that disappear during the walk,
+    /// such as heap slabs that are removed lazily in the
+    /// background, are skipped.
     pub(crate) fn size_on_disk(&self) -> Result<u64> {
-        let mut size = self.config.file.metadata()?.len();
+        fn file_size(path: &std::path::Path) -> u64 {
+            std::fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+        }
 
-        let base_path = self.config.get_path().join("heap");
-        let heap_dir = base_path.parent().expect(
-            "should be able to determine the parent for the heap directory",
-        );
-        let heap_files = std::fs::read_dir(heap_dir)?;
+        let mut size = 0;
 
-        for slab_file_res in heap_files {
-            let slab_file = if let Ok(bf) = slab_file_res {
-                bf
+        for entry_res in std::fs::read_dir(self.config.get_path())? {
+            let entry = if let Ok(entry) = entry_res {
+                entry
             } else {
                 continue;
             };
 
-            // it's possible the heap item was removed lazily
-            // in the background and no longer exists
-            #[cfg(not(miri))]
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+
+            if name == "db"
+                || name == "conf"
+                || name == "conf.tmp"
+                || name.starts_with("snap.")
             {
-                size += slab_file.metadata().map(|m| m.len()).unwrap_or(0);
+                size += file_size(&entry.path());
             }
+        }
 
-            // workaround to avoid missing `dirfd` shim
-            #[cfg(miri)]
-            {
-                size += std::fs::metadata(slab_file.path())
-                    .map(|m| m.len())
-                    .unwrap_or(0);
+        if let Ok(heap_files) = std::fs::read_dir(self.config.heap_dir()) {
+            for slab_file in heap_files.flatten() {
+                size += file_size(&slab_file.path());
             }
         }
 
         Ok(size)
     }
 
+    /// Reads every heap slot that a page points to, and
+    /// reports the pages whose slots are unallocated or fail
+    /// their CRC check. Pages that are rewritten while this
+    /// runs may be reported spuriously.
+    pub(crate) fn verify_heap_pointers(&self) -> Result<IntegrityReport> {
+        let guard = pin();
+        let next_pid_to_allocate = *self.next_pid_to_allocate.lock();
+
+        let mut report = IntegrityReport::default();
+        for pid in 0..next_pid_to_allocate {
+            let page_view = self.inner.get(pid, &guard);
+            report.pages_checked += 1;
+
+            let mut dangling = false;
+            let mut crc_failure = false;
+            let heap_ids = page_view
+                .cache_infos
+                .iter()
+                .filter_map(|ci| ci.pointer.heap_id());
+            for heap_id in heap_ids {
+                if !self.config.heap.is_allocated(heap_id) {
+                    dangling = true;
+                    continue;
+                }
+
+                match self.config.heap.read(heap_id) {
+                    Ok(_) => {}
+                    Err(Error::Corruption { .. }) => crc_failure = true,
+                    // the slot was handed out, but the
+                    // write never reached the file.
+                    Err(Error::Io(std::io::ErrorKind::UnexpectedEof, _)) => {
+                        dangling = true
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
+            if dangling {
+                report.dangling_pointers.push(pid);
+            }
+            if crc_failure {
+                report.crc_failures.push(pid);
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Reads every segment of the data file straight from
+    /// disk, without going through the cache, and checks the
+    /// CRC of its header and of each of its messages that is
+    /// already stable. Segments are read one at a time with
+    /// no locks held, yielding in between.
+    pub(crate) fn scrub(&self) -> Result<ScrubReport> {
+        let file = &self.config.file;
+        let segment_size = self.config.segment_size as LogOffset;
+        let file_len = file.metadata()?.len();
+
+        let mut report = ScrubReport::default();
+        let mut offset = 0;
+        while offset < file_len {
+            let stable = self.log.stable_offset();
+            let mut buf = vec![0; self.config.segment_size];
+            let size = pread_exact_or_eof(file, &mut buf, offset)?;
+            buf.truncate(size);
+
+            report.segments_scanned += 1;
+            report.bytes_read += size as u64;
+
+            let header_lsn = buf.get(..SEG_HEADER_LEN).map(|header| {
+                SegmentHeader::decode(
+                    header.try_into().unwrap(),
+                    self.config.crc_variant,
+                )
+                .lsn
+            });
+
+            if !self.scrub_segment(buf, offset, stable)? {
+                // a segment that is reused while it is read
+                // may be seen half overwritten
+                let reread =
+                    read_segment_header(file, offset, self.config.crc_variant)?;
+                if header_lsn == Some(reread.lsn) {
+                    report.crc_failures.push(offset);
+                }
+            }
+
+            offset += segment_size;
+            std::thread::yield_now();
+        }
+
+        Ok(report)
+    }
+
+    /// Checks the segment read into `buf` from `offset`,
+    /// returning `false` if anything fails its CRC check.
+    fn scrub_segment(
+        &self,
+        mut buf: Vec<u8>,
+        offset: LogOffset,
+        stable: Lsn,
+    ) -> Result<bool> {
+        if buf.len() < SEG_HEADER_LEN {
+            return Ok(true);
+        }
+
+        let header = SegmentHeader::decode(
+            buf[..SEG_HEADER_LEN].try_into().unwrap(),
+            self.config.crc_variant,
+        );
+        if !header.ok {
+            // segments that were never written or have
+            // been zeroed have no header
+            return Ok(buf[..SEG_HEADER_LEN].iter().all(|b| *b == 0));
+        }
+        if header.lsn > stable {
+            return Ok(true);
+        }
+
+        if let Some(ref transform) = self.config.segment_transform {
+            decode_from_disk(
+                &**transform,
+                header.lsn + SEG_HEADER_LEN as Lsn,
+                &mut buf[SEG_HEADER_LEN..],
+            )?;
+        }
+
+        let segment_size = self.config.segment_size as LogOffset;
+        let segment_number =
+            SegmentNumber(u64::try_from(header.lsn).unwrap() / segment_size);
+        let end = offset + buf.len() as LogOffset;
+        let based = BasedBuf { buf, offset };
+
+        let mut lid = offset + SEG_HEADER_LEN as LogOffset;
+        while lid + MAX_MSG_HEADER_LEN as LogOffset <= offset + segment_size
+            && lid < end
+            && header.lsn + Lsn::try_from(lid - offset).unwrap() <= stable
+        {
+            let inline_len = match read_message(
+                &based,
+                lid,
+                segment_number,
+                &self.config,
+            )? {
+                LogRead::Inline(_, _, len)
+                | LogRead::Heap(_, _, _, len)
+                | LogRead::Canceled(len)
+                | LogRead::DanglingHeap(_, _, len)
+                | LogRead::BatchManifest(_, len) => len,
+                LogRead::Cap(_) | LogRead::Corrupted => break,
+                LogRead::ChecksumMismatch => return Ok(false),
+            };
+            lid += LogOffset::from(inline_len);
+        }
+
+        Ok(true)
+    }
+
     fn logical_size_of_all_tree_pages(&self) -> Result<u64> {
         let guard = pin();
         let min_pid = COUNTER_PID + 1;
@@ -1553,6 +2061,8 @@ impl PageCacheInner {
                         guard,
                     )?;
 
+                    self.count_written_bytes(&log_reservation);
+
                     // NB complete must happen AFTER calls to SA, because
                     // when the iobuf's n_writers hits 0, we may transition
                     // the segment to inactive, resulting in a race otherwise.
@@ -1770,6 +2280,68 @@ impl PageCacheInner {
         self.was_recovered
     }
 
+    /// Returns the problems that were found in the log
+    /// during recovery.
+    pub(crate) fn recovery_warnings(&self) -> &[OpenWarning] {
+        &self.recovery_warnings
+    }
+
+    /// Counts the bytes written by `reservation` towards
+    /// `Config::snapshot_after_bytes`.
+    fn count_written_bytes(&self, reservation: &Reservation<'_>) {
+        if self.config.snapshot_after_bytes.is_some() {
+            let _ = self
+                .unsnapshotted_bytes
+                .fetch_add(reservation.written_len(), Relaxed);
+        }
+    }
+
+    /// Changes the capacity of the cache, evicting pages right
+    /// away if it shrinks below the bytes that are cached.
+    pub(crate) fn set_cache_capacity(
+        &self,
+        cache_capacity: usize,
+    ) -> Result<()> {
+        let guard = pin();
+        let to_evict = self.lru.set_capacity(cache_capacity);
+        trace!("shrinking cache -> paging out pids {:?}", to_evict);
+        if !to_evict.is_empty() {
+            self.page_out(to_evict, &guard)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the current capacity of the cache in bytes.
+    pub(crate) fn cache_capacity(&self) -> usize {
+        self.lru.occupancy().1
+    }
+
+    /// Returns the bytes that are counted toward the cache
+    /// capacity.
+    #[cfg(test)]
+    pub(crate) fn cache_size(&self) -> usize {
+        self.lru.occupancy().0
+    }
+
+    /// Keeps a page in the cache until `unpin` is called or
+    /// the page is freed. Its bytes still count toward the
+    /// cache capacity, so other pages are evicted instead.
+    pub(crate) fn pin(&self, pid: PageId) {
+        self.lru.pin(pid);
+    }
+
+    /// Lets a page pinned by `pin` be evicted again.
+    pub(crate) fn unpin(&self, pid: PageId) {
+        self.lru.unpin(pid);
+    }
+
+    /// Returns `true` if the page is held in memory rather
+    /// than only on disk.
+    #[cfg(test)]
+    pub(crate) fn is_resident(&self, pid: PageId, guard: &Guard) -> bool {
+        self.inner.get(pid, guard).update.is_some()
+    }
+
     /// Generate a monotonic ID. Not guaranteed to be
     /// contiguous. Written to disk every `idgen_persist_interval`
     /// operations, followed by a blocking flush. During recovery, we
@@ -1898,6 +2470,88 @@ impl PageCacheInner {
         }
     }
 
+    /// Atomically moves the `Meta` mapping of `old` to `new`,
+    /// returning the root that was moved, or `None` if `old`
+    /// has no mapping or `new` already has one.
+    pub(crate) fn rename_in_meta(
+        &self,
+        old: &[u8],
+        new: &[u8],
+        guard: &Guard,
+    ) -> Result<Option<PageId>> {
+        loop {
+            let meta_view = self.get_meta(guard);
+
+            let root = match meta_view.get_root(old) {
+                Some(root) if meta_view.get_root(new).is_none() => root,
+                _ => return Ok(None),
+            };
+
+            let mut new_meta = meta_view.deref().clone();
+            new_meta.del_root(old);
+            new_meta.set_root(new.into(), root);
+
+            let new_meta_link = Update::Meta(new_meta);
+
+            let res = self.cas_page(
+                META_PID,
+                meta_view.0,
+                new_meta_link,
+                false,
+                guard,
+            )?;
+
+            match res {
+                Ok(_worked) => return Ok(Some(root)),
+                Err(Some((_current_pointer, _rejected))) => {}
+                Err(None) => {
+                    return Err(Error::ReportableBug(
+                        "replacing the META page has failed because \
+                         the pagecache does not think it currently exists.",
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Atomically removes the `Meta` mappings of all of `names`
+    /// that have one.
+    pub(crate) fn remove_from_meta(
+        &self,
+        names: &[IVec],
+        guard: &Guard,
+    ) -> Result<()> {
+        loop {
+            let meta_view = self.get_meta(guard);
+
+            let mut new_meta = meta_view.deref().clone();
+            for name in names {
+                new_meta.del_root(name);
+            }
+
+            let new_meta_link = Update::Meta(new_meta);
+
+            let res = self.cas_page(
+                META_PID,
+                meta_view.0,
+                new_meta_link,
+                false,
+                guard,
+            )?;
+
+            match res {
+                Ok(_worked) => return Ok(()),
+                Err(Some((_current_pointer, _rejected))) => {}
+                Err(None) => {
+                    return Err(Error::ReportableBug(
+                        "replacing the META page has failed because \
+                         the pagecache does not think it currently exists.",
+                    ));
+                }
+            }
+        }
+    }
+
     fn page_out(&self, to_evict: Vec<PageId>, guard: &Guard) -> Result<()> {
         #[cfg(feature = "metrics")]
         let _measure = Measure::new(&M.page_out);
@@ -1,5 +1,93 @@
+use std::{cell::Cell, sync::atomic::AtomicBool};
+
+use parking_lot::MutexGuard;
+
 use crate::{pagecache::*, *};
 
+thread_local! {
+    // The number of reservations that this thread holds.
+    static RESERVATIONS_HELD: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Keeps new reservations from being made while
+/// `PageCache::quiesce` runs. Outside of that, taking a
+/// reservation only touches two atomics, and it only waits
+/// on `lock`, which the quiescer holds, while one is running.
+#[derive(Debug, Default)]
+pub(crate) struct Quiesce {
+    quiescing: AtomicBool,
+    reservations: AtomicUsize,
+    lock: Mutex<()>,
+}
+
+impl Quiesce {
+    /// Blocks new reservations, and waits for the ones that
+    /// are held to be completed or aborted.
+    pub(super) fn start(&self) -> Quiesced<'_> {
+        let lock = self.lock.lock();
+        self.quiescing.store(true, SeqCst);
+        while self.reservations.load(SeqCst) != 0 {
+            std::thread::yield_now();
+        }
+        Quiesced { quiesce: self, _lock: lock }
+    }
+}
+
+/// Keeps new reservations from being made until it is
+/// dropped, as returned by `Quiesce::start`.
+#[derive(Debug)]
+pub(super) struct Quiesced<'a> {
+    quiesce: &'a Quiesce,
+    _lock: MutexGuard<'a, ()>,
+}
+
+impl<'a> Drop for Quiesced<'a> {
+    fn drop(&mut self) {
+        self.quiesce.quiescing.store(false, SeqCst);
+    }
+}
+
+/// Counts a reservation in `Quiesce::reservations` for as
+/// long as it is held. A thread that already holds one, such
+/// as while a batch manifest is reserved, takes another even
+/// while a quiescer is waiting, since that quiescer is
+/// waiting for this thread's reservations to be released.
+#[derive(Debug)]
+pub(super) struct QuiesceGuard<'a> {
+    quiesce: &'a Quiesce,
+}
+
+/// Returns `true` if this thread holds a reservation, which
+/// would keep the log from being flushed past it.
+pub(super) fn reservation_held() -> bool {
+    RESERVATIONS_HELD.with(Cell::get) > 0
+}
+
+impl<'a> QuiesceGuard<'a> {
+    pub(super) fn new(quiesce: &'a Quiesce) -> QuiesceGuard<'a> {
+        loop {
+            // pairs with the store and load in `Quiesce::start`,
+            // so that either this sees `quiescing` set, or the
+            // quiescer waits for this reservation
+            quiesce.reservations.fetch_add(1, SeqCst);
+            if reservation_held() || !quiesce.quiescing.load(SeqCst) {
+                break;
+            }
+            quiesce.reservations.fetch_sub(1, SeqCst);
+            drop(quiesce.lock.lock());
+        }
+        RESERVATIONS_HELD.with(|held| held.set(held.get() + 1));
+        QuiesceGuard { quiesce }
+    }
+}
+
+impl<'a> Drop for QuiesceGuard<'a> {
+    fn drop(&mut self) {
+        RESERVATIONS_HELD.with(|held| held.set(held.get().saturating_sub(1)));
+        self.quiesce.reservations.fetch_sub(1, SeqCst);
+    }
+}
+
 /// A pending log reservation which can be aborted or completed.
 /// NB the holder should quickly call `complete` or `abort` as
 /// taking too long to decide will cause the underlying IO
@@ -14,6 +102,9 @@ pub struct Reservation<'a> {
     pub lsn: Lsn,
     pub(super) is_heap_item_rewrite: bool,
     pub(super) header_len: usize,
+    pub(super) heap_len: u64,
+    // dropped after `Drop` has flushed the reservation
+    pub(super) _quiesce: QuiesceGuard<'a>,
 }
 
 impl<'a> Drop for Reservation<'a> {
@@ -48,6 +139,13 @@ impl<'a> Reservation<'a> {
         self.flush(false)
     }
 
+    /// Returns the number of bytes that this reservation writes
+    /// to the log, plus those written to the heap for an item
+    /// that is stored there.
+    pub(crate) fn written_len(&self) -> u64 {
+        self.buf.len() as u64 + self.heap_len
+    }
+
     /// Complete the reservation, placing the buffer on disk. returns
     /// the log sequence number of the write, and the file offset.
     pub fn complete(mut self) -> Result<(Lsn, DiskPtr)> {
@@ -125,6 +223,7 @@ impl<'a> Reservation<'a> {
         }
 
         let crc32 = calculate_message_crc32(
+            self.log.config.crc_variant,
             self.buf[..self.header_len].as_ref(),
             &self.buf[self.header_len..],
         );
@@ -95,6 +95,9 @@ pub(crate) struct SegmentAccountant {
     segment_cleaner: SegmentCleaner,
     ordering: BTreeMap<Lsn, LogOffset>,
     async_truncations: BTreeMap<LogOffset, OneShot<Result<()>>>,
+    // segments that dropped below compaction_fill_threshold
+    // and were queued to be compacted since startup
+    compacted_segments: u64,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -451,6 +454,12 @@ impl Segment {
 }
 
 impl SegmentAccountant {
+    /// The number of segments that have been queued to be
+    /// compacted since startup.
+    pub(super) const fn compacted_segments(&self) -> u64 {
+        self.compacted_segments
+    }
+
     /// Create a new `SegmentAccountant` from previously recovered segments.
     pub(super) fn start(
         config: RunningConfig,
@@ -468,6 +477,7 @@ impl SegmentAccountant {
             segment_cleaner,
             ordering: BTreeMap::default(),
             async_truncations: BTreeMap::default(),
+            compacted_segments: 0,
         };
 
         ret.initialize_from_snapshot(snapshot)?;
@@ -631,6 +641,14 @@ impl SegmentAccountant {
             }
         }
 
+        if self.config.verify_only {
+            // freeing and cleaning segments writes to them, which
+            // is never needed because nothing else is written
+            // either.
+            to_free.clear();
+            maybe_clean.clear();
+        }
+
         for segment_base in to_free {
             self.free_segment(segment_base)?;
             io_fail!(self.config, "zero garbage segment SA");
@@ -864,12 +882,22 @@ impl SegmentAccountant {
         let segment_start = (idx * self.config.segment_size) as LogOffset;
 
         if let Segment::Inactive(inactive) = &mut self.segments[idx] {
-            let live_pct = (inactive.max_pids - inactive.replaced_pids) * 50
-                / (inactive.max_pids + 1);
+            #[allow(clippy::cast_precision_loss)]
+            let live_fraction = (inactive.max_pids - inactive.replaced_pids)
+                as f32
+                / (inactive.max_pids + 1) as f32;
+
+            let reuses_exhausted = matches!(
+                self.config.max_segment_reuses,
+                Some(max) if inactive.replaced_pids >= max as usize
+            );
 
-            let can_drain = live_pct <= SEGMENT_CLEANUP_THRESHOLD;
+            let can_drain = live_fraction
+                < self.config.effective_compaction_fill_threshold()
+                || reuses_exhausted;
 
             if can_drain {
+                self.compacted_segments += 1;
                 // can be cleaned
                 trace!(
                     "SA inserting {} into to_clean from possibly_clean_or_free_segment",
@@ -1045,6 +1073,14 @@ impl SegmentAccountant {
         Ok(lid)
     }
 
+    /// Waits for every file truncation that is in progress.
+    pub(super) fn complete_truncations(&mut self) -> Result<()> {
+        for (_, promise) in self.async_truncations.split_off(&0) {
+            promise.wait().expect("threadpool should not crash")?;
+        }
+        Ok(())
+    }
+
     /// Returns the next offset to write a new segment in, as well
     /// as whether the corresponding segment must be persisted using
     /// fsync due to having been allocated from the file's tip, rather
@@ -1071,6 +1107,18 @@ impl SegmentAccountant {
             (self.bump_tip()?, true)
         };
 
+        if !from_tip && self.config.zero_on_reuse {
+            // don't leave the segment's previous contents
+            // readable past the end of what gets written to it
+            io_fail!(self.config, "zero reused segment");
+            pwrite_all(
+                &self.config.file,
+                &vec![0; self.config.segment_size],
+                lid,
+            )?;
+            self.config.sync_file(&self.config.file)?;
+        }
+
         // pin lsn to this segment
         let idx = self.segment_id(lid);
 
@@ -1167,3 +1215,75 @@ impl SegmentAccountant {
         idx
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Overwrites a key with `0xAB` bytes until a segment is
+    /// freed, then with `0xCD` bytes until one of the freed
+    /// segments is reused, and returns the reused segment's
+    /// contents once a `0xCD` value has been flushed to it.
+    fn reused_segment(zero_on_reuse: bool) -> Vec<u8> {
+        let value = |byte: u8, writes: u32| {
+            let mut value = vec![byte; 32];
+            value[..4].copy_from_slice(&writes.to_be_bytes());
+            value
+        };
+
+        let db = Config::new()
+            .temporary(true)
+            .segment_size(1024)
+            .flush_every_ms(None)
+            .zero_on_reuse(zero_on_reuse)
+            .open()
+            .unwrap();
+        let sa = &db.context.pagecache.log.iobufs.segment_accountant;
+
+        let mut writes = 0;
+        while sa.lock().free.is_empty() {
+            db.insert(b"k", value(0xAB, writes)).unwrap();
+            db.flush().unwrap();
+            db.context.pagecache.attempt_gc().unwrap();
+            writes += 1;
+            assert!(writes < 10_000, "no segment was freed");
+        }
+        let freed = sa.lock().free.clone();
+
+        let mut reused = None;
+        loop {
+            db.insert(b"k", value(0xCD, writes)).unwrap();
+            db.flush().unwrap();
+            writes += 1;
+            assert!(writes < 20_000, "no freed segment was reused");
+
+            if reused.is_none() {
+                let mut sa = sa.lock();
+                reused = freed.iter().copied().find(|lid| {
+                    let idx = sa.segment_id(*lid);
+                    sa.segments[idx].is_active()
+                });
+            }
+
+            // a segment becomes active as soon as it is reserved,
+            // which may be before the first write to it is flushed.
+            if let Some(lid) = reused {
+                let mut buf = vec![0; db.context.segment_size];
+                pread_exact(&db.context.file, &mut buf, lid).unwrap();
+                if has_value(&buf, 0xCD) {
+                    return buf;
+                }
+            }
+        }
+    }
+
+    fn has_value(segment: &[u8], byte: u8) -> bool {
+        segment.windows(16).any(|window| window.iter().all(|b| *b == byte))
+    }
+
+    #[test]
+    fn zero_on_reuse() {
+        assert!(has_value(&reused_segment(false), 0xAB));
+        assert!(!has_value(&reused_segment(true), 0xAB));
+    }
+}
@@ -1,10 +1,20 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
 use crate::*;
 
 use super::{
-    arr_to_u32, pwrite_all, raw_segment_iter_from, u32_to_arr, u64_to_arr,
-    BasedBuf, DiskPtr, HeapId, LogIter, LogKind, LogOffset, Lsn, MessageKind,
+    arr_to_u32, encode_for_disk, pwrite_all, raw_segment_iter_from,
+    read_segment_header, u32_to_arr, u64_to_arr, BasedBuf, DiskPtr, HeapId,
+    LogIter, LogKind, LogOffset, Lsn, MessageKind,
 };
 
+/// The least amount of time between two calls to the
+/// `Config::on_recovery_progress` callback.
+const RECOVERY_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
 /// A snapshot of the state required to quickly restart
 /// the `PageCache` and `SegmentAccountant`.
 #[derive(PartialEq, Debug, Default)]
@@ -249,10 +259,126 @@ impl Snapshot {
     }
 }
 
+/// Tracks whether the pages replayed so far are consistent
+/// without the rest of the log, which may have moved pages out
+/// of the segments and heap slots that they point to, before
+/// those were reused. Pages written at or after `replay_start`
+/// are in segments that were all replayed.
+///
+/// Replay may pause at every segment once it runs out of time,
+/// so after the first check, only the pages that were replayed
+/// since, or that were inconsistent before, are checked again.
+#[derive(Debug)]
+struct ReplayStopCheck {
+    replay_start: Lsn,
+    // the file is not written during replay, so each segment
+    // header only has to be read once
+    segment_lsns: Map<LogOffset, Option<Lsn>>,
+    inconsistent: FastSet8<PageId>,
+    // `None` until the first check, which covers every page
+    changed: Option<FastSet8<PageId>>,
+    checked_len: usize,
+}
+
+impl ReplayStopCheck {
+    fn new(replay_start: Lsn) -> ReplayStopCheck {
+        ReplayStopCheck {
+            replay_start,
+            segment_lsns: Map::default(),
+            inconsistent: FastSet8::default(),
+            changed: None,
+            checked_len: 0,
+        }
+    }
+
+    fn page_replayed(&mut self, pid: PageId) {
+        if let Some(changed) = &mut self.changed {
+            let _ = changed.insert(pid);
+        }
+    }
+
+    fn can_stop(
+        &mut self,
+        snapshot: &Snapshot,
+        config: &RunningConfig,
+    ) -> bool {
+        let changed = self.changed.take();
+        let pids: FastSet8<PageId> = if let Some(changed) = changed {
+            let added = self.checked_len as PageId..snapshot.pt.len() as PageId;
+            changed
+                .into_iter()
+                .chain(self.inconsistent.iter().copied())
+                .chain(added)
+                .collect()
+        } else {
+            (0..snapshot.pt.len() as PageId).collect()
+        };
+        self.changed = Some(FastSet8::default());
+        self.checked_len = snapshot.pt.len();
+
+        for pid in pids {
+            let page = &snapshot.pt[usize::try_from(pid).unwrap()];
+            if self.page_is_consistent(page, config) {
+                let _ = self.inconsistent.remove(&pid);
+            } else {
+                let _ = self.inconsistent.insert(pid);
+            }
+        }
+
+        self.inconsistent.is_empty()
+    }
+
+    fn page_is_consistent(
+        &mut self,
+        page: &PageState,
+        config: &RunningConfig,
+    ) -> bool {
+        let pointers = match page {
+            PageState::Present { base, frags } => {
+                let mut pointers = vec![*base];
+                pointers.extend_from_slice(frags);
+                pointers
+            }
+            PageState::Free(lsn, ptr) => vec![(*lsn, *ptr)],
+            PageState::Uninitialized => return false,
+        };
+
+        for (lsn, ptr) in pointers {
+            if let Some(heap_id) = ptr.heap_id() {
+                if config.heap.read(heap_id).is_err() {
+                    return false;
+                }
+            }
+
+            let lid = match ptr.lid() {
+                Some(lid) if lsn < self.replay_start => lid,
+                _ => continue,
+            };
+            let segment_lid = config.normalize(lid);
+            let header_lsn =
+                *self.segment_lsns.entry(segment_lid).or_insert_with(|| {
+                    read_segment_header(
+                        &config.file,
+                        segment_lid,
+                        config.crc_variant,
+                    )
+                    .ok()
+                    .map(|header| header.lsn)
+                });
+            if header_lsn != Some(config.normalize(lsn)) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
 fn advance_snapshot(
     mut iter: LogIter,
     mut snapshot: Snapshot,
     config: &RunningConfig,
+    warnings: &mut Vec<OpenWarning>,
 ) -> Result<Snapshot> {
     #[cfg(feature = "metrics")]
     let _measure = Measure::new(&M.advance_snapshot);
@@ -261,29 +387,101 @@ fn advance_snapshot(
 
     let old_stable_lsn = snapshot.stable_lsn;
 
-    for (log_kind, pid, lsn, ptr) in &mut iter {
-        trace!(
-            "in advance_snapshot looking at item with pid {} lsn {} ptr {}",
-            pid,
-            lsn,
-            ptr
-        );
+    #[cfg(feature = "event_log")]
+    let mut replayed_segments = Set::new();
+
+    // the iterator consumes its segments as it goes, so the
+    // progress is tracked against the segment lsns up front.
+    let segment_lsns: Vec<Lsn> = iter.segments.keys().copied().collect();
+    let first_lsn = segment_lsns.first().copied().unwrap_or(0);
+    let mut last_lsn = first_lsn;
+    let mut last_progress: Option<Instant> = None;
+
+    let report_progress = |lsn: Lsn| {
+        if let Some(callback) = &config.on_recovery_progress {
+            let segment_start = config.normalize(lsn);
+            let segments_recovered = segment_lsns
+                .iter()
+                .take_while(|segment_lsn| **segment_lsn <= segment_start)
+                .count();
+            callback(RecoveryProgress {
+                segments_recovered: segments_recovered as u64,
+                segments_total: segment_lsns.len() as u64,
+                bytes_replayed: u64::try_from(lsn - first_lsn).unwrap_or(0),
+            });
+        }
+    };
 
-        if lsn < snapshot.stable_lsn.unwrap_or(-1) {
-            // don't process already-processed Lsn's. stable_lsn is for the last
-            // item ALREADY INCLUDED lsn in the snapshot.
+    // the log before this was not replayed, so its segments
+    // may have been reused since the pages were written there
+    let mut stop_check = ReplayStopCheck::new(iter.contiguous_to.unwrap_or(0));
+
+    loop {
+        for (log_kind, pid, lsn, ptr) in &mut iter {
             trace!(
-                "continuing in advance_snapshot, lsn {} ptr {} stable_lsn {:?}",
+                "in advance_snapshot looking at item with pid {} lsn {} ptr {}",
+                pid,
                 lsn,
-                ptr,
-                snapshot.stable_lsn
+                ptr
             );
-            continue;
+
+            last_lsn = lsn;
+            let due = match last_progress {
+                Some(last) => last.elapsed() >= RECOVERY_PROGRESS_INTERVAL,
+                None => true,
+            };
+            if due {
+                last_progress = Some(Instant::now());
+                report_progress(lsn);
+            }
+
+            if lsn < snapshot.stable_lsn.unwrap_or(-1) {
+                // don't process already-processed Lsn's. stable_lsn is for
+                // the last item ALREADY INCLUDED lsn in the snapshot.
+                trace!(
+                    "continuing in advance_snapshot, lsn {} ptr {} stable_lsn {:?}",
+                    lsn,
+                    ptr,
+                    snapshot.stable_lsn
+                );
+                continue;
+            }
+
+            #[cfg(feature = "event_log")]
+            {
+                let _ = replayed_segments.insert(config.normalize(lsn));
+            }
+
+            snapshot.apply(log_kind, pid, lsn, ptr)?;
+            stop_check.page_replayed(pid);
+        }
+
+        if !iter.paused || stop_check.can_stop(&snapshot, config) {
+            break;
         }
 
-        snapshot.apply(log_kind, pid, lsn, ptr)?;
+        debug!(
+            "not stopping replay before the segment at lsn {:?} \
+             because the replayed pages are not consistent on their own",
+            iter.paused_at
+        );
+        iter.paused = false;
+    }
+
+    if !segment_lsns.is_empty() {
+        report_progress(last_lsn);
+    }
+
+    for lsn in &iter.checksum_failures {
+        warn!("log message at lsn {} failed its checksum", lsn);
+        warnings.push(OpenWarning::LogChecksumMismatch {
+            lsn: u64::try_from(*lsn).unwrap(),
+        });
     }
 
+    #[cfg(feature = "event_log")]
+    config.event_log.replayed_segments(replayed_segments.len());
+
     // `snapshot.tip_lid` can be set based on 4 possibilities for the tip of the
     // log:
     // 1. an empty DB - tip set to None, causing a fresh segment to be
@@ -367,8 +565,21 @@ fn advance_snapshot(
                     shred_base,
                     shred_base + shred_len as LogOffset
                 );
-                pwrite_all(&config.file, &shred_zone, shred_base)?;
-                config.file.sync_all()?;
+                // the tail only needs to be zeroed so that it can
+                // be written to again.
+                if !config.verify_only {
+                    if let Some(ref transform) = config.segment_transform {
+                        let encoded = encode_for_disk(
+                            &**transform,
+                            iterated_lsn,
+                            &shred_zone,
+                        )?;
+                        pwrite_all(&config.file, &encoded, shred_base)?;
+                    } else {
+                        pwrite_all(&config.file, &shred_zone, shred_base)?;
+                    }
+                    config.file.sync_all()?;
+                }
             }
             (iterated_lsn, iter.segment_base.map(|bb| bb.offset))
         };
@@ -383,6 +594,15 @@ fn advance_snapshot(
             return Err(Error::corruption(None));
         }
 
+        if let Some(pid) = snapshot
+            .pt
+            .iter()
+            .position(|page| *page == PageState::Uninitialized)
+        {
+            error!("page {} was never recovered, but pages after it were", pid);
+            return Err(Error::corruption(None));
+        }
+
         snapshot.stable_lsn = Some(stable_lsn);
         snapshot.active_segment = active_segment;
         snapshot.filter_inner_heap_ids();
@@ -395,7 +615,7 @@ fn advance_snapshot(
         return Err(Error::corruption(None));
     }
 
-    if snapshot.stable_lsn > old_stable_lsn {
+    if snapshot.stable_lsn > old_stable_lsn && !config.verify_only {
         write_snapshot(config, &snapshot)?;
     }
 
@@ -431,7 +651,31 @@ fn advance_snapshot(
         reverse_segments
     };
 
+    if iter.paused {
+        let recovered_to_lsn = snapshot.stable_lsn.unwrap_or(0);
+        error!(
+            "log replay exceeded max_recovery_duration, so recovery stopped \
+             at lsn {} and {} later segments are being discarded along \
+             with any writes they held",
+            recovered_to_lsn,
+            iter.segments.len()
+        );
+        warnings.push(OpenWarning::PartialRecovery {
+            recovered_to_lsn: u64::try_from(recovered_to_lsn).unwrap(),
+            skipped_segments: iter.segments.len() as u64,
+        });
+    }
+
     for (lsn, to_zero) in &iter.segments {
+        warnings.push(OpenWarning::UnreplayedSegment {
+            lsn: u64::try_from(*lsn).unwrap(),
+        });
+
+        if config.verify_only {
+            debug!("not zeroing torn segment at lsn {} lid {}", lsn, to_zero);
+            continue;
+        }
+
         debug!("zeroing torn segment at lsn {} lid {}", lsn, to_zero);
 
         #[cfg(feature = "testing")]
@@ -471,40 +715,132 @@ fn advance_snapshot(
 
 /// Read a `Snapshot` or generate a default, then advance it to
 /// the tip of the data file, if present.
-pub fn read_snapshot_or_default(config: &RunningConfig) -> Result<Snapshot> {
-    // NB we want to error out if the read snapshot was corrupted.
-    // We only use a default Snapshot when there is no snapshot found.
-    let last_snap = read_snapshot(config)?.unwrap_or_default();
+pub fn read_snapshot_or_default(
+    config: &RunningConfig,
+    warnings: &mut Vec<OpenWarning>,
+) -> Result<Snapshot> {
+    // NB a snapshot that was only partially written is discarded,
+    // and we use a default Snapshot when no valid one is found.
+    let target = config
+        .recover_to_snapshot
+        .map(|lsn| Lsn::try_from(lsn).unwrap_or(Lsn::MAX));
+    let mut last_snap =
+        read_snapshot(config, target, warnings)?.unwrap_or_default();
+
+    // NB the marker is removed even if it is not used, so
+    // that a crash after this point can never be mistaken
+    // for a clean shutdown.
+    let start_lsn = match config.take_clean_shutdown()? {
+        Some(lsn)
+            if config.fast_clean_restart
+                && target.is_none()
+                && Some(lsn) == last_snap.stable_lsn =>
+        {
+            // the snapshot was written after everything else, so
+            // there is nothing to replay, and the segments don't
+            // need to be scanned either.
+            debug!("skipping log replay after clean shutdown at lsn {}", lsn);
+
+            // like after a replay, heap items are only found
+            // through the snapshot now.
+            last_snap.filter_inner_heap_ids();
+
+            #[cfg(feature = "event_log")]
+            {
+                // the snapshot's lsn is the last stable one, while
+                // recovery reports the next one, like stabilizations.
+                config.event_log.replayed_segments(0);
+                config.event_log.recovered_lsn(lsn + 1);
+            }
+
+            return Ok(last_snap);
+        }
+        Some(lsn) => {
+            debug!(
+                "ignoring clean shutdown marker at lsn {} that does not \
+                 match the latest snapshot {:?}",
+                lsn, last_snap.stable_lsn
+            );
+            last_snap.stable_lsn.unwrap_or(0)
+        }
+        None => last_snap.stable_lsn.unwrap_or(0),
+    };
 
-    let log_iter =
-        raw_segment_iter_from(last_snap.stable_lsn.unwrap_or(0), config)?;
+    let mut log_iter = raw_segment_iter_from(start_lsn, config)?;
+    if let Some(target) = target {
+        debug!("replaying the log only up to lsn {}", target);
+        log_iter.max_lsn = log_iter.max_lsn.map(|max_lsn| max_lsn.min(target));
+    }
+    log_iter.deadline =
+        config.max_recovery_duration.map(|budget| Instant::now() + budget);
 
-    let res = advance_snapshot(log_iter, last_snap, config)?;
+    let res = advance_snapshot(log_iter, last_snap, config, warnings)?;
 
     Ok(res)
 }
 
-/// Read a `Snapshot` from disk.
-/// Returns an error if the read snapshot was corrupted.
-/// Returns `Ok(Some(snapshot))` if there was nothing written.
-fn read_snapshot(config: &RunningConfig) -> Result<Option<Snapshot>> {
+/// Read the newest valid `Snapshot` from disk, discarding
+/// snapshot files that were only partially written. Only
+/// snapshots at or below `target` are considered, if set.
+/// Returns `Ok(None)` if there was nothing written.
+fn read_snapshot(
+    config: &RunningConfig,
+    target: Option<Lsn>,
+    warnings: &mut Vec<OpenWarning>,
+) -> Result<Option<Snapshot>> {
+    remove_stale_generating(config, warnings)?;
+
     let mut candidates = config.get_snapshot_files()?;
+    if let Some(target) = target {
+        candidates.retain(|path| {
+            let lsn = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix("snap."))
+                .and_then(|lsn| Lsn::from_str_radix(lsn, 16).ok());
+            matches!(lsn, Some(lsn) if lsn <= target)
+        });
+    }
     if candidates.is_empty() {
         debug!("no previous snapshot found");
         return Ok(None);
     }
 
     candidates.sort();
-    let path = candidates.pop().unwrap();
 
-    let mut f = std::fs::OpenOptions::new().read(true).open(&path)?;
+    let mut newest = None;
+    for path in candidates.into_iter().rev() {
+        match read_snapshot_file(config, &path)? {
+            Some(snapshot) => {
+                if newest.is_none() {
+                    newest = Some(snapshot);
+                }
+            }
+            None => discard_snapshot(config, path, warnings)?,
+        }
+    }
+
+    if newest.is_none() {
+        warn!("no valid snapshot found, replaying the whole log");
+    }
+
+    Ok(newest)
+}
+
+/// Reads the snapshot at `path`, returning `Ok(None)` if its
+/// length or CRC shows that it was only partially written.
+fn read_snapshot_file(
+    config: &RunningConfig,
+    path: &Path,
+) -> Result<Option<Snapshot>> {
+    let mut f = std::fs::OpenOptions::new().read(true).open(path)?;
 
     let mut buf = vec![];
     let _read = f.read_to_end(&mut buf)?;
     let len = buf.len();
     if len <= 12 {
         warn!("empty/corrupt snapshot file found at path: {:?}", path);
-        return Err(Error::corruption(None));
+        return Ok(None);
     }
 
     let mut len_expected_bytes = [0; 8];
@@ -514,9 +850,20 @@ fn read_snapshot(config: &RunningConfig) -> Result<Option<Snapshot>> {
     crc_expected_bytes.copy_from_slice(&buf[len - 4..]);
 
     let _ = buf.split_off(len - 12);
+    let len_expected = u64::from_le_bytes(len_expected_bytes);
+
+    if len_expected != buf.len() as u64 {
+        warn!(
+            "corrupt snapshot file found, length does not match expected. \
+            path: {:?}",
+            path
+        );
+        return Ok(None);
+    }
+
     let crc_expected: u32 = arr_to_u32(&crc_expected_bytes);
 
-    let crc_actual = crc32(&buf);
+    let crc_actual = crc::checksum(config.crc_variant, &buf);
 
     if crc_expected != crc_actual {
         warn!(
@@ -524,21 +871,72 @@ fn read_snapshot(config: &RunningConfig) -> Result<Option<Snapshot>> {
             path: {:?}",
             path
         );
-        return Err(Error::corruption(None));
+        return Ok(None);
     }
 
     Snapshot::deserialize(&mut buf.as_slice()).map(Some)
 }
 
+/// Removes the `.generating` files left behind by snapshots
+/// that were interrupted by a crash, once they are older than
+/// `Config::stale_snapshot_age`.
+fn remove_stale_generating(
+    config: &RunningConfig,
+    warnings: &mut Vec<OpenWarning>,
+) -> Result<()> {
+    for entry in std::fs::read_dir(config.get_path())? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !name.starts_with("snap.") || !name.ends_with(".generating") {
+            continue;
+        }
+
+        let age = entry
+            .metadata()?
+            .modified()?
+            .elapsed()
+            .unwrap_or_else(|_| Duration::from_secs(0));
+        if age < config.stale_snapshot_age {
+            debug!(
+                "keeping snapshot file {:?} that was modified {:?} ago",
+                entry.path(),
+                age
+            );
+            continue;
+        }
+
+        discard_snapshot(config, entry.path(), warnings)?;
+    }
+    Ok(())
+}
+
+fn discard_snapshot(
+    config: &RunningConfig,
+    path: PathBuf,
+    warnings: &mut Vec<OpenWarning>,
+) -> Result<()> {
+    if config.verify_only {
+        warn!("ignoring partially written snapshot file {:?}", path);
+    } else {
+        warn!("removing partially written snapshot file {:?}", path);
+        std::fs::remove_file(&path)?;
+    }
+    warnings.push(OpenWarning::DiscardedSnapshot { path });
+    Ok(())
+}
+
 pub(in crate::pagecache) fn write_snapshot(
     config: &RunningConfig,
     snapshot: &Snapshot,
 ) -> Result<()> {
+    config.check_writable()?;
+
     trace!("writing snapshot {:?}", snapshot);
 
     let bytes = snapshot.serialize();
 
-    let crc32: [u8; 4] = u32_to_arr(crc32(&bytes));
+    let crc32: [u8; 4] = u32_to_arr(crc::checksum(config.crc_variant, &bytes));
     let len_bytes: [u8; 8] = u64_to_arr(bytes.len() as u64);
 
     let path_1_suffix =
@@ -555,8 +953,11 @@ pub(in crate::pagecache) fn write_snapshot(
 
     let parent = path_1.parent().unwrap();
     std::fs::create_dir_all(parent)?;
-    let mut f =
-        std::fs::OpenOptions::new().write(true).create(true).open(&path_1)?;
+    let mut f = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path_1)?;
 
     // write the snapshot bytes, followed by a crc64 checksum at the end
     io_fail!(config, "snap write");
@@ -573,7 +974,7 @@ pub(in crate::pagecache) fn write_snapshot(
     io_fail!(config, "snap write mv");
     std::fs::rename(&path_1, &path_2)?;
     io_fail!(config, "snap write dir fsync");
-    maybe_fsync_directory(config.get_path())?;
+    config.fsync_directory(config.get_path())?;
     io_fail!(config, "snap write mv post");
 
     trace!("renamed snapshot to {}", path_2.to_string_lossy());
@@ -3,6 +3,7 @@ use std::{
     error::Error as StdError,
     fmt::{self, Display},
     io,
+    path::PathBuf,
 };
 
 use crate::pagecache::{DiskPtr, PageView};
@@ -26,7 +27,7 @@ pub(crate) type CasResult<'a, R> =
 
 /// An Error type encapsulating various issues that may come up
 /// in the operation of a `Db`.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum Error {
     /// The underlying collection no longer exists.
     CollectionNotFound,
@@ -37,6 +38,21 @@ pub enum Error {
     /// A read or write error has happened when interacting with the file
     /// system.
     Io(io::ErrorKind, &'static str),
+    /// The database was created with a different compression
+    /// setting than the one configured. Setting
+    /// `Config::use_compression` to `db_uses_compression`
+    /// allows it to be opened.
+    CompressionMismatch {
+        /// Whether the stored database was created with
+        /// compression enabled.
+        db_uses_compression: bool,
+    },
+    /// The database file is locked by another `Db`, which
+    /// may be in this or another process.
+    LockContended {
+        /// The directory of the database whose file is locked.
+        path: PathBuf,
+    },
     /// Corruption has been detected in the storage file.
     Corruption {
         /// The file location that corrupted data was found at.
@@ -76,6 +92,20 @@ impl PartialEq for Error {
                     false
                 }
             }
+            CompressionMismatch { db_uses_compression: l } => {
+                if let CompressionMismatch { db_uses_compression: r } = *other {
+                    l == r
+                } else {
+                    false
+                }
+            }
+            LockContended { path: ref l } => {
+                if let LockContended { path: ref r } = *other {
+                    l == r
+                } else {
+                    false
+                }
+            }
             #[cfg(feature = "failpoints")]
             FailPoint => {
                 matches!(other, FailPoint)
@@ -120,6 +150,13 @@ impl From<Error> for io::Error {
                     what
                 ),
             ),
+            CompressionMismatch { .. } => io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("operation not supported: {}", error),
+            ),
+            LockContended { .. } => {
+                io::Error::new(ErrorKind::WouldBlock, format!("{}", error))
+            }
             Corruption { .. } => io::Error::new(
                 ErrorKind::InvalidData,
                 format!("corruption encountered: {:?}", error),
@@ -150,11 +187,29 @@ impl Display for Error {
                  PLEASE REPORT THIS BUG!",
                 e
             ),
+            CompressionMismatch { db_uses_compression: true } => write!(
+                f,
+                "Cannot change compression configuration across restarts. \
+                 This database was created with compression enabled, \
+                 so Config::use_compression must be set to true."
+            ),
+            CompressionMismatch { db_uses_compression: false } => write!(
+                f,
+                "Cannot change compression configuration across restarts. \
+                 This database was created without compression enabled, \
+                 so Config::use_compression must be set to false."
+            ),
             #[cfg(feature = "failpoints")]
             FailPoint => write!(f, "Fail point has been triggered."),
             Io(ref kind, ref reason) => {
                 write!(f, "IO error: ({:?}, {})", kind, reason)
             }
+            LockContended { ref path } => write!(
+                f,
+                "Could not acquire the lock on the database at {:?}, \
+                 which is already open in this or another process.",
+                path
+            ),
             Corruption { at } => {
                 write!(f, "Read corrupted data at file offset {:?}", at)
             }
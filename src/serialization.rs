@@ -846,6 +846,31 @@ mod qc {
         }
     }
 
+    #[test]
+    fn counter_encoding_is_host_independent() {
+        // the idgen high-water mark is persisted as a `u64`,
+        // so these bytes must not depend on the host's
+        // endianness for a database to be portable.
+        let cases: &[(u64, &[u8])] = &[
+            (240, &[240]),
+            (2287, &[248, 255]),
+            (67823, &[249, 255, 255]),
+            (3_000_000, &[250, 0xC0, 0xC6, 0x2D]),
+            (0x0102_0304_0506, &[253, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]),
+            (
+                0x0102_0304_0506_0708,
+                &[255, 0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01],
+            ),
+        ];
+
+        for (counter, expected) in cases {
+            assert_eq!(&counter.serialize(), expected);
+            let mut buf = *expected;
+            assert_eq!(u64::deserialize(&mut buf).unwrap(), *counter);
+            assert!(buf.is_empty());
+        }
+    }
+
     quickcheck::quickcheck! {
         #[cfg_attr(miri, ignore)]
         fn bool(item: bool) -> bool {
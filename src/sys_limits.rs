@@ -5,7 +5,10 @@ use std::io;
 #[cfg(any(target_os = "linux"))]
 use {std::fs::File, std::io::Read};
 
-use std::convert::TryFrom;
+use std::{convert::TryFrom, path::Path};
+
+#[cfg(all(not(miri), target_os = "linux"))]
+use std::{ffi::CString, os::unix::ffi::OsStrExt};
 
 /// See the Kernel's documentation for more information about this subsystem,
 /// found at:  [Documentation/cgroup-v1/memory.txt](https://www.kernel.org/doc/Documentation/cgroup-v1/memory.txt)
@@ -60,6 +63,18 @@ pub fn get_available_memory() -> io::Result<usize> {
     Ok(usize::try_from(pages).unwrap() * usize::try_from(page_size).unwrap())
 }
 
+/// Returns the total physical memory of the system, in bytes,
+/// ignoring any cgroup or rlimit restrictions.
+#[cfg(all(not(miri), any(target_os = "linux", target_os = "macos")))]
+pub fn get_total_memory() -> Option<usize> {
+    get_available_memory().ok()
+}
+
+#[cfg(any(miri, not(any(target_os = "linux", target_os = "macos"))))]
+pub fn get_total_memory() -> Option<usize> {
+    None
+}
+
 #[cfg(miri)]
 pub fn get_memory_limit() -> Option<usize> {
     None
@@ -120,3 +135,62 @@ pub fn get_memory_limit() -> Option<usize> {
         Some(ret)
     }
 }
+
+#[cfg(all(test, target_os = "linux"))]
+thread_local! {
+    /// Replaces the `f_type` that `get_filesystem_type`
+    /// reports on this thread.
+    pub static MOCK_FS_TYPE: std::cell::Cell<Option<u64>> =
+        const { std::cell::Cell::new(None) };
+}
+
+#[cfg(all(not(miri), target_os = "linux"))]
+const NFS_SUPER_MAGIC: u64 = 0x6969;
+#[cfg(all(not(miri), target_os = "linux"))]
+const OVERLAYFS_SUPER_MAGIC: u64 = 0x794c_7630;
+#[cfg(all(not(miri), target_os = "linux"))]
+const TMPFS_MAGIC: u64 = 0x0102_1994;
+
+/// Returns the `f_type` magic number that `statfs` reports
+/// for the filesystem holding `path`.
+#[cfg(all(not(miri), target_os = "linux"))]
+fn get_filesystem_type(path: &Path) -> io::Result<u64> {
+    #[cfg(test)]
+    {
+        if let Some(fs_type) = MOCK_FS_TYPE.with(std::cell::Cell::get) {
+            return Ok(fs_type);
+        }
+    }
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut buf = std::mem::MaybeUninit::<libc::statfs>::uninit();
+
+    let ret = unsafe { libc::statfs(c_path.as_ptr(), buf.as_mut_ptr()) };
+
+    if ret == 0 {
+        #[allow(clippy::cast_sign_loss)]
+        Ok(unsafe { buf.assume_init() }.f_type as u64)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Returns the name of the filesystem holding `path` if
+/// it is one whose fsync does not uphold sled's durability
+/// guarantees: NFS, overlayfs, or tmpfs, which loses
+/// everything on reboot.
+#[cfg(all(not(miri), target_os = "linux"))]
+pub fn get_risky_filesystem(path: &Path) -> Option<&'static str> {
+    match get_filesystem_type(path).ok()? {
+        NFS_SUPER_MAGIC => Some("nfs"),
+        OVERLAYFS_SUPER_MAGIC => Some("overlayfs"),
+        TMPFS_MAGIC => Some("tmpfs"),
+        _ => None,
+    }
+}
+
+#[cfg(any(miri, not(target_os = "linux")))]
+pub fn get_risky_filesystem(_path: &Path) -> Option<&'static str> {
+    None
+}
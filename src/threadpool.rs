@@ -244,7 +244,7 @@ pub fn truncate(config: crate::RunningConfig, at: u64) -> OneShot<Result<()>> {
                 .map_err(Into::into);
 
             if let Err(e) = &ret {
-                config.set_global_error(*e);
+                config.set_global_error(e.clone());
             }
 
             ret
@@ -99,7 +99,7 @@ pub struct TransactionalTree {
 
 /// An error type that is returned from the closure
 /// passed to the `transaction` method.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum UnabortableTransactionError {
     /// An internal conflict has occurred and the `transaction` method will
     /// retry the passed-in closure until it succeeds. This should never be
@@ -209,6 +209,9 @@ pub enum TransactionError<T = Error> {
     /// attention from an operator or a remediating system, such as
     /// corruption.
     Storage(Error),
+    /// The transaction conflicted more times than
+    /// `Config::max_transaction_retries` allows.
+    TooManyRetries,
 }
 
 impl<E: fmt::Display> fmt::Display for TransactionError<E> {
@@ -217,6 +220,9 @@ impl<E: fmt::Display> fmt::Display for TransactionError<E> {
         match self {
             Abort(e) => e.fmt(f),
             Storage(e) => e.fmt(f),
+            TooManyRetries => {
+                write!(f, "Transaction exceeded max_transaction_retries")
+            }
         }
     }
 }
@@ -264,9 +270,13 @@ impl TransactionalTree {
         K: AsRef<[u8]> + Into<IVec>,
         V: Into<IVec>,
     {
+        // NB checked here rather than at commit, so that no
+        // tree in a multi-tree transaction is partially applied
+        let value = value.into();
+        self.tree.check_value_size(value.len())?;
         let old = self.get(key.as_ref())?;
         let mut writes = self.writes.borrow_mut();
-        writes.insert(key, value.into());
+        writes.insert(key, value);
         Ok(old)
     }
 
@@ -415,6 +425,12 @@ impl TransactionalTrees {
         peg.seal_batch()
     }
 
+    fn max_retries(&self) -> Option<usize> {
+        self.inner
+            .first()
+            .and_then(|tree| tree.tree.context.max_transaction_retries)
+    }
+
     fn flush_if_configured(&self) -> Result<()> {
         let mut should_flush = None;
 
@@ -455,11 +471,14 @@ pub trait Transactional<E = ()> {
     /// Runs a transaction, possibly retrying the passed-in closure if
     /// a concurrent conflict is detected that would cause a violation
     /// of serializability. This is the only trait method that
-    /// you're most likely to use directly.
+    /// you're most likely to use directly. Once the closure has
+    /// been retried `Config::max_transaction_retries` times,
+    /// another conflict returns `TransactionError::TooManyRetries`.
     fn transaction<F, A>(&self, f: F) -> TransactionResult<A, E>
     where
         F: Fn(&Self::View) -> ConflictableTransactionResult<A, E>,
     {
+        let mut retries = 0;
         loop {
             let tt = self.make_overlay()?;
             let view = Self::view_overlay(&tt);
@@ -467,7 +486,16 @@ pub trait Transactional<E = ()> {
             // NB locks must exist until this function returns.
             let locks = tt.stage();
             let ret = f(&view);
-            if !tt.validate() {
+            let valid = tt.validate();
+            if !valid
+                || matches!(ret, Err(ConflictableTransactionError::Conflict))
+            {
+                if matches!(tt.max_retries(), Some(max) if retries >= max) {
+                    return Err(TransactionError::TooManyRetries);
+                }
+                retries += 1;
+            }
+            if !valid {
                 tt.unstage();
                 continue;
             }
@@ -3,12 +3,16 @@ use std::{
     fmt::{self, Debug},
     num::NonZeroU64,
     ops::{self, Deref, RangeBounds},
-    sync::atomic::Ordering::SeqCst,
+    sync::atomic::{AtomicBool, Ordering::SeqCst},
 };
 
 use parking_lot::RwLock;
 
-use crate::{atomic_shim::AtomicU64, pagecache::NodeView, *};
+use crate::{
+    atomic_shim::{AtomicI64, AtomicU64},
+    pagecache::NodeView,
+    *,
+};
 
 #[derive(Debug, Clone)]
 pub(crate) struct View<'g> {
@@ -37,6 +41,11 @@ const fn out_of_bounds(numba: usize) -> bool {
     numba > MAX_BLOB
 }
 
+#[cold]
+const fn max_value_size_error() -> Result<()> {
+    Err(Error::Unsupported("value exceeds configured max_value_size"))
+}
+
 #[cold]
 const fn bounds_error() -> Result<()> {
     Err(Error::Unsupported(
@@ -111,6 +120,27 @@ pub struct TreeInner {
     pub(crate) subscribers: Subscribers,
     pub(crate) root: AtomicU64,
     pub(crate) merge_operator: RwLock<Option<Box<dyn MergeOperator>>>,
+    // set by `Db::pin_tree`, so that pages gained by
+    // splitting are pinned too
+    pub(crate) pinned: AtomicBool,
+    // shared by every handle to the tree
+    pub(crate) user_bytes: Arc<UserBytes>,
+}
+
+/// The total size of the keys and values stored in a tree,
+/// for `Db::tree_sizes`.
+pub(crate) struct UserBytes {
+    // the size when the tree was opened, or `None` if it must
+    // be counted
+    base: Mutex<Option<i64>>,
+    // the change in that size since then
+    delta: AtomicI64,
+}
+
+impl UserBytes {
+    pub(crate) fn new(base: Option<i64>) -> UserBytes {
+        UserBytes { base: Mutex::new(base), delta: AtomicI64::new(0) }
+    }
 }
 
 impl Drop for TreeInner {
@@ -174,6 +204,47 @@ impl Tree {
         }
     }
 
+    /// Records a write that replaced `old` with `new` at `key`
+    /// for `Db::space_amplification` and `Db::tree_sizes`.
+    fn account_user_bytes(
+        &self,
+        key: &[u8],
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) {
+        let size = |value: Option<&[u8]>| {
+            value.map_or(0, |value| (key.len() + value.len()) as i64)
+        };
+        let delta = size(new) - size(old);
+        self.user_bytes.delta.fetch_add(delta, SeqCst);
+        self.context.pagecache.add_user_bytes(delta);
+    }
+
+    /// Returns the total size of the keys and values stored in
+    /// this tree. The first call for a tree that was not
+    /// created by this process reads the whole tree to count
+    /// them, after which every write keeps the total up to
+    /// date.
+    pub(crate) fn user_bytes(&self) -> Result<u64> {
+        let base = {
+            let mut base = self.user_bytes.base.lock();
+            if let Some(base) = *base {
+                base
+            } else {
+                let delta = self.user_bytes.delta.load(SeqCst);
+                let mut counted = 0;
+                for kv in self.iter() {
+                    let (k, v) = kv?;
+                    counted += (k.len() + v.len()) as i64;
+                }
+                *base.insert(counted - delta)
+            }
+        };
+
+        Ok(u64::try_from(base + self.user_bytes.delta.load(SeqCst))
+            .unwrap_or(0))
+    }
+
     pub(crate) fn insert_inner(
         &self,
         key: &[u8],
@@ -192,6 +263,10 @@ impl Tree {
             bounds_error()?;
         }
 
+        if let Some(ref value) = value {
+            self.check_value_size(value.len())?;
+        }
+
         let View { node_view, pid, .. } =
             self.view_for_key(key.as_ref(), guard)?;
 
@@ -234,6 +309,7 @@ impl Tree {
 
         if link.is_ok() {
             // success
+            self.account_user_bytes(key, last_value, value.as_deref());
             if let Some(Some(res)) = subscriber_reservation.take() {
                 let event = subscriber::Event::single_update(
                     self.clone(),
@@ -422,6 +498,12 @@ impl Tree {
 
         trace!("applying batch {:?}", batch);
 
+        // check every value before writing any of them, so that
+        // an oversized value does not leave the batch half-applied.
+        for value in batch.writes.values().flatten() {
+            self.check_value_size(value.len())?;
+        }
+
         let mut subscriber_reservation = self.subscribers.reserve_batch(&batch);
 
         for (k, v_opt) in &batch.writes {
@@ -645,7 +727,11 @@ impl Tree {
         let guard = pin();
         let _cc = concurrency_control::read();
 
-        let new2 = new.map(Into::into);
+        let new2: Option<IVec> = new.map(Into::into);
+
+        if let Some(ref new) = new2 {
+            self.check_value_size(new.len())?;
+        }
 
         // we need to retry caps until old != cur, since just because
         // cap fails it doesn't mean our value was changed.
@@ -687,6 +773,11 @@ impl Tree {
                 self.context.pagecache.link(pid, node_view.0, frag, &guard)?;
 
             if link.is_ok() {
+                self.account_user_bytes(
+                    key.as_ref(),
+                    current_value,
+                    new2.as_deref(),
+                );
                 if let Some(res) = subscriber_reservation.take() {
                     let event = subscriber::Event::single_update(
                         self.clone(),
@@ -931,26 +1022,19 @@ impl Tree {
     /// and calls fsync. If this succeeds, it is
     /// guaranteed that all previous writes will
     /// be recovered if the system crashes. Returns
-    /// the number of bytes flushed during this call.
+    /// the number of bytes flushed while waiting.
+    ///
+    /// This does not block the calling thread or
+    /// spawn one of its own. The buffers are written
+    /// by sled's IO threads, which wake the task once
+    /// the data is durable.
     ///
     /// Flushing can take quite a lot of time, and you
     /// should measure the performance impact of
     /// using it on realistic sustained workloads
     /// running on realistic hardware.
-    // this clippy check is miss-firing on async code.
-    #[allow(clippy::used_underscore_binding)]
-    #[allow(clippy::shadow_same)]
     pub async fn flush_async(&self) -> Result<usize> {
-        let pagecache = self.context.pagecache.clone();
-        if let Some(result) = threadpool::spawn(move || pagecache.flush()).await
-        {
-            result
-        } else {
-            Err(Error::ReportableBug(
-                "threadpool failed to complete \
-                action before shutdown"
-            ))
-        }
+        self.context.pagecache.flush_async().await
     }
 
     /// Returns `true` if the `Tree` contains a value for
@@ -1179,6 +1263,10 @@ impl Tree {
             let tmp = current_value.as_ref().map(AsRef::as_ref);
             let new_opt = merge_operator(key, tmp, value).map(IVec::from);
 
+            if let Some(ref new) = new_opt {
+                self.check_value_size(new.len())?;
+            }
+
             if new_opt.as_ref().map(AsRef::as_ref) == current_value {
                 // short-circuit no-op write
                 return Ok(Ok(new_opt));
@@ -1195,6 +1283,7 @@ impl Tree {
                 self.context.pagecache.link(pid, node_view.0, frag, &guard)?;
 
             if link.is_ok() {
+                self.account_user_bytes(key, current_value, new_opt.as_deref());
                 if let Some(res) = subscriber_reservation.take() {
                     let event = subscriber::Event::single_update(
                         self.clone(),
@@ -1628,6 +1717,10 @@ impl Tree {
         #[cfg(feature = "metrics")]
         M.tree_child_split_success();
 
+        if self.pinned.load(Acquire) {
+            self.context.pagecache.pin(rhs_pid);
+        }
+
         // either install parent split or hoist root
         if let Some(parent_view) = parent_view_opt {
             #[cfg(feature = "metrics")]
@@ -1704,6 +1797,10 @@ impl Tree {
             #[cfg(feature = "metrics")]
             M.tree_root_split_success();
 
+            if self.pinned.load(Acquire) {
+                self.context.pagecache.pin(new_root_pid);
+            }
+
             // we spin in a cas loop because it's possible
             // 2 threads are at this point, and we don't want
             // to cause roots to diverge between meta and
@@ -1734,6 +1831,13 @@ impl Tree {
         }
     }
 
+    pub(crate) fn check_value_size(&self, len: usize) -> Result<()> {
+        match self.context.max_value_size {
+            Some(max) if len > max => max_value_size_error(),
+            _ => Ok(()),
+        }
+    }
+
     pub(crate) fn view_for_pid<'g>(
         &self,
         pid: PageId,
@@ -0,0 +1,27 @@
+// the limit is process-wide, so this lives in its own
+// test binary to avoid interfering with other tests.
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn max_open_databases() {
+    sled::set_max_open_databases(1);
+
+    let config = || sled::Config::new().temporary(true);
+
+    let db = config().open().unwrap();
+    assert_eq!(
+        config().open().unwrap_err(),
+        sled::Error::Unsupported("open database limit reached")
+    );
+
+    // trees keep the database open too
+    let tree = db.open_tree(b"tree").unwrap();
+    drop(db);
+    assert!(config().open().is_err());
+    drop(tree);
+
+    let db = config().open().unwrap();
+    drop(db);
+
+    sled::set_max_open_databases(usize::MAX);
+}
@@ -672,6 +672,64 @@ fn concurrent_tree_transactions() -> TransactionResult<()> {
     Ok(())
 }
 
+#[test]
+#[cfg(not(miri))] // can't create threads
+fn bounded_transaction_retries() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Mutex};
+
+    common::setup_logger();
+
+    let db = Config::new()
+        .temporary(true)
+        .max_transaction_retries(Some(5))
+        .open()
+        .unwrap();
+
+    // another thread holds the resource for the whole
+    // transaction, so every attempt conflicts
+    let resource = Arc::new(Mutex::new(()));
+    let (locked_tx, locked_rx) = mpsc::channel();
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    let holder = {
+        let resource = resource.clone();
+        std::thread::spawn(move || {
+            let _held = resource.lock().unwrap();
+            locked_tx.send(()).unwrap();
+            done_rx.recv().unwrap();
+        })
+    };
+    locked_rx.recv().unwrap();
+
+    let attempts = AtomicUsize::new(0);
+    let res = db.transaction::<_, _, ()>(|tx| {
+        attempts.fetch_add(1, Ordering::SeqCst);
+        let _held = resource
+            .try_lock()
+            .map_err(|_| ConflictableTransactionError::Conflict)?;
+        tx.insert(b"k1", b"v1")?;
+        Ok(())
+    });
+
+    assert_eq!(res, Err(TransactionError::TooManyRetries));
+    assert_eq!(attempts.load(Ordering::SeqCst), 6);
+    assert_eq!(db.get(b"k1").unwrap(), None);
+
+    done_tx.send(()).unwrap();
+    holder.join().unwrap();
+
+    // once the contention is gone the same transaction succeeds
+    db.transaction::<_, _, ()>(|tx| {
+        let _held = resource
+            .try_lock()
+            .map_err(|_| ConflictableTransactionError::Conflict)?;
+        tx.insert(b"k1", b"v1")?;
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(db.get(b"k1").unwrap(), Some(IVec::from(b"v1")));
+}
+
 #[test]
 fn tree_flush_in_transaction() {
     let config = sled::Config::new().temporary(true);
@@ -781,6 +839,952 @@ fn tree_subdir() {
     std::fs::remove_dir_all(&parent_path).unwrap();
 }
 
+#[test]
+#[cfg_attr(miri, ignore)]
+fn nested_db_rejected() {
+    let mut outer_path = std::env::temp_dir();
+    outer_path.push("test_nested_db_rejected");
+
+    let _ = std::fs::remove_dir_all(&outer_path);
+
+    let outer = Config::new().path(&outer_path).open().unwrap();
+    outer.insert(b"k", b"v").unwrap();
+    drop(outer);
+
+    let inner_path = outer_path.join("inner");
+
+    let res = Config::new().path(&inner_path).open();
+    assert_eq!(
+        res.unwrap_err(),
+        Error::Unsupported(
+            "configured path is nested inside another sled database"
+        )
+    );
+
+    let inner =
+        Config::new().path(&inner_path).allow_nested(true).open().unwrap();
+    drop(inner);
+
+    std::fs::remove_dir_all(&outer_path).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn checkpoint() {
+    let db = Config::new().temporary(true).open().unwrap();
+
+    for i in 0_u32..100 {
+        db.insert(i.to_be_bytes(), vec![0; 100]).unwrap();
+    }
+
+    let lsn = db.checkpoint().unwrap();
+    let expected = format!("snap.{:016X}", lsn);
+
+    let snapshots = db.context.get_snapshot_files().unwrap();
+    assert!(
+        snapshots.iter().any(|path| path.ends_with(&expected)),
+        "expected to find {} in {:?}",
+        expected,
+        snapshots
+    );
+
+    db.insert(b"after", b"checkpoint").unwrap();
+    assert!(db.checkpoint().unwrap() > lsn);
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn recover_to_snapshot() {
+    let path = std::env::temp_dir().join("test_recover_to_snapshot");
+    let _ = std::fs::remove_dir_all(&path);
+
+    let db = Config::new().path(&path).open().unwrap();
+    for i in 0_u32..100 {
+        db.insert(i.to_be_bytes(), b"first").unwrap();
+    }
+    let first = db.checkpoint().unwrap();
+    for i in 50_u32..150 {
+        db.insert(i.to_be_bytes(), b"second").unwrap();
+    }
+    let second = db.checkpoint().unwrap();
+    assert!(second > first);
+    drop(db);
+
+    let db = Config::new()
+        .path(&path)
+        .recover_to_snapshot(Some(first))
+        .open()
+        .unwrap();
+    assert_eq!(db.len(), 100);
+    for i in 0_u32..100 {
+        assert_eq!(db.get(i.to_be_bytes()).unwrap().unwrap(), b"first");
+    }
+    assert!(matches!(db.insert(b"k", b"v"), Err(Error::Unsupported(_))));
+    drop(db);
+
+    // the later writes are still there
+    let db = Config::new().path(&path).open().unwrap();
+    assert_eq!(db.len(), 150);
+    assert_eq!(db.get(149_u32.to_be_bytes()).unwrap().unwrap(), b"second");
+    drop(db);
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn discarded_snapshots() {
+    let path = std::env::temp_dir().join("test_discarded_snapshots");
+    let _ = std::fs::remove_dir_all(&path);
+
+    // the temporary directory may be a tmpfs
+    let config = Config::new().path(&path).allow_risky_filesystem(true);
+    let db = config.open().unwrap();
+    for i in 0_u32..100 {
+        db.insert(i.to_be_bytes(), vec![0; 100]).unwrap();
+    }
+    let lsn = db.checkpoint().unwrap();
+    drop(db);
+
+    let valid = path.join(format!("snap.{:016X}", lsn));
+    let bytes = std::fs::read(&valid).unwrap();
+
+    // a newer snapshot that was torn by a crash, and one that
+    // was never finished
+    let truncated = path.join(format!("snap.{:016X}", lsn + (1 << 32)));
+    std::fs::write(&truncated, &bytes[..bytes.len() / 2]).unwrap();
+    let generating =
+        path.join(format!("snap.{:016X}.generating", lsn + (2 << 32)));
+    std::fs::write(&generating, &bytes).unwrap();
+
+    // only the valid snapshot is used
+    let db = config.open().unwrap();
+    let mut warnings = db.open_warnings().to_vec();
+    warnings.sort_by_key(|warning| format!("{:?}", warning));
+    assert_eq!(
+        warnings,
+        vec![
+            OpenWarning::DiscardedSnapshot { path: truncated.clone() },
+            OpenWarning::DiscardedSnapshot { path: generating.clone() },
+        ]
+    );
+    assert!(!truncated.exists());
+    assert!(!generating.exists());
+    assert_eq!(db.len(), 100);
+    drop(db);
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn size_on_disk() {
+    let db = Config::new().temporary(true).open().unwrap();
+
+    let mut written = 0;
+    for i in 0_u32..100 {
+        let value = vec![1_u8; 1024];
+        written += value.len();
+        db.insert(i.to_be_bytes(), value).unwrap();
+    }
+    db.insert(b"heap", vec![2_u8; 128 * 1024]).unwrap();
+    written += 128 * 1024;
+    db.flush().unwrap();
+
+    let on_disk = db.size_on_disk().unwrap();
+    assert!(
+        on_disk > written as u64,
+        "{} bytes of values only took up {} bytes on disk",
+        written,
+        on_disk
+    );
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn pending_flush_bytes() {
+    // snapshots flush the log, and the testing features take
+    // them often
+    let db = Config::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .snapshot_after_ops(u64::MAX)
+        .open()
+        .unwrap();
+
+    db.flush().unwrap();
+    assert_eq!(db.pending_flush_bytes(), 0);
+
+    // small enough to stay in the log with the tiny heap
+    // threshold of the testing features
+    for i in 0_u32..100 {
+        db.insert(i.to_be_bytes(), vec![0; 64]).unwrap();
+    }
+    assert!(db.pending_flush_bytes() >= 100 * 64);
+
+    db.flush().unwrap();
+    assert_eq!(db.pending_flush_bytes(), 0);
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn flush_async() {
+    use std::future::Future;
+    use std::task::{Context, Poll, Wake};
+
+    struct Unparker(std::thread::Thread);
+
+    impl Wake for Unparker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Arc::new(Unparker(std::thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            std::thread::park();
+        }
+    }
+
+    // periodic flushing is disabled so that only
+    // `flush_async` can make the data durable.
+    let db = Config::new().temporary(true).flush_every_ms(None).open().unwrap();
+
+    for i in 0_u32..100 {
+        db.insert(i.to_be_bytes(), vec![0; 100]).unwrap();
+    }
+
+    let flushed = block_on(db.flush_async()).unwrap();
+    assert!(flushed > 0);
+    assert_eq!(db.pending_flush_bytes(), 0);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+#[cfg_attr(miri, ignore)]
+fn sequential_access_pattern() {
+    let config = Config::new()
+        .temporary(true)
+        .segment_size(4096)
+        .access_pattern(sled::AccessPattern::Sequential);
+
+    let db = config.open().unwrap();
+    for i in 0..100_u32 {
+        db.insert(i.to_be_bytes(), vec![0; 256]).unwrap();
+    }
+    drop(db);
+
+    // recovery scans the log with the hint in place
+    let db = config.open().unwrap();
+    assert_eq!(db.len(), 100);
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn with_data_file() {
+    let db = Config::new().temporary(true).open().unwrap();
+    db.insert(b"k", vec![0; 1024]).unwrap();
+    db.flush().unwrap();
+
+    let len = db.with_data_file(|file| file.metadata().unwrap().len());
+    assert!(len > 0);
+    assert_eq!(
+        len,
+        std::fs::metadata(db.storage_path().join("db")).unwrap().len()
+    );
+}
+
+#[test]
+#[cfg(unix)]
+#[cfg_attr(miri, ignore)]
+fn verify_integrity_flags_corrupt_heap_slot() {
+    use std::os::unix::fs::FileExt;
+
+    let db = Config::new().temporary(true).open().unwrap();
+    db.insert(b"small", b"value").unwrap();
+    db.insert(b"large", vec![7_u8; 1024 * 1024]).unwrap();
+    db.flush().unwrap();
+
+    let report = db.verify_integrity().unwrap();
+    assert!(report.pages_checked > 0);
+    assert!(report.dangling_pointers.is_empty());
+    assert!(report.crc_failures.is_empty());
+
+    // the large value is the only item stored in the heap
+    let mut corrupted = 0;
+    for entry in std::fs::read_dir(db.storage_path().join("heap")).unwrap() {
+        let path = entry.unwrap().path();
+        if std::fs::metadata(&path).unwrap().len() > 0 {
+            let file =
+                std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+            file.write_all_at(&[0xFF; 16], 64).unwrap();
+            corrupted += 1;
+        }
+    }
+    assert_eq!(corrupted, 1);
+
+    let report = db.verify_integrity().unwrap();
+    assert!(report.dangling_pointers.is_empty());
+    assert_eq!(report.crc_failures.len(), 1);
+}
+
+#[test]
+#[cfg(unix)]
+#[cfg_attr(miri, ignore)]
+fn scrub_flags_corrupt_segment() {
+    use std::os::unix::fs::FileExt;
+
+    const SEGMENT_SIZE: u64 = 4096;
+
+    let db = Config::new()
+        .temporary(true)
+        .segment_size(SEGMENT_SIZE as usize)
+        .open()
+        .unwrap();
+    for i in 0_u32..64 {
+        db.insert(i.to_be_bytes(), vec![0; 64]).unwrap();
+    }
+    // small enough to stay in the log with the tiny heap
+    // threshold of the testing features. the value gets a tree
+    // of its own, so that it is written as a link to an empty
+    // leaf rather than as part of a large node that could be
+    // moved to the heap.
+    let marked = db.open_tree(b"marked").unwrap();
+    marked.insert(b"k", vec![0xA5_u8; 64]).unwrap();
+    db.flush().unwrap();
+
+    let report = db.scrub().unwrap();
+    assert!(report.segments_scanned > 0);
+    assert_eq!(
+        report.bytes_read,
+        std::fs::metadata(db.storage_path().join("db")).unwrap().len()
+    );
+    assert!(report.crc_failures.is_empty());
+
+    // flip a byte in the middle of the marked value
+    let path = db.storage_path().join("db");
+    let contents = std::fs::read(&path).unwrap();
+    let position = contents
+        .windows(64)
+        .position(|window| window.iter().all(|b| *b == 0xA5))
+        .unwrap() as u64;
+    let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.write_all_at(&[0x5A], position + 32).unwrap();
+
+    let report = db.scrub().unwrap();
+    assert_eq!(
+        report.crc_failures,
+        vec![position / SEGMENT_SIZE * SEGMENT_SIZE]
+    );
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn snapshot_after_bytes() {
+    fn has_snapshot(db: &Db) -> bool {
+        std::fs::read_dir(db.storage_path()).unwrap().any(|entry| {
+            let name = entry.unwrap().file_name();
+            let name = name.to_string_lossy();
+            name.starts_with("snap.") && !name.ends_with(".generating")
+        })
+    }
+
+    // snapshots are written in the background
+    fn wait_for_snapshot(db: &Db) {
+        let mut waited = 0;
+        while !has_snapshot(db) {
+            assert!(waited < 100, "no snapshot was taken");
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            waited += 1;
+        }
+    }
+
+    fn write_values(db: &Db) {
+        for i in 0_u32..64 {
+            db.insert(i.to_be_bytes(), vec![0; 1024]).unwrap();
+        }
+    }
+
+    // without a byte threshold, these writes are too few to
+    // reach snapshot_after_ops.
+    let db = Config::new()
+        .temporary(true)
+        .snapshot_after_ops(1_000_000)
+        .open()
+        .unwrap();
+    write_values(&db);
+    assert!(!has_snapshot(&db));
+    drop(db);
+
+    let db = Config::new()
+        .temporary(true)
+        .snapshot_after_ops(1_000_000)
+        .snapshot_after_bytes(Some(16 * 1024))
+        .open()
+        .unwrap();
+    write_values(&db);
+
+    wait_for_snapshot(&db);
+    drop(db);
+
+    // values stored in the heap count their full size, even
+    // though only a pointer to them is written to the log
+    let db = Config::new()
+        .temporary(true)
+        .snapshot_after_ops(1_000_000)
+        .snapshot_after_bytes(Some(256 * 1024))
+        .open()
+        .unwrap();
+    for i in 0_u32..8 {
+        db.insert(i.to_be_bytes(), vec![0; 64 * 1024]).unwrap();
+    }
+    wait_for_snapshot(&db);
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn separate_heap_path() {
+    let path = std::env::temp_dir().join("test_separate_heap_path");
+    let heap_path = std::env::temp_dir().join("test_separate_heap_path_heap");
+    let _ = std::fs::remove_dir_all(&path);
+    let _ = std::fs::remove_dir_all(&heap_path);
+
+    let config = Config::new().path(&path).heap_path(Some(heap_path.clone()));
+
+    let db = config.open().unwrap();
+    db.insert(b"large", vec![7_u8; 1024 * 1024]).unwrap();
+    drop(db);
+
+    assert!(!path.join("heap").exists());
+    let heap_bytes: u64 = std::fs::read_dir(&heap_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    assert!(heap_bytes >= 1024 * 1024);
+
+    let db = config.open().unwrap();
+    assert_eq!(db.get(b"large").unwrap().unwrap(), vec![7_u8; 1024 * 1024]);
+    drop(db);
+
+    // the heap path is persisted
+    assert!(matches!(
+        Config::new().path(&path).open(),
+        Err(Error::Unsupported(_))
+    ));
+
+    std::fs::remove_dir_all(&path).unwrap();
+    std::fs::remove_dir_all(&heap_path).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn recovery_progress() {
+    let path = std::env::temp_dir().join("test_recovery_progress");
+    let _ = std::fs::remove_dir_all(&path);
+
+    // without snapshots, every segment written is replayed
+    let config = Config::new()
+        .path(&path)
+        .segment_size(4096)
+        .snapshot_after_ops(u64::MAX);
+
+    let db = config.open().unwrap();
+    for i in 0_u32..1000 {
+        db.insert(i.to_be_bytes(), vec![0; 8]).unwrap();
+    }
+    drop(db);
+
+    let progress = Arc::new(std::sync::Mutex::new(vec![]));
+    let progress2 = progress.clone();
+    let db = config
+        .on_recovery_progress(Arc::new(move |p| {
+            progress2.lock().unwrap().push(p)
+        }))
+        .open()
+        .unwrap();
+    assert_eq!(db.len(), 1000);
+    drop(db);
+
+    let progress = progress.lock().unwrap();
+    assert!(progress.len() >= 2);
+    for pair in progress.windows(2) {
+        assert!(pair[0].segments_recovered <= pair[1].segments_recovered);
+        assert!(pair[0].bytes_replayed <= pair[1].bytes_replayed);
+        assert_eq!(pair[0].segments_total, pair[1].segments_total);
+    }
+    let last = progress.last().unwrap();
+    assert!(last.segments_total > 1);
+    assert_eq!(last.segments_recovered, last.segments_total);
+    assert!(last.bytes_replayed > 0);
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn sync_data_mode() {
+    let path = std::env::temp_dir().join("test_sync_data_mode");
+    let _ = std::fs::remove_dir_all(&path);
+
+    let config = Config::new().path(&path).sync_mode(sled::SyncMode::SyncData);
+
+    let db = config.open().unwrap();
+    db.insert(b"k", b"v").unwrap();
+    assert!(db.flush().unwrap() > 0);
+    drop(db);
+
+    let db = config.open().unwrap();
+    assert_eq!(db.get(b"k").unwrap().unwrap(), b"v");
+    drop(db);
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn default_merge_operator() {
+    fn sum(_key: &[u8], old: Option<&[u8]>, merged: &[u8]) -> Option<Vec<u8>> {
+        Some(vec![old.map_or(0, |old| old[0]) + merged[0]])
+    }
+
+    let db = Config::new()
+        .temporary(true)
+        .default_merge_operator(std::sync::Arc::new(sum))
+        .open()
+        .unwrap();
+
+    // no call to set_merge_operator is needed
+    db.merge(b"counter", vec![1]).unwrap();
+    db.merge(b"counter", vec![2]).unwrap();
+    assert_eq!(db.get(b"counter").unwrap().unwrap(), vec![3]);
+}
+
+#[test]
+fn wait_for_durable() {
+    let db = Config::new().temporary(true).flush_every_ms(None).open().unwrap();
+
+    db.insert(b"k", b"v").unwrap();
+    let lsn = db.last_written_lsn();
+
+    // nothing flushes the write on its own
+    assert_eq!(
+        db.wait_for_durable(lsn, Some(Duration::from_millis(10))),
+        Ok(false)
+    );
+
+    let waiter = {
+        let db = db.clone();
+        std::thread::spawn(move || db.wait_for_durable(lsn, None))
+    };
+
+    std::thread::sleep(Duration::from_millis(50));
+    db.flush().unwrap();
+
+    assert_eq!(waiter.join().unwrap(), Ok(true));
+    assert_eq!(
+        db.wait_for_durable(lsn, Some(Duration::from_millis(0))),
+        Ok(true)
+    );
+}
+
+#[test]
+fn max_value_size() {
+    let db = Config::new()
+        .temporary(true)
+        .max_value_size(Some(1024))
+        .open()
+        .unwrap();
+
+    assert_eq!(
+        db.insert(b"large", vec![0; 2048]),
+        Err(Error::Unsupported("value exceeds configured max_value_size"))
+    );
+    assert_eq!(db.get(b"large").unwrap(), None);
+
+    db.insert(b"small", vec![0; 500]).unwrap();
+    assert_eq!(db.get(b"small").unwrap().unwrap().len(), 500);
+
+    // batches are rejected as a whole
+    let mut batch = sled::Batch::default();
+    batch.insert(b"a", vec![0; 500]);
+    batch.insert(b"b", vec![0; 2048]);
+    assert!(db.apply_batch(batch).is_err());
+    assert_eq!(db.get(b"a").unwrap(), None);
+
+    assert!(
+        db.compare_and_swap(b"small", Some(vec![0; 500]), Some(vec![0; 2048]))
+            .is_err()
+    );
+
+    // transactions over several trees are rejected before any is written
+    let other = db.open_tree(b"other").unwrap();
+    let res: TransactionResult<()> =
+        (&*db, &other).transaction(|(db, other)| {
+            db.insert(b"c", vec![0; 500])?;
+            other.insert(b"d", vec![0; 2048])?;
+            Ok(())
+        });
+    assert_eq!(
+        res,
+        Err(TransactionError::Storage(Error::Unsupported(
+            "value exceeds configured max_value_size"
+        )))
+    );
+    assert_eq!(db.get(b"c").unwrap(), None);
+    assert_eq!(other.get(b"d").unwrap(), None);
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn missing_db_file_with_snapshots() {
+    let mut path = std::env::temp_dir();
+    path.push("test_missing_db_file_with_snapshots");
+
+    let _ = std::fs::remove_dir_all(&path);
+
+    let db = Config::new().path(&path).open().unwrap();
+    db.insert(b"k", b"v").unwrap();
+    db.checkpoint().unwrap();
+    drop(db);
+
+    std::fs::remove_file(path.join("db")).unwrap();
+
+    assert_eq!(
+        Config::new().path(&path).open().unwrap_err(),
+        Error::Unsupported(
+            "the db file is missing, and snapshots cannot be restored \
+             without it. See error log for more details."
+        )
+    );
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn tree_sizes() {
+    let mut path = std::env::temp_dir();
+    path.push("test_tree_sizes");
+    let _ = std::fs::remove_dir_all(&path);
+
+    let db = Config::new().path(&path).open().unwrap();
+
+    let small = db.open_tree(b"small").unwrap();
+    let large = db.open_tree(b"large").unwrap();
+
+    for i in 0_u32..10 {
+        small.insert(i.to_be_bytes(), vec![0; 100]).unwrap();
+    }
+    for i in 0_u32..1000 {
+        large.insert(i.to_be_bytes(), vec![0; 100]).unwrap();
+    }
+    large.remove(0_u32.to_be_bytes()).unwrap();
+    db.insert(b"k", b"v").unwrap();
+
+    let sizes = db.tree_sizes().unwrap();
+    assert_eq!(sizes.len(), 3);
+    assert_eq!(sizes[&b"small".to_vec()], 10 * 104);
+    assert_eq!(sizes[&b"large".to_vec()], 999 * 104);
+    assert_eq!(sizes[&b"__sled__default".to_vec()], 2);
+
+    drop((small, large, db));
+
+    // recovered trees are counted on the first call
+    let db = Config::new().path(&path).open().unwrap();
+    assert_eq!(db.tree_sizes().unwrap(), sizes);
+
+    db.open_tree(b"small").unwrap().clear().unwrap();
+    assert_eq!(db.tree_sizes().unwrap()[&b"small".to_vec()], 0);
+
+    drop(db);
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn fast_clean_restart() {
+    let mut path = std::env::temp_dir();
+    path.push("test_fast_clean_restart");
+
+    let _ = std::fs::remove_dir_all(&path);
+
+    // the log is only replayed, and progress reported, when
+    // there is no clean shutdown marker
+    let replays = Arc::new(AtomicUsize::new(0));
+    let config = || {
+        let replays = replays.clone();
+        Config::new().path(&path).fast_clean_restart(true).on_recovery_progress(
+            Arc::new(move |_| {
+                let _ = replays.fetch_add(1, SeqCst);
+            }),
+        )
+    };
+
+    for restart in 0_u32..5 {
+        let db = config().open().unwrap();
+        for i in 0_u32..100 {
+            let key = (restart * 100 + i).to_be_bytes();
+            db.insert(key, vec![restart as u8; 64]).unwrap();
+        }
+        drop(db);
+
+        // a background snapshot may still hold the pagecache,
+        // which records the clean shutdown once it is dropped
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while !path.join("clean_shutdown").exists() {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "no clean shutdown was recorded"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+    assert_eq!(replays.load(SeqCst), 0);
+
+    let db = config().open().unwrap();
+    assert!(!path.join("clean_shutdown").exists());
+    assert_eq!(db.len(), 500);
+    for (i, kv) in db.iter().enumerate() {
+        let (k, v) = kv.unwrap();
+        assert_eq!(&*k, &(i as u32).to_be_bytes());
+        assert_eq!(v, vec![(i / 100) as u8; 64]);
+    }
+    assert_eq!(replays.load(SeqCst), 0);
+    drop(db);
+
+    // without the marker, as after a crash, the log is replayed
+    std::fs::remove_file(path.join("clean_shutdown")).unwrap();
+    let db = config().open().unwrap();
+    assert!(replays.load(SeqCst) > 0);
+    assert_eq!(db.len(), 500);
+    drop(db);
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn max_segment_reuses() {
+    let mut path = std::env::temp_dir();
+    path.push("test_max_segment_reuses");
+
+    let _ = std::fs::remove_dir_all(&path);
+
+    assert!(
+        Config::new()
+            .temporary(true)
+            .max_segment_reuses(Some(0))
+            .open()
+            .is_err()
+    );
+
+    let config = || {
+        Config::new().path(&path).segment_size(4096).max_segment_reuses(Some(1))
+    };
+
+    for restart in 0_u8..3 {
+        let db = config().open().unwrap();
+        for round in 0_u8..20 {
+            for i in 0_u32..50 {
+                db.insert(i.to_be_bytes(), vec![restart ^ round; 100]).unwrap();
+            }
+        }
+        db.flush().unwrap();
+        drop(db);
+    }
+
+    let db = config().open().unwrap();
+    assert_eq!(db.len(), 50);
+    for kv in db.iter() {
+        let (_, v) = kv.unwrap();
+        assert_eq!(v, vec![2 ^ 19; 100]);
+    }
+    drop(db);
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+#[cfg_attr(miri, ignore)]
+fn flush_thread_name() {
+    let thread_names = || -> Vec<String> {
+        std::fs::read_dir("/proc/self/task")
+            .unwrap()
+            .filter_map(|task| {
+                let comm = task.unwrap().path().join("comm");
+                std::fs::read_to_string(comm).ok()
+            })
+            .map(|name| name.trim_end().to_owned())
+            .collect()
+    };
+
+    let db = Config::new()
+        .temporary(true)
+        .flush_every_ms(Some(100))
+        .flush_thread_name("sled-flush-tst".to_owned())
+        .flush_thread_stack_size(Some(256 * 1024))
+        .open()
+        .unwrap();
+
+    // the name is applied by the thread itself once it starts
+    let mut found = false;
+    for _ in 0..100 {
+        if thread_names().iter().any(|name| name == "sled-flush-tst") {
+            found = true;
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(found, "flush thread not found in {:?}", thread_names());
+
+    drop(db);
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn clone_offline() {
+    let mut src = std::env::temp_dir();
+    src.push("test_clone_offline_src");
+    let mut dst = std::env::temp_dir();
+    dst.push("test_clone_offline_dst");
+
+    let _ = std::fs::remove_dir_all(&src);
+    let _ = std::fs::remove_dir_all(&dst);
+
+    let db = Config::new().path(&src).open().unwrap();
+    db.insert(b"a", b"1").unwrap();
+    db.insert(b"b", vec![7_u8; 128 * 1024]).unwrap();
+    db.open_tree(b"other").unwrap().insert(b"c", b"3").unwrap();
+    db.flush().unwrap();
+
+    // refuses to clone a database that is in use
+    assert!(Config::clone_offline(&src, &dst).is_err());
+
+    drop(db);
+
+    Config::clone_offline(&src, &dst).unwrap();
+
+    // refuses to clone over an existing database
+    assert!(Config::clone_offline(&src, &dst).is_err());
+
+    // the copy is independent of the original
+    std::fs::remove_dir_all(&src).unwrap();
+
+    let clone = Config::new().path(&dst).open().unwrap();
+    assert_eq!(clone.get(b"a").unwrap().unwrap(), b"1");
+    assert_eq!(clone.get(b"b").unwrap().unwrap(), vec![7_u8; 128 * 1024]);
+    assert_eq!(
+        clone.open_tree(b"other").unwrap().get(b"c").unwrap().unwrap(),
+        b"3"
+    );
+    drop(clone);
+
+    std::fs::remove_dir_all(&dst).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn clone_offline_external_heap() {
+    let src = std::env::temp_dir().join("test_clone_offline_heap_src");
+    let heap = std::env::temp_dir().join("test_clone_offline_heap_heap");
+    let dst = std::env::temp_dir().join("test_clone_offline_heap_dst");
+
+    let _ = std::fs::remove_dir_all(&src);
+    let _ = std::fs::remove_dir_all(&heap);
+    let _ = std::fs::remove_dir_all(&dst);
+
+    let db =
+        Config::new().path(&src).heap_path(Some(heap.clone())).open().unwrap();
+    db.insert(b"b", vec![7_u8; 128 * 1024]).unwrap();
+    drop(db);
+
+    Config::clone_offline(&src, &dst).unwrap();
+
+    // the clone has its own heap
+    let clone = Config::from_existing(&dst).unwrap().open().unwrap();
+    assert_eq!(clone.get(b"b").unwrap().unwrap(), vec![7_u8; 128 * 1024]);
+    clone.insert(b"b", vec![8_u8; 128 * 1024]).unwrap();
+    drop(clone);
+
+    std::fs::remove_dir_all(&dst).unwrap();
+
+    let db =
+        Config::new().path(&src).heap_path(Some(heap.clone())).open().unwrap();
+    assert_eq!(db.get(b"b").unwrap().unwrap(), vec![7_u8; 128 * 1024]);
+    drop(db);
+
+    std::fs::remove_dir_all(&src).unwrap();
+    std::fs::remove_dir_all(&heap).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn segment_transform() {
+    struct Xor(&'static str, u8);
+
+    impl SegmentTransform for Xor {
+        fn id(&self) -> &str {
+            self.0
+        }
+
+        fn encode(&self, lsn: u64, buf: &mut Vec<u8>) {
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte ^= (lsn + i as u64) as u8 ^ self.1;
+            }
+        }
+
+        fn decode(&self, lsn: u64, buf: &mut Vec<u8>) -> Result<()> {
+            self.encode(lsn, buf);
+            Ok(())
+        }
+    }
+
+    let mut path = std::env::temp_dir();
+    path.push("test_segment_transform");
+
+    let _ = std::fs::remove_dir_all(&path);
+
+    let config = |id, key| {
+        Config::new().path(&path).segment_transform(Arc::new(Xor(id, key)))
+    };
+
+    let db = config("xor: v1", 0x5A).open().unwrap();
+    for i in 0_u32..100 {
+        db.insert(i.to_be_bytes(), i.to_le_bytes().repeat(8)).unwrap();
+    }
+    db.insert(b"heap", vec![0x42_u8; 128 * 1024]).unwrap();
+    db.flush().unwrap();
+    drop(db);
+
+    // the plaintext of the heap value never reaches the disk
+    let heap_dir = path.join("heap");
+    for entry in std::fs::read_dir(&heap_dir).unwrap() {
+        let contents = std::fs::read(entry.unwrap().path()).unwrap();
+        assert!(!contents.windows(64).any(|w| w == &[0x42_u8; 64][..]));
+    }
+
+    let db = config("xor: v1", 0x5A).open().unwrap();
+    for i in 0_u32..100 {
+        assert_eq!(
+            db.get(i.to_be_bytes()).unwrap().unwrap(),
+            i.to_le_bytes().repeat(8)
+        );
+    }
+    assert_eq!(db.get(b"heap").unwrap().unwrap(), vec![0x42_u8; 128 * 1024]);
+    drop(db);
+
+    assert!(Config::new().path(&path).open().is_err());
+    assert!(config("xor: v2", 0x5A).open().is_err());
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
 #[test]
 #[cfg_attr(miri, ignore)]
 fn tree_small_keys_iterator() {
@@ -1056,6 +2060,80 @@ fn create_tree() {
     std::fs::remove_dir_all(path).unwrap();
 }
 
+#[test]
+#[cfg_attr(miri, ignore)]
+fn set_flush_interval() {
+    common::setup_logger();
+
+    // snapshots flush the log too
+    let db = Config::new()
+        .temporary(true)
+        .flush_every_ms(Some(60_000))
+        .snapshot_after_ops(u64::MAX)
+        .open()
+        .unwrap();
+    let log = &db.context.pagecache.log;
+
+    // the flusher's writes complete in the background
+    let wait_until_stable_past = |lsn, what| {
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while log.stable_offset() <= lsn {
+            assert!(std::time::Instant::now() < deadline, "{}", what);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    };
+
+    // returns once the flusher has flushed and gone to sleep
+    db.set_flush_interval(Some(60_000));
+    db.flush().unwrap();
+
+    let stable_before = log.stable_offset();
+    db.insert(b"a", b"a").unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(
+        log.stable_offset(),
+        stable_before,
+        "should not have flushed before the 60s interval elapsed"
+    );
+
+    db.set_flush_interval(Some(50));
+    wait_until_stable_past(stable_before, "should have flushed right away");
+    let stable_after_first = log.stable_offset();
+
+    db.insert(b"b", b"b").unwrap();
+    wait_until_stable_past(
+        stable_after_first,
+        "should have flushed on the new 50ms interval",
+    );
+
+    db.set_flush_interval(None);
+    db.flush().unwrap();
+    let stable_paused = log.stable_offset();
+    db.insert(b"c", b"c").unwrap();
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(log.stable_offset(), stable_paused, "flushing should be paused");
+
+    db.flush().unwrap();
+    assert!(log.stable_offset() > stable_paused);
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn temporary_storage_path() {
+    let db = Config::new().temporary(true).open().unwrap();
+    let path = db.storage_path();
+
+    assert!(path.is_absolute());
+    assert!(path.join("conf").exists());
+
+    if cfg!(target_os = "linux") {
+        assert!(path.starts_with("/dev/shm"), "unexpected path {:?}", path);
+    } else {
+        let temp_dir = std::fs::canonicalize(std::env::temp_dir()).unwrap();
+        assert!(path.starts_with(temp_dir), "unexpected path {:?}", path);
+    }
+}
+
 #[test]
 fn contains_tree() {
     let db = Config::new().temporary(true).flush_every_ms(None).open().unwrap();
@@ -168,10 +168,10 @@ struct ReferenceEntry {
     crash_epoch: u32,
 }
 
-fn prop_tree_crashes_nicely(ops: Vec<Op>, flusher: bool) -> bool {
-    // forces quickcheck to run one thread at a time
-    static M: Lazy<Mutex<()>, fn() -> Mutex<()>> = Lazy::new(|| Mutex::new(()));
+// forces quickcheck to run one thread at a time
+static M: Lazy<Mutex<()>, fn() -> Mutex<()>> = Lazy::new(|| Mutex::new(()));
 
+fn prop_tree_crashes_nicely(ops: Vec<Op>, flusher: bool) -> bool {
     let _lock = M.lock().expect("our test lock should not be poisoned");
 
     // clear all failpoints that may be left over from the last run
@@ -1845,3 +1845,307 @@ fn failpoints_bug_39() {
         ))
     }
 }
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn directory_fsync_policy() {
+    let _lock = M.lock().expect("our test lock should not be poisoned");
+
+    for (policy, should_open) in [
+        (DirFsyncPolicy::Required, false),
+        (DirFsyncPolicy::BestEffort, true),
+        (DirFsyncPolicy::Disabled, true),
+    ] {
+        tear_down_failpoints();
+        sled::fail::set("fsync directory", u64::MAX);
+
+        let res = Config::new().temporary(true).directory_fsync(policy).open();
+
+        tear_down_failpoints();
+
+        assert_eq!(
+            res.is_ok(),
+            should_open,
+            "unexpected result {:?} for policy {:?}",
+            res.map(|_| ()),
+            policy
+        );
+    }
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn on_global_error_fires_once() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let _lock = M.lock().expect("our test lock should not be poisoned");
+    tear_down_failpoints();
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let calls2 = calls.clone();
+
+    let db = Config::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .on_global_error(Arc::new(move |e| {
+            assert_eq!(*e, Error::FailPoint);
+            calls2.fetch_add(1, Ordering::SeqCst);
+        }))
+        .open()
+        .unwrap();
+
+    sled::fail::set("buffer write", u64::MAX);
+
+    db.insert(b"a", b"a").unwrap();
+    assert!(db.flush().is_err());
+    let _ = db.insert(b"b", b"b");
+    assert!(db.flush().is_err());
+
+    tear_down_failpoints();
+
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn open_timeout() {
+    let _lock = M.lock().expect("our test lock should not be poisoned");
+    tear_down_failpoints();
+
+    let path = std::env::temp_dir().join("test_open_timeout");
+    let _ = std::fs::remove_dir_all(&path);
+
+    sled::fail::set("slow recovery", 1);
+    let res = Config::new()
+        .path(&path)
+        .open_timeout(Some(std::time::Duration::from_millis(50)))
+        .open();
+    assert!(matches!(
+        res,
+        Err(Error::Io(
+            std::io::ErrorKind::TimedOut,
+            "open exceeded open_timeout"
+        ))
+    ));
+    tear_down_failpoints();
+
+    // the lock is released once the abandoned open finishes
+    let db = Config::new()
+        .path(&path)
+        .lock_wait(Some(std::time::Duration::from_secs(5)))
+        .open_timeout(Some(std::time::Duration::from_secs(60)))
+        .open()
+        .unwrap();
+    drop(db);
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn write_config_failure_removes_temporary_file() {
+    let _lock = M.lock().expect("our test lock should not be poisoned");
+    tear_down_failpoints();
+
+    let path = std::env::temp_dir()
+        .join("test_write_config_failure_removes_temporary_file");
+    let _ = std::fs::remove_dir_all(&path);
+
+    sled::fail::set("write_config fsync", u64::MAX);
+    assert_eq!(Config::new().path(&path).open().unwrap_err(), Error::FailPoint);
+    tear_down_failpoints();
+
+    assert!(!path.join("conf.tmp").exists());
+
+    // one left behind by a crash is removed when opening
+    std::fs::write(path.join("conf.tmp"), b"partial").unwrap();
+    drop(Config::new().path(&path).open().unwrap());
+    assert!(!path.join("conf.tmp").exists());
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn max_recovery_duration() {
+    let _lock = M.lock().expect("our test lock should not be poisoned");
+    tear_down_failpoints();
+
+    let path = std::env::temp_dir().join("test_max_recovery_duration");
+    let _ = std::fs::remove_dir_all(&path);
+
+    let config = || {
+        Config::new()
+            .path(&path)
+            .segment_size(1024)
+            .flush_every_ms(None)
+            .snapshot_after_ops(u64::MAX)
+    };
+    let updated = |db: &Db| {
+        (0..10_000_u32)
+            .step_by(20)
+            .take_while(|i| {
+                db.get(i.to_be_bytes()).unwrap().unwrap() == vec![2; 32]
+            })
+            .count()
+    };
+
+    let db = config().open().unwrap();
+    for i in 0..10_000_u32 {
+        db.insert(i.to_be_bytes(), vec![1; 32]).unwrap();
+    }
+    // replay starts from the last snapshot. Spreading the
+    // updates across many nodes keeps them from being
+    // consolidated, which would let segments be reused.
+    db.checkpoint().unwrap();
+    for i in (0..10_000_u32).step_by(20) {
+        db.insert(i.to_be_bytes(), vec![2; 32]).unwrap();
+    }
+    db.flush().unwrap();
+    drop(db);
+
+    sled::fail::set("slow replay", u64::MAX);
+    let db = config()
+        .max_recovery_duration(Some(std::time::Duration::from_millis(100)))
+        .open()
+        .unwrap();
+    tear_down_failpoints();
+
+    let skipped = db
+        .open_warnings()
+        .iter()
+        .find_map(|warning| match warning {
+            OpenWarning::PartialRecovery { skipped_segments, .. } => {
+                Some(*skipped_segments)
+            }
+            _ => None,
+        })
+        .expect("replay should have been cut short");
+    assert!(skipped > 0);
+
+    // only a prefix of the updates survives
+    let recovered = updated(&db);
+    assert!(recovered < 500, "recovered all updates");
+    assert_eq!(db.len(), 10_000);
+    for i in (0..10_000_u32).step_by(20).skip(recovered) {
+        assert_eq!(db.get(i.to_be_bytes()).unwrap().unwrap(), vec![1; 32]);
+    }
+    db.insert(b"after", b"partial recovery").unwrap();
+    db.flush().unwrap();
+    drop(db);
+
+    // the discarded segments are gone for good
+    let db = config().open().unwrap();
+    assert!(db.open_warnings().iter().all(|warning| !matches!(
+        warning,
+        OpenWarning::PartialRecovery { .. }
+    )));
+    assert_eq!(updated(&db), recovered);
+    assert_eq!(db.get(b"after").unwrap().unwrap(), b"partial recovery");
+    drop(db);
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn max_unflushed_bytes() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+    use std::thread;
+
+    let _lock = M.lock().expect("our test lock should not be poisoned");
+    tear_down_failpoints();
+
+    const LIMIT: u64 = 16 * 1024;
+
+    // values stay below the heap threshold of the testing
+    // features, so that every write goes through the log, and
+    // snapshots, which flush the log, are not taken.
+    let db = Config::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .snapshot_after_ops(u64::MAX)
+        .max_unflushed_bytes(Some(LIMIT))
+        .open()
+        .unwrap();
+
+    sled::fail::set("slow flush", u64::MAX);
+    let done = Arc::new(AtomicBool::new(false));
+    let sampler = {
+        let db = db.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut max_pending = 0;
+            while !done.load(SeqCst) {
+                max_pending = max_pending.max(db.pending_flush_bytes());
+                thread::yield_now();
+            }
+            max_pending
+        })
+    };
+    let writers: Vec<_> = (0..4_u32)
+        .map(|t| {
+            let db = db.clone();
+            thread::spawn(move || {
+                for i in 0..256_u32 {
+                    let key = [t.to_be_bytes(), i.to_be_bytes()].concat();
+                    db.insert(key, vec![0; 64]).unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    done.store(true, SeqCst);
+    let max_pending = sampler.join().unwrap();
+    tear_down_failpoints();
+
+    // each writer may pass the check just before the others
+    // reserve their writes, which may be whole nodes of up to
+    // 32k. Without the limit, megabytes end up pending.
+    assert!(
+        max_pending <= LIMIT + 4 * 32 * 1024,
+        "{} bytes were pending",
+        max_pending
+    );
+    assert_eq!(db.len(), 1024);
+}
+
+// with the event log, buffers are written by the thread that
+// seals them before it continues, so a write never waits for
+// a flush that is still in progress.
+#[test]
+#[cfg(not(feature = "event_log"))]
+#[cfg_attr(miri, ignore)]
+fn max_unflushed_wait() {
+    use std::io::ErrorKind;
+    use std::time::Duration;
+
+    let _lock = M.lock().expect("our test lock should not be poisoned");
+    tear_down_failpoints();
+
+    let db = Config::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .snapshot_after_ops(u64::MAX)
+        .max_unflushed_bytes(Some(16 * 1024))
+        .max_unflushed_wait(Some(Duration::from_millis(1)))
+        .open()
+        .unwrap();
+    // each activation is only used once
+    sled::fail::set("slow flush", u64::MAX);
+    let timed_out = (0..1024_u32)
+        .find_map(|i| db.insert(i.to_be_bytes(), vec![0; 64]).err());
+    tear_down_failpoints();
+    assert!(
+        matches!(timed_out, Some(Error::Io(ErrorKind::TimedOut, _))),
+        "{:?}",
+        timed_out
+    );
+    // writes go through again once the log has been flushed
+    db.flush().unwrap();
+    db.insert(b"after", b"timeout").unwrap();
+}
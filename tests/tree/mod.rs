@@ -390,7 +390,7 @@ fn prop_tree_matches_btreemap_inner(
     }
 
     let space_amplification = tree
-        .space_amplification()
+        .log_space_amplification()
         .expect("should be able to read files and pages");
 
     assert!(